@@ -54,7 +54,7 @@ interface RunnerToServerEvents {
     'tunnel:ready': (data: { tunnelId: string }) => void
     'tunnel:data': (data: { tunnelId: string; data: string }) => void
     'tunnel:close': (data: { tunnelId: string }) => void
-    'tunnel:error': (data: { tunnelId: string; message: string }) => void
+    'tunnel:error': (data: { tunnelId: string; message: string; code?: string }) => void
 }
 
 type MachineRpcHandlers = {
@@ -343,9 +343,9 @@ export class ApiMachineClient {
             this.socket.emit('tunnel:close', { tunnelId })
         })
 
-        tcpSocket.on('error', (err) => {
+        tcpSocket.on('error', (err: NodeJS.ErrnoException) => {
             this.tunnels.delete(tunnelId)
-            this.socket.emit('tunnel:error', { tunnelId, message: err.message })
+            this.socket.emit('tunnel:error', { tunnelId, message: err.message, code: err.code })
         })
 
         this.tunnels.set(tunnelId, tcpSocket)
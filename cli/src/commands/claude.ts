@@ -4,7 +4,7 @@ import { z } from 'zod'
 import { PROTOCOL_VERSION } from '@hapi/protocol'
 import type { StartOptions } from '@/claude/runClaude'
 import { configuration } from '@/configuration'
-import { isRunnerRunningCurrentlyInstalledHappyVersion } from '@/runner/controlClient'
+import { getExternalRunnerUrl, isRunnerRunningCurrentlyInstalledHappyVersion } from '@/runner/controlClient'
 import { authAndSetupMachineIfNeeded } from '@/ui/auth'
 import { logger } from '@/ui/logger'
 import { initializeToken } from '@/ui/tokenInit'
@@ -122,7 +122,8 @@ ${chalk.bold.cyan('Claude Code Options (from `claude --help`):')}
 
         logger.debug('Ensuring hapi background service is running & matches our version...')
 
-        if (!(await isRunnerRunningCurrentlyInstalledHappyVersion())) {
+        // A session spawned by an external runner must not start a second one
+        if (!getExternalRunnerUrl() && !(await isRunnerRunningCurrentlyInstalledHappyVersion())) {
             logger.debug('Starting hapi background service...')
 
             const runnerProcess = spawnHappyCLI(['runner', 'start-sync'], {
@@ -33,27 +33,39 @@ export function getInstalledCliMtimeMs(): number | undefined {
   }
 }
 
+/**
+ * Set for sessions spawned by a runner other than this CLI's own (e.g. happier),
+ * which serves the runner endpoints there instead of via runner.state.json.
+ */
+export function getExternalRunnerUrl(): string | undefined {
+  return process.env.HAPI_RUNNER_URL?.replace(/\/+$/, '') || undefined;
+}
+
 async function runnerPost(path: string, body?: any): Promise<{ error?: string } | any> {
-  const state = await readRunnerState();
-  if (!state?.httpPort) {
-    const errorMessage = 'No runner running, no state file found';
-    logger.debug(`[CONTROL CLIENT] ${errorMessage}`);
-    return {
-      error: errorMessage
-    };
-  }
+  let baseUrl = getExternalRunnerUrl();
+  if (!baseUrl) {
+    const state = await readRunnerState();
+    if (!state?.httpPort) {
+      const errorMessage = 'No runner running, no state file found';
+      logger.debug(`[CONTROL CLIENT] ${errorMessage}`);
+      return {
+        error: errorMessage
+      };
+    }
 
-  if (!isProcessAlive(state.pid)) {
-    const errorMessage = 'Runner is not running, file is stale';
-    logger.debug(`[CONTROL CLIENT] ${errorMessage}`);
-    return {
-      error: errorMessage
-    };
+    if (!isProcessAlive(state.pid)) {
+      const errorMessage = 'Runner is not running, file is stale';
+      logger.debug(`[CONTROL CLIENT] ${errorMessage}`);
+      return {
+        error: errorMessage
+      };
+    }
+    baseUrl = `http://127.0.0.1:${state.httpPort}`;
   }
 
   try {
     const timeout = process.env.HAPI_RUNNER_HTTP_TIMEOUT ? parseInt(process.env.HAPI_RUNNER_HTTP_TIMEOUT) : 10_000;
-    const response = await fetch(`http://127.0.0.1:${state.httpPort}${path}`, {
+    const response = await fetch(`${baseUrl}${path}`, {
       method: 'POST',
       headers: { 'Content-Type': 'application/json' },
       body: JSON.stringify(body || {}),
@@ -0,0 +1,378 @@
+use futures_util::{SinkExt, StreamExt};
+use serde::Serialize;
+use serde_json::Value;
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::{mpsc, oneshot, Notify};
+use tokio::task::JoinHandle;
+use tokio::time::{timeout, Instant};
+use tokio_tungstenite::{connect_async, tungstenite::Message};
+use url::Url;
+
+use crate::event::{self, Event, Received};
+use crate::outbox::{Entry, Outbox};
+use crate::packet::{fill_placeholders, EnginePacket, Packet, PacketType};
+
+/// How long `disconnect` waits for the write queue to drain.
+const CLOSE_TIMEOUT: Duration = Duration::from_secs(5);
+/// How long the server has to accept our namespace connect.
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+/// Frames queued for the writer task before `emit` waits.
+const WRITE_QUEUE: usize = 128;
+
+/// The server refused our namespace connect (`44`), with its reason.
+#[derive(Debug)]
+pub struct ConnectRefused(pub String);
+
+impl std::fmt::Display for ConnectRefused {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Socket.IO connect error: {}", self.0)
+    }
+}
+
+impl std::error::Error for ConnectRefused {}
+
+/// Hooks for metrics; every method defaults to doing nothing.
+pub trait Observer: Send + Sync {
+    /// A new connection's write queue.
+    fn queue(&self, _queue: Queue) {}
+    /// Time from `emit_with_ack` to the ack.
+    fn ack(&self, _latency: Duration) {}
+    fn ack_timeout(&self) {}
+}
+
+impl Observer for () {}
+
+/// A connection's write queue, without keeping it open.
+pub struct Queue(mpsc::WeakSender<Message>);
+
+impl Queue {
+    /// Frames waiting for the writer task; 0 once the connection is gone.
+    pub fn depth(&self) -> usize {
+        match self.0.upgrade() {
+            Some(tx) if !tx.is_closed() => tx.max_capacity() - tx.capacity(),
+            _ => 0,
+        }
+    }
+}
+
+/// A minimal Socket.IO (EIO4) client over WebSocket, joined to one namespace.
+#[derive(Clone)]
+pub struct SocketClient {
+    write_tx: mpsc::Sender<Message>,
+    ack_waiters: Arc<std::sync::Mutex<HashMap<u64, oneshot::Sender<Vec<Value>>>>>,
+    next_id: Arc<AtomicU64>,
+    namespace: String,
+    disconnect_notify: Arc<Notify>,
+    /// Unix millis of the last frame received from the server.
+    last_inbound: Arc<AtomicU64>,
+    observer: Arc<dyn Observer>,
+    /// Taken by `disconnect` to wait for queued frames to go out.
+    writer: Arc<std::sync::Mutex<Option<JoinHandle<()>>>>,
+}
+
+impl SocketClient {
+    /// Connect to `api_url` and join `namespace` with `auth`. `on_event` runs
+    /// on the read task for every event, so it must not block.
+    pub async fn connect(
+        api_url: &str,
+        namespace: &str,
+        auth: Value,
+        observer: Arc<dyn Observer>,
+        on_event: impl Fn(Received, SocketClient) + Send + Sync + 'static,
+    ) -> Result<Self, Box<dyn std::error::Error>> {
+        // Build WebSocket URL
+        let mut url = Url::parse(api_url)?;
+        let scheme = match url.scheme() {
+            "https" => "wss",
+            _ => "ws",
+        };
+        url.set_scheme(scheme).map_err(|_| "invalid url scheme")?;
+        url.set_path("/socket.io/");
+        url.set_query(Some("EIO=4&transport=websocket"));
+
+        let (stream, _) = connect_async(url.as_str()).await?;
+        let (ws_write, mut ws_read) = stream.split();
+
+        // Spawn writer task
+        let (write_tx, mut write_rx) = mpsc::channel::<Message>(WRITE_QUEUE);
+        observer.queue(Queue(write_tx.downgrade()));
+        let writer = tokio::spawn(async move {
+            let mut ws_write = ws_write;
+            while let Some(msg) = write_rx.recv().await {
+                let close = matches!(msg, Message::Close(_));
+                if ws_write.send(msg).await.is_err() || close {
+                    break;
+                }
+            }
+        });
+
+        let client = SocketClient {
+            write_tx: write_tx.clone(),
+            ack_waiters: Arc::new(std::sync::Mutex::new(HashMap::new())),
+            next_id: Arc::new(AtomicU64::new(1)),
+            namespace: namespace.to_string(),
+            disconnect_notify: Arc::new(Notify::new()),
+            last_inbound: Arc::new(AtomicU64::new(now_millis())),
+            observer,
+            writer: Arc::new(std::sync::Mutex::new(Some(writer))),
+        };
+
+        // Engine.IO open
+        match ws_read.next().await {
+            Some(Ok(Message::Text(text))) => match EnginePacket::decode(&text) {
+                Ok(EnginePacket::Open(_)) => {}
+                _ => {
+                    let start: String = text.chars().take(80).collect();
+                    return Err(format!("expected EIO open, got: {}", start).into());
+                }
+            },
+            _ => return Err("no EIO open packet".into()),
+        }
+
+        client.send(&Packet::connect(namespace, Some(auth))).await?;
+        let deadline = Instant::now() + CONNECT_TIMEOUT;
+        loop {
+            let frame = match timeout(deadline.saturating_duration_since(Instant::now()), ws_read.next()).await {
+                Ok(Some(Ok(Message::Text(text)))) => text,
+                Ok(Some(Ok(_))) => continue,
+                Ok(Some(Err(e))) => return Err(e.into()),
+                Ok(None) => return Err("WebSocket closed during connect".into()),
+                Err(_) => return Err("Socket.IO connect ack timed out".into()),
+            };
+            let packet = match EnginePacket::decode(&frame) {
+                Ok(EnginePacket::Ping(data)) => {
+                    client.send_engine(EnginePacket::Pong(data)).await?;
+                    continue;
+                }
+                Ok(EnginePacket::Close) => return Err("Engine.IO closed during connect".into()),
+                Ok(EnginePacket::Message(packet)) => packet,
+                _ => continue,
+            };
+            let Ok(packet) = Packet::decode(&packet) else {
+                continue;
+            };
+            if packet.namespace != namespace {
+                continue;
+            }
+            match packet.kind {
+                PacketType::Connect => break,
+                PacketType::ConnectError => {
+                    let data = packet.data.unwrap_or(Value::Null);
+                    let reason = match data["message"].as_str() {
+                        Some(message) => message.to_string(),
+                        None => data.as_str().map_or_else(|| data.to_string(), String::from),
+                    };
+                    return Err(ConnectRefused(reason).into());
+                }
+                PacketType::Disconnect => {
+                    return Err(format!("Socket.IO closed during connect: {}", frame).into())
+                }
+                _ => {}
+            }
+        }
+
+        let reader = client.clone();
+        tokio::spawn(async move {
+            reader.read_loop(ws_read, on_event).await;
+            reader.disconnect_notify.notify_waiters();
+        });
+
+        Ok(client)
+    }
+
+    async fn read_loop<S>(&self, mut ws_read: S, on_event: impl Fn(Received, SocketClient))
+    where
+        S: futures_util::Stream<Item = Result<Message, tokio_tungstenite::tungstenite::Error>> + Unpin,
+    {
+        // A binary packet waiting for its attachments
+        let mut partial: Option<(Packet, Vec<Vec<u8>>)> = None;
+        while let Some(Ok(msg)) = ws_read.next().await {
+            self.last_inbound.store(now_millis(), Ordering::Relaxed);
+            let packet = match msg {
+                Message::Text(text) => match EnginePacket::decode(&text) {
+                    Ok(EnginePacket::Ping(data)) => {
+                        let _ = self.send_engine(EnginePacket::Pong(data)).await;
+                        continue;
+                    }
+                    Ok(EnginePacket::Close) => break,
+                    Ok(EnginePacket::Message(packet)) => match Packet::decode(&packet) {
+                        Ok(packet) => packet,
+                        Err(e) => {
+                            log::debug!("Ignoring Socket.IO packet: {}", e);
+                            continue;
+                        }
+                    },
+                    _ => continue,
+                },
+                Message::Binary(bytes) => {
+                    let Some((packet, mut attachments)) = partial.take() else {
+                        continue;
+                    };
+                    attachments.push(bytes);
+                    if attachments.len() < packet.attachments {
+                        partial = Some((packet, attachments));
+                        continue;
+                    }
+                    let mut packet = packet;
+                    if let Some(data) = packet.data.as_mut() {
+                        if let Err(e) = fill_placeholders(data, &attachments) {
+                            log::debug!("Ignoring Socket.IO packet: {}", e);
+                            continue;
+                        }
+                    }
+                    self.dispatch(packet, &on_event);
+                    continue;
+                }
+                Message::Close(_) => break,
+                _ => continue,
+            };
+            if packet.namespace != self.namespace {
+                continue;
+            }
+            match packet.kind {
+                PacketType::Disconnect => break,
+                kind if kind.is_binary() && packet.attachments > 0 => {
+                    partial = Some((packet, Vec::new()));
+                }
+                _ => self.dispatch(packet, &on_event),
+            }
+        }
+    }
+
+    fn dispatch(&self, packet: Packet, on_event: &impl Fn(Received, SocketClient)) {
+        match packet.kind {
+            PacketType::Ack | PacketType::BinaryAck => {
+                let Some(id) = packet.id else { return };
+                let args = match packet.data {
+                    Some(Value::Array(args)) => args,
+                    _ => Vec::new(),
+                };
+                if let Some(tx) = self.ack_waiters.lock().unwrap().remove(&id) {
+                    let _ = tx.send(args);
+                }
+            }
+            PacketType::Event | PacketType::BinaryEvent => {
+                let Some((name, args)) = packet.event_args() else {
+                    return;
+                };
+                let received = Received {
+                    name: name.to_string(),
+                    args: args.to_vec(),
+                    ack: packet.id,
+                };
+                on_event(received, self.clone());
+            }
+            _ => {}
+        }
+    }
+
+    async fn send_engine(&self, packet: EnginePacket) -> Result<(), Box<dyn std::error::Error>> {
+        self.write_tx
+            .send(Message::Text(packet.encode()))
+            .await
+            .map_err(|_| "socket write failed")?;
+        Ok(())
+    }
+
+    async fn send(&self, packet: &Packet) -> Result<(), Box<dyn std::error::Error>> {
+        self.send_engine(EnginePacket::Message(packet.encode())).await
+    }
+
+    pub async fn emit(&self, event: &str, data: Value) -> Result<(), Box<dyn std::error::Error>> {
+        self.send(&Packet::event(&self.namespace, event, vec![data], None))
+            .await
+    }
+
+    /// Emit a typed event.
+    pub async fn emit_event<E: Event + Serialize>(
+        &self,
+        event: &E,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let args = event::args(event)?;
+        self.send(&Packet::event(&self.namespace, E::NAME, args, None))
+            .await
+    }
+
+    /// Emit `event` and wait for the server's ack; returns its arguments as
+    /// a JSON array.
+    pub async fn emit_with_ack(
+        &self,
+        event: &str,
+        data: Value,
+        timeout_secs: u64,
+    ) -> Result<Value, Box<dyn std::error::Error>> {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let (tx, rx) = oneshot::channel();
+        self.ack_waiters.lock().unwrap().insert(id, tx);
+        let packet = Packet::event(&self.namespace, event, vec![data], Some(id));
+        if let Err(e) = self.send(&packet).await {
+            self.ack_waiters.lock().unwrap().remove(&id);
+            return Err(e);
+        }
+        let sent = Instant::now();
+        let result = match timeout(Duration::from_secs(timeout_secs), rx).await {
+            Ok(result) => result?,
+            Err(elapsed) => {
+                self.ack_waiters.lock().unwrap().remove(&id);
+                self.observer.ack_timeout();
+                return Err(elapsed.into());
+            }
+        };
+        self.observer.ack(sent.elapsed());
+        Ok(Value::Array(result))
+    }
+
+    /// Emit `event`, or keep it in `outbox` if the socket is gone.
+    pub async fn emit_or_queue(&self, outbox: &Outbox, entry: Entry) {
+        if self.emit(&entry.event, entry.data.clone()).await.is_err() {
+            outbox.push(entry);
+        }
+    }
+
+    /// Emit what `outbox` holds, oldest first; returns how many were sent.
+    pub async fn flush(&self, outbox: &Outbox) -> Result<usize, Box<dyn std::error::Error>> {
+        outbox
+            .flush(|entry| async move { self.emit(&entry.event, entry.data).await })
+            .await
+    }
+
+    /// Answer an event the server sent with an ack id.
+    pub async fn ack(&self, id: u64, data: Value) -> Result<(), Box<dyn std::error::Error>> {
+        self.send(&Packet::ack(&self.namespace, id, vec![data])).await
+    }
+
+    /// Leave the namespace and close the WebSocket once everything queued
+    /// before has been written, waiting up to `CLOSE_TIMEOUT` for that.
+    pub async fn disconnect(&self) -> Result<(), Box<dyn std::error::Error>> {
+        let _ = self.send(&Packet::disconnect(&self.namespace)).await;
+        let _ = self.write_tx.send(Message::Close(None)).await;
+        let writer = self.writer.lock().unwrap().take();
+        if let Some(writer) = writer {
+            if timeout(CLOSE_TIMEOUT, writer).await.is_err() {
+                log::debug!("Socket.IO writer did not finish within {:?}", CLOSE_TIMEOUT);
+            }
+        }
+        Ok(())
+    }
+
+    /// Notified once the read task ends: the server left or the socket closed.
+    pub fn on_disconnect(&self) -> Arc<Notify> {
+        self.disconnect_notify.clone()
+    }
+
+    /// Shared timestamp of the last inbound frame; the server pings every
+    /// ~25s, so a stale value means the connection is wedged.
+    pub fn last_inbound(&self) -> Arc<AtomicU64> {
+        self.last_inbound.clone()
+    }
+}
+
+fn now_millis() -> u64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis() as u64
+}
@@ -0,0 +1,38 @@
+//! Typed events: a struct names its event, and serde does the payload.
+
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use serde_json::Value;
+
+/// An event with a fixed name whose first argument is `Self`.
+pub trait Event {
+    const NAME: &'static str;
+}
+
+/// An event from the server.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Received {
+    pub name: String,
+    /// The arguments after the name, binary attachments filled in.
+    pub args: Vec<Value>,
+    /// Set when the server wants an answer through `SocketClient::ack`.
+    pub ack: Option<u64>,
+}
+
+impl Received {
+    /// The first argument, or `null` for an event without any.
+    pub fn data(&self) -> &Value {
+        self.args.first().unwrap_or(&Value::Null)
+    }
+
+    /// The payload as `E`: `None` for another event, `Some(Err)` if it is `E`
+    /// but the payload does not fit.
+    pub fn parse<E: Event + DeserializeOwned>(&self) -> Option<Result<E, serde_json::Error>> {
+        (self.name == E::NAME).then(|| E::deserialize(self.data()))
+    }
+}
+
+/// The arguments to emit `event` with.
+pub fn args<E: Event + Serialize>(event: &E) -> Result<Vec<Value>, serde_json::Error> {
+    Ok(vec![serde_json::to_value(event)?])
+}
@@ -0,0 +1,19 @@
+//! A Socket.IO v4 client over WebSocket, as much of the protocol as
+//! happier needs: one namespace per connection, events, acks and binary
+//! attachments, but no HTTP long-polling.
+//!
+//! `packet` is the wire format, `event` the typed layer on top, `outbox`
+//! holds what could not be sent, and `mock` (feature `mock`) is an
+//! in-process server for tests.
+
+mod client;
+pub mod event;
+#[cfg(feature = "mock")]
+pub mod mock;
+pub mod outbox;
+pub mod packet;
+
+pub use client::{ConnectRefused, Observer, Queue, SocketClient};
+pub use event::{Event, Received};
+pub use outbox::Outbox;
+pub use packet::{DecodeError, EnginePacket, Packet, PacketType};
@@ -0,0 +1,361 @@
+//! An in-process Socket.IO server for tests. It does the Engine.IO
+//! handshake and hands each client over as a `MockConnection`, which the
+//! test drives packet by packet. Plain HTTP requests on the same port go
+//! to a handler, for servers that also have a REST API.
+
+use futures_util::{SinkExt, StreamExt};
+use serde_json::{json, Value};
+use std::collections::VecDeque;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::mpsc;
+use tokio::task::JoinHandle;
+use tokio_tungstenite::tungstenite::handshake::server::{Request, Response};
+use tokio_tungstenite::tungstenite::{Error, Message};
+use tokio_tungstenite::WebSocketStream;
+
+use crate::packet::{EnginePacket, Packet, PacketType};
+
+/// Longest HTTP request head we read.
+const MAX_HEAD: usize = 16 * 1024;
+
+/// An HTTP request that is not a WebSocket upgrade.
+#[derive(Debug, Clone)]
+pub struct HttpRequest {
+    pub method: String,
+    pub path: String,
+    /// Names in lower case.
+    pub headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+}
+
+impl HttpRequest {
+    pub fn header(&self, name: &str) -> Option<&str> {
+        let name = name.to_ascii_lowercase();
+        self.headers
+            .iter()
+            .find(|(key, _)| *key == name)
+            .map(|(_, value)| value.as_str())
+    }
+
+    /// The body as JSON, or `null`.
+    pub fn json(&self) -> Value {
+        serde_json::from_slice(&self.body).unwrap_or(Value::Null)
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct HttpResponse {
+    pub status: u16,
+    pub headers: Vec<(String, String)>,
+    pub body: String,
+}
+
+impl HttpResponse {
+    pub fn json(status: u16, body: &Value) -> Self {
+        HttpResponse {
+            status,
+            headers: vec![("Content-Type".into(), "application/json".into())],
+            body: body.to_string(),
+        }
+    }
+
+    pub fn not_found() -> Self {
+        HttpResponse::json(404, &json!({ "error": "Not found" }))
+    }
+}
+
+type HttpHandler = Arc<dyn Fn(HttpRequest) -> HttpResponse + Send + Sync>;
+
+/// Listens on a loopback port until dropped.
+pub struct MockServer {
+    addr: SocketAddr,
+    connections: mpsc::UnboundedReceiver<MockConnection>,
+    accept_task: JoinHandle<()>,
+}
+
+impl MockServer {
+    /// A server that answers plain HTTP with 404.
+    pub async fn start() -> std::io::Result<Self> {
+        MockServer::start_with_http(|_| HttpResponse::not_found()).await
+    }
+
+    /// A server that answers plain HTTP with `http`.
+    pub async fn start_with_http(
+        http: impl Fn(HttpRequest) -> HttpResponse + Send + Sync + 'static,
+    ) -> std::io::Result<Self> {
+        let http: HttpHandler = Arc::new(http);
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let addr = listener.local_addr()?;
+        let (tx, connections) = mpsc::unbounded_channel();
+        let sids = Arc::new(AtomicU64::new(1));
+        let accept_task = tokio::spawn(async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                let tx = tx.clone();
+                let http = http.clone();
+                let sid = format!("mock-{}", sids.fetch_add(1, Ordering::Relaxed));
+                tokio::spawn(async move {
+                    match read_head(&stream).await {
+                        Some(head) if is_upgrade(&head) => {
+                            if let Ok(connection) = MockConnection::handshake(stream, sid).await {
+                                let _ = tx.send(connection);
+                            }
+                        }
+                        Some(head) => serve_http(stream, &head, &*http).await,
+                        None => {}
+                    }
+                });
+            }
+        });
+        Ok(MockServer {
+            addr,
+            connections,
+            accept_task,
+        })
+    }
+
+    pub fn addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    /// The base URL to give a client, e.g. `http://127.0.0.1:41234`.
+    pub fn url(&self) -> String {
+        format!("http://{}", self.addr)
+    }
+
+    /// The next client, once it has received the Engine.IO open packet.
+    pub async fn accept(&mut self) -> Option<MockConnection> {
+        self.connections.recv().await
+    }
+}
+
+impl Drop for MockServer {
+    fn drop(&mut self) {
+        self.accept_task.abort();
+    }
+}
+
+/// The request head, peeked so a WebSocket handshake can still read it.
+async fn read_head(stream: &TcpStream) -> Option<String> {
+    let mut buf = vec![0; MAX_HEAD];
+    loop {
+        let n = stream.peek(&mut buf).await.ok()?;
+        if n == 0 {
+            return None;
+        }
+        if let Some(end) = buf[..n].windows(4).position(|w| w == b"\r\n\r\n") {
+            return String::from_utf8(buf[..end + 4].to_vec()).ok();
+        }
+        if n == buf.len() {
+            return None;
+        }
+        // Peeking again returns at once while only part of the head is in
+        tokio::time::sleep(std::time::Duration::from_millis(5)).await;
+    }
+}
+
+fn is_upgrade(head: &str) -> bool {
+    head.lines().any(|line| {
+        let line = line.to_ascii_lowercase();
+        line.starts_with("upgrade:") && line.contains("websocket")
+    })
+}
+
+async fn serve_http(mut stream: TcpStream, head: &str, http: &(dyn Fn(HttpRequest) -> HttpResponse + Send + Sync)) {
+    let mut skip = vec![0; head.len()];
+    if stream.read_exact(&mut skip).await.is_err() {
+        return;
+    }
+    let mut lines = head.lines();
+    let mut request_line = lines.next().unwrap_or("").split(' ');
+    let method = request_line.next().unwrap_or("").to_string();
+    let path = request_line.next().unwrap_or("").to_string();
+    let headers: Vec<(String, String)> = lines
+        .filter_map(|line| line.split_once(':'))
+        .map(|(key, value)| (key.trim().to_ascii_lowercase(), value.trim().to_string()))
+        .collect();
+    let length = headers
+        .iter()
+        .find(|(key, _)| key == "content-length")
+        .and_then(|(_, value)| value.parse().ok())
+        .unwrap_or(0);
+    let mut body = vec![0; length];
+    if stream.read_exact(&mut body).await.is_err() {
+        return;
+    }
+
+    let response = http(HttpRequest {
+        method,
+        path,
+        headers,
+        body,
+    });
+    let mut out = format!("HTTP/1.1 {} Mock\r\n", response.status);
+    for (key, value) in &response.headers {
+        out.push_str(&format!("{}: {}\r\n", key, value));
+    }
+    out.push_str(&format!(
+        "Content-Length: {}\r\nConnection: close\r\n\r\n{}",
+        response.body.len(),
+        response.body
+    ));
+    let _ = stream.write_all(out.as_bytes()).await;
+    let _ = stream.shutdown().await;
+}
+
+/// One client's WebSocket, past the Engine.IO handshake.
+pub struct MockConnection {
+    ws: WebSocketStream<TcpStream>,
+    /// Path and query of the upgrade request.
+    pub uri: String,
+    pub sid: String,
+    /// Packets read while waiting for something else, e.g. a pong.
+    pending: VecDeque<Packet>,
+}
+
+impl MockConnection {
+    // The callback's error type is tungstenite's
+    #[allow(clippy::result_large_err)]
+    async fn handshake(stream: TcpStream, sid: String) -> Result<Self, Error> {
+        let mut uri = String::new();
+        let ws = tokio_tungstenite::accept_hdr_async(stream, |request: &Request, response: Response| {
+            uri = request.uri().to_string();
+            Ok(response)
+        })
+        .await?;
+        let mut connection = MockConnection {
+            ws,
+            uri,
+            sid,
+            pending: VecDeque::new(),
+        };
+        let open = json!({
+            "sid": connection.sid,
+            "upgrades": [],
+            "pingInterval": 25000,
+            "pingTimeout": 20000,
+            "maxPayload": 1000000,
+        });
+        connection
+            .send_engine(EnginePacket::Open(open.to_string()))
+            .await?;
+        Ok(connection)
+    }
+
+    /// The next Socket.IO packet from the client. Frames that don't decode
+    /// are skipped; `None` once the client closes.
+    pub async fn recv(&mut self) -> Option<Packet> {
+        if let Some(packet) = self.pending.pop_front() {
+            return Some(packet);
+        }
+        loop {
+            match self.next_engine().await? {
+                EnginePacket::Message(text) => {
+                    if let Ok(packet) = Packet::decode(&text) {
+                        return Some(packet);
+                    }
+                }
+                EnginePacket::Close => return None,
+                _ => {}
+            }
+        }
+    }
+
+    async fn next_engine(&mut self) -> Option<EnginePacket> {
+        loop {
+            match self.ws.next().await? {
+                Ok(Message::Text(text)) => {
+                    if let Ok(packet) = EnginePacket::decode(&text) {
+                        return Some(packet);
+                    }
+                }
+                Ok(Message::Close(_)) | Err(_) => return None,
+                Ok(_) => {}
+            }
+        }
+    }
+
+    /// Wait for the client to join a namespace and let it in; returns its auth.
+    pub async fn accept_connect(&mut self) -> Option<Value> {
+        let packet = self.expect(PacketType::Connect).await?;
+        let reply = Packet::connect(&packet.namespace, Some(json!({ "sid": self.sid })));
+        self.send(&reply).await.ok()?;
+        Some(packet.data.unwrap_or(Value::Null))
+    }
+
+    /// Wait for the client to join a namespace and refuse it with `message`.
+    pub async fn refuse_connect(&mut self, message: &str) -> Option<Value> {
+        let packet = self.expect(PacketType::Connect).await?;
+        let reply = Packet::connect_error(&packet.namespace, message);
+        self.send(&reply).await.ok()?;
+        Some(packet.data.unwrap_or(Value::Null))
+    }
+
+    /// The next packet of type `kind`, dropping others before it.
+    pub async fn expect(&mut self, kind: PacketType) -> Option<Packet> {
+        loop {
+            let packet = self.recv().await?;
+            if packet.kind == kind {
+                return Some(packet);
+            }
+        }
+    }
+
+    /// The next event called `name`, dropping other packets before it.
+    pub async fn expect_event(&mut self, name: &str) -> Option<Packet> {
+        loop {
+            let packet = self.recv().await?;
+            if packet.event_args().is_some_and(|(event, _)| event == name) {
+                return Some(packet);
+            }
+        }
+    }
+
+    /// Ping and wait for the pong; packets that arrive meanwhile stay
+    /// queued for `recv`.
+    pub async fn ping(&mut self) -> bool {
+        if self.send_engine(EnginePacket::Ping(String::new())).await.is_err() {
+            return false;
+        }
+        loop {
+            match self.next_engine().await {
+                Some(EnginePacket::Pong(_)) => return true,
+                Some(EnginePacket::Message(text)) => {
+                    if let Ok(packet) = Packet::decode(&text) {
+                        self.pending.push_back(packet);
+                    }
+                }
+                Some(EnginePacket::Close) | None => return false,
+                Some(_) => {}
+            }
+        }
+    }
+
+    pub async fn send(&mut self, packet: &Packet) -> Result<(), Error> {
+        self.send_engine(EnginePacket::Message(packet.encode())).await
+    }
+
+    pub async fn send_engine(&mut self, packet: EnginePacket) -> Result<(), Error> {
+        self.send_raw(Message::Text(packet.encode())).await
+    }
+
+    /// Any frame, e.g. a binary attachment or a malformed packet.
+    pub async fn send_raw(&mut self, message: Message) -> Result<(), Error> {
+        self.ws.send(message).await
+    }
+
+    /// Emit `event` with one argument on `namespace`.
+    pub async fn emit(&mut self, namespace: &str, event: &str, data: Value) -> Result<(), Error> {
+        self.send(&Packet::event(namespace, event, vec![data], None))
+            .await
+    }
+
+    /// Close the WebSocket without a Socket.IO disconnect, like a dropped
+    /// connection.
+    pub async fn close(mut self) {
+        let _ = self.ws.close(None).await;
+    }
+}
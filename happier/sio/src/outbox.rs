@@ -0,0 +1,371 @@
+//! Events that could not be sent, kept until the socket is back, as the
+//! Node CLI's `socketOutbox.ts`. Entries with a key coalesce: a newer one
+//! replaces the queued one and goes to the back, so only the latest state
+//! of each thing is delivered, in the order of the latest changes.
+//!
+//! An outbox may also live in a JSON file, so what a process could not
+//! send survives a restart. The file is written by a thread of its own, so
+//! pushing from async code never waits on the disk.
+
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::collections::VecDeque;
+use std::fs;
+use std::future::Future;
+use std::io::Write;
+use std::os::unix::fs::{DirBuilderExt, OpenOptionsExt};
+use std::path::Path;
+use std::sync::{mpsc, Mutex};
+use std::thread::JoinHandle;
+use std::time::Duration;
+
+/// How much an outbox holds before it drops its oldest entries.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Limits {
+    pub max_items: usize,
+    /// Serialized JSON, all entries together.
+    pub max_bytes: usize,
+    /// Larger entries are refused outright.
+    pub max_item_bytes: usize,
+    /// Entries older than this are dropped; `None` keeps them.
+    pub max_age: Option<Duration>,
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Limits {
+            max_items: 500,
+            max_bytes: 16_000_000,
+            max_item_bytes: 1_000_000,
+            max_age: Some(Duration::from_secs(15 * 60)),
+        }
+    }
+}
+
+/// One event to emit later.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Entry {
+    pub event: String,
+    pub data: Value,
+    /// Entries with the same key replace each other.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub key: Option<String>,
+}
+
+impl Entry {
+    pub fn new(event: &str, data: Value) -> Self {
+        Entry {
+            event: event.to_string(),
+            data,
+            key: None,
+        }
+    }
+
+    pub fn keyed(key: &str, event: &str, data: Value) -> Self {
+        Entry {
+            key: Some(key.to_string()),
+            ..Entry::new(event, data)
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+struct Queued {
+    #[serde(flatten)]
+    entry: Entry,
+    /// Milliseconds since the epoch.
+    queued_at: u64,
+    /// Tells this entry from a newer one with the same key while it is
+    /// being delivered.
+    #[serde(skip)]
+    seq: u64,
+    #[serde(skip)]
+    size: usize,
+}
+
+#[derive(Default)]
+struct Inner {
+    entries: VecDeque<Queued>,
+    bytes: usize,
+    next_seq: u64,
+}
+
+pub struct Outbox {
+    limits: Limits,
+    writer: Option<Writer>,
+    inner: Mutex<Inner>,
+}
+
+/// Keeps the file in step with the entries: each message is the whole new
+/// content, or `None` to remove the file.
+struct Writer {
+    tx: Option<mpsc::Sender<Option<Vec<u8>>>>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl Drop for Outbox {
+    /// Waits for the last save to reach the disk.
+    fn drop(&mut self) {
+        if let Some(writer) = &mut self.writer {
+            drop(writer.tx.take());
+            if let Some(thread) = writer.thread.take() {
+                let _ = thread.join();
+            }
+        }
+    }
+}
+
+impl Outbox {
+    /// An outbox in memory only.
+    pub fn new(limits: Limits) -> Self {
+        Outbox {
+            limits,
+            writer: None,
+            inner: Mutex::new(Inner::default()),
+        }
+    }
+
+    /// An outbox kept in `path` as well, starting with what is there. A
+    /// file that can't be read is logged and replaced.
+    pub fn persistent(path: &Path, limits: Limits) -> Self {
+        let (tx, rx) = mpsc::channel();
+        let writer_path = path.to_path_buf();
+        let thread = std::thread::Builder::new()
+            .name("outbox".to_string())
+            .spawn(move || write_loop(&writer_path, rx))
+            .map_err(|e| log::warn!("Outbox {} is kept in memory only: {}", path.display(), e))
+            .ok();
+        let outbox = Outbox {
+            writer: thread.map(|thread| Writer {
+                tx: Some(tx),
+                thread: Some(thread),
+            }),
+            limits,
+            inner: Mutex::new(Inner::default()),
+        };
+        let loaded: Vec<Queued> = match std::fs::read(path) {
+            Ok(bytes) => serde_json::from_slice(&bytes).unwrap_or_else(|e| {
+                log::warn!("Ignoring unreadable outbox {}: {}", path.display(), e);
+                Vec::new()
+            }),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Vec::new(),
+            Err(e) => {
+                log::warn!("Ignoring unreadable outbox {}: {}", path.display(), e);
+                Vec::new()
+            }
+        };
+        {
+            let mut inner = outbox.inner.lock().unwrap();
+            for mut queued in loaded {
+                queued.size = size_of(&queued.entry);
+                queued.seq = inner.next_seq;
+                inner.next_seq += 1;
+                outbox.enforce(&mut inner, queued.size);
+                inner.bytes += queued.size;
+                inner.entries.push_back(queued);
+            }
+            outbox.expire(&mut inner);
+            if !inner.entries.is_empty() {
+                log::info!(
+                    "Outbox {} holds {} events from an earlier run",
+                    path.display(),
+                    inner.entries.len()
+                );
+            }
+        }
+        outbox
+    }
+
+    /// Queue `entry`, replacing a queued one with the same key; returns
+    /// false if it is too large to keep.
+    pub fn push(&self, entry: Entry) -> bool {
+        let size = size_of(&entry);
+        if size > self.limits.max_item_bytes || size > self.limits.max_bytes {
+            log::warn!("Outbox: dropping {} of {} bytes, too large to keep", entry.event, size);
+            return false;
+        }
+        let mut inner = self.inner.lock().unwrap();
+        self.expire(&mut inner);
+        if let Some(key) = &entry.key {
+            if let Some(i) = inner.entries.iter().position(|q| q.entry.key.as_ref() == Some(key)) {
+                let replaced = inner.entries.remove(i).unwrap();
+                inner.bytes -= replaced.size;
+            }
+        }
+        self.enforce(&mut inner, size);
+        let seq = inner.next_seq;
+        inner.next_seq += 1;
+        inner.bytes += size;
+        inner.entries.push_back(Queued {
+            entry,
+            queued_at: now_millis(),
+            seq,
+            size,
+        });
+        self.save(&inner);
+        true
+    }
+
+    pub fn len(&self) -> usize {
+        self.inner.lock().unwrap().entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Remove and return the entry queued under `key`, if any.
+    pub fn take(&self, key: &str) -> Option<Entry> {
+        let mut inner = self.inner.lock().unwrap();
+        let i = inner.entries.iter().position(|q| q.entry.key.as_deref() == Some(key))?;
+        let taken = inner.entries.remove(i).unwrap();
+        inner.bytes -= taken.size;
+        self.save(&inner);
+        Some(taken.entry)
+    }
+
+    /// The queued entries, oldest first.
+    pub fn entries(&self) -> Vec<Entry> {
+        let inner = self.inner.lock().unwrap();
+        inner.entries.iter().map(|q| q.entry.clone()).collect()
+    }
+
+    /// Hand each entry to `deliver`, oldest first, removing it once
+    /// delivered. Stops at the first error, which is returned with the rest
+    /// still queued. Entries pushed meanwhile are delivered too.
+    pub async fn flush<F, Fut>(&self, mut deliver: F) -> Result<usize, Box<dyn std::error::Error>>
+    where
+        F: FnMut(Entry) -> Fut,
+        Fut: Future<Output = Result<(), Box<dyn std::error::Error>>>,
+    {
+        let mut delivered = 0;
+        loop {
+            let (entry, seq) = {
+                let mut inner = self.inner.lock().unwrap();
+                self.expire(&mut inner);
+                match inner.entries.front() {
+                    Some(front) => (front.entry.clone(), front.seq),
+                    None => break,
+                }
+            };
+            deliver(entry).await?;
+            delivered += 1;
+            let mut inner = self.inner.lock().unwrap();
+            // A newer entry with the same key may have replaced it meanwhile
+            if let Some(i) = inner.entries.iter().position(|q| q.seq == seq) {
+                let sent = inner.entries.remove(i).unwrap();
+                inner.bytes -= sent.size;
+            }
+            self.save(&inner);
+        }
+        Ok(delivered)
+    }
+
+    fn expire(&self, inner: &mut Inner) {
+        let Some(max_age) = self.limits.max_age else {
+            return;
+        };
+        let cutoff = now_millis().saturating_sub(max_age.as_millis() as u64);
+        while inner.entries.front().is_some_and(|q| q.queued_at < cutoff) {
+            let expired = inner.entries.pop_front().unwrap();
+            inner.bytes -= expired.size;
+            log::warn!("Outbox: dropping {}, queued over {:?} ago", expired.entry.event, max_age);
+        }
+    }
+
+    /// Drop the oldest entries until `incoming` more bytes and one more
+    /// entry fit.
+    fn enforce(&self, inner: &mut Inner, incoming: usize) {
+        let full = |inner: &Inner| {
+            inner.entries.len() >= self.limits.max_items
+                || inner.bytes + incoming > self.limits.max_bytes
+        };
+        while full(inner) {
+            let Some(dropped) = inner.entries.pop_front() else {
+                break;
+            };
+            inner.bytes -= dropped.size;
+            log::warn!("Outbox full: dropping {}", dropped.entry.event);
+        }
+    }
+
+    /// Have the writer save the entries, or remove the file once there are none.
+    fn save(&self, inner: &Inner) {
+        let Some(tx) = self.writer.as_ref().and_then(|w| w.tx.as_ref()) else {
+            return;
+        };
+        let content = if inner.entries.is_empty() {
+            None
+        } else {
+            match serde_json::to_vec(&inner.entries) {
+                Ok(json) => Some(json),
+                Err(e) => {
+                    log::warn!("Failed to serialize outbox: {}", e);
+                    return;
+                }
+            }
+        };
+        let _ = tx.send(content);
+    }
+}
+
+/// Save each content sent until the outbox is dropped. Only the latest of
+/// those waiting is written.
+fn write_loop(path: &Path, rx: mpsc::Receiver<Option<Vec<u8>>>) {
+    while let Ok(mut content) = rx.recv() {
+        while let Ok(newer) = rx.try_recv() {
+            content = newer;
+        }
+        let result = match content {
+            Some(json) => write_atomic(path, &json),
+            None => match fs::remove_file(path) {
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(()),
+                result => result,
+            },
+        };
+        if let Err(e) = result {
+            log::warn!("Failed to save outbox {}: {}", path.display(), e);
+        }
+    }
+}
+
+/// Write `json` with mode 0600 to a temporary file next to `path`, sync it,
+/// rename it over `path` and sync the directory: a crash leaves either the
+/// old or the new file, readable only by its owner.
+fn write_atomic(path: &Path, json: &[u8]) -> std::io::Result<()> {
+    let dir = match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    };
+    fs::DirBuilder::new().recursive(true).mode(0o700).create(dir)?;
+    let name = path.file_name().and_then(|n| n.to_str()).unwrap_or("outbox");
+    let tmp = dir.join(format!(".{}.{}.tmp", name, std::process::id()));
+    let result = (|| {
+        let mut file = fs::OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .mode(0o600)
+            .open(&tmp)?;
+        file.write_all(json)?;
+        file.sync_all()?;
+        fs::rename(&tmp, path)?;
+        // Persist the rename itself
+        fs::File::open(dir)?.sync_all()
+    })();
+    if result.is_err() {
+        let _ = fs::remove_file(&tmp);
+    }
+    result
+}
+
+fn size_of(entry: &Entry) -> usize {
+    serde_json::to_vec(entry).map_or(usize::MAX, |json| json.len())
+}
+
+fn now_millis() -> u64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis() as u64
+}
@@ -0,0 +1,416 @@
+//! The two wire layers, as text frames over WebSocket.
+//!
+//! Engine.IO (EIO4) packets are a type digit and a payload: `0{open}`, `2`
+//! ping, `3` pong, `4<socket.io packet>`. Binary frames carry attachments
+//! and have no type digit.
+//!
+//! Socket.IO (v5 protocol, as in socket.io 4) packets are
+//! `<type>[<attachments>-][<namespace>,][<ack id>][<json>]`, e.g.
+//! `42/cli,7["event",{"a":1}]`. The namespace is left out for `/`.
+
+use serde_json::Value;
+
+/// Why a frame could not be decoded.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DecodeError {
+    Empty,
+    /// An Engine.IO or Socket.IO type digit we don't know.
+    UnknownType(char),
+    /// A binary packet without a valid `<n>-` attachment count.
+    BadAttachments,
+    /// An ack id that is not a number or does not fit in a u64.
+    BadAckId,
+    /// The JSON payload does not parse.
+    BadJson(String),
+    /// The payload parses but is not allowed for the packet type, e.g. an
+    /// event that is not an array starting with its name.
+    BadPayload,
+    /// A binary placeholder that points past the attachments received.
+    BadPlaceholder,
+}
+
+impl std::fmt::Display for DecodeError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            DecodeError::Empty => f.write_str("empty packet"),
+            DecodeError::UnknownType(c) => write!(f, "unknown packet type {:?}", c),
+            DecodeError::BadAttachments => f.write_str("invalid attachment count"),
+            DecodeError::BadAckId => f.write_str("invalid ack id"),
+            DecodeError::BadJson(e) => write!(f, "invalid JSON payload: {}", e),
+            DecodeError::BadPayload => f.write_str("payload not valid for the packet type"),
+            DecodeError::BadPlaceholder => f.write_str("binary placeholder out of range"),
+        }
+    }
+}
+
+impl std::error::Error for DecodeError {}
+
+/// An Engine.IO text packet.
+#[derive(Debug, Clone, PartialEq)]
+pub enum EnginePacket {
+    /// The handshake: sid, ping interval and timeout, as JSON.
+    Open(String),
+    Close,
+    Ping(String),
+    Pong(String),
+    /// A Socket.IO packet, still encoded.
+    Message(String),
+    Upgrade,
+    Noop,
+}
+
+impl EnginePacket {
+    pub fn decode(text: &str) -> Result<Self, DecodeError> {
+        let mut chars = text.chars();
+        let kind = chars.next().ok_or(DecodeError::Empty)?;
+        let rest = chars.as_str().to_string();
+        Ok(match kind {
+            '0' => EnginePacket::Open(rest),
+            '1' => EnginePacket::Close,
+            '2' => EnginePacket::Ping(rest),
+            '3' => EnginePacket::Pong(rest),
+            '4' => EnginePacket::Message(rest),
+            '5' => EnginePacket::Upgrade,
+            '6' => EnginePacket::Noop,
+            other => return Err(DecodeError::UnknownType(other)),
+        })
+    }
+
+    pub fn encode(&self) -> String {
+        match self {
+            EnginePacket::Open(data) => format!("0{}", data),
+            EnginePacket::Close => "1".to_string(),
+            EnginePacket::Ping(data) => format!("2{}", data),
+            EnginePacket::Pong(data) => format!("3{}", data),
+            EnginePacket::Message(data) => format!("4{}", data),
+            EnginePacket::Upgrade => "5".to_string(),
+            EnginePacket::Noop => "6".to_string(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PacketType {
+    Connect,
+    Disconnect,
+    Event,
+    Ack,
+    ConnectError,
+    BinaryEvent,
+    BinaryAck,
+}
+
+impl PacketType {
+    fn digit(self) -> char {
+        match self {
+            PacketType::Connect => '0',
+            PacketType::Disconnect => '1',
+            PacketType::Event => '2',
+            PacketType::Ack => '3',
+            PacketType::ConnectError => '4',
+            PacketType::BinaryEvent => '5',
+            PacketType::BinaryAck => '6',
+        }
+    }
+
+    fn from_digit(c: char) -> Result<Self, DecodeError> {
+        Ok(match c {
+            '0' => PacketType::Connect,
+            '1' => PacketType::Disconnect,
+            '2' => PacketType::Event,
+            '3' => PacketType::Ack,
+            '4' => PacketType::ConnectError,
+            '5' => PacketType::BinaryEvent,
+            '6' => PacketType::BinaryAck,
+            other => return Err(DecodeError::UnknownType(other)),
+        })
+    }
+
+    pub fn is_binary(self) -> bool {
+        matches!(self, PacketType::BinaryEvent | PacketType::BinaryAck)
+    }
+}
+
+/// A Socket.IO packet.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Packet {
+    pub kind: PacketType,
+    /// `/` unless the packet names one.
+    pub namespace: String,
+    pub id: Option<u64>,
+    /// Binary frames that follow, for `BinaryEvent` and `BinaryAck`.
+    pub attachments: usize,
+    pub data: Option<Value>,
+}
+
+impl Packet {
+    fn new(kind: PacketType, namespace: &str, data: Option<Value>) -> Self {
+        Packet {
+            kind,
+            namespace: namespace.to_string(),
+            id: None,
+            attachments: 0,
+            data,
+        }
+    }
+
+    /// Join `namespace`, with `auth` as the handshake payload.
+    pub fn connect(namespace: &str, auth: Option<Value>) -> Self {
+        Packet::new(PacketType::Connect, namespace, auth)
+    }
+
+    pub fn disconnect(namespace: &str) -> Self {
+        Packet::new(PacketType::Disconnect, namespace, None)
+    }
+
+    /// `event` with `args`, asking for an ack if `id` is set.
+    pub fn event(namespace: &str, event: &str, args: Vec<Value>, id: Option<u64>) -> Self {
+        let mut data = vec![Value::String(event.to_string())];
+        data.extend(args);
+        Packet {
+            id,
+            ..Packet::new(PacketType::Event, namespace, Some(Value::Array(data)))
+        }
+    }
+
+    pub fn ack(namespace: &str, id: u64, args: Vec<Value>) -> Self {
+        Packet {
+            id: Some(id),
+            ..Packet::new(PacketType::Ack, namespace, Some(Value::Array(args)))
+        }
+    }
+
+    pub fn connect_error(namespace: &str, message: &str) -> Self {
+        let data = serde_json::json!({ "message": message });
+        Packet::new(PacketType::ConnectError, namespace, Some(data))
+    }
+
+    pub fn encode(&self) -> String {
+        let mut out = String::new();
+        out.push(self.kind.digit());
+        if self.kind.is_binary() {
+            out.push_str(&format!("{}-", self.attachments));
+        }
+        if self.namespace != "/" {
+            out.push_str(&self.namespace);
+            out.push(',');
+        }
+        if let Some(id) = self.id {
+            out.push_str(&id.to_string());
+        }
+        if let Some(data) = &self.data {
+            out.push_str(&data.to_string());
+        }
+        out
+    }
+
+    pub fn decode(text: &str) -> Result<Self, DecodeError> {
+        let mut chars = text.chars();
+        let kind = PacketType::from_digit(chars.next().ok_or(DecodeError::Empty)?)?;
+        let mut rest = chars.as_str();
+
+        let mut attachments = 0;
+        if kind.is_binary() {
+            let (count, after) = rest.split_once('-').ok_or(DecodeError::BadAttachments)?;
+            if count.is_empty() || !count.bytes().all(|b| b.is_ascii_digit()) {
+                return Err(DecodeError::BadAttachments);
+            }
+            attachments = count.parse().map_err(|_| DecodeError::BadAttachments)?;
+            rest = after;
+        }
+
+        let mut namespace = "/";
+        if rest.starts_with('/') {
+            // Up to the comma, or the whole rest for e.g. `41/cli`
+            match rest.split_once(',') {
+                Some((name, after)) => {
+                    namespace = name;
+                    rest = after;
+                }
+                None => {
+                    namespace = rest;
+                    rest = "";
+                }
+            }
+        }
+
+        let digits = rest.bytes().take_while(u8::is_ascii_digit).count();
+        let id = if digits > 0 {
+            let id = rest[..digits].parse().map_err(|_| DecodeError::BadAckId)?;
+            rest = &rest[digits..];
+            Some(id)
+        } else {
+            None
+        };
+
+        let data = if rest.is_empty() {
+            None
+        } else {
+            let value: Value =
+                serde_json::from_str(rest).map_err(|e| DecodeError::BadJson(e.to_string()))?;
+            Some(value)
+        };
+        if !payload_valid(kind, data.as_ref()) {
+            return Err(DecodeError::BadPayload);
+        }
+
+        Ok(Packet {
+            kind,
+            namespace: namespace.to_string(),
+            id,
+            attachments,
+            data,
+        })
+    }
+
+    /// For events, the name and the arguments after it.
+    pub fn event_args(&self) -> Option<(&str, &[Value])> {
+        if !matches!(self.kind, PacketType::Event | PacketType::BinaryEvent) {
+            return None;
+        }
+        let (name, args) = self.data.as_ref()?.as_array()?.split_first()?;
+        Some((name.as_str()?, args))
+    }
+}
+
+/// What socket.io-parser accepts for each packet type.
+fn payload_valid(kind: PacketType, data: Option<&Value>) -> bool {
+    match (kind, data) {
+        (PacketType::Connect, None | Some(Value::Object(_))) => true,
+        (PacketType::Disconnect, None) => true,
+        (PacketType::ConnectError, Some(Value::Object(_) | Value::String(_))) => true,
+        (PacketType::Event | PacketType::BinaryEvent, Some(Value::Array(items))) => {
+            matches!(items.first(), Some(Value::String(_) | Value::Number(_)))
+        }
+        (PacketType::Ack | PacketType::BinaryAck, Some(Value::Array(_))) => true,
+        _ => false,
+    }
+}
+
+/// The JSON that stands in for attachment `num` in a binary packet.
+pub fn placeholder(num: usize) -> Value {
+    serde_json::json!({ "_placeholder": true, "num": num })
+}
+
+/// Replace each placeholder in `data` with its attachment, as an array of
+/// byte values (which serde reads as `Vec<u8>`).
+pub fn fill_placeholders(data: &mut Value, attachments: &[Vec<u8>]) -> Result<(), DecodeError> {
+    match data {
+        Value::Object(map) if map.get("_placeholder") == Some(&Value::Bool(true)) => {
+            let num = map.get("num").and_then(Value::as_u64);
+            let bytes = num
+                .and_then(|num| attachments.get(num as usize))
+                .ok_or(DecodeError::BadPlaceholder)?;
+            *data = Value::Array(bytes.iter().map(|b| Value::from(*b)).collect());
+            Ok(())
+        }
+        Value::Object(map) => map
+            .values_mut()
+            .try_for_each(|value| fill_placeholders(value, attachments)),
+        Value::Array(items) => items
+            .iter_mut()
+            .try_for_each(|value| fill_placeholders(value, attachments)),
+        _ => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn decodes_engine_packets() {
+        assert_eq!(EnginePacket::decode("2"), Ok(EnginePacket::Ping(String::new())));
+        assert_eq!(EnginePacket::decode("3probe"), Ok(EnginePacket::Pong("probe".into())));
+        assert_eq!(EnginePacket::decode("42[\"a\"]"), Ok(EnginePacket::Message("2[\"a\"]".into())));
+        assert_eq!(EnginePacket::decode(""), Err(DecodeError::Empty));
+        assert_eq!(EnginePacket::decode("9"), Err(DecodeError::UnknownType('9')));
+    }
+
+    #[test]
+    fn decodes_event_with_namespace_and_ack_id() {
+        let packet = Packet::decode(r#"2/cli,12["tunnel:open",{"port":80}]"#).unwrap();
+        assert_eq!(packet.kind, PacketType::Event);
+        assert_eq!(packet.namespace, "/cli");
+        assert_eq!(packet.id, Some(12));
+        let (name, args) = packet.event_args().unwrap();
+        assert_eq!(name, "tunnel:open");
+        assert_eq!(args, &[json!({ "port": 80 })]);
+    }
+
+    #[test]
+    fn defaults_to_the_main_namespace() {
+        let packet = Packet::decode(r#"2["hello"]"#).unwrap();
+        assert_eq!(packet.namespace, "/");
+        assert_eq!(packet.id, None);
+    }
+
+    #[test]
+    fn namespace_without_comma() {
+        let packet = Packet::decode("1/cli").unwrap();
+        assert_eq!(packet, Packet::disconnect("/cli"));
+    }
+
+    #[test]
+    fn decodes_connect_error() {
+        let packet = Packet::decode(r#"4/cli,{"message":"Invalid token"}"#).unwrap();
+        assert_eq!(packet, Packet::connect_error("/cli", "Invalid token"));
+    }
+
+    #[test]
+    fn decodes_binary_event() {
+        let packet =
+            Packet::decode(r#"52-/cli,["file",{"_placeholder":true,"num":0},{"_placeholder":true,"num":1}]"#)
+                .unwrap();
+        assert_eq!(packet.kind, PacketType::BinaryEvent);
+        assert_eq!(packet.attachments, 2);
+        let mut data = packet.data.unwrap();
+        fill_placeholders(&mut data, &[vec![1, 2], vec![255]]).unwrap();
+        assert_eq!(data, json!(["file", [1, 2], [255]]));
+    }
+
+    #[test]
+    fn rejects_placeholder_out_of_range() {
+        let mut data = json!(["file", placeholder(1)]);
+        assert_eq!(
+            fill_placeholders(&mut data, &[vec![0]]),
+            Err(DecodeError::BadPlaceholder)
+        );
+    }
+
+    #[test]
+    fn rejects_malformed_packets() {
+        assert_eq!(Packet::decode(""), Err(DecodeError::Empty));
+        assert_eq!(Packet::decode("7"), Err(DecodeError::UnknownType('7')));
+        assert_eq!(Packet::decode(r#"5["a"]"#), Err(DecodeError::BadAttachments));
+        assert_eq!(Packet::decode(r#"5x-["a"]"#), Err(DecodeError::BadAttachments));
+        assert_eq!(
+            Packet::decode(r#"299999999999999999999["a"]"#),
+            Err(DecodeError::BadAckId)
+        );
+        assert!(matches!(Packet::decode("2[\"a\""), Err(DecodeError::BadJson(_))));
+        // Events are a non-empty array led by the name
+        assert_eq!(Packet::decode("2{}"), Err(DecodeError::BadPayload));
+        assert_eq!(Packet::decode("2[]"), Err(DecodeError::BadPayload));
+        assert_eq!(Packet::decode("2"), Err(DecodeError::BadPayload));
+        assert_eq!(Packet::decode("1/cli,{}"), Err(DecodeError::BadPayload));
+    }
+
+    #[test]
+    fn encodes_like_socket_io_parser() {
+        let event = Packet::event("/cli", "machine-alive", vec![json!({ "time": 1 })], Some(3));
+        assert_eq!(event.encode(), r#"2/cli,3["machine-alive",{"time":1}]"#);
+        assert_eq!(Packet::ack("/", 4, vec![json!("ok")]).encode(), r#"34["ok"]"#);
+        assert_eq!(Packet::disconnect("/cli").encode(), "1/cli,");
+        let binary = Packet {
+            kind: PacketType::BinaryEvent,
+            attachments: 1,
+            ..Packet::event("/", "file", vec![placeholder(0)], None)
+        };
+        assert_eq!(
+            binary.encode(),
+            r#"51-["file",{"_placeholder":true,"num":0}]"#
+        );
+    }
+}
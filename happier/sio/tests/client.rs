@@ -0,0 +1,233 @@
+//! The client against the in-process mock server.
+
+use happier_sio::mock::{MockConnection, MockServer};
+use happier_sio::{ConnectRefused, Event, Packet, PacketType, Received, SocketClient};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::mpsc;
+use tokio::time::timeout;
+use tokio_tungstenite::tungstenite::Message;
+
+const WAIT: Duration = Duration::from_secs(5);
+
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+#[serde(rename_all = "camelCase")]
+struct Greeting {
+    machine_id: String,
+    count: u32,
+}
+
+impl Event for Greeting {
+    const NAME: &'static str = "greeting";
+}
+
+/// A connected client, the server's end of it, and the events the client got.
+async fn connected() -> (
+    MockServer,
+    SocketClient,
+    MockConnection,
+    mpsc::UnboundedReceiver<Received>,
+) {
+    let mut server = MockServer::start().await.unwrap();
+    let (tx, events) = mpsc::unbounded_channel();
+    let url = server.url();
+    let client = tokio::spawn(async move {
+        SocketClient::connect(&url, "/cli", json!({ "token": "t" }), Arc::new(()), move |event, _| {
+            let _ = tx.send(event);
+        })
+        .await
+        .map_err(|e| e.to_string())
+    });
+    let mut connection = server.accept().await.unwrap();
+    assert!(connection.uri.starts_with("/socket.io/?EIO=4&transport=websocket"));
+    let auth = connection.accept_connect().await.unwrap();
+    assert_eq!(auth, json!({ "token": "t" }));
+    let client = client.await.unwrap().unwrap();
+    (server, client, connection, events)
+}
+
+#[tokio::test]
+async fn emits_events_and_typed_events() {
+    let (_server, client, mut connection, _events) = connected().await;
+    client.emit("hello", json!({ "a": 1 })).await.unwrap();
+    let greeting = Greeting {
+        machine_id: "m1".into(),
+        count: 2,
+    };
+    client.emit_event(&greeting).await.unwrap();
+
+    let packet = connection.recv().await.unwrap();
+    assert_eq!(packet, Packet::event("/cli", "hello", vec![json!({ "a": 1 })], None));
+    let packet = connection.recv().await.unwrap();
+    let data = json!({ "machineId": "m1", "count": 2 });
+    assert_eq!(packet, Packet::event("/cli", "greeting", vec![data], None));
+}
+
+#[tokio::test]
+async fn receives_typed_events_with_ack_ids() {
+    let (_server, client, mut connection, mut events) = connected().await;
+    let data = json!({ "machineId": "m1", "count": 3 });
+    let packet = Packet::event("/cli", "greeting", vec![data], Some(9));
+    connection.send(&packet).await.unwrap();
+    // Other namespaces are not ours
+    connection.emit("/", "greeting", json!({})).await.unwrap();
+    connection.emit("/cli", "other", json!(1)).await.unwrap();
+
+    let event = timeout(WAIT, events.recv()).await.unwrap().unwrap();
+    assert_eq!(event.ack, Some(9));
+    let greeting: Greeting = event.parse().unwrap().unwrap();
+    assert_eq!(greeting.count, 3);
+    client.ack(9, json!("done")).await.unwrap();
+    assert_eq!(connection.recv().await.unwrap(), Packet::ack("/cli", 9, vec![json!("done")]));
+
+    let event = timeout(WAIT, events.recv()).await.unwrap().unwrap();
+    assert_eq!(event.name, "other");
+    assert!(event.parse::<Greeting>().is_none());
+    assert_eq!(event.data(), &json!(1));
+}
+
+#[tokio::test]
+async fn emit_with_ack_gets_the_answer() {
+    let (_server, client, mut connection, _events) = connected().await;
+    let answer = tokio::spawn(async move { client.emit_with_ack("update", json!({}), 5).await.map_err(|e| e.to_string()) });
+    let packet = connection.expect_event("update").await.unwrap();
+    let id = packet.id.expect("asks for an ack");
+    connection
+        .send(&Packet::ack("/cli", id, vec![json!({ "result": "success" })]))
+        .await
+        .unwrap();
+    let answer = answer.await.unwrap().unwrap();
+    assert_eq!(answer, json!([{ "result": "success" }]));
+}
+
+#[tokio::test]
+async fn emit_with_ack_times_out() {
+    let (_server, client, _connection, _events) = connected().await;
+    assert!(client.emit_with_ack("update", json!({}), 1).await.is_err());
+}
+
+#[tokio::test]
+async fn binary_events_are_reassembled() {
+    let (_server, _client, mut connection, mut events) = connected().await;
+    let mut packet = Packet::event(
+        "/cli",
+        "file",
+        vec![json!({ "name": "a", "bytes": happier_sio::packet::placeholder(0) })],
+        None,
+    );
+    packet.kind = PacketType::BinaryEvent;
+    packet.attachments = 1;
+    connection.send(&packet).await.unwrap();
+    connection.send_raw(Message::Binary(vec![0, 1, 255])).await.unwrap();
+
+    let event = timeout(WAIT, events.recv()).await.unwrap().unwrap();
+    assert_eq!(event.data(), &json!({ "name": "a", "bytes": [0, 1, 255] }));
+}
+
+#[tokio::test]
+async fn answers_pings_and_tracks_inbound() {
+    let (_server, client, mut connection, _events) = connected().await;
+    let before = client.last_inbound().load(std::sync::atomic::Ordering::Relaxed);
+    tokio::time::sleep(Duration::from_millis(20)).await;
+    assert!(connection.ping().await);
+    let after = client.last_inbound().load(std::sync::atomic::Ordering::Relaxed);
+    assert!(after > before);
+}
+
+#[tokio::test]
+async fn ignores_garbage() {
+    let (_server, _client, mut connection, mut events) = connected().await;
+    for frame in ["", "9", "4", "42/cli,", "42/cli,[", "45-[\"x\"]", "43/cli,abc"] {
+        connection.send_raw(Message::Text(frame.into())).await.unwrap();
+    }
+    connection.emit("/cli", "still-here", Value::Null).await.unwrap();
+    let event = timeout(WAIT, events.recv()).await.unwrap().unwrap();
+    assert_eq!(event.name, "still-here");
+}
+
+#[tokio::test]
+async fn notifies_when_the_server_leaves() {
+    let (_server, client, mut connection, _events) = connected().await;
+    let left = client.on_disconnect();
+    let notified = left.notified();
+    connection.send(&Packet::disconnect("/cli")).await.unwrap();
+    timeout(WAIT, notified).await.unwrap();
+}
+
+#[tokio::test]
+async fn notifies_when_the_socket_drops() {
+    let (_server, client, connection, _events) = connected().await;
+    let left = client.on_disconnect();
+    let notified = left.notified();
+    connection.close().await;
+    timeout(WAIT, notified).await.unwrap();
+}
+
+#[tokio::test]
+async fn disconnect_leaves_the_namespace() {
+    let (_server, client, mut connection, _events) = connected().await;
+    client.emit("last", Value::Null).await.unwrap();
+    client.disconnect().await.unwrap();
+    assert!(connection.expect_event("last").await.is_some());
+    assert_eq!(connection.recv().await, Some(Packet::disconnect("/cli")));
+    assert_eq!(connection.recv().await, None);
+}
+
+#[tokio::test]
+async fn refused_connect_is_reported() {
+    let mut server = MockServer::start().await.unwrap();
+    let url = server.url();
+    let client = tokio::spawn(async move {
+        let result = SocketClient::connect(&url, "/cli", json!({}), Arc::new(()), |_, _| {}).await;
+        match result {
+            Ok(_) => panic!("connected"),
+            Err(e) => e.downcast::<ConnectRefused>().map(|refused| refused.0).ok(),
+        }
+    });
+    let mut connection = server.accept().await.unwrap();
+    connection.refuse_connect("Invalid token").await.unwrap();
+    assert_eq!(client.await.unwrap().as_deref(), Some("Invalid token"));
+}
+
+#[tokio::test]
+async fn connect_answers_pings_before_the_ack() {
+    let mut server = MockServer::start().await.unwrap();
+    let url = server.url();
+    let client = tokio::spawn(async move {
+        SocketClient::connect(&url, "/cli", json!({}), Arc::new(()), |_, _| {})
+            .await
+            .map(|_| ())
+            .map_err(|e| e.to_string())
+    });
+    let mut connection = server.accept().await.unwrap();
+    assert!(connection.ping().await);
+    connection.accept_connect().await.unwrap();
+    client.await.unwrap().unwrap();
+}
+
+#[tokio::test]
+async fn plain_http_goes_to_the_handler() {
+    use happier_sio::mock::HttpResponse;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    let server = MockServer::start_with_http(|request| {
+        assert_eq!(request.header("Content-Type"), Some("application/json"));
+        HttpResponse::json(201, &json!({ "path": request.path, "got": request.json() }))
+    })
+    .await
+    .unwrap();
+    let mut stream = tokio::net::TcpStream::connect(server.addr()).await.unwrap();
+    let body = r#"{"a":1}"#;
+    let request = format!(
+        "POST /cli/machines HTTP/1.1\r\nHost: x\r\nContent-Type: application/json\r\nContent-Length: {}\r\n\r\n{}",
+        body.len(),
+        body
+    );
+    stream.write_all(request.as_bytes()).await.unwrap();
+    let mut response = String::new();
+    stream.read_to_string(&mut response).await.unwrap();
+    assert!(response.starts_with("HTTP/1.1 201 "));
+    assert!(response.ends_with(r#"{"got":{"a":1},"path":"/cli/machines"}"#));
+}
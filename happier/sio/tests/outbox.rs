@@ -0,0 +1,238 @@
+//! The outbox on its own, on disk, and flushed through a client.
+
+use happier_sio::mock::MockServer;
+use happier_sio::outbox::{Entry, Limits};
+use happier_sio::{Outbox, Packet, SocketClient};
+use serde_json::{json, Value};
+use std::os::unix::fs::PermissionsExt;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+fn events(outbox: &Outbox) -> Vec<(String, Value)> {
+    outbox
+        .entries()
+        .into_iter()
+        .map(|entry| (entry.event, entry.data))
+        .collect()
+}
+
+/// A file path of its own under the system temp dir, removed on drop.
+struct TempFile(PathBuf);
+
+impl TempFile {
+    fn new() -> Self {
+        static NEXT: AtomicUsize = AtomicUsize::new(0);
+        let dir = std::env::temp_dir().join(format!(
+            "happier-sio-outbox-{}-{}",
+            std::process::id(),
+            NEXT.fetch_add(1, Ordering::Relaxed)
+        ));
+        std::fs::create_dir_all(&dir).unwrap();
+        TempFile(dir.join("outbox.json"))
+    }
+}
+
+impl Drop for TempFile {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(self.0.parent().unwrap());
+    }
+}
+
+#[test]
+fn keyed_entries_coalesce_to_the_back() {
+    let outbox = Outbox::new(Limits::default());
+    outbox.push(Entry::keyed("state", "update", json!(1)));
+    outbox.push(Entry::new("exit", json!("a")));
+    outbox.push(Entry::keyed("state", "update", json!(2)));
+    outbox.push(Entry::new("exit", json!("b")));
+    assert_eq!(
+        events(&outbox),
+        vec![
+            ("exit".into(), json!("a")),
+            ("update".into(), json!(2)),
+            ("exit".into(), json!("b")),
+        ]
+    );
+}
+
+#[test]
+fn full_outboxes_drop_the_oldest() {
+    let limits = Limits {
+        max_items: 3,
+        ..Limits::default()
+    };
+    let outbox = Outbox::new(limits);
+    for i in 0..5 {
+        outbox.push(Entry::new("n", json!(i)));
+    }
+    let kept: Vec<Value> = outbox.entries().into_iter().map(|e| e.data).collect();
+    assert_eq!(kept, vec![json!(2), json!(3), json!(4)]);
+
+    let limits = Limits {
+        max_bytes: 100,
+        max_item_bytes: 60,
+        ..Limits::default()
+    };
+    let outbox = Outbox::new(limits);
+    assert!(!outbox.push(Entry::new("big", json!("x".repeat(60)))));
+    assert!(outbox.push(Entry::new("a", json!("x".repeat(20)))));
+    assert!(outbox.push(Entry::new("b", json!("x".repeat(20)))));
+    // Two entries of ~43 bytes leave no room for a third
+    assert!(outbox.push(Entry::new("c", json!("x".repeat(20)))));
+    let kept: Vec<String> = outbox.entries().into_iter().map(|e| e.event).collect();
+    assert_eq!(kept, vec!["b", "c"]);
+}
+
+#[test]
+fn old_entries_expire() {
+    let limits = Limits {
+        max_age: Some(Duration::from_millis(50)),
+        ..Limits::default()
+    };
+    let outbox = Outbox::new(limits);
+    outbox.push(Entry::new("old", Value::Null));
+    std::thread::sleep(Duration::from_millis(100));
+    outbox.push(Entry::new("new", Value::Null));
+    let kept: Vec<String> = outbox.entries().into_iter().map(|e| e.event).collect();
+    assert_eq!(kept, vec!["new"]);
+}
+
+#[tokio::test]
+async fn flush_delivers_in_order_and_stops_at_a_failure() {
+    let outbox = Outbox::new(Limits::default());
+    for i in 0..4 {
+        outbox.push(Entry::new("n", json!(i)));
+    }
+    let delivered = Mutex::new(Vec::new());
+    let result = outbox
+        .flush(|entry| {
+            let delivered = &delivered;
+            async move {
+                if entry.data == json!(2) {
+                    return Err("socket write failed".into());
+                }
+                delivered.lock().unwrap().push(entry.data);
+                Ok(())
+            }
+        })
+        .await;
+    assert!(result.is_err());
+    assert_eq!(*delivered.lock().unwrap(), vec![json!(0), json!(1)]);
+    let left: Vec<Value> = outbox.entries().into_iter().map(|e| e.data).collect();
+    assert_eq!(left, vec![json!(2), json!(3)]);
+
+    let sent = outbox.flush(|_| async { Ok(()) }).await.unwrap();
+    assert_eq!(sent, 2);
+    assert!(outbox.is_empty());
+}
+
+#[tokio::test]
+async fn a_replacement_pushed_during_delivery_is_kept() {
+    let outbox = Outbox::new(Limits::default());
+    outbox.push(Entry::keyed("state", "update", json!(1)));
+    let mut first = true;
+    let sent = outbox
+        .flush(|entry| {
+            if first {
+                first = false;
+                outbox.push(Entry::keyed("state", "update", json!(2)));
+            }
+            assert!(entry.data == json!(1) || entry.data == json!(2));
+            async { Ok(()) }
+        })
+        .await
+        .unwrap();
+    assert_eq!(sent, 2);
+    assert!(outbox.is_empty());
+}
+
+#[test]
+fn taken_entries_leave_the_rest_queued() {
+    let outbox = Outbox::new(Limits::default());
+    outbox.push(Entry::new("exit", json!("a")));
+    outbox.push(Entry::keyed("state", "update", json!({ "status": "stopped" })));
+    let taken = outbox.take("state").unwrap();
+    assert_eq!(taken.data, json!({ "status": "stopped" }));
+    assert!(outbox.take("state").is_none());
+    assert_eq!(events(&outbox), vec![("exit".to_string(), json!("a"))]);
+}
+
+#[test]
+fn persistent_outboxes_survive_a_restart() {
+    let file = TempFile::new();
+    {
+        let outbox = Outbox::persistent(&file.0, Limits::default());
+        outbox.push(Entry::keyed("state", "update", json!({ "status": "stopped" })));
+        outbox.push(Entry::new("exit", json!("a")));
+    }
+    // Dropping waits for the writer; only the owner may read the file
+    let mode = std::fs::metadata(&file.0).unwrap().permissions().mode();
+    assert_eq!(mode & 0o777, 0o600);
+    let outbox = Outbox::persistent(&file.0, Limits::default());
+    assert_eq!(
+        outbox.entries(),
+        vec![
+            Entry::keyed("state", "update", json!({ "status": "stopped" })),
+            Entry::new("exit", json!("a")),
+        ]
+    );
+    // Keys still coalesce after loading
+    outbox.push(Entry::keyed("state", "update", json!({ "status": "running" })));
+    assert_eq!(outbox.len(), 2);
+}
+
+#[tokio::test]
+async fn flushed_persistent_outboxes_remove_their_file() {
+    let file = TempFile::new();
+    let outbox = Outbox::persistent(&file.0, Limits::default());
+    outbox.push(Entry::new("exit", json!("a")));
+    drop(outbox);
+    assert!(file.0.exists());
+    let outbox = Outbox::persistent(&file.0, Limits::default());
+    outbox.flush(|_| async { Ok(()) }).await.unwrap();
+    drop(outbox);
+    assert!(!file.0.exists());
+}
+
+#[test]
+fn unreadable_files_start_empty() {
+    let file = TempFile::new();
+    std::fs::write(&file.0, "not json").unwrap();
+    let outbox = Outbox::persistent(&file.0, Limits::default());
+    assert!(outbox.is_empty());
+    outbox.push(Entry::new("exit", json!("a")));
+    drop(outbox);
+    let outbox = Outbox::persistent(&file.0, Limits::default());
+    assert_eq!(outbox.len(), 1);
+}
+
+#[tokio::test]
+async fn the_client_emits_the_outbox_in_order() {
+    let mut server = MockServer::start().await.unwrap();
+    let url = server.url();
+    let client = tokio::spawn(async move {
+        SocketClient::connect(&url, "/cli", json!({}), Arc::new(()), |_, _| {})
+            .await
+            .map_err(|e| e.to_string())
+    });
+    let mut connection = server.accept().await.unwrap();
+    connection.accept_connect().await.unwrap();
+    let client = client.await.unwrap().unwrap();
+
+    let outbox = Outbox::new(Limits::default());
+    outbox.push(Entry::new("first", json!(1)));
+    outbox.push(Entry::new("second", json!(2)));
+    assert_eq!(client.flush(&outbox).await.unwrap(), 2);
+    assert!(outbox.is_empty());
+    client
+        .emit_or_queue(&outbox, Entry::new("third", json!(3)))
+        .await;
+    assert!(outbox.is_empty());
+
+    for (name, data) in [("first", 1), ("second", 2), ("third", 3)] {
+        let packet = connection.recv().await.unwrap();
+        assert_eq!(packet, Packet::event("/cli", name, vec![json!(data)], None));
+    }
+}
@@ -0,0 +1,137 @@
+//! Property tests for the packet codec, and fuzzing of the decoders with
+//! arbitrary and mangled input.
+
+use happier_sio::packet::{fill_placeholders, placeholder};
+use happier_sio::{EnginePacket, Packet, PacketType};
+use proptest::prelude::*;
+use serde_json::Value;
+
+fn json_leaf() -> impl Strategy<Value = Value> {
+    prop_oneof![
+        Just(Value::Null),
+        any::<bool>().prop_map(Value::Bool),
+        any::<i64>().prop_map(Value::from),
+        any::<u64>().prop_map(Value::from),
+        // Finite and exact in decimal: serde_json's default float parsing
+        // may be off by one ulp otherwise
+        (any::<i32>(), 0..8).prop_map(|(m, e)| Value::from(m as f64 / f64::from(1 << e))),
+        ".*".prop_map(Value::String),
+    ]
+}
+
+fn json_value() -> impl Strategy<Value = Value> {
+    json_leaf().prop_recursive(4, 32, 6, |inner| {
+        prop_oneof![
+            prop::collection::vec(inner.clone(), 0..6).prop_map(Value::Array),
+            prop::collection::btree_map(".*", inner, 0..6)
+                .prop_map(|map| Value::Object(map.into_iter().collect())),
+        ]
+    })
+}
+
+fn json_object() -> impl Strategy<Value = Value> {
+    prop::collection::btree_map(".*", json_value(), 0..6)
+        .prop_map(|map| Value::Object(map.into_iter().collect()))
+}
+
+fn namespace() -> impl Strategy<Value = String> {
+    prop_oneof![Just("/".to_string()), "/[a-zA-Z0-9_:.-]{1,12}"]
+}
+
+/// Any packet socket.io-parser would produce.
+fn packet() -> impl Strategy<Value = Packet> {
+    let id = prop::option::of(any::<u64>());
+    let args = prop::collection::vec(json_value(), 0..4).boxed();
+    prop_oneof![
+        (namespace(), prop::option::of(json_object()))
+            .prop_map(|(ns, auth)| Packet::connect(&ns, auth)),
+        namespace().prop_map(|ns| Packet::disconnect(&ns)),
+        (namespace(), ".*", args.clone(), id.clone())
+            .prop_map(|(ns, name, args, id)| Packet::event(&ns, &name, args, id)),
+        (namespace(), any::<u64>(), args.clone())
+            .prop_map(|(ns, id, args)| Packet::ack(&ns, id, args)),
+        (namespace(), ".*").prop_map(|(ns, message)| Packet::connect_error(&ns, &message)),
+        (namespace(), ".*", args, id, 0..8usize, any::<bool>()).prop_map(
+            |(ns, name, args, id, attachments, ack)| {
+                let mut packet = if ack {
+                    Packet::ack(&ns, id.unwrap_or(0), args)
+                } else {
+                    Packet::event(&ns, &name, args, id)
+                };
+                packet.kind = if ack {
+                    PacketType::BinaryAck
+                } else {
+                    PacketType::BinaryEvent
+                };
+                packet.attachments = attachments;
+                packet
+            }
+        ),
+    ]
+}
+
+proptest! {
+    #![proptest_config(ProptestConfig::with_cases(512))]
+
+    #[test]
+    fn packets_round_trip(packet in packet()) {
+        let encoded = packet.encode();
+        prop_assert_eq!(Packet::decode(&encoded), Ok(packet));
+    }
+
+    #[test]
+    fn engine_messages_round_trip(packet in packet()) {
+        let message = EnginePacket::Message(packet.encode());
+        prop_assert_eq!(EnginePacket::decode(&message.encode()), Ok(message));
+    }
+
+    #[test]
+    fn event_args_survive_the_wire(name in ".*", args in prop::collection::vec(json_value(), 0..4)) {
+        let packet = Packet::decode(&Packet::event("/cli", &name, args.clone(), None).encode()).unwrap();
+        let (decoded_name, decoded_args) = packet.event_args().unwrap();
+        prop_assert_eq!(decoded_name, name.as_str());
+        prop_assert_eq!(decoded_args, args.as_slice());
+    }
+
+    #[test]
+    fn placeholders_are_filled_in_order(buffers in prop::collection::vec(prop::collection::vec(any::<u8>(), 0..16), 1..5)) {
+        let mut data = Value::Array((0..buffers.len()).map(placeholder).collect());
+        fill_placeholders(&mut data, &buffers).unwrap();
+        let expected: Vec<Value> = buffers
+            .iter()
+            .map(|b| Value::Array(b.iter().map(|x| Value::from(*x)).collect()))
+            .collect();
+        prop_assert_eq!(data, Value::Array(expected));
+    }
+
+    // Fuzzing: the decoders must return, never panic, whatever they get
+
+    #[test]
+    fn decoders_accept_any_string(text in ".*") {
+        let _ = EnginePacket::decode(&text);
+        let _ = Packet::decode(&text);
+    }
+
+    #[test]
+    fn decoders_accept_protocol_like_strings(text in r#"[0-9]{0,3}(-)?(/[a-z,]{0,4})?,?[0-9]{0,25}[\[\]{}",:a-z0-9_]{0,24}"#) {
+        let _ = Packet::decode(&text);
+    }
+
+    #[test]
+    fn decoders_accept_mangled_packets(packet in packet(), cut in any::<prop::sample::Index>(), byte in any::<char>(), insert in any::<prop::sample::Index>()) {
+        let encoded = packet.encode();
+        let chars: Vec<char> = encoded.chars().collect();
+        let truncated: String = chars[..cut.index(chars.len() + 1)].iter().collect();
+        let _ = Packet::decode(&truncated);
+        let mut mutated = chars.clone();
+        mutated.insert(insert.index(chars.len() + 1), byte);
+        let mutated: String = mutated.into_iter().collect();
+        let _ = Packet::decode(&mutated);
+    }
+
+    #[test]
+    fn filling_any_value_never_panics(data in json_value(), buffers in prop::collection::vec(prop::collection::vec(any::<u8>(), 0..4), 0..3)) {
+        let mut data = data;
+        let _ = fill_placeholders(&mut data, &buffers);
+    }
+}
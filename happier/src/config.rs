@@ -1,14 +1,416 @@
+use clap::{Args, Parser, Subcommand};
 use serde::{Deserialize, Serialize};
 use std::fs;
-use std::path::PathBuf;
+use std::io::Write;
+use std::net::SocketAddr;
+use std::ops::RangeInclusive;
+use std::os::unix::fs::{DirBuilderExt, OpenOptionsExt, PermissionsExt};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
 
-#[derive(Debug)]
+#[derive(Parser, Debug, Clone)]
+#[command(name = "happier", version, about = "Lightweight hapi machine runner")]
+pub struct Cli {
+    /// Path to config file [default: $HAPI_HOME/happier.toml]
+    #[arg(short, long, global = true)]
+    pub config: Option<PathBuf>,
+
+    /// Directory for settings.json, sockets and logs [env: HAPI_HOME] [default: ~/.hapi]
+    #[arg(long, global = true)]
+    pub hapi_home: Option<PathBuf>,
+
+    #[command(flatten)]
+    pub run: RunArgs,
+
+    #[command(subcommand)]
+    pub command: Option<Command>,
+}
+
+/// Options for running the daemon (the default when no subcommand is given).
+#[derive(Args, Debug, Clone, Default)]
+pub struct RunArgs {
+    /// Detach from the terminal and log to $HAPI_HOME/logs/happier.log
+    #[arg(long)]
+    pub daemon: bool,
+
+    /// Hub URL [env: HAPI_API_URL]
+    #[arg(long)]
+    pub api_url: Option<String>,
+
+    /// Read the CLI token from this file [env: CLI_API_TOKEN_FILE]
+    #[arg(long)]
+    pub token_file: Option<PathBuf>,
+
+    /// Display name shown in the web UI [env: HAPI_MACHINE_NAME]
+    #[arg(long)]
+    pub machine_name: Option<String>,
+
+    /// Log filter, e.g. `debug` or `info,happier::tunnel=trace` [env: RUST_LOG]
+    #[arg(long)]
+    pub log_level: Option<String>,
+}
+
+#[derive(Subcommand, Debug, Clone)]
+pub enum Command {
+    /// Show the running daemon's connection, tunnels and counters
+    Status {
+        /// Print raw JSON
+        #[arg(long)]
+        json: bool,
+    },
+    /// Ask the running daemon to shut down
+    Stop,
+    /// Check configuration and hub connectivity
+    Doctor,
+    /// Pair this machine with the hub from the web app and store the token
+    Login {
+        /// Web app URL the QR code points to [default: the hub URL]
+        #[arg(long)]
+        web_url: Option<String>,
+        /// Replace a token already stored in settings.json
+        #[arg(long)]
+        force: bool,
+    },
+    /// Write a systemd unit file for this binary
+    InstallService {
+        /// Install a system unit in /etc/systemd/system instead of a user unit
+        #[arg(long)]
+        system: bool,
+        /// Print the unit instead of writing it
+        #[arg(long)]
+        print: bool,
+        /// Overwrite an existing unit
+        #[arg(long)]
+        force: bool,
+    },
+}
+
+#[derive(Debug, Clone)]
 pub struct Config {
     pub api_url: String,
     pub token: String,
     pub machine_id: String,
     pub machine_name: Option<String>,
     pub hapi_home: PathBuf,
+    pub tunnel: TunnelPolicy,
+    /// How often to sample resource telemetry; `None` disables it.
+    pub telemetry_interval: Option<Duration>,
+    pub sessions: SessionPolicy,
+    pub sandbox: Vec<SandboxPolicy>,
+    /// Where to serve Prometheus metrics; `None` disables them.
+    pub metrics_listen: Option<SocketAddr>,
+    pub shutdown: ShutdownPolicy,
+    /// Keep undelivered runner state and metadata in `hapi_home/outbox.json`
+    /// across restarts.
+    pub persist_outbox: bool,
+    /// Which hub this config is for: `None` for the main one, else the
+    /// `[[profile]]` name. See `Config::hub`.
+    pub profile: Option<String>,
+    /// Extra hubs served alongside the main one.
+    pub profiles: Vec<HubProfile>,
+}
+
+/// A hub from `[[profile]]`, with its own token and machine id. Its
+/// settings.json and its sessions' `HAPI_HOME` is `profile_home`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct HubProfile {
+    pub name: String,
+    pub api_url: String,
+    pub token: String,
+    pub machine_id: String,
+    pub machine_name: Option<String>,
+}
+
+impl Config {
+    /// The config of every hub: the main one, then each profile.
+    pub fn hubs(&self) -> Vec<Config> {
+        let profiles = self.profiles.iter().map(|p| Some(p.name.as_str()));
+        std::iter::once(None)
+            .chain(profiles)
+            .filter_map(|name| self.hub(name))
+            .collect()
+    }
+
+    /// The config for the main hub (`None`) or the named profile.
+    pub fn hub(&self, profile: Option<&str>) -> Option<Config> {
+        let Some(name) = profile else {
+            return Some(Config {
+                profile: None,
+                profiles: Vec::new(),
+                ..self.clone()
+            });
+        };
+        let p = self.profiles.iter().find(|p| p.name == name)?;
+        Some(Config {
+            api_url: p.api_url.clone(),
+            token: p.token.clone(),
+            machine_id: p.machine_id.clone(),
+            machine_name: p.machine_name.clone(),
+            hapi_home: profile_home(&self.hapi_home, name),
+            profile: Some(name.to_string()),
+            profiles: Vec::new(),
+            ..self.clone()
+        })
+    }
+}
+
+/// How the daemon writes its log.
+#[derive(Debug, Clone, PartialEq)]
+pub struct LogPolicy {
+    pub format: LogFormat,
+    /// Write to `hapi_home/logs/happier.log` instead of stderr. Implied by `--daemon`.
+    pub file: bool,
+    /// Rotate the log file once it reaches this many bytes...
+    pub max_size: u64,
+    /// ...or once it is this old.
+    pub max_age: Option<Duration>,
+    /// Rotated files to keep, as happier.log.1 (newest) and up.
+    pub max_files: u32,
+}
+
+impl Default for LogPolicy {
+    fn default() -> Self {
+        LogPolicy {
+            format: LogFormat::Text,
+            file: false,
+            max_size: 10 << 20,
+            max_age: None,
+            max_files: 5,
+        }
+    }
+}
+
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+    Text,
+    /// One JSON object per line, with structured fields such as `tunnel_id`.
+    Json,
+}
+
+/// Default telemetry cadence, and the fastest allowed.
+const DEFAULT_TELEMETRY_SECS: u64 = 60;
+const MIN_TELEMETRY_SECS: u64 = 15;
+
+/// Which tunnel targets the hub may open, and how long to try.
+#[derive(Debug, Clone, PartialEq)]
+pub struct TunnelPolicy {
+    pub connect_timeout: Duration,
+    /// Host names/addresses tunnels may target, as requested. Empty allows any.
+    pub allowed_hosts: Vec<String>,
+    /// Empty allows any port.
+    pub allowed_ports: Vec<RangeInclusive<u16>>,
+}
+
+impl Default for TunnelPolicy {
+    fn default() -> Self {
+        TunnelPolicy {
+            connect_timeout: Duration::from_secs(10),
+            allowed_hosts: Vec::new(),
+            allowed_ports: Vec::new(),
+        }
+    }
+}
+
+impl TunnelPolicy {
+    pub fn check(&self, host: &str, port: u16) -> Result<(), String> {
+        if !self.allowed_hosts.is_empty()
+            && !self.allowed_hosts.iter().any(|h| h.eq_ignore_ascii_case(host))
+        {
+            return Err(format!("host {} is not in tunnel.allowed_hosts", host));
+        }
+        if !self.allowed_ports.is_empty() && !self.allowed_ports.iter().any(|r| r.contains(&port)) {
+            return Err(format!("port {} is not in tunnel.allowed_ports", port));
+        }
+        Ok(())
+    }
+}
+
+/// How agent sessions requested by the hub are started.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SessionPolicy {
+    /// The hapi CLI, run as `<command> <agent> --started-by runner ...`.
+    pub command: String,
+    pub limits: SessionLimits,
+}
+
+impl Default for SessionPolicy {
+    fn default() -> Self {
+        SessionPolicy {
+            command: "hapi".to_string(),
+            limits: SessionLimits::default(),
+        }
+    }
+}
+
+/// What happens on SIGINT, SIGTERM or `happier stop`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ShutdownPolicy {
+    /// How long open tunnels may keep transferring before they are closed.
+    pub drain: Duration,
+    /// Stop running sessions too, rather than leaving them to finish.
+    pub stop_sessions: bool,
+}
+
+impl Default for ShutdownPolicy {
+    fn default() -> Self {
+        ShutdownPolicy {
+            drain: Duration::from_secs(10),
+            stop_sessions: false,
+        }
+    }
+}
+
+/// Per-session resource limits; `None` leaves a resource unlimited.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct SessionLimits {
+    /// Bytes
+    pub memory_max: Option<u64>,
+    /// 100 is one full CPU
+    pub cpu_percent: Option<u32>,
+    pub pids_max: Option<u64>,
+    pub nofile: Option<u64>,
+}
+
+/// Sandbox for sessions and worktree commands in `directory` or below.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SandboxPolicy {
+    pub directory: PathBuf,
+    /// Restrict the filesystem with Landlock
+    pub filesystem: bool,
+    /// Allow network access; without it commands run in an empty network namespace
+    pub network: bool,
+    /// Readable beyond the system paths
+    pub read_only: Vec<PathBuf>,
+    /// Writable beyond the working directory
+    pub writable: Vec<PathBuf>,
+}
+
+/// `happier.toml`. Every key is optional; see happier.toml in the repo for docs.
+#[derive(Deserialize, Default, Debug)]
+#[serde(default)]
+struct FileConfig {
+    hub: HubSection,
+    tunnel: TunnelSection,
+    telemetry: TelemetrySection,
+    sessions: SessionsSection,
+    sandbox: Vec<SandboxSection>,
+    logging: LoggingSection,
+    metrics: MetricsSection,
+    shutdown: ShutdownSection,
+    outbox: OutboxSection,
+    profile: Vec<ProfileSection>,
+}
+
+#[derive(Deserialize, Default, Debug)]
+#[serde(default)]
+struct HubSection {
+    api_url: Option<String>,
+    machine_name: Option<String>,
+    token_file: Option<PathBuf>,
+}
+
+#[derive(Deserialize, Default, Debug)]
+#[serde(default)]
+struct TunnelSection {
+    connect_timeout_secs: Option<u64>,
+    allowed_hosts: Option<Vec<String>>,
+    allowed_ports: Option<Vec<PortSpec>>,
+}
+
+/// `5173` or `"3000-3999"`.
+#[derive(Deserialize, Debug)]
+#[serde(untagged)]
+enum PortSpec {
+    Single(u16),
+    Range(String),
+}
+
+#[derive(Deserialize, Default, Debug)]
+#[serde(default)]
+struct TelemetrySection {
+    interval_secs: Option<u64>,
+}
+
+#[derive(Deserialize, Default, Debug)]
+#[serde(default)]
+struct SessionsSection {
+    command: Option<String>,
+    limits: LimitsSection,
+}
+
+#[derive(Deserialize, Default, Debug)]
+#[serde(default)]
+struct LimitsSection {
+    memory_max: Option<SizeSpec>,
+    cpu_percent: Option<u32>,
+    pids_max: Option<u64>,
+    nofile: Option<u64>,
+}
+
+/// `1073741824` or `"1G"`.
+#[derive(Deserialize, Debug)]
+#[serde(untagged)]
+enum SizeSpec {
+    Bytes(u64),
+    Text(String),
+}
+
+#[derive(Deserialize, Debug)]
+struct SandboxSection {
+    directory: String,
+    #[serde(default = "default_true")]
+    filesystem: bool,
+    #[serde(default = "default_true")]
+    network: bool,
+    #[serde(default)]
+    read_only: Vec<String>,
+    #[serde(default)]
+    writable: Vec<String>,
+}
+
+fn default_true() -> bool {
+    true
+}
+
+#[derive(Deserialize, Default, Debug)]
+#[serde(default)]
+struct LoggingSection {
+    level: Option<String>,
+    format: Option<LogFormat>,
+    file: Option<bool>,
+    max_size: Option<SizeSpec>,
+    max_age_days: Option<u64>,
+    max_files: Option<u32>,
+}
+
+#[derive(Deserialize, Default, Debug)]
+#[serde(default)]
+struct MetricsSection {
+    listen: Option<String>,
+}
+
+#[derive(Deserialize, Debug)]
+struct ProfileSection {
+    name: String,
+    api_url: String,
+    #[serde(default)]
+    token_file: Option<PathBuf>,
+    #[serde(default)]
+    machine_name: Option<String>,
+}
+
+#[derive(Deserialize, Default, Debug)]
+#[serde(default)]
+struct ShutdownSection {
+    drain_secs: Option<u64>,
+    stop_sessions: Option<bool>,
+}
+
+#[derive(Deserialize, Default, Debug)]
+#[serde(default)]
+struct OutboxSection {
+    persist: Option<bool>,
 }
 
 #[derive(Serialize, Deserialize, Default)]
@@ -24,7 +426,103 @@ struct Settings {
     extra: serde_json::Map<String, serde_json::Value>,
 }
 
-fn hapi_home() -> PathBuf {
+/// Name of the systemd credential holding the token (`LoadCredential=cli-api-token:...`).
+const TOKEN_CREDENTIAL: &str = "cli-api-token";
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TokenSource {
+    /// `CLI_API_TOKEN`
+    Env,
+    /// `--token-file`, `CLI_API_TOKEN_FILE` or `hub.token_file`
+    File(PathBuf),
+    /// `$CREDENTIALS_DIRECTORY/cli-api-token`
+    Credential(PathBuf),
+    Settings,
+}
+
+impl std::fmt::Display for TokenSource {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            TokenSource::Env => write!(f, "CLI_API_TOKEN"),
+            TokenSource::File(path) => write!(f, "token file {}", path.display()),
+            TokenSource::Credential(path) => write!(f, "systemd credential {}", path.display()),
+            TokenSource::Settings => write!(f, "settings.json"),
+        }
+    }
+}
+
+/// Every problem found while resolving the config, reported together.
+#[derive(Debug)]
+pub struct ConfigError(pub Vec<String>);
+
+impl std::fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "invalid configuration:")?;
+        for e in &self.0 {
+            write!(f, "\n  - {}", e)?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
+/// Config as resolved from flags, env, happier.toml and settings.json
+/// (in that order of precedence), without generating or persisting anything.
+pub struct Resolved {
+    pub hapi_home: PathBuf,
+    pub config_path: PathBuf,
+    pub api_url: String,
+    /// The token and where it came from.
+    pub token: Option<(String, TokenSource)>,
+    pub machine_id: Option<String>,
+    pub machine_name: Option<String>,
+    pub log_filter: String,
+    pub logging: LogPolicy,
+    pub tunnel: TunnelPolicy,
+    pub telemetry_interval: Option<Duration>,
+    pub sessions: SessionPolicy,
+    pub sandbox: Vec<SandboxPolicy>,
+    pub metrics_listen: Option<SocketAddr>,
+    pub shutdown: ShutdownPolicy,
+    pub persist_outbox: bool,
+    pub profiles: Vec<ResolvedProfile>,
+}
+
+/// A `[[profile]]` as resolved; `load` fills in the machine id.
+pub struct ResolvedProfile {
+    pub name: String,
+    pub api_url: String,
+    pub token: Option<(String, TokenSource)>,
+    pub machine_id: Option<String>,
+    pub machine_name: Option<String>,
+}
+
+impl Resolved {
+    pub fn into_config(self, token: String, machine_id: String) -> Config {
+        Config {
+            api_url: self.api_url,
+            token,
+            machine_id,
+            machine_name: self.machine_name,
+            hapi_home: self.hapi_home,
+            telemetry_interval: self.telemetry_interval,
+            tunnel: self.tunnel,
+            sessions: self.sessions,
+            sandbox: self.sandbox,
+            metrics_listen: self.metrics_listen,
+            shutdown: self.shutdown,
+            persist_outbox: self.persist_outbox,
+            profile: None,
+            profiles: Vec::new(),
+        }
+    }
+}
+
+pub fn hapi_home(cli: &Cli) -> PathBuf {
+    if let Some(home) = &cli.hapi_home {
+        return home.clone();
+    }
     if let Ok(home) = std::env::var("HAPI_HOME") {
         return PathBuf::from(home);
     }
@@ -32,57 +530,814 @@ fn hapi_home() -> PathBuf {
     PathBuf::from(home).join(".hapi")
 }
 
-fn read_settings(hapi_home: &PathBuf) -> Settings {
-    let path = hapi_home.join("settings.json");
+fn read_settings(hapi_home: &Path) -> Result<Settings, String> {
+    let path = settings_path(hapi_home);
     match fs::read_to_string(&path) {
-        Ok(content) => serde_json::from_str(&content).unwrap_or_default(),
-        Err(_) => Settings::default(),
+        Ok(content) => serde_json::from_str(&content)
+            .map_err(|e| format!("{}: invalid JSON: {}", path.display(), e)),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(Settings::default()),
+        Err(e) => Err(format!("{}: {}", path.display(), e)),
     }
 }
 
-fn write_settings(hapi_home: &PathBuf, settings: &Settings) -> Result<(), Box<dyn std::error::Error>> {
-    fs::create_dir_all(hapi_home)?;
-    let path = hapi_home.join("settings.json");
+/// Write settings.json atomically with mode 0600: a crash leaves either the
+/// old or the new file, and the token is never readable by other users.
+fn write_settings(hapi_home: &Path, settings: &Settings) -> Result<(), Box<dyn std::error::Error>> {
+    fs::DirBuilder::new()
+        .recursive(true)
+        .mode(0o700)
+        .create(hapi_home)?;
+    let path = settings_path(hapi_home);
+    let tmp = hapi_home.join(format!(".settings.json.{}.tmp", std::process::id()));
     let content = serde_json::to_string_pretty(settings)?;
-    fs::write(&path, content)?;
-    Ok(())
+
+    let result = (|| -> std::io::Result<()> {
+        let mut file = fs::OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .mode(0o600)
+            .open(&tmp)?;
+        file.write_all(content.as_bytes())?;
+        file.sync_all()?;
+        fs::rename(&tmp, &path)?;
+        // Persist the rename itself
+        fs::File::open(hapi_home)?.sync_all()
+    })();
+    if result.is_err() {
+        let _ = fs::remove_file(&tmp);
+    }
+    Ok(result?)
 }
 
-pub fn load() -> Result<Config, Box<dyn std::error::Error>> {
-    let hapi_home = hapi_home();
-    let mut settings = read_settings(&hapi_home);
+/// Save the hub URL and token issued by `happier login` to settings.json,
+/// keeping every other field.
+pub fn store_login(
+    hapi_home: &Path,
+    api_url: &str,
+    token: &str,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let mut settings = read_settings(hapi_home)?;
+    settings.api_url = Some(api_url.to_string());
+    settings.cli_api_token = Some(token.to_string());
+    write_settings(hapi_home, &settings)
+}
 
-    // Resolve API URL: env > settings > default
-    let api_url = std::env::var("HAPI_API_URL")
+/// Whether settings.json already holds a token.
+pub fn has_stored_token(hapi_home: &Path) -> bool {
+    read_settings(hapi_home)
         .ok()
+        .and_then(|s| s.cli_api_token)
+        .is_some_and(|t| !t.is_empty())
+}
+
+/// Permission bits of `path` if group or others can read it.
+pub fn exposed_mode(path: &Path) -> Option<u32> {
+    let mode = fs::metadata(path).ok()?.permissions().mode() & 0o777;
+    (mode & 0o077 != 0).then_some(mode)
+}
+
+fn read_token_file(path: &Path) -> Result<String, String> {
+    let token = fs::read_to_string(path)
+        .map_err(|e| format!("token file {}: {}", path.display(), e))?;
+    let token = token.trim().to_string();
+    if token.is_empty() {
+        return Err(format!("token file {} is empty", path.display()));
+    }
+    Ok(token)
+}
+
+/// Token sources, highest first: `--token-file`, `CLI_API_TOKEN`,
+/// `CLI_API_TOKEN_FILE`, systemd credential, `hub.token_file`, settings.json.
+fn resolve_token(
+    cli: &Cli,
+    file_token_path: Option<PathBuf>,
+    settings: &Settings,
+    errors: &mut Vec<String>,
+) -> Option<(String, TokenSource)> {
+    let from_file = |path: PathBuf, errors: &mut Vec<String>| match read_token_file(&path) {
+        Ok(token) => Some((token, TokenSource::File(path))),
+        Err(e) => {
+            errors.push(e);
+            None
+        }
+    };
+
+    if let Some(path) = &cli.run.token_file {
+        return from_file(path.clone(), errors);
+    }
+    if let Some(token) = env_var("CLI_API_TOKEN") {
+        return Some((token, TokenSource::Env));
+    }
+    if let Some(path) = env_var("CLI_API_TOKEN_FILE") {
+        return from_file(PathBuf::from(path), errors);
+    }
+    if let Some(dir) = env_var("CREDENTIALS_DIRECTORY") {
+        let path = Path::new(&dir).join(TOKEN_CREDENTIAL);
+        if path.exists() {
+            return match read_token_file(&path) {
+                Ok(token) => Some((token, TokenSource::Credential(path))),
+                Err(e) => {
+                    errors.push(e);
+                    None
+                }
+            };
+        }
+    }
+    if let Some(path) = file_token_path {
+        return from_file(path, errors);
+    }
+    settings
+        .cli_api_token
+        .clone()
+        .filter(|t| !t.is_empty())
+        .map(|t| (t, TokenSource::Settings))
+}
+
+pub fn settings_path(hapi_home: &Path) -> PathBuf {
+    hapi_home.join("settings.json")
+}
+
+/// Home of a `[[profile]]`: its settings.json, and `HAPI_HOME` for its sessions.
+pub fn profile_home(hapi_home: &Path, name: &str) -> PathBuf {
+    hapi_home.join("profiles").join(name)
+}
+
+/// Check and resolve the `[[profile]]` tables. A profile's token comes
+/// from its `token_file`, else `cliApiToken` in its own settings.json.
+fn parse_profiles(
+    sections: Vec<ProfileSection>,
+    hapi_home: &Path,
+    errors: &mut Vec<String>,
+) -> Vec<ResolvedProfile> {
+    let mut profiles: Vec<ResolvedProfile> = Vec::new();
+    for section in sections {
+        let name = section.name;
+        let valid_name = !name.is_empty()
+            && name
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_');
+        if !valid_name {
+            errors.push(format!(
+                "profile name {:?} must be letters, digits, - and _",
+                name
+            ));
+            continue;
+        }
+        // `main` labels the main hub in metrics
+        if name == "main" || profiles.iter().any(|p| p.name == name) {
+            errors.push(format!("profile name {:?} is reserved or used twice", name));
+            continue;
+        }
+        let mut profile_errors = Vec::new();
+        validate_url(&section.api_url, &mut profile_errors);
+        if section.machine_name.as_deref().is_some_and(|n| n.trim().is_empty()) {
+            profile_errors.push("machine_name must not be blank".to_string());
+        }
+        let settings = read_settings(&profile_home(hapi_home, &name)).unwrap_or_else(|e| {
+            profile_errors.push(e);
+            Settings::default()
+        });
+        let token = match section.token_file {
+            Some(path) => match read_token_file(&path) {
+                Ok(token) => Some((token, TokenSource::File(path))),
+                Err(e) => {
+                    profile_errors.push(e);
+                    None
+                }
+            },
+            None => settings
+                .cli_api_token
+                .filter(|t| !t.is_empty())
+                .map(|t| (t, TokenSource::Settings)),
+        };
+        errors.extend(profile_errors.into_iter().map(|e| format!("profile {}: {}", name, e)));
+        profiles.push(ResolvedProfile {
+            api_url: section.api_url.trim_end_matches('/').to_string(),
+            token,
+            machine_id: settings.machine_id.filter(|id| !id.is_empty()),
+            machine_name: section.machine_name,
+            name,
+        });
+    }
+    profiles
+}
+
+fn read_file_config(path: &Path, explicit: bool, errors: &mut Vec<String>) -> FileConfig {
+    let content = match fs::read_to_string(path) {
+        Ok(content) => content,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound && !explicit => {
+            return FileConfig::default();
+        }
+        Err(e) => {
+            errors.push(format!("{}: {}", path.display(), e));
+            return FileConfig::default();
+        }
+    };
+
+    let mut unknown = Vec::new();
+    let parsed = serde_ignored::deserialize(toml::Deserializer::new(&content), |key| {
+        unknown.push(key.to_string())
+    });
+    match parsed {
+        Ok(file) => {
+            for key in unknown {
+                errors.push(format!("{}: unknown key `{}`", path.display(), key));
+            }
+            file
+        }
+        Err(e) => {
+            errors.push(format!("{}: {}", path.display(), e.to_string().trim_end()));
+            FileConfig::default()
+        }
+    }
+}
+
+fn env_var(name: &str) -> Option<String> {
+    std::env::var(name).ok().filter(|v| !v.is_empty())
+}
+
+fn validate_url(url: &str, errors: &mut Vec<String>) {
+    match url::Url::parse(url) {
+        Ok(parsed) if matches!(parsed.scheme(), "http" | "https") && parsed.has_host() => {}
+        Ok(_) => errors.push(format!("api_url {:?} must be an http(s) URL with a host", url)),
+        Err(e) => errors.push(format!("api_url {:?}: {}", url, e)),
+    }
+}
+
+/// Check an env_logger filter: comma-separated `level`, `module` or
+/// `module=level` directives, optionally followed by `/regex`.
+fn validate_log_filter(filter: &str, errors: &mut Vec<String>) {
+    const LEVELS: [&str; 6] = ["off", "error", "warn", "info", "debug", "trace"];
+    let is_level = |s: &str| LEVELS.iter().any(|l| l.eq_ignore_ascii_case(s));
+    let is_module = |s: &str| {
+        !s.is_empty() && s.chars().all(|c| c.is_alphanumeric() || c == '_' || c == ':' || c == '-')
+    };
+
+    let directives = filter.split('/').next().unwrap_or_default();
+    for directive in directives.split(',').map(str::trim).filter(|d| !d.is_empty()) {
+        let valid = match directive.split_once('=') {
+            Some((module, level)) => is_module(module.trim()) && is_level(level.trim()),
+            None => is_level(directive) || is_module(directive),
+        };
+        if !valid {
+            errors.push(format!("log level {:?}: invalid directive {:?}", filter, directive));
+        }
+    }
+}
+
+fn parse_logging(section: &LoggingSection, errors: &mut Vec<String>) -> LogPolicy {
+    let mut logging = LogPolicy::default();
+    if let Some(format) = section.format {
+        logging.format = format;
+    }
+    if let Some(file) = section.file {
+        logging.file = file;
+    }
+    if let Some(spec) = &section.max_size {
+        match parse_size(spec) {
+            Some(0) | None => errors.push(format!(
+                "logging.max_size: {:?} is not a size such as 10M",
+                spec
+            )),
+            Some(bytes) => logging.max_size = bytes,
+        }
+    }
+    match section.max_age_days {
+        Some(0) => errors.push("logging.max_age_days must be greater than 0".to_string()),
+        Some(days) => logging.max_age = Some(Duration::from_secs(days * 24 * 60 * 60)),
+        None => {}
+    }
+    if let Some(files) = section.max_files {
+        logging.max_files = files;
+    }
+    logging
+}
+
+fn parse_ports(specs: &[PortSpec], errors: &mut Vec<String>) -> Vec<RangeInclusive<u16>> {
+    let mut ranges = Vec::new();
+    for spec in specs {
+        match spec {
+            PortSpec::Single(0) => errors.push("tunnel.allowed_ports: port 0 is invalid".to_string()),
+            PortSpec::Single(port) => ranges.push(*port..=*port),
+            PortSpec::Range(s) => {
+                let parsed = match s.split_once('-') {
+                    Some((lo, hi)) => lo.trim().parse::<u16>().ok().zip(hi.trim().parse::<u16>().ok()),
+                    None => s.trim().parse::<u16>().ok().map(|p| (p, p)),
+                };
+                match parsed {
+                    Some((lo, hi)) if lo > 0 && lo <= hi => ranges.push(lo..=hi),
+                    _ => errors.push(format!(
+                        "tunnel.allowed_ports: {:?} is not a port or `low-high` range",
+                        s
+                    )),
+                }
+            }
+        }
+    }
+    ranges
+}
+
+/// Bytes from `SizeSpec`, with optional K/M/G/T suffix (powers of 1024).
+fn parse_size(spec: &SizeSpec) -> Option<u64> {
+    let text = match spec {
+        SizeSpec::Bytes(n) => return Some(*n),
+        SizeSpec::Text(text) => text.trim(),
+    };
+    let (digits, shift) = match text.char_indices().last()? {
+        (i, 'k' | 'K') => (&text[..i], 10),
+        (i, 'm' | 'M') => (&text[..i], 20),
+        (i, 'g' | 'G') => (&text[..i], 30),
+        (i, 't' | 'T') => (&text[..i], 40),
+        _ => (text, 0),
+    };
+    digits.trim().parse::<u64>().ok()?.checked_mul(1 << shift)
+}
+
+fn parse_limits(section: LimitsSection, errors: &mut Vec<String>) -> SessionLimits {
+    let memory_max = section.memory_max.and_then(|spec| match parse_size(&spec) {
+        Some(0) | None => {
+            errors.push(format!(
+                "sessions.limits.memory_max: {:?} is not a size such as 2G or 512M",
+                spec
+            ));
+            None
+        }
+        bytes => bytes,
+    });
+    let mut positive = |key: &str, value: Option<u64>| match value {
+        Some(0) => {
+            errors.push(format!("sessions.limits.{} must be greater than 0", key));
+            None
+        }
+        value => value,
+    };
+    SessionLimits {
+        memory_max,
+        cpu_percent: positive("cpu_percent", section.cpu_percent.map(u64::from)).map(|p| p as u32),
+        pids_max: positive("pids_max", section.pids_max),
+        nofile: positive("nofile", section.nofile),
+    }
+}
+
+/// Absolute path from config, with `~/` expanded.
+fn config_path(key: &str, value: &str, errors: &mut Vec<String>) -> Option<PathBuf> {
+    let path = match value.strip_prefix("~/") {
+        Some(rest) => PathBuf::from(std::env::var("HOME").unwrap_or_default()).join(rest),
+        None => PathBuf::from(value),
+    };
+    if path.is_absolute() {
+        Some(path)
+    } else {
+        errors.push(format!("{}: {:?} must be an absolute path", key, value));
+        None
+    }
+}
+
+fn parse_sandbox(sections: Vec<SandboxSection>, errors: &mut Vec<String>) -> Vec<SandboxPolicy> {
+    let mut policies: Vec<SandboxPolicy> = Vec::new();
+    for section in sections {
+        let Some(directory) = config_path("sandbox.directory", &section.directory, errors) else {
+            continue;
+        };
+        if policies.iter().any(|p| p.directory == directory) {
+            errors.push(format!("sandbox: {} has more than one policy", directory.display()));
+            continue;
+        }
+        let mut paths = |key: &str, values: &[String]| -> Vec<PathBuf> {
+            values
+                .iter()
+                .filter_map(|v| config_path(key, v, errors))
+                .collect()
+        };
+        policies.push(SandboxPolicy {
+            read_only: paths("sandbox.read_only", &section.read_only),
+            writable: paths("sandbox.writable", &section.writable),
+            directory,
+            filesystem: section.filesystem,
+            network: section.network,
+        });
+    }
+    policies
+}
+
+/// Resolve everything that can be resolved and collect every problem found.
+pub fn resolve(cli: &Cli) -> (Resolved, Vec<String>) {
+    let mut errors = Vec::new();
+    let hapi_home = hapi_home(cli);
+    let config_path = cli
+        .config
+        .clone()
+        .unwrap_or_else(|| hapi_home.join("happier.toml"));
+    let file = read_file_config(&config_path, cli.config.is_some(), &mut errors);
+    let settings = read_settings(&hapi_home).unwrap_or_else(|e| {
+        errors.push(e);
+        Settings::default()
+    });
+
+    // flags > env > happier.toml > settings.json > default
+    let api_url = cli
+        .run
+        .api_url
+        .clone()
+        .or_else(|| env_var("HAPI_API_URL"))
+        .or(file.hub.api_url)
         .or_else(|| settings.api_url.clone())
         .unwrap_or_else(|| "http://localhost:3006".to_string());
+    validate_url(&api_url, &mut errors);
+    let api_url = api_url.trim_end_matches('/').to_string();
 
-    // Resolve token: env > settings > error
-    let token = std::env::var("CLI_API_TOKEN")
-        .ok()
-        .or_else(|| settings.cli_api_token.clone())
-        .ok_or("CLI_API_TOKEN not set (env or settings.json)")?;
-
-    // Resolve machine ID: settings > generate new
-    let machine_id = match &settings.machine_id {
-        Some(id) if !id.is_empty() => id.clone(),
-        _ => {
-            let id = uuid::Uuid::new_v4().to_string();
-            log::info!("Generated new machineId: {}", id);
-            settings.machine_id = Some(id.clone());
-            write_settings(&hapi_home, &settings)?;
-            id
+    let token = resolve_token(cli, file.hub.token_file, &settings, &mut errors);
+
+    let machine_name = cli
+        .run
+        .machine_name
+        .clone()
+        .or_else(|| env_var("HAPI_MACHINE_NAME"))
+        .or(file.hub.machine_name);
+    if machine_name.as_deref().is_some_and(|n| n.trim().is_empty()) {
+        errors.push("machine_name must not be blank".to_string());
+    }
+
+    let log_filter = cli
+        .run
+        .log_level
+        .clone()
+        .or_else(|| env_var("RUST_LOG"))
+        .or(file.logging.level.clone())
+        .unwrap_or_else(|| "info".to_string());
+    validate_log_filter(&log_filter, &mut errors);
+    let logging = parse_logging(&file.logging, &mut errors);
+
+    let mut tunnel = TunnelPolicy::default();
+    if let Some(secs) = file.tunnel.connect_timeout_secs {
+        if secs == 0 {
+            errors.push("tunnel.connect_timeout_secs must be greater than 0".to_string());
+        } else {
+            tunnel.connect_timeout = Duration::from_secs(secs);
+        }
+    }
+    if let Some(hosts) = file.tunnel.allowed_hosts {
+        if hosts.iter().any(|h| h.trim().is_empty()) {
+            errors.push("tunnel.allowed_hosts must not contain blank entries".to_string());
         }
+        tunnel.allowed_hosts = hosts;
+    }
+    if let Some(ports) = file.tunnel.allowed_ports {
+        tunnel.allowed_ports = parse_ports(&ports, &mut errors);
+    }
+
+    let telemetry_interval = match file.telemetry.interval_secs {
+        Some(0) => None,
+        Some(secs) if secs < MIN_TELEMETRY_SECS => {
+            errors.push(format!(
+                "telemetry.interval_secs must be 0 (off) or at least {}",
+                MIN_TELEMETRY_SECS
+            ));
+            None
+        }
+        secs => Some(Duration::from_secs(secs.unwrap_or(DEFAULT_TELEMETRY_SECS))),
+    };
+
+    let mut sessions = SessionPolicy {
+        limits: parse_limits(file.sessions.limits, &mut errors),
+        ..SessionPolicy::default()
     };
+    if let Some(command) = file.sessions.command {
+        if command.trim().is_empty() {
+            errors.push("sessions.command must not be blank".to_string());
+        } else {
+            sessions.command = command;
+        }
+    }
+
+    let sandbox = parse_sandbox(file.sandbox, &mut errors);
+
+    let metrics_listen = file.metrics.listen.and_then(|listen| match listen.parse() {
+        Ok(addr) => Some(addr),
+        Err(_) => {
+            errors.push(format!(
+                "metrics.listen: {:?} is not an address such as 127.0.0.1:9464",
+                listen
+            ));
+            None
+        }
+    });
+
+    let mut shutdown = ShutdownPolicy::default();
+    if let Some(secs) = file.shutdown.drain_secs {
+        shutdown.drain = Duration::from_secs(secs);
+    }
+    if let Some(stop) = file.shutdown.stop_sessions {
+        shutdown.stop_sessions = stop;
+    }
 
-    let machine_name = std::env::var("HAPI_MACHINE_NAME").ok();
+    let profiles = parse_profiles(file.profile, &hapi_home, &mut errors);
 
-    Ok(Config {
+    let resolved = Resolved {
+        machine_id: settings.machine_id.filter(|id| !id.is_empty()),
+        hapi_home,
+        config_path,
         api_url,
         token,
-        machine_id,
         machine_name,
-        hapi_home,
-    })
+        log_filter,
+        logging,
+        tunnel,
+        telemetry_interval,
+        sessions,
+        sandbox,
+        metrics_listen,
+        shutdown,
+        persist_outbox: file.outbox.persist.unwrap_or(true),
+        profiles,
+    };
+    (resolved, errors)
+}
+
+/// Resolve and validate the config for running the daemon, generating and
+/// persisting a machine id on first start.
+pub fn load(cli: &Cli) -> Result<Config, Box<dyn std::error::Error>> {
+    let (resolved, mut errors) = resolve(cli);
+    if resolved.token.is_none() {
+        errors.push(
+            "no CLI token: set CLI_API_TOKEN, --token-file or cliApiToken in settings.json"
+                .to_string(),
+        );
+    }
+    for profile in resolved.profiles.iter().filter(|p| p.token.is_none()) {
+        errors.push(format!(
+            "profile {}: no CLI token: set token_file or cliApiToken in {}",
+            profile.name,
+            settings_path(&profile_home(&resolved.hapi_home, &profile.name)).display()
+        ));
+    }
+    if !errors.is_empty() {
+        return Err(ConfigError(errors).into());
+    }
+
+    let settings_path = settings_path(&resolved.hapi_home);
+    if let Some(mode) = exposed_mode(&settings_path) {
+        log::warn!(
+            "{} has mode {:03o} and may expose the token to other users; run chmod 600 {}",
+            settings_path.display(),
+            mode,
+            settings_path.display()
+        );
+    }
+    if let Some((_, TokenSource::File(path))) = &resolved.token {
+        if let Some(mode) = exposed_mode(path) {
+            log::warn!(
+                "token file {} has mode {:03o}; run chmod 600 {}",
+                path.display(),
+                mode,
+                path.display()
+            );
+        }
+    }
+
+    let token = resolved.token.as_ref().map(|(t, _)| t.clone()).unwrap_or_default();
+    let machine_id = load_machine_id(&resolved.hapi_home, resolved.machine_id.as_ref())?;
+
+    let mut profiles = Vec::new();
+    for p in &resolved.profiles {
+        let home = profile_home(&resolved.hapi_home, &p.name);
+        profiles.push(HubProfile {
+            name: p.name.clone(),
+            api_url: p.api_url.clone(),
+            token: p.token.as_ref().map(|(t, _)| t.clone()).unwrap_or_default(),
+            machine_id: load_machine_id(&home, p.machine_id.as_ref())?,
+            machine_name: p.machine_name.clone(),
+        });
+    }
+
+    let mut config = resolved.into_config(token, machine_id);
+    config.profiles = profiles;
+    Ok(config)
+}
+
+/// The machine id from settings.json, or a new one saved there.
+fn load_machine_id(
+    hapi_home: &Path,
+    stored: Option<&String>,
+) -> Result<String, Box<dyn std::error::Error>> {
+    if let Some(id) = stored {
+        return Ok(id.clone());
+    }
+    let mut settings = read_settings(hapi_home)?;
+    let id = uuid::Uuid::new_v4().to_string();
+    log::info!("Generated new machineId {} in {}", id, settings_path(hapi_home).display());
+    settings.machine_id = Some(id.clone());
+    write_settings(hapi_home, &settings)?;
+    Ok(id)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_sizes() {
+        let size = |text: &str| parse_size(&SizeSpec::Text(text.to_string()));
+        assert_eq!(parse_size(&SizeSpec::Bytes(1024)), Some(1024));
+        assert_eq!(size("512"), Some(512));
+        assert_eq!(size("4k"), Some(4096));
+        assert_eq!(size(" 512M "), Some(512 << 20));
+        assert_eq!(size("2G"), Some(2 << 30));
+        assert_eq!(size("1 T"), Some(1 << 40));
+        assert_eq!(size(""), None);
+        assert_eq!(size("G"), None);
+        assert_eq!(size("1.5G"), None);
+        assert_eq!(size("2GB"), None);
+        assert_eq!(size("-1M"), None);
+        assert_eq!(size("99999999999T"), None);
+    }
+
+    /// Every variable `resolve` reads; the tests below set them, so they
+    /// take turns.
+    const ENV: [&str; 6] = [
+        "HAPI_API_URL",
+        "CLI_API_TOKEN",
+        "CLI_API_TOKEN_FILE",
+        "CREDENTIALS_DIRECTORY",
+        "HAPI_MACHINE_NAME",
+        "RUST_LOG",
+    ];
+    static ENV_LOCK: std::sync::Mutex<()> = std::sync::Mutex::new(());
+
+    /// A `hapi_home` of its own with the given happier.toml and settings.json.
+    struct Home(PathBuf);
+
+    impl Home {
+        fn new(name: &str, toml: &str, settings: &str) -> Self {
+            let dir = std::env::temp_dir()
+                .join(format!("happier-config-{}-{}", name, std::process::id()));
+            let _ = fs::remove_dir_all(&dir);
+            fs::create_dir_all(&dir).unwrap();
+            fs::write(dir.join("happier.toml"), toml).unwrap();
+            if !settings.is_empty() {
+                fs::write(dir.join("settings.json"), settings).unwrap();
+            }
+            Home(dir)
+        }
+
+        fn cli(&self, flags: &[&str]) -> Cli {
+            let home = self.0.display().to_string();
+            let args = ["happier", "--hapi-home", &home].into_iter().chain(flags.iter().copied());
+            Cli::parse_from(args)
+        }
+
+        /// A file in the home holding `content`.
+        fn file(&self, name: &str, content: &str) -> String {
+            let path = self.0.join(name);
+            fs::write(&path, content).unwrap();
+            path.display().to_string()
+        }
+    }
+
+    impl Drop for Home {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.0);
+        }
+    }
+
+    /// Run `f` with only `vars` set among `ENV`.
+    fn with_env<T>(vars: &[(&str, String)], f: impl FnOnce() -> T) -> T {
+        let _lock = ENV_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+        let saved: Vec<_> = ENV.iter().map(|k| (*k, std::env::var_os(k))).collect();
+        for key in ENV {
+            std::env::remove_var(key);
+        }
+        for (key, value) in vars {
+            std::env::set_var(key, value);
+        }
+        let result = f();
+        for (key, value) in saved {
+            match value {
+                Some(value) => std::env::set_var(key, value),
+                None => std::env::remove_var(key),
+            }
+        }
+        result
+    }
+
+    #[test]
+    fn flags_beat_env_beat_toml_beat_settings() {
+        let all = ["flag", "env", "toml", "settings"];
+        // Each row drops the layer that won the one before
+        for (i, expected) in all.iter().enumerate() {
+            let layers = &all[i..];
+            let has = |layer: &str| layers.contains(&layer);
+            let mut toml = String::new();
+            if has("toml") {
+                toml.push_str("[hub]\napi_url = \"http://toml\"\nmachine_name = \"toml\"\n");
+            }
+            let settings = if has("settings") {
+                r#"{"apiUrl": "http://settings"}"#
+            } else {
+                ""
+            };
+            let home = Home::new(&format!("layers-{}", i), &toml, settings);
+            let mut flags = Vec::new();
+            if has("flag") {
+                flags.extend(["--api-url", "http://flag", "--machine-name", "flag"]);
+                flags.extend(["--log-level", "flag"]);
+            }
+            let mut env = Vec::new();
+            if has("env") {
+                env.push(("HAPI_API_URL", "http://env".to_string()));
+                env.push(("HAPI_MACHINE_NAME", "env".to_string()));
+                env.push(("RUST_LOG", "env".to_string()));
+            }
+            let (resolved, _) = with_env(&env, || resolve(&home.cli(&flags)));
+
+            assert_eq!(resolved.api_url, format!("http://{}", expected));
+            let name = (*expected != "settings").then(|| expected.to_string());
+            assert_eq!(resolved.machine_name, name, "machine_name with {:?}", layers);
+            let level = if matches!(*expected, "flag" | "env") { expected } else { "info" };
+            assert_eq!(resolved.log_filter, level, "log_filter with {:?}", layers);
+        }
+
+        let home = Home::new("defaults", "", "");
+        let (resolved, _) = with_env(&[], || resolve(&home.cli(&[])));
+        assert_eq!(resolved.api_url, "http://localhost:3006");
+        assert_eq!(resolved.machine_name, None);
+    }
+
+    #[test]
+    fn tokens_come_from_the_highest_source_given() {
+        let sources = [
+            "--token-file",
+            "CLI_API_TOKEN",
+            "CLI_API_TOKEN_FILE",
+            "credential",
+            "hub.token_file",
+            "settings.json",
+        ];
+        for (i, expected) in sources.iter().enumerate() {
+            let has = |source: &str| sources[i..].contains(&source);
+            let home = Home::new(&format!("token-{}", i), "", "");
+            let mut toml = String::new();
+            if has("hub.token_file") {
+                let path = home.file("toml-token", "from-toml\n");
+                toml = format!("[hub]\ntoken_file = {:?}\n", path);
+            }
+            fs::write(home.0.join("happier.toml"), toml).unwrap();
+            if has("settings.json") {
+                home.file("settings.json", r#"{"cliApiToken": "from-settings"}"#);
+            }
+            let flag_file = home.file("flag-token", "from-flag");
+            let mut flags = Vec::new();
+            if has("--token-file") {
+                flags.extend(["--token-file", flag_file.as_str()]);
+            }
+            let mut env = Vec::new();
+            if has("CLI_API_TOKEN") {
+                env.push(("CLI_API_TOKEN", "from-env".to_string()));
+            }
+            if has("CLI_API_TOKEN_FILE") {
+                env.push(("CLI_API_TOKEN_FILE", home.file("env-token", " from-env-file ")));
+            }
+            if has("credential") {
+                let credentials = home.0.join("credentials");
+                fs::create_dir(&credentials).unwrap();
+                fs::write(credentials.join(TOKEN_CREDENTIAL), "from-credential").unwrap();
+                env.push(("CREDENTIALS_DIRECTORY", credentials.display().to_string()));
+            }
+            let (resolved, errors) = with_env(&env, || resolve(&home.cli(&flags)));
+            assert!(errors.is_empty(), "{:?}", errors);
+
+            let (token, source) = resolved.token.unwrap();
+            let expected_token = match *expected {
+                "--token-file" => "from-flag",
+                "CLI_API_TOKEN" => "from-env",
+                "CLI_API_TOKEN_FILE" => "from-env-file",
+                "credential" => "from-credential",
+                "hub.token_file" => "from-toml",
+                _ => "from-settings",
+            };
+            assert_eq!(token, expected_token);
+            let kind = match source {
+                TokenSource::Env => "env",
+                TokenSource::File(_) => "file",
+                TokenSource::Credential(_) => "credential",
+                TokenSource::Settings => "settings",
+            };
+            let expected_kind = match *expected {
+                "CLI_API_TOKEN" => "env",
+                "credential" => "credential",
+                "settings.json" => "settings",
+                _ => "file",
+            };
+            assert_eq!(kind, expected_kind, "source of {}", expected);
+        }
+    }
+
+    #[test]
+    fn a_missing_token_is_reported_with_the_other_errors() {
+        let home = Home::new("missing-token", "[hub]\napi_url = \"ftp://hub\"\n", "");
+        let error = with_env(&[], || load(&home.cli(&[]))).unwrap_err().to_string();
+        assert!(error.contains("must be an http(s) URL"), "{}", error);
+        assert!(error.contains("no CLI token"), "{}", error);
+    }
 }
@@ -1,22 +1,178 @@
-use serde_json::json;
+use base64::engine::general_purpose::STANDARD as B64;
+use base64::Engine;
+use serde_json::{json, Value};
+use std::sync::Arc;
 use std::time::Duration;
 use tokio::sync::mpsc;
 
+use happier_sio::Received;
+
 use crate::config::Config;
-use crate::socket::SocketClient;
+use crate::events;
+use crate::logging;
+use crate::rpc::RpcHandlers;
+use crate::socket::{ConnectRefused, Entry, Outbox, SocketClient, SocketMetrics};
+use crate::tunnel::{Delivery, Inbox, Routes};
+
+/// Longest Retry-After we honor.
+const MAX_RETRY_AFTER: Duration = Duration::from_secs(3600);
+
+/// The hub rejected our token; retrying with it cannot succeed.
+#[derive(Debug)]
+pub struct AuthError(pub String);
+
+impl std::fmt::Display for AuthError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl std::error::Error for AuthError {}
 
-/// Events forwarded from Socket.IO to the main loop.
+/// The hub has no record of this machine, e.g. after its database was reset
+/// or the machine was deleted in the web app. Registering again fixes it.
+#[derive(Debug)]
+pub struct UnknownMachine;
+
+impl std::fmt::Display for UnknownMachine {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("the hub does not know this machine")
+    }
+}
+
+impl std::error::Error for UnknownMachine {}
+
+/// How to treat a failed registration or connect.
+pub enum Failure {
+    /// Bad credentials: give up.
+    Fatal,
+    /// Network trouble or an unhealthy hub: retry, after `retry_after` if the hub said.
+    Transient { retry_after: Option<Duration> },
+}
+
+/// Classify an HTTP status from the hub, with its `Retry-After` header.
+pub fn classify_status(status: u16, retry_after: Option<&str>) -> Failure {
+    match status {
+        401 | 403 => Failure::Fatal,
+        429 => Failure::Transient {
+            // Delay-seconds only; an HTTP date falls back to our own backoff
+            retry_after: retry_after
+                .and_then(|v| v.trim().parse().ok())
+                .map(|secs| Duration::from_secs(secs).min(MAX_RETRY_AFTER)),
+        },
+        _ => Failure::Transient { retry_after: None },
+    }
+}
+
+/// Classify an error from `connect`: a refusal that names the token, or an
+/// HTTP 401/403 on the WebSocket upgrade, is fatal.
+pub fn classify_connect_error(e: &(dyn std::error::Error + 'static)) -> Failure {
+    if let Some(ConnectRefused(reason)) = e.downcast_ref() {
+        let reason = reason.to_ascii_lowercase();
+        let auth = ["token", "auth", "unauthorized", "forbidden"]
+            .iter()
+            .any(|word| reason.contains(word));
+        return if auth {
+            Failure::Fatal
+        } else {
+            Failure::Transient { retry_after: None }
+        };
+    }
+    if let Some(tokio_tungstenite::tungstenite::Error::Http(response)) = e.downcast_ref() {
+        let retry_after = response
+            .headers()
+            .get("retry-after")
+            .and_then(|v| v.to_str().ok());
+        return classify_status(response.status().as_u16(), retry_after);
+    }
+    Failure::Transient { retry_after: None }
+}
+
+/// Somewhere between half and all of `delay`, so machines that lost the hub
+/// together don't all come back at once.
+pub fn jitter(delay: Duration) -> Duration {
+    use std::hash::{BuildHasher, Hasher};
+    // Randomly keyed per instance, so this is a random u64
+    let random = std::collections::hash_map::RandomState::new()
+        .build_hasher()
+        .finish();
+    let fraction = 0.5 + (random % 1000) as f64 / 2000.0;
+    delay.mul_f64(fraction)
+}
+
+/// Queue `tunnel:data` for its tunnel and turn the other tunnel events into
+/// work for the tunnel manager; malformed ones are dropped. Runs on the
+/// socket's read loop, so it must not wait.
+fn socket_event(event: &Received, routes: &Routes) -> Option<SocketEvent> {
+    if let Some(data) = event.parse::<events::TunnelData>() {
+        let data = data.ok().filter(|d| !d.tunnel_id.is_empty())?;
+        let bytes = match B64.decode(&data.data) {
+            Ok(bytes) => bytes,
+            Err(e) => {
+                log::warn!(
+                    tunnel_id = data.tunnel_id.as_str(), event = "bad_data";
+                    "Tunnel {} base64 decode error: {}", data.tunnel_id, e
+                );
+                return None;
+            }
+        };
+        return match routes.deliver(&data.tunnel_id, bytes) {
+            Delivery::Queued => None,
+            Delivery::NoTunnel => {
+                log::debug!(
+                    tunnel_id = data.tunnel_id.as_str(), event = "no_tunnel";
+                    "Dropping data for tunnel {}, which is not open", data.tunnel_id
+                );
+                None
+            }
+            Delivery::Overflow { generation } => Some(SocketEvent::TunnelOverflow {
+                tunnel_id: data.tunnel_id,
+                generation,
+            }),
+        };
+    }
+    if let Some(open) = event.parse::<events::TunnelOpen>() {
+        let open = open.ok().filter(|o| !o.tunnel_id.is_empty() && o.port != 0)?;
+        let inbox = routes.open(&open.tunnel_id);
+        return Some(SocketEvent::TunnelOpen {
+            tunnel_id: open.tunnel_id,
+            host: open.host,
+            port: open.port,
+            inbox,
+        });
+    }
+    if let Some(close) = event.parse::<events::TunnelClose>() {
+        let close = close.ok().filter(|c| !c.tunnel_id.is_empty())?;
+        routes.close(&close.tunnel_id);
+        return Some(SocketEvent::TunnelClose {
+            tunnel_id: close.tunnel_id,
+        });
+    }
+    let error = event.parse::<events::HubError>()?.ok()?;
+    let unknown = error.scope.as_deref() == Some("machine") && error.code.as_deref() == Some("not-found");
+    unknown.then_some(SocketEvent::UnknownMachine)
+}
+
+/// Events forwarded from Socket.IO to the tunnel manager. Tunnel data goes
+/// through `Routes` instead, so these are few and the channel is unbounded:
+/// none may be lost.
 #[derive(Debug)]
 pub enum SocketEvent {
-    TunnelOpen { tunnel_id: String, host: Option<String>, port: u16 },
-    TunnelData { tunnel_id: String, data: String },
+    TunnelOpen { tunnel_id: String, host: Option<String>, port: u16, inbox: Inbox },
     TunnelClose { tunnel_id: String },
+    /// The tunnel's target fell behind; its route is already gone.
+    TunnelOverflow { tunnel_id: String, generation: u64 },
+    /// The hub no longer knows our machine id.
+    UnknownMachine,
     Disconnected,
 }
 
 pub async fn connect(
     config: &Config,
-    event_tx: mpsc::Sender<SocketEvent>,
+    event_tx: mpsc::UnboundedSender<SocketEvent>,
+    routes: Routes,
+    rpc: Arc<RpcHandlers>,
+    metrics: Arc<SocketMetrics>,
 ) -> Result<SocketClient, Box<dyn std::error::Error>> {
     let auth = json!({
         "token": config.token,
@@ -25,79 +181,223 @@ pub async fn connect(
     });
 
     let tx = event_tx.clone();
-    let client = SocketClient::connect(&config.api_url, "/cli", auth, move |event, data, _client| {
-        let tx = tx.clone();
-        let socket_event = match event.as_str() {
-            "tunnel:open" => {
-                let tunnel_id = data["tunnelId"].as_str().unwrap_or("").to_string();
-                let port = data["port"].as_u64().unwrap_or(0) as u16;
-                let host = data["host"].as_str().map(|s| s.to_string());
-                if tunnel_id.is_empty() || port == 0 {
+    let machine_id = config.machine_id.clone();
+    let on_event = move |event: Received, client: SocketClient| {
+        // Runs in the socket's read task, which doesn't know which hub it serves
+        logging::sync_scope(&machine_id, || {
+            if let Some(request) = event.parse::<events::RpcRequest>() {
+                let (Ok(request), Some(ack_id)) = (request, event.ack) else {
                     return;
-                }
-                SocketEvent::TunnelOpen { tunnel_id, host, port }
-            }
-            "tunnel:data" => {
-                let tunnel_id = data["tunnelId"].as_str().unwrap_or("").to_string();
-                let data = data["data"].as_str().unwrap_or("").to_string();
-                if tunnel_id.is_empty() {
-                    return;
-                }
-                SocketEvent::TunnelData { tunnel_id, data }
+                };
+                let params = request.params.unwrap_or_else(|| "null".to_string());
+                let rpc = rpc.clone();
+                // Handlers may run git for a while; never block the read loop
+                logging::spawn(async move {
+                    let response = rpc.handle(&request.method, &params).await;
+                    if let Err(e) = client.ack(ack_id, Value::String(response)).await {
+                        log::warn!("Failed to answer RPC {}: {}", request.method, e);
+                    }
+                });
+                return;
             }
-            "tunnel:close" => {
-                let tunnel_id = data["tunnelId"].as_str().unwrap_or("").to_string();
-                if tunnel_id.is_empty() {
-                    return;
-                }
-                SocketEvent::TunnelClose { tunnel_id }
+            if let Some(socket_event) = socket_event(&event, &routes) {
+                let _ = tx.send(socket_event);
             }
-            _ => return,
-        };
-        let _ = tx.try_send(socket_event);
-    })
-    .await?;
+        })
+    };
+    let client =
+        SocketClient::connect(&config.api_url, "/cli", auth, metrics, on_event).await?;
 
     // Handle disconnection — forward to event loop
     let dc_notify = client.on_disconnect();
     let dc_tx = event_tx.clone();
-    tokio::spawn(async move {
+    logging::spawn(async move {
         dc_notify.notified().await;
-        let _ = dc_tx.send(SocketEvent::Disconnected).await;
+        let _ = dc_tx.send(SocketEvent::Disconnected);
     });
 
     Ok(client)
 }
 
-pub async fn emit_initial_state(
-    client: &SocketClient,
-    machine_id: &str,
-) -> Result<(), Box<dyn std::error::Error>> {
-    let now = std::time::SystemTime::now()
-        .duration_since(std::time::UNIX_EPOCH)
-        .unwrap()
-        .as_millis() as u64;
-
-    let _ack = client
-        .emit_with_ack(
-            "machine-update-state",
-            json!({
+/// Outbox keys of the versioned fields.
+pub const METADATA: &str = "metadata";
+pub const RUNNER_STATE: &str = "runnerState";
+
+/// A machine field the hub versions (`metadata` or `runnerState`), written by
+/// compare-and-swap. A version mismatch means someone else wrote; we take the
+/// hub's version and overwrite.
+pub struct VersionedField {
+    event: &'static str,
+    key: &'static str,
+    version: u64,
+}
+
+impl VersionedField {
+    pub fn metadata(version: u64) -> Self {
+        VersionedField {
+            event: "machine-update-metadata",
+            key: METADATA,
+            version,
+        }
+    }
+
+    pub fn runner_state(version: u64) -> Self {
+        VersionedField {
+            event: "machine-update-state",
+            key: RUNNER_STATE,
+            version,
+        }
+    }
+
+    pub fn version(&self) -> u64 {
+        self.version
+    }
+
+    /// An outbox entry for `value`, replacing any queued before.
+    pub fn entry(&self, value: Value) -> Entry {
+        Entry::keyed(self.key, self.event, value)
+    }
+
+    pub async fn update(
+        &mut self,
+        client: &SocketClient,
+        machine_id: &str,
+        value: &Value,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        for _ in 0..3 {
+            let mut payload = json!({
                 "machineId": machine_id,
-                "runnerState": {
-                    "status": "running",
-                    "startedAt": now,
-                },
-                "expectedVersion": 0,
-            }),
-            10,
-        )
-        .await?;
+                "expectedVersion": self.version,
+            });
+            payload[self.key] = value.clone();
+            let ack = client.emit_with_ack(self.event, payload, 10).await?;
+            let answer = ack.get(0).unwrap_or(&ack);
+            let version = answer["version"].as_u64();
+            match (answer["result"].as_str(), version) {
+                (Some("success"), Some(version)) => {
+                    self.version = version;
+                    return Ok(());
+                }
+                (Some("version-mismatch"), Some(version)) => self.version = version,
+                (Some("error"), _) if answer["reason"] == "not-found" => {
+                    return Err(UnknownMachine.into())
+                }
+                _ => return Err(format!("{} rejected: {}", self.event, answer).into()),
+            }
+        }
+        Err(format!("{} kept hitting version mismatches", self.event).into())
+    }
+}
+
+/// The `runnerState` we publish: status, session limits and exits, and the
+/// latest telemetry sample.
+pub struct RunnerState {
+    field: VersionedField,
+    machine_id: String,
+    /// `running`, then `shutting-down` and `stopped` as in the Node runner.
+    status: &'static str,
+    started_at: u64,
+    /// When shutdown was requested and by what (`os-signal` or `cli`).
+    shutdown: Option<(u64, &'static str)>,
+    limits: Option<Value>,
+    sessions: Option<Value>,
+    telemetry: Option<Value>,
+    /// How the run before this one ended, from the state it left unsent.
+    previous_run: Option<Value>,
+}
+
+impl RunnerState {
+    pub fn new(machine_id: &str, version: u64) -> Self {
+        RunnerState {
+            field: VersionedField::runner_state(version),
+            machine_id: machine_id.to_string(),
+            status: "running",
+            started_at: crate::state::now_millis(),
+            shutdown: None,
+            limits: None,
+            sessions: None,
+            telemetry: None,
+            previous_run: None,
+        }
+    }
+
+    /// Start over from a new registration's version.
+    pub fn reset_version(&mut self, version: u64) {
+        self.field = VersionedField::runner_state(version);
+    }
 
-    log::info!("Emitted initial runner state");
-    Ok(())
+    /// Move to `status`, recording the shutdown request the first time.
+    pub fn set_shutdown(&mut self, status: &'static str, source: &'static str) {
+        self.status = status;
+        self.shutdown
+            .get_or_insert_with(|| (crate::state::now_millis(), source));
+    }
+
+    pub fn set_limits(&mut self, limits: Option<Value>) {
+        self.limits = limits;
+    }
+
+    pub fn set_sessions(&mut self, sessions: Value) {
+        self.sessions = Some(sessions);
+    }
+
+    pub fn set_telemetry(&mut self, telemetry: Value) {
+        self.telemetry = Some(telemetry);
+    }
+
+    /// Keep the pid and shutdown of the run that left `saved` behind as
+    /// `previousRun`; the rest of it is no longer current.
+    pub fn set_previous_run(&mut self, saved: &Value) {
+        let previous: serde_json::Map<String, Value> =
+            ["pid", "startedAt", "status", "shutdownRequestedAt", "shutdownSource"]
+                .into_iter()
+                .filter_map(|key| Some((key.to_string(), saved.get(key)?.clone())))
+                .collect();
+        self.previous_run = Some(Value::Object(previous));
+    }
+
+    fn snapshot(&self) -> Value {
+        let mut state = json!({
+            "status": self.status,
+            "pid": std::process::id(),
+            "startedAt": self.started_at,
+        });
+        if let Some((requested_at, source)) = self.shutdown {
+            state["shutdownRequestedAt"] = requested_at.into();
+            state["shutdownSource"] = source.into();
+        }
+        if let Some(limits) = &self.limits {
+            state["limits"] = limits.clone();
+        }
+        if let Some(sessions) = &self.sessions {
+            state["sessions"] = sessions.clone();
+        }
+        if let Some(telemetry) = &self.telemetry {
+            state["telemetry"] = telemetry.clone();
+        }
+        if let Some(previous) = &self.previous_run {
+            state["previousRun"] = previous.clone();
+        }
+        state
+    }
+
+    pub async fn publish(&mut self, client: &SocketClient) -> Result<(), Box<dyn std::error::Error>> {
+        let state = self.snapshot();
+        self.deliver(client, &state).await
+    }
+
+    /// Write `state`, e.g. one from the outbox, at the current version.
+    pub async fn deliver(&mut self, client: &SocketClient, state: &Value) -> Result<(), Box<dyn std::error::Error>> {
+        self.field.update(client, &self.machine_id, state).await
+    }
+
+    /// Leave the current state for the next connect, replacing any queued before.
+    pub fn queue(&self, outbox: &Outbox) {
+        outbox.push(self.field.entry(self.snapshot()));
+    }
 }
 
-pub async fn keep_alive(client: SocketClient, machine_id: String) {
+pub async fn keep_alive(client: SocketClient, machine_id: String, metrics: Arc<SocketMetrics>) {
     let mut interval = tokio::time::interval(Duration::from_secs(20));
     loop {
         interval.tick().await;
@@ -106,16 +406,13 @@ pub async fn keep_alive(client: SocketClient, machine_id: String) {
             .unwrap()
             .as_millis() as u64;
 
-        if let Err(e) = client
-            .emit(
-                "machine-alive",
-                json!({
-                    "machineId": machine_id,
-                    "time": now,
-                }),
-            )
-            .await
-        {
+        let alive = events::MachineAlive {
+            machine_id: &machine_id,
+            time: now,
+        };
+        if let Err(e) = client.emit_event(&alive).await {
+            let failures = &metrics.keep_alive_failures;
+            failures.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
             log::warn!("Failed to send keep-alive: {}", e);
         }
     }
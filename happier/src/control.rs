@@ -0,0 +1,272 @@
+//! Local control interface: a Unix socket under `hapi_home` speaking
+//! newline-delimited JSON. Used by `happier status` and `happier stop`, and
+//! shared by every hub profile.
+
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::os::unix::fs::{DirBuilderExt, PermissionsExt};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::net::{UnixListener, UnixStream};
+use tokio::task::JoinHandle;
+use tokio::time::timeout;
+
+use crate::instance;
+use crate::state::{DaemonState, HubSnapshot, StatusSnapshot};
+
+const SOCKET_NAME: &str = "happier.sock";
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+
+#[derive(Serialize, Deserialize)]
+#[serde(tag = "command", rename_all = "kebab-case")]
+enum Request {
+    Status,
+    Stop,
+}
+
+pub fn socket_path(hapi_home: &Path) -> PathBuf {
+    hapi_home.join(SOCKET_NAME)
+}
+
+/// Running control server. Dropping it stops accepting and removes the socket.
+pub struct ControlServer {
+    path: PathBuf,
+    task: JoinHandle<()>,
+}
+
+impl Drop for ControlServer {
+    fn drop(&mut self) {
+        self.task.abort();
+        let _ = std::fs::remove_file(&self.path);
+    }
+}
+
+pub async fn serve(
+    hapi_home: &Path,
+    state: DaemonState,
+) -> Result<ControlServer, Box<dyn std::error::Error>> {
+    std::fs::create_dir_all(hapi_home)?;
+    let path = socket_path(hapi_home);
+
+    if path.exists() {
+        if UnixStream::connect(&path).await.is_ok() {
+            return Err(format!(
+                "another happier is already running (control socket {} is live)",
+                path.display()
+            )
+            .into());
+        }
+        // Left behind by a process that did not shut down cleanly
+        std::fs::remove_file(&path)?;
+    }
+
+    let listener = bind_private(hapi_home, &path)?;
+    log::debug!("Control socket listening on {}", path.display());
+
+    let task = tokio::spawn(async move {
+        loop {
+            match listener.accept().await {
+                Ok((stream, _)) => {
+                    tokio::spawn(handle_connection(stream, state.clone()));
+                }
+                Err(e) => {
+                    log::warn!("Control socket accept failed: {}", e);
+                    tokio::time::sleep(Duration::from_secs(1)).await;
+                }
+            }
+        }
+    });
+
+    Ok(ControlServer { path, task })
+}
+
+/// Bind inside a fresh 0700 directory, tighten the socket to 0600 and only
+/// then move it into place, so it is never reachable with umask permissions.
+fn bind_private(hapi_home: &Path, path: &Path) -> std::io::Result<UnixListener> {
+    let staging = hapi_home.join(format!(".{}.{}", SOCKET_NAME, std::process::id()));
+    let _ = std::fs::remove_dir_all(&staging);
+    std::fs::DirBuilder::new().mode(0o700).create(&staging)?;
+    let staged = staging.join(SOCKET_NAME);
+    let result = UnixListener::bind(&staged).and_then(|listener| {
+        std::fs::set_permissions(&staged, std::fs::Permissions::from_mode(0o600))?;
+        std::fs::rename(&staged, path)?;
+        Ok(listener)
+    });
+    let _ = std::fs::remove_dir_all(&staging);
+    result
+}
+
+async fn handle_connection(stream: UnixStream, state: DaemonState) {
+    let (read, mut write) = stream.into_split();
+    let mut lines = BufReader::new(read).lines();
+    while let Ok(Some(line)) = lines.next_line().await {
+        let (response, stop) = match serde_json::from_str::<Request>(&line) {
+            Ok(Request::Status) => (json!(state.snapshot()), false),
+            Ok(Request::Stop) => {
+                log::info!("Stop requested via control socket");
+                (json!({ "status": "stopping" }), true)
+            }
+            Err(e) => (json!({ "error": format!("invalid request: {}", e) }), false),
+        };
+        let mut out = response.to_string();
+        out.push('\n');
+        if write.write_all(out.as_bytes()).await.is_err() {
+            break;
+        }
+        if stop {
+            state.request_shutdown();
+        }
+    }
+}
+
+async fn request(hapi_home: &Path, req: &Request) -> Result<Value, Box<dyn std::error::Error>> {
+    let path = socket_path(hapi_home);
+    let stream = UnixStream::connect(&path)
+        .await
+        .map_err(|e| format!("happier is not running ({}: {})", path.display(), e))?;
+    let (read, mut write) = stream.into_split();
+
+    let mut line = serde_json::to_string(req)?;
+    line.push('\n');
+    write.write_all(line.as_bytes()).await?;
+
+    let mut lines = BufReader::new(read).lines();
+    let reply = timeout(REQUEST_TIMEOUT, lines.next_line())
+        .await
+        .map_err(|_| "control request timed out")??
+        .ok_or("control socket closed without a reply")?;
+    let value: Value = serde_json::from_str(&reply)?;
+    if let Some(err) = value.get("error").and_then(|e| e.as_str()) {
+        return Err(err.to_string().into());
+    }
+    Ok(value)
+}
+
+/// Fetch the running daemon's state, failing if none is listening.
+pub async fn query_status(hapi_home: &Path) -> Result<StatusSnapshot, Box<dyn std::error::Error>> {
+    let value = request(hapi_home, &Request::Status).await?;
+    Ok(serde_json::from_value(value)?)
+}
+
+/// `happier status`: print the running daemon's state.
+pub async fn status(hapi_home: &Path, as_json: bool) -> Result<(), Box<dyn std::error::Error>> {
+    let s = query_status(hapi_home).await?;
+    if as_json {
+        println!("{}", serde_json::to_string_pretty(&s)?);
+        return Ok(());
+    }
+
+    let now = crate::state::now_millis();
+    println!("happier {} (pid {})", s.version, s.pid);
+    println!("  Uptime:     {}", format_duration(now.saturating_sub(s.started_at)));
+    print_hub(&s.hub, now);
+    for profile in &s.profiles {
+        println!();
+        println!("  Profile {}", profile.profile.as_deref().unwrap_or("?"));
+        print_hub(profile, now);
+    }
+    Ok(())
+}
+
+fn print_hub(hub: &HubSnapshot, now: u64) {
+    println!("  Machine:    {}", hub.machine_id);
+    println!("  Hub:        {}", hub.api_url);
+    match hub.connected_since {
+        Some(since) => println!(
+            "  Connection: {} for {} ({} reconnects)",
+            hub.connection.as_str(),
+            format_duration(now.saturating_sub(since)),
+            hub.reconnects
+        ),
+        None => println!(
+            "  Connection: {} ({} reconnects)",
+            hub.connection.as_str(),
+            hub.reconnects
+        ),
+    }
+    println!("  Tunnels:    {}", hub.tunnels.len());
+    for t in &hub.tunnels {
+        println!(
+            "    {}  {}  in {}  out {}  open {}",
+            t.id,
+            t.target,
+            format_bytes(t.bytes_in),
+            format_bytes(t.bytes_out),
+            format_duration(now.saturating_sub(t.opened_at))
+        );
+    }
+    println!("  Sessions:   {}", hub.sessions.len());
+    for s in &hub.sessions {
+        println!(
+            "    {}  pid {}  {}  up {}",
+            s.session_id.as_deref().unwrap_or("(starting)"),
+            s.pid,
+            s.directory.display(),
+            format_duration(now.saturating_sub(s.started_at))
+        );
+    }
+    if !hub.recent_exits.is_empty() {
+        println!("  Recent exits:");
+    }
+    for e in hub.recent_exits.iter().rev() {
+        println!(
+            "    {}  pid {}  {}  {} ago",
+            e.session_id.as_deref().unwrap_or("(unreported)"),
+            e.pid,
+            e.describe(),
+            format_duration(now.saturating_sub(e.exited_at))
+        );
+    }
+}
+
+/// `happier stop`: ask the daemon to shut down and wait for it to go away.
+/// Falls back to SIGTERM via the PID file when the control socket is unreachable.
+pub async fn stop(hapi_home: &Path) -> Result<(), Box<dyn std::error::Error>> {
+    if let Err(e) = request(hapi_home, &Request::Stop).await {
+        if !instance::is_running(hapi_home)? {
+            return Err(e);
+        }
+        let pid = instance::read_pid(hapi_home)
+            .ok_or("happier holds the instance lock but wrote no PID file")?;
+        log::warn!("Control socket unavailable ({}), sending SIGTERM to {}", e, pid);
+        if unsafe { libc::kill(pid as libc::pid_t, libc::SIGTERM) } != 0 {
+            return Err(std::io::Error::last_os_error().into());
+        }
+    }
+
+    let deadline = tokio::time::Instant::now() + Duration::from_secs(30);
+    while tokio::time::Instant::now() < deadline {
+        if !instance::is_running(hapi_home)? {
+            println!("happier stopped");
+            return Ok(());
+        }
+        tokio::time::sleep(Duration::from_millis(200)).await;
+    }
+    Err("happier did not stop within 30s".into())
+}
+
+fn format_duration(millis: u64) -> String {
+    let secs = millis / 1000;
+    match secs {
+        0..=59 => format!("{}s", secs),
+        60..=3599 => format!("{}m{}s", secs / 60, secs % 60),
+        3600..=86399 => format!("{}h{}m", secs / 3600, (secs % 3600) / 60),
+        _ => format!("{}d{}h", secs / 86400, (secs % 86400) / 3600),
+    }
+}
+
+fn format_bytes(n: u64) -> String {
+    const UNITS: [&str; 4] = ["B", "KiB", "MiB", "GiB"];
+    let mut value = n as f64;
+    let mut unit = 0;
+    while value >= 1024.0 && unit < UNITS.len() - 1 {
+        value /= 1024.0;
+        unit += 1;
+    }
+    if unit == 0 {
+        format!("{} B", n)
+    } else {
+        format!("{:.1} {}", value, UNITS[unit])
+    }
+}
@@ -0,0 +1,559 @@
+//! `happier doctor`: checks configuration and hub connectivity, and prints
+//! a report with a hint for every problem found.
+
+use std::net::SocketAddr;
+use std::os::unix::fs::PermissionsExt;
+use std::path::Path;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::net::TcpStream;
+use tokio::sync::mpsc;
+use tokio::time::timeout;
+use url::Url;
+
+use crate::config::{self, Cli, Config, SandboxPolicy, TokenSource};
+use crate::rpc::RpcHandlers;
+use crate::state::{ConnectionState, StatusSnapshot};
+use crate::tunnel::Routes;
+use crate::{connection, control, instance, sandbox};
+
+const NET_TIMEOUT: Duration = Duration::from_secs(10);
+/// Clock difference to the hub above which we warn.
+const MAX_CLOCK_SKEW_SECS: i64 = 30;
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Outcome {
+    Pass,
+    Warn,
+    Fail,
+    Skip,
+}
+
+#[derive(Default)]
+struct Report {
+    failures: usize,
+    warnings: usize,
+}
+
+impl Report {
+    fn record(&mut self, outcome: Outcome, name: &str, detail: &str, hint: Option<&str>) {
+        let tag = match outcome {
+            Outcome::Pass => " ok ",
+            Outcome::Warn => "warn",
+            Outcome::Fail => "FAIL",
+            Outcome::Skip => "skip",
+        };
+        match outcome {
+            Outcome::Fail => self.failures += 1,
+            Outcome::Warn => self.warnings += 1,
+            _ => {}
+        }
+        println!("[{}] {:<14} {}", tag, name, detail);
+        if let Some(hint) = hint {
+            println!("       {:<14} -> {}", "", hint);
+        }
+    }
+
+    fn pass(&mut self, name: &str, detail: &str) {
+        self.record(Outcome::Pass, name, detail, None);
+    }
+
+    fn warn(&mut self, name: &str, detail: &str, hint: &str) {
+        self.record(Outcome::Warn, name, detail, Some(hint));
+    }
+
+    fn fail(&mut self, name: &str, detail: &str, hint: &str) {
+        self.record(Outcome::Fail, name, detail, Some(hint));
+    }
+
+    fn skip(&mut self, name: &str, detail: &str) {
+        self.record(Outcome::Skip, name, detail, None);
+    }
+}
+
+pub async fn run(cli: &Cli) -> Result<(), Box<dyn std::error::Error>> {
+    let (inspection, errors) = config::resolve(cli);
+    let mut report = Report::default();
+
+    println!("happier {} doctor", env!("CARGO_PKG_VERSION"));
+    println!("  hapi home: {}", inspection.hapi_home.display());
+    println!("  config:    {}", inspection.config_path.display());
+    println!("  hub:       {}", inspection.api_url);
+    println!();
+
+    if errors.is_empty() {
+        report.pass("config", "valid");
+    } else {
+        for e in &errors {
+            report.fail("config", e, "fix the value; happier refuses to start until it is valid");
+        }
+    }
+    check_settings(&mut report, &inspection.hapi_home);
+    check_sandbox(&mut report, &inspection.sandbox);
+    let instance = check_instance(&mut report, &inspection.hapi_home).await;
+
+    let token = match &inspection.token {
+        Some((token, source)) => {
+            report.pass("token", &format!("present (from {})", source));
+            if let TokenSource::File(path) = source {
+                if let Some(mode) = config::exposed_mode(path) {
+                    report.warn(
+                        "token file",
+                        &format!("mode {:03o} is readable by other users", mode),
+                        &format!("chmod 600 {}", path.display()),
+                    );
+                }
+            }
+            Some(token.clone())
+        }
+        None => {
+            report.fail(
+                "token",
+                "not set",
+                "set CLI_API_TOKEN, --token-file, a cli-api-token systemd credential, or cliApiToken in settings.json",
+            );
+            None
+        }
+    };
+
+    let reachable = check_network(&mut report, &inspection.api_url).await;
+
+    let config = match (token, inspection.machine_id.clone()) {
+        (Some(token), Some(machine_id)) => Some(inspection.into_config(token, machine_id)),
+        (Some(_), None) => {
+            report.skip(
+                "registration",
+                "no machineId yet (generated on first start)",
+            );
+            report.skip("socket.io", "no machineId yet");
+            None
+        }
+        _ => {
+            report.skip("registration", "needs a token");
+            report.skip("socket.io", "needs a token");
+            None
+        }
+    };
+
+    if let Some(config) = config {
+        if reachable {
+            if check_registration(&mut report, &config).await {
+                check_socket(&mut report, &config, instance.as_ref()).await;
+            } else {
+                report.skip("socket.io", "machine not registered");
+            }
+        } else {
+            report.skip("registration", "hub unreachable");
+            report.skip("socket.io", "hub unreachable");
+        }
+    }
+
+    println!();
+    if report.failures > 0 {
+        return Err(format!(
+            "{} check(s) failed, {} warning(s)",
+            report.failures, report.warnings
+        )
+        .into());
+    }
+    if report.warnings > 0 {
+        println!("All checks passed with {} warning(s)", report.warnings);
+    } else {
+        println!("All checks passed");
+    }
+    Ok(())
+}
+
+fn check_sandbox(report: &mut Report, policies: &[SandboxPolicy]) {
+    if policies.is_empty() {
+        report.skip("sandbox", "no [[sandbox]] policies");
+        return;
+    }
+    let filesystem = policies.iter().filter(|p| p.filesystem).count();
+    if filesystem > 0 && !sandbox::landlock_supported() {
+        report.fail(
+            "sandbox",
+            &format!("{} policies restrict the filesystem, but Landlock is unavailable", filesystem),
+            "use Linux 5.13+ with Landlock in the lsm= boot parameter, or set filesystem = false",
+        );
+        return;
+    }
+    report.pass("sandbox", &format!("{} policies", policies.len()));
+}
+
+fn check_settings(report: &mut Report, hapi_home: &Path) {
+    let path = config::settings_path(hapi_home);
+    let meta = match std::fs::metadata(&path) {
+        Ok(meta) => meta,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+            report.warn(
+                "settings.json",
+                &format!("{} does not exist", path.display()),
+                "it is created on first start; the token must then come from CLI_API_TOKEN",
+            );
+            return;
+        }
+        Err(e) => {
+            report.fail(
+                "settings.json",
+                &format!("cannot stat {}: {}", path.display(), e),
+                &format!("check ownership and permissions of {}", hapi_home.display()),
+            );
+            return;
+        }
+    };
+
+    let content = match std::fs::read_to_string(&path) {
+        Ok(content) => content,
+        Err(e) => {
+            report.fail(
+                "settings.json",
+                &format!("cannot read {}: {}", path.display(), e),
+                "make the file readable by the user running happier",
+            );
+            return;
+        }
+    };
+    if let Err(e) = serde_json::from_str::<serde_json::Value>(&content) {
+        report.fail(
+            "settings.json",
+            &format!("invalid JSON: {}", e),
+            "fix or remove the file; happier won't start until it parses",
+        );
+        return;
+    }
+
+    match config::exposed_mode(&path) {
+        Some(mode) => report.warn(
+            "settings.json",
+            &format!("mode {:03o} is readable by other users", mode),
+            &format!("chmod 600 {}", path.display()),
+        ),
+        None => report.pass(
+            "settings.json",
+            &format!("readable, mode {:03o}", meta.permissions().mode() & 0o777),
+        ),
+    }
+}
+
+async fn check_instance(report: &mut Report, hapi_home: &Path) -> Option<StatusSnapshot> {
+    match control::query_status(hapi_home).await {
+        Ok(status) => {
+            report.pass(
+                "instance",
+                &format!(
+                    "happier {} running (pid {}, {})",
+                    status.version,
+                    status.pid,
+                    status.hub.connection.as_str()
+                ),
+            );
+            Some(status)
+        }
+        Err(_) => match instance::is_running(hapi_home) {
+            Ok(true) => {
+                let pid = instance::read_pid(hapi_home)
+                    .map(|p| p.to_string())
+                    .unwrap_or_else(|| "unknown".to_string());
+                report.warn(
+                    "instance",
+                    &format!("happier holds the lock (pid {}) but its control socket does not answer", pid),
+                    "the process may be hung; check its logs or run `happier stop`",
+                );
+                None
+            }
+            Err(e) => {
+                report.fail(
+                    "instance",
+                    &format!("cannot check for a running happier: {}", e),
+                    "check the ownership and permissions of hapi_home",
+                );
+                None
+            }
+            Ok(false) if control::socket_path(hapi_home).exists() => {
+                report.warn(
+                    "instance",
+                    "stale control socket, no process answering",
+                    "a previous happier did not exit cleanly; it is cleaned up on next start",
+                );
+                None
+            }
+            Ok(false) => {
+                report.pass("instance", "no other happier running");
+                None
+            }
+        },
+    }
+}
+
+/// DNS, TCP and HTTP(S) reachability of the hub. Returns whether HTTP worked.
+async fn check_network(report: &mut Report, api_url: &str) -> bool {
+    let url = match Url::parse(api_url) {
+        Ok(url) => url,
+        Err(e) => {
+            report.fail("api url", &format!("{}: {}", api_url, e), "fix HAPI_API_URL or apiUrl");
+            return false;
+        }
+    };
+    let (Some(host), Some(port)) = (url.host_str(), url.port_or_known_default()) else {
+        report.fail("api url", "missing host or port", "fix HAPI_API_URL or apiUrl");
+        return false;
+    };
+
+    let addrs: Vec<SocketAddr> = match timeout(NET_TIMEOUT, tokio::net::lookup_host((host, port))).await
+    {
+        Ok(Ok(addrs)) => addrs.collect(),
+        Ok(Err(e)) => {
+            report.fail(
+                "dns",
+                &format!("cannot resolve {}: {}", host, e),
+                "check /etc/resolv.conf and the hostname in HAPI_API_URL",
+            );
+            return false;
+        }
+        Err(_) => {
+            report.fail("dns", &format!("resolving {} timed out", host), "check DNS servers");
+            return false;
+        }
+    };
+    let listed: Vec<String> = addrs.iter().map(|a| a.ip().to_string()).collect();
+    report.pass("dns", &format!("{} -> {}", host, listed.join(", ")));
+
+    let started = Instant::now();
+    let mut last_err = None;
+    let mut connected = None;
+    for addr in &addrs {
+        match timeout(NET_TIMEOUT, TcpStream::connect(addr)).await {
+            Ok(Ok(_)) => {
+                connected = Some(*addr);
+                break;
+            }
+            Ok(Err(e)) => last_err = Some(format!("{}: {}", addr, e)),
+            Err(_) => last_err = Some(format!("{}: timed out", addr)),
+        }
+    }
+    match connected {
+        Some(addr) => report.pass(
+            "tcp",
+            &format!("connected to {} in {}ms", addr, started.elapsed().as_millis()),
+        ),
+        None => {
+            report.fail(
+                "tcp",
+                &last_err.unwrap_or_else(|| "no addresses".to_string()),
+                "check that the hub is running and no firewall blocks the port",
+            );
+            return false;
+        }
+    }
+
+    let label = if url.scheme() == "https" { "tls/http" } else { "http" };
+    let client = match reqwest::Client::builder().timeout(NET_TIMEOUT).build() {
+        Ok(client) => client,
+        Err(e) => {
+            report.fail(label, &e.to_string(), "internal error building HTTP client");
+            return false;
+        }
+    };
+    let started = Instant::now();
+    let resp = match client.get(api_url).send().await {
+        Ok(resp) => resp,
+        Err(e) => {
+            let hint = if url.scheme() == "https" {
+                "check the certificate chain and that the host name matches"
+            } else {
+                "check that HAPI_API_URL points at the hub"
+            };
+            report.fail(label, &error_chain(&e), hint);
+            return false;
+        }
+    };
+    report.pass(
+        label,
+        &format!("HTTP {} in {}ms", resp.status(), started.elapsed().as_millis()),
+    );
+
+    let server_time = resp
+        .headers()
+        .get(reqwest::header::DATE)
+        .and_then(|v| v.to_str().ok())
+        .and_then(parse_http_date);
+    match server_time {
+        Some(server) => {
+            let local = crate::state::now_millis() as i64 / 1000;
+            let skew = local - server;
+            if skew.abs() > MAX_CLOCK_SKEW_SECS {
+                report.warn(
+                    "clock",
+                    &format!("local clock is {}s {} the hub", skew.abs(), if skew > 0 { "ahead of" } else { "behind" }),
+                    "enable time sync (e.g. systemd-timesyncd or chrony)",
+                );
+            } else {
+                report.pass("clock", &format!("skew {}s", skew));
+            }
+        }
+        None => report.skip("clock", "hub sent no Date header"),
+    }
+    true
+}
+
+/// Look the machine up with `GET /cli/machines/:id`, which unlike the
+/// `POST` that registers it changes nothing on the hub. True if the hub
+/// knows the machine.
+async fn check_registration(report: &mut Report, config: &Config) -> bool {
+    let client = match reqwest::Client::builder().timeout(NET_TIMEOUT).build() {
+        Ok(client) => client,
+        Err(e) => {
+            report.fail("registration", &e.to_string(), "internal error building HTTP client");
+            return false;
+        }
+    };
+    let url = format!("{}/cli/machines/{}", config.api_url, config.machine_id);
+    let response = client
+        .get(&url)
+        .header("Authorization", format!("Bearer {}", config.token))
+        .send()
+        .await;
+    match response {
+        Ok(resp) if resp.status().is_success() => {
+            report.pass("registration", &format!("GET /cli/machines/:id -> {}", resp.status()));
+            true
+        }
+        Ok(resp) if resp.status().as_u16() == 404 => {
+            report.skip(
+                "registration",
+                "the hub doesn't know this machine yet; happier registers it when it starts",
+            );
+            false
+        }
+        Ok(resp) if resp.status().as_u16() == 401 => {
+            report.fail(
+                "registration",
+                &format!("GET /cli/machines/:id -> {}", resp.status()),
+                "the token was rejected; issue a new CLI token in the hub",
+            );
+            false
+        }
+        Ok(resp) if resp.status().as_u16() == 403 => {
+            report.fail(
+                "registration",
+                &format!("GET /cli/machines/:id -> {}", resp.status()),
+                "the machine id belongs to another namespace; use the token it was registered with",
+            );
+            false
+        }
+        Ok(resp) => {
+            let status = resp.status();
+            let body = resp.text().await.unwrap_or_default();
+            let body: String = body
+                .split_whitespace()
+                .collect::<Vec<_>>()
+                .join(" ")
+                .chars()
+                .take(120)
+                .collect();
+            report.fail(
+                "registration",
+                &format!("GET /cli/machines/:id -> {} {}", status, body.trim()),
+                "check hub logs; the hub may be misconfigured or outdated",
+            );
+            false
+        }
+        Err(e) => {
+            report.fail("registration", &error_chain(&e), "check connectivity to the hub");
+            false
+        }
+    }
+}
+
+async fn check_socket(report: &mut Report, config: &Config, instance: Option<&StatusSnapshot>) {
+    // Don't open a second machine-scoped socket next to a healthy daemon
+    if let Some(status) = instance {
+        if status.hub.connection == ConnectionState::Connected {
+            report.pass("socket.io", "handshake ok (running instance is connected)");
+            return;
+        }
+    }
+
+    let (tx, _rx) = mpsc::unbounded_channel();
+    let started = Instant::now();
+    let rpc = Arc::new(RpcHandlers::new(&config.machine_id));
+    match timeout(NET_TIMEOUT, connection::connect(config, tx, Routes::default(), rpc, Arc::default())).await {
+        Ok(Ok(client)) => {
+            let elapsed = started.elapsed().as_millis();
+            let _ = client.disconnect().await;
+            report.pass("socket.io", &format!("handshake ok in {}ms", elapsed));
+        }
+        Ok(Err(e)) => {
+            let msg = e.to_string();
+            let hint = if msg.contains("connect error") {
+                "the hub refused the /cli namespace; check the token and machine id"
+            } else {
+                "check that proxies in front of the hub allow WebSocket upgrades"
+            };
+            report.fail("socket.io", &msg, hint);
+        }
+        Err(_) => report.fail(
+            "socket.io",
+            "handshake timed out",
+            "check that proxies in front of the hub allow WebSocket upgrades",
+        ),
+    }
+}
+
+fn error_chain(e: &dyn std::error::Error) -> String {
+    let mut out = e.to_string();
+    let mut source = e.source();
+    while let Some(s) = source {
+        out.push_str(": ");
+        out.push_str(&s.to_string());
+        source = s.source();
+    }
+    out
+}
+
+/// Parse an IMF-fixdate (`Sun, 06 Nov 1994 08:49:37 GMT`) into Unix seconds.
+fn parse_http_date(s: &str) -> Option<i64> {
+    let parts: Vec<&str> = s.split_whitespace().collect();
+    let [_, day, month, year, time, "GMT"] = parts.as_slice() else {
+        return None;
+    };
+    let day: i64 = day.parse().ok()?;
+    let year: i64 = year.parse().ok()?;
+    let month = [
+        "Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec",
+    ]
+    .iter()
+    .position(|m| m == month)? as i64
+        + 1;
+    let mut hms = time.split(':').map(|p| p.parse::<i64>().ok());
+    let (h, m, sec) = (hms.next()??, hms.next()??, hms.next()??);
+
+    // Days from civil date (Howard Hinnant's algorithm)
+    let y = if month <= 2 { year - 1 } else { year };
+    let era = y.div_euclid(400);
+    let yoe = y - era * 400;
+    let mp = (month + 9) % 12;
+    let doy = (153 * mp + 2) / 5 + day - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    let days = era * 146097 + doe - 719468;
+
+    Some(days * 86400 + h * 3600 + m * 60 + sec)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_http_dates() {
+        assert_eq!(parse_http_date("Sun, 06 Nov 1994 08:49:37 GMT"), Some(784111777));
+        assert_eq!(parse_http_date("Thu, 01 Jan 1970 00:00:00 GMT"), Some(0));
+        // Leap day
+        assert_eq!(parse_http_date("Thu, 29 Feb 2024 12:00:00 GMT"), Some(1709208000));
+        assert_eq!(parse_http_date("Sun, 06 Nov 1994 08:49:37 UTC"), None);
+        assert_eq!(parse_http_date("Sun, 06 Foo 1994 08:49:37 GMT"), None);
+        assert_eq!(parse_http_date("Sun, 06 Nov 1994 08:49 GMT"), None);
+        assert_eq!(parse_http_date("Sunday, 06-Nov-94 08:49:37 GMT"), None);
+    }
+}
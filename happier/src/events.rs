@@ -0,0 +1,115 @@
+//! The hub's `/cli` events that carry one object, as in the hub's socket
+//! handlers. Field names are the hub's camelCase.
+
+use happier_sio::Event;
+use serde::{Deserialize, Serialize};
+
+/// Hub → machine: call one of our registered RPC methods; answered by ack.
+#[derive(Deserialize)]
+pub struct RpcRequest {
+    pub method: String,
+    /// JSON-encoded, as the Node runner expects.
+    #[serde(default)]
+    pub params: Option<String>,
+}
+
+impl Event for RpcRequest {
+    const NAME: &'static str = "rpc-request";
+}
+
+/// Machine → hub: serve `method` for RPC.
+#[derive(Serialize)]
+pub struct RpcRegister<'a> {
+    pub method: &'a str,
+}
+
+impl Event for RpcRegister<'_> {
+    const NAME: &'static str = "rpc-register";
+}
+
+/// Machine → hub, every 20s.
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct MachineAlive<'a> {
+    pub machine_id: &'a str,
+    pub time: u64,
+}
+
+impl Event for MachineAlive<'_> {
+    const NAME: &'static str = "machine-alive";
+}
+
+/// Hub → machine: the hub's access errors, e.g. in reply to machine-alive.
+#[derive(Deserialize)]
+pub struct HubError {
+    #[serde(default)]
+    pub scope: Option<String>,
+    #[serde(default)]
+    pub code: Option<String>,
+}
+
+impl Event for HubError {
+    const NAME: &'static str = "error";
+}
+
+/// Hub → machine: connect to `host:port` (loopback if no host).
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct TunnelOpen {
+    pub tunnel_id: String,
+    #[serde(default)]
+    pub host: Option<String>,
+    pub port: u16,
+}
+
+impl Event for TunnelOpen {
+    const NAME: &'static str = "tunnel:open";
+}
+
+/// Machine → hub: the target accepted the connection.
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct TunnelReady<'a> {
+    pub tunnel_id: &'a str,
+}
+
+impl Event for TunnelReady<'_> {
+    const NAME: &'static str = "tunnel:ready";
+}
+
+/// Both ways: base64 bytes for the other end.
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct TunnelData {
+    pub tunnel_id: String,
+    pub data: String,
+}
+
+impl Event for TunnelData {
+    const NAME: &'static str = "tunnel:data";
+}
+
+/// Both ways: this end of the tunnel is done.
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct TunnelClose {
+    pub tunnel_id: String,
+}
+
+impl Event for TunnelClose {
+    const NAME: &'static str = "tunnel:close";
+}
+
+/// Machine → hub: the tunnel could not be opened or broke, with an errno
+/// style code.
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct TunnelError<'a> {
+    pub tunnel_id: &'a str,
+    pub code: &'a str,
+    pub message: &'a str,
+}
+
+impl Event for TunnelError<'_> {
+    const NAME: &'static str = "tunnel:error";
+}
@@ -0,0 +1,142 @@
+//! Single-instance guard and daemon mode.
+//!
+//! An exclusive `flock` on `hapi_home/happier.lock` keeps two processes from
+//! running with the same machine id; `happier.pid` records who holds it.
+
+use std::fs::{self, File, OpenOptions};
+use std::io;
+use std::os::unix::fs::OpenOptionsExt;
+use std::os::unix::io::AsRawFd;
+use std::path::{Path, PathBuf};
+
+const LOCK_NAME: &str = "happier.lock";
+const PID_NAME: &str = "happier.pid";
+
+/// Held for the lifetime of the daemon. The lock is released when the file
+/// is closed, including when the process is killed.
+pub struct InstanceLock {
+    _file: File,
+    pid_path: PathBuf,
+}
+
+impl Drop for InstanceLock {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.pid_path);
+    }
+}
+
+pub fn pid_path(hapi_home: &Path) -> PathBuf {
+    hapi_home.join(PID_NAME)
+}
+
+fn open_lock(hapi_home: &Path) -> io::Result<File> {
+    fs::create_dir_all(hapi_home)?;
+    OpenOptions::new()
+        .create(true)
+        .truncate(false)
+        .write(true)
+        .mode(0o600)
+        .open(hapi_home.join(LOCK_NAME))
+}
+
+/// Try to take the exclusive lock. Returns `Ok(false)` if another process holds it.
+fn try_lock(file: &File) -> io::Result<bool> {
+    let ret = unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_EX | libc::LOCK_NB) };
+    if ret == 0 {
+        return Ok(true);
+    }
+    let err = io::Error::last_os_error();
+    if err.raw_os_error() == Some(libc::EWOULDBLOCK) {
+        Ok(false)
+    } else {
+        Err(err)
+    }
+}
+
+pub fn read_pid(hapi_home: &Path) -> Option<u32> {
+    fs::read_to_string(pid_path(hapi_home))
+        .ok()
+        .and_then(|s| s.trim().parse().ok())
+}
+
+pub fn acquire(hapi_home: &Path) -> Result<InstanceLock, Box<dyn std::error::Error>> {
+    let file = open_lock(hapi_home)?;
+    if !try_lock(&file)? {
+        let holder = match read_pid(hapi_home) {
+            Some(pid) => format!("pid {}", pid),
+            None => "unknown pid".to_string(),
+        };
+        return Err(format!(
+            "another happier is already running for {} ({})",
+            hapi_home.display(),
+            holder
+        )
+        .into());
+    }
+    Ok(InstanceLock {
+        _file: file,
+        pid_path: pid_path(hapi_home),
+    })
+}
+
+impl InstanceLock {
+    /// Record the current pid. Call after `daemonize`, which changes it.
+    pub fn write_pid(&self) -> io::Result<()> {
+        fs::write(&self.pid_path, format!("{}\n", std::process::id()))
+    }
+}
+
+/// Whether some process currently holds the instance lock. Fails if the
+/// lock can't be checked, e.g. a `hapi_home` owned by another user. Creates
+/// nothing: without a lock file nothing has ever run here.
+pub fn is_running(hapi_home: &Path) -> io::Result<bool> {
+    let path = hapi_home.join(LOCK_NAME);
+    let file = match File::open(&path) {
+        Ok(file) => file,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(false),
+        Err(e) => return Err(io::Error::new(e.kind(), format!("{}: {}", path.display(), e))),
+    };
+    // Got it, so nobody else has it; the probe lock is dropped with the file
+    Ok(!try_lock(&file)?)
+}
+
+/// Detach from the terminal: double fork, new session, stdio redirected to
+/// `log_path`. Must run before the async runtime starts any threads.
+pub fn daemonize(log_path: &Path) -> Result<(), Box<dyn std::error::Error>> {
+    if let Some(dir) = log_path.parent() {
+        fs::create_dir_all(dir)?;
+    }
+    let log = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .mode(0o600)
+        .open(log_path)?;
+    let devnull = File::open("/dev/null")?;
+
+    fork_and_exit_parent()?;
+    if unsafe { libc::setsid() } == -1 {
+        return Err(io::Error::last_os_error().into());
+    }
+    // Second fork: the session leader exits so we can never reacquire a terminal
+    fork_and_exit_parent()?;
+
+    std::env::set_current_dir("/")?;
+    unsafe {
+        if libc::dup2(devnull.as_raw_fd(), libc::STDIN_FILENO) == -1
+            || libc::dup2(log.as_raw_fd(), libc::STDOUT_FILENO) == -1
+            || libc::dup2(log.as_raw_fd(), libc::STDERR_FILENO) == -1
+        {
+            return Err(io::Error::last_os_error().into());
+        }
+    }
+    Ok(())
+}
+
+fn fork_and_exit_parent() -> io::Result<()> {
+    match unsafe { libc::fork() } {
+        -1 => Err(io::Error::last_os_error()),
+        0 => Ok(()),
+        // `_exit` skips destructors so the parent doesn't remove files the child owns
+        _ => unsafe { libc::_exit(0) },
+    }
+}
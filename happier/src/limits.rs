@@ -0,0 +1,324 @@
+//! Per-session resource limits.
+//!
+//! With cgroup v2 and a writable subtree (systemd `Delegate=yes`), happier
+//! moves itself into a `daemon` leaf and gives each session a sibling
+//! `session-<id>` cgroup with `memory.max`, `cpu.max` and `pids.max`. That
+//! also tells us afterwards whether the kernel OOM-killed the session.
+//! Without it only open files can be limited, as an rlimit: `RLIMIT_AS` caps
+//! address space that Node reserves by the gigabyte, and `RLIMIT_NPROC`
+//! counts every process of the user, so neither stands in for a cgroup.
+//! Sessions are refused rather than run without the limits configured for
+//! them.
+
+use std::fs::{self, File};
+use std::os::fd::AsRawFd;
+use std::path::{Path, PathBuf};
+
+use serde_json::{json, Map, Value};
+
+use crate::config::SessionLimits;
+
+const CGROUP_ROOT: &str = "/sys/fs/cgroup";
+const DAEMON_LEAF: &str = "daemon";
+/// `cpu.max` period in microseconds
+const CPU_PERIOD: u64 = 100_000;
+
+/// How a limit is applied, as reported in `runnerState.limits`.
+#[derive(Clone, Copy, PartialEq)]
+enum Enforcement {
+    Cgroup,
+    Rlimit,
+    None,
+}
+
+impl Enforcement {
+    fn as_str(self) -> &'static str {
+        match self {
+            Enforcement::Cgroup => "cgroup",
+            Enforcement::Rlimit => "rlimit",
+            Enforcement::None => "none",
+        }
+    }
+}
+
+pub struct Limiter {
+    limits: SessionLimits,
+    /// Parent of the per-session cgroups, when cgroups are usable.
+    cgroup: Option<PathBuf>,
+    /// Configured limits that need the cgroup we don't have.
+    unenforced: Vec<&'static str>,
+}
+
+/// Limits prepared for one session, before and after it runs.
+pub struct Prepared {
+    cgroup: Option<PathBuf>,
+    /// `cgroup.procs` of the session cgroup; the child writes itself into it.
+    procs: Option<File>,
+    rlimits: Vec<(libc::__rlimit_resource_t, u64)>,
+}
+
+impl Limiter {
+    pub fn new(limits: &SessionLimits) -> Self {
+        let mut controllers = Vec::new();
+        if limits.memory_max.is_some() {
+            controllers.push("memory");
+        }
+        if limits.cpu_percent.is_some() {
+            controllers.push("cpu");
+        }
+        if limits.pids_max.is_some() {
+            controllers.push("pids");
+        }
+        let cgroup = if controllers.is_empty() {
+            None
+        } else {
+            match delegate(&controllers) {
+                Ok(root) => {
+                    log::info!("Session limits use cgroup v2 under {}", root.display());
+                    Some(root)
+                }
+                Err(e) => {
+                    log::warn!("cgroup v2 unavailable for session limits: {}", e);
+                    None
+                }
+            }
+        };
+        let unenforced: Vec<&'static str> = if cgroup.is_none() {
+            [
+                ("memory_max", limits.memory_max.is_some()),
+                ("cpu_percent", limits.cpu_percent.is_some()),
+                ("pids_max", limits.pids_max.is_some()),
+            ]
+            .into_iter()
+            .filter_map(|(key, set)| set.then_some(key))
+            .collect()
+        } else {
+            Vec::new()
+        };
+        if !unenforced.is_empty() {
+            log::warn!(
+                "sessions.limits {} need cgroup v2; sessions will be refused",
+                unenforced.join(", ")
+            );
+        }
+        Limiter {
+            limits: limits.clone(),
+            cgroup,
+            unenforced,
+        }
+    }
+
+    /// How the cgroup-controlled limits are enforced.
+    fn cgroup_enforcement(&self) -> Enforcement {
+        if self.cgroup.is_some() {
+            Enforcement::Cgroup
+        } else {
+            Enforcement::None
+        }
+    }
+
+    /// Configured limits and how each is enforced, for `runnerState.limits`.
+    pub fn report(&self) -> Option<Value> {
+        let mut report = Map::new();
+        let mut add = |key: &str, value: Option<u64>, enforcement: Enforcement| {
+            if let Some(value) = value {
+                report.insert(
+                    key.to_string(),
+                    json!({ "value": value, "enforcedBy": enforcement.as_str() }),
+                );
+            }
+        };
+        let cgroup = self.cgroup_enforcement();
+        add("memoryMax", self.limits.memory_max, cgroup);
+        add("cpuPercent", self.limits.cpu_percent.map(u64::from), cgroup);
+        add("pidsMax", self.limits.pids_max, cgroup);
+        add("nofile", self.limits.nofile, Enforcement::Rlimit);
+        (!report.is_empty()).then_some(Value::Object(report))
+    }
+
+    /// Create the session's cgroup and collect its rlimits. Fails when a
+    /// configured limit cannot be enforced.
+    pub fn prepare(&self, name: &str) -> std::io::Result<Prepared> {
+        if !self.unenforced.is_empty() {
+            return Err(std::io::Error::other(format!(
+                "{} need cgroup v2, which is unavailable, and sessions are not run without them",
+                self.unenforced.join(", ")
+            )));
+        }
+        let mut prepared = Prepared {
+            cgroup: None,
+            procs: None,
+            rlimits: Vec::new(),
+        };
+        if let Some(nofile) = self.limits.nofile {
+            prepared.rlimits.push((libc::RLIMIT_NOFILE, nofile));
+        }
+
+        let Some(root) = &self.cgroup else {
+            return Ok(prepared);
+        };
+
+        let dir = root.join(format!("session-{}", name));
+        fs::create_dir(&dir)?;
+        let configured = (|| -> std::io::Result<File> {
+            if let Some(bytes) = self.limits.memory_max {
+                fs::write(dir.join("memory.max"), bytes.to_string())?;
+                // Otherwise a session over its limit swaps instead of being killed
+                if dir.join("memory.swap.max").exists() {
+                    fs::write(dir.join("memory.swap.max"), "0")?;
+                }
+            }
+            if let Some(percent) = self.limits.cpu_percent {
+                let quota = u64::from(percent) * CPU_PERIOD / 100;
+                fs::write(dir.join("cpu.max"), format!("{} {}", quota, CPU_PERIOD))?;
+            }
+            if let Some(pids) = self.limits.pids_max {
+                fs::write(dir.join("pids.max"), pids.to_string())?;
+            }
+            fs::OpenOptions::new().write(true).open(dir.join("cgroup.procs"))
+        })();
+        match configured {
+            Ok(procs) => {
+                prepared.procs = Some(procs);
+                prepared.cgroup = Some(dir);
+                Ok(prepared)
+            }
+            Err(e) => {
+                let _ = fs::remove_dir(&dir);
+                Err(e)
+            }
+        }
+    }
+}
+
+impl Prepared {
+    /// Put the child in its own session and process group, then into its
+    /// cgroup and under its rlimits, before it execs.
+    pub fn apply(&self, command: &mut tokio::process::Command) {
+        let procs = self.procs.as_ref().map(|f| f.as_raw_fd());
+        let rlimits = self.rlimits.clone();
+        // Only async-signal-safe calls from here on: this runs after fork
+        unsafe {
+            command.pre_exec(move || {
+                if libc::setsid() < 0 {
+                    return Err(std::io::Error::last_os_error());
+                }
+                if let Some(fd) = procs {
+                    // "0" moves the writing process
+                    if libc::write(fd, b"0".as_ptr().cast(), 1) < 0 {
+                        return Err(std::io::Error::last_os_error());
+                    }
+                }
+                for (resource, value) in &rlimits {
+                    let limit = libc::rlimit {
+                        rlim_cur: *value,
+                        rlim_max: *value,
+                    };
+                    if libc::setrlimit(*resource, &limit) != 0 {
+                        return Err(std::io::Error::last_os_error());
+                    }
+                }
+                Ok(())
+            });
+        }
+    }
+
+    /// The limit the kernel enforced by killing the session, if any. Only
+    /// cgroups record this.
+    pub fn killed_by(&self) -> Option<&'static str> {
+        let dir = self.cgroup.as_ref()?;
+        (event_count(&dir.join("memory.events"), "oom_kill") > 0).then_some("memory")
+    }
+
+    /// Limits the session ran into without being killed, e.g. a failed fork.
+    pub fn hit(&self) -> Vec<&'static str> {
+        let mut hit = Vec::new();
+        if let Some(dir) = &self.cgroup {
+            if event_count(&dir.join("memory.events"), "max") > 0 {
+                hit.push("memory");
+            }
+            if event_count(&dir.join("pids.events"), "max") > 0 {
+                hit.push("pids");
+            }
+        }
+        hit
+    }
+
+    /// Remove the session cgroup. Fails quietly while processes the session
+    /// left behind still live in it.
+    pub fn cleanup(&self) {
+        if let Some(dir) = &self.cgroup {
+            if let Err(e) = fs::remove_dir(dir) {
+                log::debug!("Keeping {}: {}", dir.display(), e);
+            }
+        }
+    }
+}
+
+/// `key value` counter from a cgroup `*.events` file.
+fn event_count(path: &Path, key: &str) -> u64 {
+    fs::read_to_string(path)
+        .ok()
+        .and_then(|content| {
+            content.lines().find_map(|line| {
+                let (k, v) = line.split_once(' ')?;
+                (k == key).then(|| v.trim().parse().ok())?
+            })
+        })
+        .unwrap_or(0)
+}
+
+/// Make our own cgroup a parent for session cgroups with `controllers`
+/// enabled, returning its path. A cgroup can only hand controllers to its
+/// children while it holds no processes itself, so happier first moves into
+/// a `daemon` leaf.
+fn delegate(controllers: &[&str]) -> Result<PathBuf, String> {
+    let own = fs::read_to_string("/proc/self/cgroup").map_err(|e| e.to_string())?;
+    let path = own
+        .lines()
+        .find_map(|line| line.strip_prefix("0::"))
+        .ok_or("not on the cgroup v2 unified hierarchy")?;
+    let mut root = Path::new(CGROUP_ROOT).join(path.trim_start_matches('/'));
+    // Restarted inside our own leaf, e.g. by a supervisor that kept the cgroup
+    if root.file_name().is_some_and(|n| n == DAEMON_LEAF) {
+        root.pop();
+    }
+
+    if !Path::new(CGROUP_ROOT).join("cgroup.controllers").exists() {
+        return Err(format!("cgroup v2 is not mounted at {}", CGROUP_ROOT));
+    }
+    let available = fs::read_to_string(root.join("cgroup.controllers"))
+        .map_err(|e| format!("{}: {}", root.display(), e))?;
+    let missing: Vec<&str> = controllers
+        .iter()
+        .copied()
+        .filter(|c| !available.split_whitespace().any(|a| a == *c))
+        .collect();
+    if !missing.is_empty() {
+        return Err(format!(
+            "{} does not offer the {} controller(s)",
+            root.display(),
+            missing.join(", ")
+        ));
+    }
+
+    let leaf = root.join(DAEMON_LEAF);
+    match fs::create_dir(&leaf) {
+        Ok(()) => {}
+        Err(e) if e.kind() == std::io::ErrorKind::AlreadyExists => {}
+        Err(e) => return Err(format!("{}: {}", leaf.display(), e)),
+    }
+    fs::write(leaf.join("cgroup.procs"), std::process::id().to_string())
+        .map_err(|e| format!("moving into {}: {}", leaf.display(), e))?;
+
+    let enable: Vec<String> = controllers.iter().map(|c| format!("+{}", c)).collect();
+    fs::write(root.join("cgroup.subtree_control"), enable.join(" ")).map_err(|e| {
+        format!(
+            "enabling {} in {}: {}",
+            controllers.join(", "),
+            root.display(),
+            e
+        )
+    })?;
+    Ok(root)
+}
@@ -0,0 +1,239 @@
+//! Logger setup. The daemon logs to stderr, or with `logging.file` (always
+//! under `--daemon`) to `hapi_home/logs/happier.log`, rotated by size and
+//! age. The JSON format writes one object per line, with the record's
+//! structured fields such as `tunnel_id` and `event` plus the machine id
+//! of the hub the record is about, taken from the task it is logged in.
+//! The level filter can be replaced at runtime, for config reloads.
+
+use std::ffi::OsString;
+use std::fs::{self, File, OpenOptions};
+use std::future::Future;
+use std::io::{self, Write};
+use std::os::fd::AsRawFd;
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::{Path, PathBuf};
+use std::sync::{OnceLock, RwLock};
+use std::time::{Duration, SystemTime};
+
+use env_filter::Filter;
+use env_logger::fmt::Formatter;
+use log::kv::{Key, Value, VisitSource};
+use serde_json::{Map, Value as Json};
+use tokio::task::JoinHandle;
+
+use crate::config::{LogFormat, LogPolicy};
+
+static FILTER: OnceLock<RwLock<Filter>> = OnceLock::new();
+
+tokio::task_local! {
+    /// The machine id of the hub the current task works for.
+    static MACHINE_ID: String;
+}
+
+/// Run `future` for the hub with `machine_id`: its records, and those of the
+/// tasks it starts with `spawn`, carry that id.
+pub fn scope<F: Future>(machine_id: &str, future: F) -> impl Future<Output = F::Output> {
+    MACHINE_ID.scope(machine_id.to_string(), future)
+}
+
+/// `scope` for synchronous code, such as socket callbacks.
+pub fn sync_scope<R>(machine_id: &str, f: impl FnOnce() -> R) -> R {
+    MACHINE_ID.sync_scope(machine_id.to_string(), f)
+}
+
+/// `tokio::spawn`, keeping the current hub's machine id for the task.
+pub fn spawn<F>(future: F) -> JoinHandle<F::Output>
+where
+    F: Future + Send + 'static,
+    F::Output: Send + 'static,
+{
+    match MACHINE_ID.try_with(String::clone) {
+        Ok(id) => tokio::spawn(MACHINE_ID.scope(id, future)),
+        Err(_) => tokio::spawn(future),
+    }
+}
+
+/// Install the global logger, writing to `file` if given and stderr otherwise.
+pub fn init(filter: &str, policy: &LogPolicy, file: Option<LogFile>) {
+    let mut builder = env_logger::Builder::new();
+    // Filtering happens in `Reloadable`
+    builder.filter_level(log::LevelFilter::Trace);
+    match policy.format {
+        // Structured fields repeat what text messages already say
+        LogFormat::Text => builder
+            .format_timestamp_millis()
+            .format_key_values(env_logger::fmt::hidden_kv_format),
+        LogFormat::Json => builder.format(format_json),
+    };
+    if let Some(file) = file {
+        builder.target(env_logger::Target::Pipe(Box::new(file)));
+    }
+    let filter = FILTER.get_or_init(|| RwLock::new(parse_filter(filter)));
+    log::set_max_level(filter.read().unwrap().filter());
+    log::set_boxed_logger(Box::new(Reloadable(builder.build()))).expect("logger already set");
+}
+
+/// Replace the level filter, in `RUST_LOG` syntax.
+pub fn set_filter(filter: &str) {
+    let Some(current) = FILTER.get() else {
+        return;
+    };
+    let filter = parse_filter(filter);
+    log::set_max_level(filter.filter());
+    *current.write().unwrap() = filter;
+}
+
+fn parse_filter(filter: &str) -> Filter {
+    env_filter::Builder::new().parse(filter).build()
+}
+
+/// env_logger's output behind the replaceable `FILTER`.
+struct Reloadable(env_logger::Logger);
+
+impl log::Log for Reloadable {
+    fn enabled(&self, metadata: &log::Metadata) -> bool {
+        FILTER.get().is_some_and(|f| f.read().unwrap().enabled(metadata))
+    }
+
+    fn log(&self, record: &log::Record) {
+        if FILTER.get().is_some_and(|f| f.read().unwrap().matches(record)) {
+            self.0.log(record);
+        }
+    }
+
+    fn flush(&self) {
+        self.0.flush();
+    }
+}
+
+fn format_json(buf: &mut Formatter, record: &log::Record) -> io::Result<()> {
+    let mut fields = Map::new();
+    fields.insert("ts".into(), buf.timestamp_millis().to_string().into());
+    fields.insert("level".into(), record.level().as_str().into());
+    fields.insert("target".into(), record.target().into());
+    let _ = MACHINE_ID.try_with(|id| fields.insert("machine_id".into(), id.as_str().into()));
+    let _ = record.key_values().visit(&mut Fields(&mut fields));
+    fields.insert("msg".into(), record.args().to_string().into());
+    writeln!(buf, "{}", Json::Object(fields))
+}
+
+struct Fields<'a>(&'a mut Map<String, Json>);
+
+impl<'kvs> VisitSource<'kvs> for Fields<'_> {
+    fn visit_pair(&mut self, key: Key<'kvs>, value: Value<'kvs>) -> Result<(), log::kv::Error> {
+        let value = match value.to_u64() {
+            Some(n) => n.into(),
+            None => value.to_string().into(),
+        };
+        self.0.insert(key.as_str().to_string(), value);
+        Ok(())
+    }
+}
+
+/// `happier.log`, rotated to `happier.log.1` (newest) up to
+/// `happier.log.<max_files>` once it grows past `max_size` or gets older
+/// than `max_age`.
+pub struct LogFile {
+    path: PathBuf,
+    file: File,
+    size: u64,
+    opened: SystemTime,
+    max_size: u64,
+    max_age: Option<Duration>,
+    max_files: u32,
+    /// Point stdout and stderr at each new file, so a daemon's panics land
+    /// in the current log rather than a rotated one.
+    stdio: bool,
+}
+
+impl LogFile {
+    /// Open the log under `hapi_home`, which may be relative: the path is
+    /// resolved now, before a daemon changes directory.
+    pub fn open(hapi_home: &Path, policy: &LogPolicy, stdio: bool) -> io::Result<Self> {
+        let dir = hapi_home.join("logs");
+        fs::create_dir_all(&dir)?;
+        let path = fs::canonicalize(dir)?.join("happier.log");
+        let (file, size, opened) = open_file(&path)?;
+        Ok(LogFile {
+            path,
+            file,
+            size,
+            opened,
+            max_size: policy.max_size,
+            max_age: policy.max_age,
+            max_files: policy.max_files,
+            stdio,
+        })
+    }
+
+    fn due(&self, incoming: usize) -> bool {
+        if self.size == 0 {
+            return false;
+        }
+        self.size + incoming as u64 > self.max_size
+            || self
+                .max_age
+                .is_some_and(|age| self.opened.elapsed().is_ok_and(|elapsed| elapsed >= age))
+    }
+
+    fn rotate(&mut self) -> io::Result<()> {
+        if self.max_files == 0 {
+            fs::remove_file(&self.path)?;
+        } else {
+            // Renaming over the last one drops the oldest
+            for n in (1..self.max_files).rev() {
+                match fs::rename(self.rotated(n), self.rotated(n + 1)) {
+                    Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e),
+                    _ => {}
+                }
+            }
+            fs::rename(&self.path, self.rotated(1))?;
+        }
+        let (file, size, opened) = open_file(&self.path)?;
+        if self.stdio {
+            unsafe {
+                libc::dup2(file.as_raw_fd(), libc::STDOUT_FILENO);
+                libc::dup2(file.as_raw_fd(), libc::STDERR_FILENO);
+            }
+        }
+        self.file = file;
+        self.size = size;
+        self.opened = opened;
+        Ok(())
+    }
+
+    fn rotated(&self, n: u32) -> PathBuf {
+        let mut name = OsString::from(self.path.as_os_str());
+        name.push(format!(".{}", n));
+        PathBuf::from(name)
+    }
+}
+
+impl Write for LogFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if self.due(buf.len()) && self.rotate().is_err() {
+            // Nowhere to report it; keep appending and retry after another max_size
+            self.size = 1;
+            self.opened = SystemTime::now();
+        }
+        let written = self.file.write(buf)?;
+        self.size += written as u64;
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.file.flush()
+    }
+}
+
+/// Open for append, returning the current size and when the file was started.
+fn open_file(path: &Path) -> io::Result<(File, u64, SystemTime)> {
+    let file = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .mode(0o600)
+        .open(path)?;
+    let meta = file.metadata()?;
+    let opened = meta.created().or_else(|_| meta.modified())?;
+    Ok((file, meta.len(), opened))
+}
@@ -0,0 +1,157 @@
+//! `happier login`: pair with the hub through the web app's QR flow
+//! (`POST /api/qr`, approve at `/qr/:id`, poll `GET /api/qr/:id`) and store
+//! the issued token in settings.json.
+
+use std::time::Duration;
+
+use qrcode::render::unicode::Dense1x2;
+use qrcode::QrCode;
+use serde::Deserialize;
+
+use crate::config::{self, Cli, TokenSource};
+use crate::instance;
+
+/// Matches the web client's poll rate; sessions expire on the hub after 5 minutes.
+const POLL_INTERVAL: Duration = Duration::from_secs(2);
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(15);
+
+#[derive(Deserialize)]
+struct QrSession {
+    id: String,
+    secret: String,
+}
+
+#[derive(Deserialize)]
+#[serde(tag = "status", rename_all = "lowercase")]
+enum PollResult {
+    Pending,
+    Expired,
+    Confirmed {
+        #[serde(rename = "accessToken")]
+        access_token: String,
+    },
+}
+
+/// Short code shown next to the QR code; the confirm page derives the same
+/// one from the session id so the user can check they approve the right request.
+fn short_code(id: &str) -> String {
+    let hex: String = id
+        .chars()
+        .filter(|c| *c != '-')
+        .take(8)
+        .collect::<String>()
+        .to_uppercase();
+    match hex.len() {
+        8 => format!("{}-{}", &hex[..4], &hex[4..]),
+        _ => hex,
+    }
+}
+
+fn confirm_url(web_url: &str, session: &QrSession) -> Result<String, Box<dyn std::error::Error>> {
+    let mut url = url::Url::parse(web_url)?.join(&format!("qr/{}", session.id))?;
+    url.query_pairs_mut().append_pair("s", &session.secret);
+    Ok(url.to_string())
+}
+
+fn print_qr(url: &str) {
+    match QrCode::new(url.as_bytes()) {
+        Ok(code) => {
+            // Light modules drawn as blocks, so it scans on dark terminal backgrounds
+            let image = code
+                .render::<Dense1x2>()
+                .dark_color(Dense1x2::Light)
+                .light_color(Dense1x2::Dark)
+                .quiet_zone(true)
+                .build();
+            println!("{}", image);
+        }
+        Err(e) => log::debug!("QR encoding failed: {}", e),
+    }
+}
+
+pub async fn run(
+    cli: &Cli,
+    web_url: Option<&str>,
+    force: bool,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let (resolved, errors) = config::resolve(cli);
+    if !errors.is_empty() {
+        return Err(config::ConfigError(errors).into());
+    }
+    if config::has_stored_token(&resolved.hapi_home) && !force {
+        return Err(format!(
+            "{} already has a token (use --force to replace it)",
+            config::settings_path(&resolved.hapi_home).display()
+        )
+        .into());
+    }
+
+    // `url::Url::join` drops the last segment unless the base ends in `/`
+    let web_url = format!(
+        "{}/",
+        web_url.unwrap_or(&resolved.api_url).trim_end_matches('/')
+    );
+    let client = reqwest::Client::builder().timeout(REQUEST_TIMEOUT).build()?;
+
+    let response = client
+        .post(format!("{}/api/qr", resolved.api_url))
+        .send()
+        .await?;
+    if !response.status().is_success() {
+        return Err(format!("hub refused pairing request: HTTP {}", response.status()).into());
+    }
+    let session: QrSession = response.json().await?;
+    let url = confirm_url(&web_url, &session)?;
+
+    println!("Scan with a device signed in to {}:", resolved.api_url);
+    println!();
+    print_qr(&url);
+    println!("Or open: {}", url);
+    println!();
+    println!("Code: {}  (check the web app shows the same code)", short_code(&session.id));
+    println!("Waiting for approval, expires in 5 minutes (Ctrl-C to cancel)...");
+
+    let poll_url = format!("{}/api/qr/{}", resolved.api_url, session.id);
+    let token = loop {
+        tokio::select! {
+            _ = tokio::time::sleep(POLL_INTERVAL) => {}
+            _ = tokio::signal::ctrl_c() => return Err("login cancelled".into()),
+        }
+        let response = match client
+            .get(&poll_url)
+            .query(&[("s", &session.secret)])
+            .send()
+            .await
+        {
+            Ok(r) => r,
+            Err(e) => {
+                // Keep polling through brief network errors until the hub expires the session
+                log::warn!("Poll failed: {}", e);
+                continue;
+            }
+        };
+        if !response.status().is_success() {
+            return Err(format!("pairing failed: HTTP {}", response.status()).into());
+        }
+        match response.json::<PollResult>().await? {
+            PollResult::Pending => continue,
+            // The hub forgets denied sessions, so a denial also reads as expired
+            PollResult::Expired => return Err("pairing request expired or was denied".into()),
+            PollResult::Confirmed { access_token } => break access_token,
+        }
+    };
+
+    config::store_login(&resolved.hapi_home, &resolved.api_url, &token)?;
+    println!(
+        "Approved. Token saved to {}",
+        config::settings_path(&resolved.hapi_home).display()
+    );
+    if resolved.token.is_some_and(|(_, source)| source != TokenSource::Settings) {
+        println!("Note: another token source takes precedence over settings.json and is still in use.");
+    }
+    // Unless we know it isn't running
+    if !matches!(instance::is_running(&resolved.hapi_home), Ok(false)) {
+        println!("Restart the running happier to use the new token: happier stop && happier --daemon");
+    }
+    Ok(())
+}
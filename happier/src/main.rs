@@ -1,119 +1,581 @@
 mod config;
 mod connection;
+mod control;
+mod doctor;
+mod events;
+mod instance;
+mod limits;
+mod logging;
+mod login;
 mod metadata;
+mod metrics;
+mod ports;
 mod register;
+mod reload;
+mod rpc;
+mod sandbox;
+mod sessions;
 mod socket;
+mod state;
+mod systemd;
+mod telemetry;
 mod tunnel;
+mod worktree;
 
+use clap::Parser;
+use config::{Cli, Command};
+use futures_util::stream::{FuturesUnordered, StreamExt};
+use state::{ConnectionState, DaemonState, RuntimeState};
+use std::sync::Arc;
 use std::time::Duration;
-use tokio::signal::unix::{signal, SignalKind};
-use tokio::sync::mpsc;
+use tokio::signal::unix::{signal, Signal, SignalKind};
+use tokio::sync::{mpsc, watch, Mutex, Notify};
 
-#[tokio::main(flavor = "current_thread")]
-async fn main() {
-    env_logger::Builder::from_env(env_logger::Env::default().default_filter_or("info"))
-        .format_timestamp_millis()
-        .init();
+/// Exit status when the hub rejects our token (sysexits `EX_NOPERM`).
+/// Supervisors should not restart on it; see the systemd unit.
+const EXIT_AUTH: i32 = 77;
 
-    if let Err(e) = run().await {
+fn main() {
+    let cli = Cli::parse();
+    let (resolved, errors) = config::resolve(&cli);
+    // An invalid filter could hide the error explaining it
+    let log_filter = if errors.is_empty() {
+        resolved.log_filter.as_str()
+    } else {
+        "info"
+    };
+    // Only the daemon logs to a file, and only with a config that says how
+    let wants_file = resolved.logging.file || cli.run.daemon;
+    let mut log_error = None;
+    let log_file = if errors.is_empty() && cli.command.is_none() && wants_file {
+        match logging::LogFile::open(&resolved.hapi_home, &resolved.logging, cli.run.daemon) {
+            Ok(file) => Some(file),
+            Err(e) => {
+                log_error = Some(e);
+                None
+            }
+        }
+    } else {
+        None
+    };
+    let to_file = log_file.is_some();
+    logging::init(log_filter, &resolved.logging, log_file);
+    if let Some(e) = log_error {
+        log::warn!("Cannot open log file, logging to stderr: {}", e);
+    }
+
+    let result = match &cli.command {
+        None => start(&cli),
+        Some(Command::Status { json }) => block_on(control::status(&resolved.hapi_home, *json)),
+        Some(Command::Stop) => block_on(control::stop(&resolved.hapi_home)),
+        Some(Command::Doctor) => block_on(doctor::run(&cli)),
+        Some(Command::Login { web_url, force }) => {
+            block_on(login::run(&cli, web_url.as_deref(), *force))
+        }
+        Some(Command::InstallService {
+            system,
+            print,
+            force,
+        }) => systemd::install(
+            &cli,
+            &systemd::InstallOptions {
+                scope: if *system {
+                    systemd::UnitScope::System
+                } else {
+                    systemd::UnitScope::User
+                },
+                print: *print,
+                force: *force,
+            },
+        ),
+    };
+
+    if let Err(e) = result {
         log::error!("Fatal: {}", e);
+        if to_file {
+            eprintln!("happier: {}", e);
+        }
+        if e.is::<connection::AuthError>() {
+            std::process::exit(EXIT_AUTH);
+        }
         std::process::exit(1);
     }
 }
 
-async fn run() -> Result<(), Box<dyn std::error::Error>> {
-    let config = config::load()?;
+fn block_on<F: std::future::Future>(future: F) -> F::Output {
+    tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()
+        .expect("failed to build tokio runtime")
+        .block_on(future)
+}
+
+/// Take the instance lock, load config and, if asked, detach before any
+/// runtime threads exist. Then run the daemon until shutdown.
+fn start(cli: &Cli) -> Result<(), Box<dyn std::error::Error>> {
+    // Before loading, which may write a new machineId to settings.json
+    let lock = instance::acquire(&config::hapi_home(cli))?;
+    let mut config = config::load(cli)?;
+    let mut cli = cli.clone();
+
+    if cli.run.daemon {
+        // We chdir to / when detaching; reloads resolve the same paths
+        config.hapi_home = std::fs::canonicalize(&config.hapi_home)?;
+        cli.hapi_home = Some(config.hapi_home.clone());
+        if let Some(path) = &cli.config {
+            cli.config = Some(std::fs::canonicalize(path)?);
+        }
+        let log_path = config.hapi_home.join("logs").join("happier.log");
+        eprintln!("happier: detaching, logging to {}", log_path.display());
+        instance::daemonize(&log_path)?;
+    }
+    lock.write_pid()?;
+
+    let result = block_on(run(cli, config));
+    drop(lock);
+    result
+}
+
+async fn run(cli: Cli, config: config::Config) -> Result<(), Box<dyn std::error::Error>> {
     log::info!(
-        "happier {} starting (machine: {}, api: {})",
+        "happier {} starting (machine: {}, api: {}, profiles: {}, pid: {})",
         env!("CARGO_PKG_VERSION"),
         config.machine_id,
-        config.api_url
+        config.api_url,
+        config.profiles.len(),
+        std::process::id()
     );
 
-    let metadata = metadata::build(&config);
-    log::info!(
-        "Machine: {} ({})",
-        metadata.display_name.as_deref().unwrap_or(&metadata.host),
-        metadata.platform
-    );
+    let hubs = config.hubs();
+    let states: Vec<RuntimeState> = hubs
+        .iter()
+        .map(|hub| RuntimeState::new(hub.profile.as_deref(), &hub.machine_id, &hub.api_url))
+        .collect();
+    let daemon = DaemonState::new(states.clone());
+    let _control = control::serve(&config.hapi_home, daemon.clone()).await?;
+    let _systemd = AbortOnDrop(tokio::spawn(systemd::supervise(daemon.clone())));
+    let metrics: Vec<Arc<metrics::Metrics>> = hubs.iter().map(|_| Arc::default()).collect();
+    let _metrics = match config.metrics_listen {
+        Some(addr) => {
+            let hubs = states.iter().cloned().zip(metrics.iter().cloned()).collect();
+            Some(AbortOnDrop(metrics::serve(addr, hubs).await?))
+        }
+        None => None,
+    };
+
+    let mut signals = Signals {
+        sigint: signal(SignalKind::interrupt())?,
+        sigterm: signal(SignalKind::terminate())?,
+        daemon,
+    };
+    // Before registering: SIGHUP's default action would end the process
+    let sighup = signal(SignalKind::hangup())?;
+
+    sandbox::configure(config.sandbox.clone());
+    let (resolved, _) = config::resolve(&cli);
+    let (live_tx, live) = watch::channel(config);
+    let _reload = AbortOnDrop(tokio::spawn(reload::watch(
+        cli,
+        sighup,
+        reload::Live {
+            config: live_tx,
+            log_filter: resolved.log_filter,
+            logging: resolved.logging,
+        },
+    )));
+
+    // Each request to stop bumps this; every hub sees every bump
+    let (stop_tx, stop) = watch::channel(None);
+    let mut running: FuturesUnordered<_> = hubs
+        .into_iter()
+        .zip(states)
+        .zip(metrics)
+        .map(|((hub, state), metrics)| {
+            let profile = hub.profile.clone();
+            let machine_id = hub.machine_id.clone();
+            let shutdown = Shutdown { stop: stop.clone() };
+            let ended = logging::scope(
+                &machine_id,
+                run_hub(hub, state, metrics, live.clone(), shutdown),
+            );
+            async move { (profile, ended.await) }
+        })
+        .collect();
+
+    let mut result = Ok(());
+    loop {
+        tokio::select! {
+            ended = running.next() => match ended {
+                None => break,
+                Some((_, Ok(()))) => {}
+                Some((Some(profile), Err(e))) => log::error!("Profile {}: {}", profile, e),
+                Some((None, Err(e))) => {
+                    // The daemon does not outlive its main hub; the others drain first
+                    if !running.is_empty() {
+                        log::error!("Main hub failed, stopping: {}", e);
+                        stop_tx.send_replace(Some("exception"));
+                    }
+                    result = Err(e);
+                }
+            },
+            source = signals.requested() => {
+                stop_tx.send_replace(Some(source));
+            }
+        }
+    }
+    if result.is_ok() {
+        log::info!("Goodbye");
+    }
+    result
+}
+
+/// Serve one hub: register, then connect and reconnect until `shutdown`.
+/// Returns an error only if the hub cannot be served at all.
+async fn run_hub(
+    config: config::Config,
+    state: RuntimeState,
+    metrics: Arc<metrics::Metrics>,
+    live: watch::Receiver<config::Config>,
+    mut shutdown: Shutdown,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let metadata = metadata::detect(&config).await;
+    log::info!("Machine: {}", metadata::describe(&metadata));
 
     // Register once at startup
-    register::register_machine(&config, &metadata).await?;
+    let registration = tokio::select! {
+        result = register::register_machine(&config, &metadata, &metrics) => result?,
+        _ = shutdown.requested() => return Ok(()),
+    };
+    let telemetry_paths = vec![
+        std::path::PathBuf::from(&metadata.home_dir),
+        config.hapi_home.clone(),
+    ];
+    let metadata_sync = Arc::new(Mutex::new(metadata::MetadataSync::new(
+        config.clone(),
+        metadata,
+        registration.metadata_version,
+    )));
+    let runner_state = Arc::new(Mutex::new(connection::RunnerState::new(
+        &config.machine_id,
+        registration.runner_state_version,
+    )));
+    // Updates the hub missed, including any a previous run left on disk
+    let limits = socket::Limits::default();
+    let outbox = Arc::new(if config.persist_outbox {
+        socket::Outbox::persistent(&config.hapi_home.join("outbox.json"), limits)
+    } else {
+        socket::Outbox::new(limits)
+    });
+
+    let sessions =
+        sessions::Sessions::start(&config, runner_state.clone(), outbox.clone()).await?;
+    state.set_sessions(sessions.clone());
+    // A state the last run couldn't send describes a process that is gone:
+    // keep its exits and how it stopped, but don't publish it as ours
+    if let Some(saved) = outbox.take(connection::RUNNER_STATE) {
+        runner_state.lock().await.set_previous_run(&saved.data);
+        sessions.restore(&saved.data).await;
+    }
+
+    let mut rpc = rpc::RpcHandlers::new(&config.machine_id);
+    worktree::register(&mut rpc);
+    sessions.register(&mut rpc);
+    let rpc = Arc::new(rpc);
+
+    let metadata_changed = Arc::new(Notify::new());
+    let _reload = AbortOnDrop(logging::spawn(reload::follow(
+        config.profile.clone(),
+        live.clone(),
+        metadata_sync.clone(),
+        metadata_changed.clone(),
+        sessions.clone(),
+    )));
 
-    let mut sigint = signal(SignalKind::interrupt())?;
-    let mut sigterm = signal(SignalKind::terminate())?;
     let mut backoff = Duration::from_secs(1);
     const MAX_BACKOFF: Duration = Duration::from_secs(30);
+    let mut reregister = false;
+    let mut registered_at = tokio::time::Instant::now();
 
     loop {
+        if reregister {
+            reregister = false;
+            log::warn!(event = "reregister"; "Hub does not know this machine; registering again");
+            state.set_connection(ConnectionState::Registering);
+            // A hub that forgets us right after registering shouldn't get a tight loop
+            if registered_at.elapsed() < Duration::from_secs(60) {
+                tokio::select! {
+                    _ = tokio::time::sleep(connection::jitter(MAX_BACKOFF)) => {},
+                    source = shutdown.requested() => return stop_offline(&sessions, &live, &runner_state, &outbox, source).await,
+                }
+            }
+            let config = live
+                .borrow()
+                .hub(config.profile.as_deref())
+                .unwrap_or_else(|| config.clone());
+            let metadata = metadata::detect(&config).await;
+            let registration = tokio::select! {
+                result = register::register_machine(&config, &metadata, &metrics) => result?,
+                source = shutdown.requested() => return stop_offline(&sessions, &live, &runner_state, &outbox, source).await,
+            };
+            *metadata_sync.lock().await = metadata::MetadataSync::new(
+                config,
+                metadata,
+                registration.metadata_version,
+            );
+            runner_state
+                .lock()
+                .await
+                .reset_version(registration.runner_state_version);
+            registered_at = tokio::time::Instant::now();
+        }
+
         // Connect
-        let (event_tx, event_rx) = mpsc::channel(512);
-        let client = match connection::connect(&config, event_tx).await {
+        state.set_connection(ConnectionState::Connecting);
+        let (event_tx, event_rx) = mpsc::unbounded_channel();
+        let routes = tunnel::Routes::default();
+        let connected = connection::connect(
+            &config,
+            event_tx,
+            routes.clone(),
+            rpc.clone(),
+            metrics.socket.clone(),
+        )
+        .await;
+        let client = match connected {
             Ok(c) => {
                 backoff = Duration::from_secs(1); // reset on success
                 c
             }
             Err(e) => {
-                log::warn!("Connect failed: {} — retrying in {:?}", e, backoff);
+                let wait = match connection::classify_connect_error(e.as_ref()) {
+                    connection::Failure::Fatal => {
+                        let message = format!("hub refused the connection: {}", e);
+                        return Err(connection::AuthError(message).into());
+                    }
+                    connection::Failure::Transient { retry_after } => {
+                        retry_after.unwrap_or_else(|| connection::jitter(backoff))
+                    }
+                };
+                log::warn!(
+                    event = "connect_failed";
+                    "Connect failed: {} — retrying in {:?}", e, wait
+                );
+                state.set_connection(ConnectionState::Disconnected);
                 tokio::select! {
-                    _ = tokio::time::sleep(backoff) => {},
-                    _ = sigint.recv() => { log::info!("Received SIGINT"); return Ok(()); }
-                    _ = sigterm.recv() => { log::info!("Received SIGTERM"); return Ok(()); }
+                    _ = tokio::time::sleep(wait) => {},
+                    source = shutdown.requested() => return stop_offline(&sessions, &live, &runner_state, &outbox, source).await,
                 }
                 backoff = (backoff * 2).min(MAX_BACKOFF);
                 continue;
             }
         };
-        log::info!("Socket.IO connected to {}/cli", config.api_url);
+        log::info!(event = "connected"; "Socket.IO connected to {}/cli", config.api_url);
 
-        // Emit initial state
-        if let Err(e) = connection::emit_initial_state(&client, &config.machine_id).await {
-            log::warn!("Failed to emit initial state: {} — reconnecting", e);
+        // Deliver what the hub missed while we were away, then the current state
+        let announced = async {
+            rpc.announce(&client).await?;
+            flush_outbox(&outbox, &client, &runner_state, &metadata_sync).await?;
+            runner_state.lock().await.publish(&client).await
+        }
+        .await;
+        if let Err(e) = announced {
+            state.set_connection(ConnectionState::Disconnected);
             let _ = client.disconnect().await;
-            tokio::time::sleep(backoff).await;
+            if e.is::<connection::UnknownMachine>() {
+                reregister = true;
+                continue;
+            }
+            log::warn!("Failed to publish runner state: {} — reconnecting", e);
+            tokio::select! {
+                _ = tokio::time::sleep(connection::jitter(backoff)) => {},
+                source = shutdown.requested() => return stop_offline(&sessions, &live, &runner_state, &outbox, source).await,
+            }
             backoff = (backoff * 2).min(MAX_BACKOFF);
             continue;
         }
+        state.set_connection(ConnectionState::Connected);
+        state.set_last_inbound(client.last_inbound());
+        sessions.attach(client.clone());
 
         // Spawn keep-alive
         let ka_client = client.clone();
         let ka_mid = config.machine_id.clone();
-        let keepalive_handle = tokio::spawn(connection::keep_alive(ka_client, ka_mid));
+        let keepalive_handle = logging::spawn(connection::keep_alive(
+            ka_client,
+            ka_mid,
+            metrics.socket.clone(),
+        ));
+        let _metadata_task = AbortOnDrop(logging::spawn(metadata::sync(
+            client.clone(),
+            metadata_sync.clone(),
+            metadata_changed.clone(),
+            outbox.clone(),
+        )));
+        let _telemetry_task = config.telemetry_interval.map(|interval| {
+            AbortOnDrop(logging::spawn(telemetry::run(
+                client.clone(),
+                runner_state.clone(),
+                telemetry_paths.clone(),
+                interval,
+            )))
+        });
 
         // Spawn tunnel manager — returns when it receives Disconnected
         let t_client = client.clone();
-        let t_mid = config.machine_id.clone();
-        let tunnel_handle = tokio::spawn(tunnel::run(event_rx, t_client, t_mid));
+        let drain = Arc::new(Notify::new());
+        let mut tunnel_handle = logging::spawn(tunnel::run(
+            event_rx,
+            t_client,
+            routes,
+            state.clone(),
+            live.clone(),
+            metrics.tunnels.clone(),
+            drain.clone(),
+        ));
 
         // Wait for disconnect or signal
+        let wait = connection::jitter(backoff);
         tokio::select! {
-            _ = tunnel_handle => {
-                // tunnel::run exited → socket disconnected
-                log::warn!("Disconnected — reconnecting in {:?}", backoff);
+            ended = &mut tunnel_handle => {
+                // tunnel::run exited → socket disconnected, or the hub forgot us
+                state.set_connection(ConnectionState::Disconnected);
                 keepalive_handle.abort();
+                if let Ok(tunnel::Ended::UnknownMachine) = ended {
+                    let _ = client.disconnect().await;
+                    reregister = true;
+                    continue;
+                }
+                log::warn!("Disconnected — reconnecting in {:?}", wait);
             }
-            _ = sigint.recv() => {
-                log::info!("Received SIGINT");
-                keepalive_handle.abort();
-                let _ = client.disconnect().await;
-                log::info!("Goodbye");
-                return Ok(());
-            }
-            _ = sigterm.recv() => {
-                log::info!("Received SIGTERM");
+            source = shutdown.requested() => {
+                let stop_sessions = live.borrow().shutdown.stop_sessions;
+                report_shutdown(&runner_state, &client, &outbox, "shutting-down", source).await;
+                drain.notify_one();
+                let sessions_stopped = async {
+                    if stop_sessions {
+                        sessions.stop_all().await;
+                    }
+                };
+                tokio::select! {
+                    _ = async { tokio::join!(&mut tunnel_handle, sessions_stopped) } => {},
+                    _ = shutdown.requested() => log::warn!("Shutting down without waiting for the drain"),
+                }
+                report_shutdown(&runner_state, &client, &outbox, "stopped", source).await;
                 keepalive_handle.abort();
                 let _ = client.disconnect().await;
-                log::info!("Goodbye");
                 return Ok(());
             }
         }
 
         // Brief pause before reconnect
         tokio::select! {
-            _ = tokio::time::sleep(backoff) => {},
-            _ = sigint.recv() => { log::info!("Received SIGINT"); return Ok(()); }
-            _ = sigterm.recv() => { log::info!("Received SIGTERM"); return Ok(()); }
+            _ = tokio::time::sleep(wait) => {},
+            source = shutdown.requested() => return stop_offline(&sessions, &live, &runner_state, &outbox, source).await,
         }
         backoff = (backoff * 2).min(MAX_BACKOFF);
     }
 }
+
+/// SIGINT, SIGTERM and `happier stop`, for the whole daemon.
+struct Signals {
+    sigint: Signal,
+    sigterm: Signal,
+    daemon: DaemonState,
+}
+
+impl Signals {
+    /// Wait for a shutdown request; returns the `shutdownSource` to report.
+    async fn requested(&mut self) -> &'static str {
+        tokio::select! {
+            _ = self.sigint.recv() => { log::info!("Received SIGINT"); "os-signal" }
+            _ = self.sigterm.recv() => { log::info!("Received SIGTERM"); "os-signal" }
+            _ = self.daemon.shutdown_requested() => "cli",
+        }
+    }
+}
+
+/// One hub's view of the daemon's shutdown requests.
+struct Shutdown {
+    stop: watch::Receiver<Option<&'static str>>,
+}
+
+impl Shutdown {
+    /// Wait for the next request to stop; returns its `shutdownSource`.
+    async fn requested(&mut self) -> &'static str {
+        if self.stop.changed().await.is_err() {
+            return std::future::pending().await;
+        }
+        self.stop.borrow_and_update().unwrap_or("os-signal")
+    }
+}
+
+/// Publish `runnerState.status` for a shutdown in progress, or leave it in
+/// the outbox if the hub can't be told.
+async fn report_shutdown(
+    runner_state: &Mutex<connection::RunnerState>,
+    client: &socket::SocketClient,
+    outbox: &socket::Outbox,
+    status: &'static str,
+    source: &'static str,
+) {
+    let mut runner = runner_state.lock().await;
+    runner.set_shutdown(status, source);
+    if let Err(e) = runner.publish(client).await {
+        log::warn!("Failed to report runner state {}: {}", status, e);
+        runner.queue(outbox);
+    }
+}
+
+/// Shut down between connections, with no hub to tell: the final state goes
+/// to the outbox for the next run to deliver.
+async fn stop_offline(
+    sessions: &sessions::Sessions,
+    live: &watch::Receiver<config::Config>,
+    runner_state: &Mutex<connection::RunnerState>,
+    outbox: &socket::Outbox,
+    source: &'static str,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let stop_sessions = live.borrow().shutdown.stop_sessions;
+    if stop_sessions {
+        sessions.stop_all().await;
+    }
+    let mut runner = runner_state.lock().await;
+    runner.set_shutdown("stopped", source);
+    runner.queue(outbox);
+    Ok(())
+}
+
+/// Deliver what `outbox` holds, oldest first: the versioned fields at their
+/// current version, anything else as a plain emit.
+async fn flush_outbox(
+    outbox: &socket::Outbox,
+    client: &socket::SocketClient,
+    runner_state: &Mutex<connection::RunnerState>,
+    metadata_sync: &Mutex<metadata::MetadataSync>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let delivered = outbox
+        .flush(|entry| async move {
+            match entry.key.as_deref() {
+                Some(connection::RUNNER_STATE) => {
+                    runner_state.lock().await.deliver(client, &entry.data).await
+                }
+                Some(connection::METADATA) => {
+                    metadata_sync.lock().await.deliver(client, &entry.data).await
+                }
+                _ => client.emit(&entry.event, entry.data).await,
+            }
+        })
+        .await?;
+    if delivered > 0 {
+        log::info!("Delivered {} updates queued while offline", delivered);
+    }
+    Ok(())
+}
+
+struct AbortOnDrop(tokio::task::JoinHandle<()>);
+
+impl Drop for AbortOnDrop {
+    fn drop(&mut self) {
+        self.0.abort();
+    }
+}
@@ -1,7 +1,34 @@
 use crate::config::Config;
+use crate::connection::VersionedField;
+use crate::ports::{self, ListeningPort};
+use crate::socket::{Outbox, SocketClient};
 use serde::Serialize;
+use serde_json::Value;
+use std::collections::BTreeMap;
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+use std::process::Stdio;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::{Mutex, Notify};
 
-#[derive(Serialize, Clone)]
+/// Agent CLIs the hub can start sessions with.
+const AGENT_FLAVORS: [&str; 4] = ["claude", "codex", "gemini", "opencode"];
+const VERSION_TIMEOUT: Duration = Duration::from_secs(5);
+/// Re-detect this often so installs and upgrades reach the web UI.
+const REFRESH_INTERVAL: Duration = Duration::from_secs(300);
+const PORT_SCAN_INTERVAL: Duration = Duration::from_secs(5);
+const PORT_DEBOUNCE: Duration = Duration::from_secs(10);
+
+#[derive(Serialize, Clone, PartialEq, Debug)]
+pub struct AgentInfo {
+    pub flavor: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub version: Option<String>,
+    pub path: String,
+}
+
+#[derive(Serialize, Clone, PartialEq, Debug)]
 pub struct MachineMetadata {
     pub host: String,
     pub platform: String,
@@ -15,9 +42,25 @@ pub struct MachineMetadata {
     pub happy_home_dir: String,
     #[serde(rename = "happyLibDir")]
     pub happy_lib_dir: String,
+    pub arch: String,
+    /// `PRETTY_NAME` from /etc/os-release, e.g. "Ubuntu 24.04.1 LTS"
+    #[serde(rename = "osRelease", skip_serializing_if = "Option::is_none")]
+    pub os_release: Option<String>,
+    #[serde(rename = "kernelVersion", skip_serializing_if = "Option::is_none")]
+    pub kernel_version: Option<String>,
+    #[serde(rename = "cpuCount")]
+    pub cpu_count: usize,
+    /// Bytes
+    #[serde(rename = "totalMemory", skip_serializing_if = "Option::is_none")]
+    pub total_memory: Option<u64>,
+    /// Installed agent CLIs only
+    pub agents: Vec<AgentInfo>,
+    /// TCP ports reachable over loopback that the tunnel policy allows
+    #[serde(rename = "listeningPorts")]
+    pub listening_ports: Vec<ListeningPort>,
 }
 
-pub fn build(config: &Config) -> MachineMetadata {
+pub async fn detect(config: &Config) -> MachineMetadata {
     let host = std::env::var("HAPI_HOSTNAME").unwrap_or_else(|_| {
         gethostname().unwrap_or_else(|| "unknown".to_string())
     });
@@ -29,17 +72,296 @@ pub fn build(config: &Config) -> MachineMetadata {
         .and_then(|p| p.parent().map(|p| p.to_string_lossy().to_string()))
         .unwrap_or_else(|| "/usr/local/bin".to_string());
 
+    // Same values as Node's os.platform(), which the web UI already understands
+    let platform = match std::env::consts::OS {
+        "macos" => "darwin",
+        os => os,
+    };
+
+    let agents = futures_util::future::join_all(
+        AGENT_FLAVORS.iter().map(|flavor| detect_agent(flavor, &home_dir)),
+    )
+    .await
+    .into_iter()
+    .flatten()
+    .collect();
+
     MachineMetadata {
         host,
-        platform: "linux".to_string(),
+        platform: platform.to_string(),
         happy_cli_version: format!("happier/{}", env!("CARGO_PKG_VERSION")),
         display_name: config.machine_name.clone(),
-        home_dir,
         happy_home_dir: config.hapi_home.to_string_lossy().to_string(),
         happy_lib_dir,
+        arch: std::env::consts::ARCH.to_string(),
+        os_release: os_release(),
+        kernel_version: kernel_version(),
+        cpu_count: std::thread::available_parallelism().map_or(1, |n| n.get()),
+        total_memory: total_memory(),
+        agents,
+        listening_ports: resolve_ports(ports::scan(&config.tunnel)).await,
+        home_dir,
+    }
+}
+
+fn is_executable(path: &Path) -> bool {
+    std::fs::metadata(path).is_ok_and(|m| m.is_file() && m.permissions().mode() & 0o111 != 0)
+}
+
+/// Look up `name` on `$PATH`, then in `~/.local/bin`, where the native
+/// installers put agents but which service units often leave off `$PATH`.
+fn find_executable(name: &str, home_dir: &str) -> Option<PathBuf> {
+    let path = std::env::var_os("PATH").unwrap_or_default();
+    std::env::split_paths(&path)
+        .chain(std::iter::once(Path::new(home_dir).join(".local/bin")))
+        .map(|dir| dir.join(name))
+        .find(|candidate| is_executable(candidate))
+}
+
+async fn detect_agent(flavor: &str, home_dir: &str) -> Option<AgentInfo> {
+    let path = find_executable(flavor, home_dir)?;
+    let version = agent_version(&path).await;
+    Some(AgentInfo {
+        flavor: flavor.to_string(),
+        version,
+        path: path.to_string_lossy().to_string(),
+    })
+}
+
+/// First version-looking word of `<agent> --version`, e.g. "1.0.98" from
+/// "1.0.98 (Claude Code)" or "codex-cli 0.40.0".
+async fn agent_version(path: &Path) -> Option<String> {
+    let output = tokio::process::Command::new(path)
+        .arg("--version")
+        .stdin(Stdio::null())
+        .stderr(Stdio::null())
+        .kill_on_drop(true)
+        .output();
+    let output = match tokio::time::timeout(VERSION_TIMEOUT, output).await {
+        Ok(Ok(output)) if output.status.success() => output,
+        Ok(Ok(_)) | Ok(Err(_)) => return None,
+        Err(_) => {
+            log::debug!("{} --version timed out", path.display());
+            return None;
+        }
+    };
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    let line = stdout.lines().next()?.trim();
+    line.split_whitespace()
+        .map(|word| word.trim_start_matches('v'))
+        .find(|word| word.starts_with(|c: char| c.is_ascii_digit()))
+        .map(|word| word.to_string())
+}
+
+fn os_release() -> Option<String> {
+    let content = std::fs::read_to_string("/etc/os-release")
+        .or_else(|_| std::fs::read_to_string("/usr/lib/os-release"))
+        .ok()?;
+    content
+        .lines()
+        .find_map(|line| line.strip_prefix("PRETTY_NAME="))
+        .map(|value| value.trim_matches('"').to_string())
+        .filter(|value| !value.is_empty())
+}
+
+fn kernel_version() -> Option<String> {
+    let mut uts: libc::utsname = unsafe { std::mem::zeroed() };
+    if unsafe { libc::uname(&mut uts) } != 0 {
+        return None;
+    }
+    let release = unsafe { std::ffi::CStr::from_ptr(uts.release.as_ptr()) };
+    Some(release.to_string_lossy().into_owned())
+}
+
+fn total_memory() -> Option<u64> {
+    let meminfo = std::fs::read_to_string("/proc/meminfo").ok()?;
+    let kb: u64 = meminfo
+        .lines()
+        .find_map(|line| line.strip_prefix("MemTotal:"))?
+        .trim()
+        .trim_end_matches("kB")
+        .trim()
+        .parse()
+        .ok()?;
+    Some(kb * 1024)
+}
+
+/// The hub's copy of our metadata and its version, so updates go out only
+/// when something changed. Registering an existing machine keeps the stored
+/// metadata, so the first connection always pushes.
+pub struct MetadataSync {
+    config: Config,
+    current: MachineMetadata,
+    field: VersionedField,
+    pushed: bool,
+}
+
+impl MetadataSync {
+    pub fn new(config: Config, current: MachineMetadata, version: u64) -> Self {
+        MetadataSync {
+            config,
+            current,
+            field: VersionedField::metadata(version),
+            pushed: false,
+        }
+    }
+
+    /// Take a reloaded config and the metadata detected with it. The hub is
+    /// updated on the next `sync` wake-up, or on connect.
+    pub fn reconfigure(&mut self, config: Config, detected: MachineMetadata) {
+        self.config = config;
+        if detected != self.current {
+            self.current = detected;
+            self.pushed = false;
+        }
+    }
+
+    async fn push(&mut self, client: &SocketClient) -> Result<(), Box<dyn std::error::Error>> {
+        let value = serde_json::to_value(&self.current)?;
+        self.field
+            .update(client, &self.config.machine_id, &value)
+            .await?;
+        self.pushed = true;
+        Ok(())
+    }
+
+    /// Leave the current metadata for the next connect.
+    fn queue(&self, outbox: &Outbox) {
+        if let Ok(value) = serde_json::to_value(&self.current) {
+            outbox.push(self.field.entry(value));
+        }
+    }
+
+    /// Write `value` from the outbox; the hub is current if it still is.
+    pub async fn deliver(&mut self, client: &SocketClient, value: &Value) -> Result<(), Box<dyn std::error::Error>> {
+        self.field
+            .update(client, &self.config.machine_id, value)
+            .await?;
+        if serde_json::to_value(&self.current).is_ok_and(|current| current == *value) {
+            self.pushed = true;
+        }
+        Ok(())
     }
 }
 
+/// Push metadata if the hub is behind, then keep it current for the
+/// lifetime of one connection: a full re-detect every `REFRESH_INTERVAL`,
+/// and listening ports every `PORT_SCAN_INTERVAL`, pushed once the set has
+/// held still for `PORT_DEBOUNCE` so a restarting dev server costs one update.
+/// `reconfigured` asks for an immediate push after a config reload. A push
+/// that fails goes to `outbox` for the next connect.
+pub async fn sync(
+    client: SocketClient,
+    sync: Arc<Mutex<MetadataSync>>,
+    reconfigured: Arc<Notify>,
+    outbox: Arc<Outbox>,
+) {
+    let mut refresh = tokio::time::interval(REFRESH_INTERVAL);
+    let mut port_scan = tokio::time::interval(PORT_SCAN_INTERVAL);
+    port_scan.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+    let mut policy = sync.lock().await.config.tunnel.clone();
+    let mut last_scan = ports::scan(&policy);
+    // Ports may have changed while we were disconnected; resolve once after the debounce
+    let mut scan_changed_at = Some(tokio::time::Instant::now());
+    // The first tick fires immediately: push without re-detecting on every reconnect
+    let mut first = true;
+    loop {
+        tokio::select! {
+            _ = refresh.tick() => {
+                let mut sync = sync.lock().await;
+                if !first {
+                    let detected = detect(&sync.config).await;
+                    if detected != sync.current {
+                        log::info!("Machine metadata changed, updating hub");
+                        sync.current = detected;
+                        sync.pushed = false;
+                    }
+                }
+                first = false;
+            }
+            _ = reconfigured.notified() => {
+                policy = sync.lock().await.config.tunnel.clone();
+            }
+            _ = port_scan.tick() => {
+                let scan = ports::scan(&policy);
+                if scan != last_scan {
+                    last_scan = scan;
+                    scan_changed_at = Some(tokio::time::Instant::now());
+                    continue;
+                }
+                match scan_changed_at {
+                    Some(at) if at.elapsed() >= PORT_DEBOUNCE => scan_changed_at = None,
+                    _ => continue,
+                }
+                let resolved = resolve_ports(last_scan.clone()).await;
+                let mut sync = sync.lock().await;
+                if resolved != sync.current.listening_ports {
+                    log::info!(
+                        "Listening ports changed: {}",
+                        resolved.iter().map(|p| p.port.to_string()).collect::<Vec<_>>().join(", ")
+                    );
+                    sync.current.listening_ports = resolved;
+                    sync.pushed = false;
+                }
+            }
+        }
+
+        let mut sync = sync.lock().await;
+        if !sync.pushed {
+            match sync.push(&client).await {
+                Ok(()) => log::debug!("Machine metadata at version {}", sync.field.version()),
+                Err(e) => {
+                    log::warn!("Failed to update machine metadata: {}", e);
+                    sync.queue(&outbox);
+                }
+            }
+        }
+    }
+}
+
+/// The /proc/*/fd walk can be slow on busy hosts; keep it off the runtime thread.
+async fn resolve_ports(scan: BTreeMap<u16, Vec<u64>>) -> Vec<ListeningPort> {
+    tokio::task::spawn_blocking(move || ports::resolve(&scan))
+        .await
+        .unwrap_or_default()
+}
+
+/// One-line summary for the startup log.
+pub fn describe(metadata: &MachineMetadata) -> String {
+    let agents = if metadata.agents.is_empty() {
+        "none".to_string()
+    } else {
+        metadata
+            .agents
+            .iter()
+            .map(|a| match &a.version {
+                Some(v) => format!("{} {}", a.flavor, v),
+                None => a.flavor.clone(),
+            })
+            .collect::<Vec<_>>()
+            .join(", ")
+    };
+    let ports = metadata
+        .listening_ports
+        .iter()
+        .map(|p| match &p.process {
+            Some(process) => format!("{} ({})", p.port, process),
+            None => p.port.to_string(),
+        })
+        .collect::<Vec<_>>()
+        .join(", ");
+    format!(
+        "{} ({} {}, {}, {} CPUs) agents: {}; listening: {}",
+        metadata.display_name.as_deref().unwrap_or(&metadata.host),
+        metadata.platform,
+        metadata.arch,
+        metadata.os_release.as_deref().unwrap_or("unknown distro"),
+        metadata.cpu_count,
+        agents,
+        if ports.is_empty() { "none" } else { &ports }
+    )
+}
+
 fn gethostname() -> Option<String> {
     let mut buf = [0u8; 256];
     let ret = unsafe { libc_gethostname(buf.as_mut_ptr() as *mut i8, buf.len()) };
@@ -0,0 +1,322 @@
+//! Optional Prometheus endpoint: `GET /metrics` on `[metrics] listen`, in
+//! the text exposition format. The socket and tunnel counters live with
+//! `SocketClient` and `tunnel::run`; this module collects and renders them.
+//! Every series has a `hub` label: `main`, or the `[[profile]]` name.
+
+use std::fmt::Write as _;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::task::JoinHandle;
+
+use crate::socket::SocketMetrics;
+use crate::state::{ConnectionState, RuntimeState};
+use crate::tunnel::TunnelMetrics;
+
+/// Upper bounds in seconds, sized for hub round trips.
+const LATENCY_BUCKETS: [f64; 11] = [
+    0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0,
+];
+
+/// Latency histogram with fixed buckets.
+pub struct Histogram {
+    /// Per bucket, not cumulative; the last counts everything above the top bound.
+    counts: [AtomicU64; LATENCY_BUCKETS.len() + 1],
+    sum_micros: AtomicU64,
+}
+
+impl Default for Histogram {
+    fn default() -> Self {
+        Histogram {
+            counts: std::array::from_fn(|_| AtomicU64::new(0)),
+            sum_micros: AtomicU64::new(0),
+        }
+    }
+}
+
+impl Histogram {
+    pub fn observe(&self, elapsed: Duration) {
+        let secs = elapsed.as_secs_f64();
+        let bucket = LATENCY_BUCKETS
+            .iter()
+            .position(|bound| secs <= *bound)
+            .unwrap_or(LATENCY_BUCKETS.len());
+        self.counts[bucket].fetch_add(1, Ordering::Relaxed);
+        self.sum_micros
+            .fetch_add(elapsed.as_micros() as u64, Ordering::Relaxed);
+    }
+
+    /// One hub's series; the caller writes the header.
+    fn render(&self, out: &mut String, name: &str, hub: &str) {
+        let mut cumulative = 0;
+        for (i, count) in self.counts.iter().enumerate() {
+            cumulative += count.load(Ordering::Relaxed);
+            let bound = LATENCY_BUCKETS
+                .get(i)
+                .map_or("+Inf".to_string(), |b| b.to_string());
+            let _ = writeln!(
+                out,
+                "{}_bucket{{hub=\"{}\",le=\"{}\"}} {}",
+                name, hub, bound, cumulative
+            );
+        }
+        let sum = self.sum_micros.load(Ordering::Relaxed) as f64 / 1e6;
+        let _ = writeln!(out, "{}_sum{{hub=\"{}\"}} {}", name, hub, sum);
+        let _ = writeln!(out, "{}_count{{hub=\"{}\"}} {}", name, hub, cumulative);
+    }
+}
+
+/// Every counter the endpoint reports for one hub, shared across reconnects.
+#[derive(Default)]
+pub struct Metrics {
+    pub socket: Arc<SocketMetrics>,
+    pub tunnels: Arc<TunnelMetrics>,
+    pub registrations_ok: AtomicU64,
+    pub registrations_failed: AtomicU64,
+}
+
+/// One hub's state and counters.
+pub type Hub = (RuntimeState, Arc<Metrics>);
+
+/// Bind `addr` and serve until the returned task is aborted.
+pub async fn serve(
+    addr: SocketAddr,
+    hubs: Vec<Hub>,
+) -> Result<JoinHandle<()>, Box<dyn std::error::Error>> {
+    let listener = TcpListener::bind(addr)
+        .await
+        .map_err(|e| format!("metrics listener {}: {}", addr, e))?;
+    log::info!("Serving metrics on http://{}/metrics", addr);
+    let hubs = Arc::new(hubs);
+    Ok(tokio::spawn(async move {
+        loop {
+            match listener.accept().await {
+                Ok((stream, _)) => {
+                    let hubs = hubs.clone();
+                    tokio::spawn(async move {
+                        let handled =
+                            tokio::time::timeout(Duration::from_secs(10), handle(stream, &hubs));
+                        if let Ok(Err(e)) = handled.await {
+                            log::debug!("Metrics request failed: {}", e);
+                        }
+                    });
+                }
+                Err(e) => {
+                    log::warn!("Metrics accept failed: {}", e);
+                    tokio::time::sleep(Duration::from_secs(1)).await;
+                }
+            }
+        }
+    }))
+}
+
+async fn handle(stream: TcpStream, hubs: &[Hub]) -> std::io::Result<()> {
+    let mut reader = BufReader::new(stream);
+    let mut request_line = String::new();
+    reader.read_line(&mut request_line).await?;
+    loop {
+        let mut header = String::new();
+        if reader.read_line(&mut header).await? == 0 || header.trim().is_empty() {
+            break;
+        }
+    }
+
+    let mut parts = request_line.split_whitespace();
+    let (method, path) = (parts.next().unwrap_or(""), parts.next().unwrap_or(""));
+    let (status, content_type, body) = if method == "GET" && path == "/metrics" {
+        ("200 OK", "text/plain; version=0.0.4", render(hubs))
+    } else {
+        ("404 Not Found", "text/plain", "not found\n".to_string())
+    };
+    let response = format!(
+        "HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        content_type,
+        body.len(),
+        body
+    );
+    reader.get_mut().write_all(response.as_bytes()).await
+}
+
+fn header(out: &mut String, name: &str, kind: &str, help: &str) {
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} {}", name, kind);
+}
+
+fn hub_label(state: &RuntimeState) -> &str {
+    state.profile().unwrap_or("main")
+}
+
+/// A metric with one unlabeled-but-for-`hub` value per hub.
+fn per_hub(
+    out: &mut String,
+    hubs: &[Hub],
+    (name, kind, help): (&str, &str, &str),
+    value: impl Fn(&RuntimeState, &Metrics) -> u64,
+) {
+    header(out, name, kind, help);
+    for (state, metrics) in hubs {
+        let _ = writeln!(
+            out,
+            "{}{{hub=\"{}\"}} {}",
+            name,
+            hub_label(state),
+            value(state, metrics)
+        );
+    }
+}
+
+fn render(hubs: &[Hub]) -> String {
+    let load = |counter: &AtomicU64| counter.load(Ordering::Relaxed);
+    let mut out = String::new();
+
+    header(
+        &mut out,
+        "happier_connection_state",
+        "gauge",
+        "1 for each hub connection's current state.",
+    );
+    for (state, _) in hubs {
+        let connection = state.connection();
+        for s in [
+            ConnectionState::Registering,
+            ConnectionState::Connecting,
+            ConnectionState::Connected,
+            ConnectionState::Disconnected,
+        ] {
+            let _ = writeln!(
+                out,
+                "happier_connection_state{{hub=\"{}\",state=\"{}\"}} {}",
+                hub_label(state),
+                s.as_str(),
+                u8::from(s == connection)
+            );
+        }
+    }
+    per_hub(
+        &mut out,
+        hubs,
+        (
+            "happier_reconnects_total",
+            "counter",
+            "Reconnections to the hub after the first connect.",
+        ),
+        |state, _| state.snapshot().reconnects,
+    );
+
+    header(
+        &mut out,
+        "happier_registration_attempts_total",
+        "counter",
+        "POST /cli/machines attempts by result.",
+    );
+    for (state, metrics) in hubs {
+        for (result, counter) in [
+            ("success", &metrics.registrations_ok),
+            ("failure", &metrics.registrations_failed),
+        ] {
+            let _ = writeln!(
+                out,
+                "happier_registration_attempts_total{{hub=\"{}\",result=\"{}\"}} {}",
+                hub_label(state),
+                result,
+                load(counter)
+            );
+        }
+    }
+
+    per_hub(
+        &mut out,
+        hubs,
+        (
+            "happier_socket_write_queue_depth",
+            "gauge",
+            "Socket.IO frames waiting to be written.",
+        ),
+        |_, metrics| metrics.socket.queue_depth() as u64,
+    );
+    let name = "happier_socket_ack_latency_seconds";
+    header(&mut out, name, "histogram", "Time from emit_with_ack to the hub's ack.");
+    for (state, metrics) in hubs {
+        metrics.socket.ack_latency.render(&mut out, name, hub_label(state));
+    }
+    per_hub(
+        &mut out,
+        hubs,
+        (
+            "happier_socket_ack_timeouts_total",
+            "counter",
+            "emit_with_ack calls that got no ack in time.",
+        ),
+        |_, metrics| load(&metrics.socket.ack_timeouts),
+    );
+    per_hub(
+        &mut out,
+        hubs,
+        (
+            "happier_keep_alive_failures_total",
+            "counter",
+            "machine-alive emits that failed.",
+        ),
+        |_, metrics| load(&metrics.socket.keep_alive_failures),
+    );
+
+    per_hub(
+        &mut out,
+        hubs,
+        ("happier_tunnels_active", "gauge", "Open tunnels."),
+        |state, _| state.tunnel_count() as u64,
+    );
+    per_hub(
+        &mut out,
+        hubs,
+        (
+            "happier_tunnel_bytes_in_total",
+            "counter",
+            "Bytes from the hub written to tunnel targets.",
+        ),
+        |_, metrics| load(&metrics.tunnels.bytes_in),
+    );
+    per_hub(
+        &mut out,
+        hubs,
+        (
+            "happier_tunnel_bytes_out_total",
+            "counter",
+            "Bytes read from tunnel targets and sent to the hub.",
+        ),
+        |_, metrics| load(&metrics.tunnels.bytes_out),
+    );
+    per_hub(
+        &mut out,
+        hubs,
+        (
+            "happier_tunnel_overflows_total",
+            "counter",
+            "Tunnels closed with ENOBUFS because their target fell behind.",
+        ),
+        |_, metrics| load(&metrics.tunnels.overflows),
+    );
+    header(
+        &mut out,
+        "happier_tunnel_open_failures_total",
+        "counter",
+        "Refused or failed tunnel opens by tunnel:error code.",
+    );
+    for (state, metrics) in hubs {
+        for (reason, count) in metrics.tunnels.open_failures() {
+            let _ = writeln!(
+                out,
+                "happier_tunnel_open_failures_total{{hub=\"{}\",reason=\"{}\"}} {}",
+                hub_label(state),
+                reason,
+                count
+            );
+        }
+    }
+    out
+}
@@ -0,0 +1,225 @@
+//! Listening TCP ports reachable over loopback, from `/proc/net/tcp{,6}`,
+//! with the owning process where /proc lets us see it.
+
+use std::collections::{BTreeMap, HashMap};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+
+use serde::Serialize;
+
+use crate::config::TunnelPolicy;
+
+/// `st` value for TCP_LISTEN in /proc/net/tcp.
+const TCP_LISTEN: &str = "0A";
+/// How a tunnel request may name this machine's loopback.
+const LOOPBACK_HOSTS: [&str; 3] = ["localhost", "127.0.0.1", "::1"];
+
+#[derive(Serialize, Clone, PartialEq, Debug)]
+pub struct ListeningPort {
+    pub port: u16,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub process: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub pid: Option<u32>,
+}
+
+/// Words are printed in host byte order, so convert back with `to_ne_bytes`.
+fn parse_addr(hex: &str) -> Option<IpAddr> {
+    match hex.len() {
+        8 => {
+            let word = u32::from_str_radix(hex, 16).ok()?;
+            Some(IpAddr::V4(Ipv4Addr::from(word.to_ne_bytes())))
+        }
+        32 => {
+            let mut bytes = [0u8; 16];
+            for (i, chunk) in bytes.chunks_mut(4).enumerate() {
+                let word = u32::from_str_radix(&hex[i * 8..i * 8 + 8], 16).ok()?;
+                chunk.copy_from_slice(&word.to_ne_bytes());
+            }
+            Some(IpAddr::V6(Ipv6Addr::from(bytes)))
+        }
+        _ => None,
+    }
+}
+
+/// Whether a tunnel to 127.0.0.1 or ::1 reaches a socket bound to `addr`.
+fn reachable_from_loopback(addr: IpAddr) -> bool {
+    match addr {
+        IpAddr::V4(v4) => v4.is_loopback() || v4.is_unspecified(),
+        IpAddr::V6(v6) => {
+            v6.is_loopback()
+                || v6.is_unspecified()
+                || v6.to_ipv4_mapped().is_some_and(|v4| v4.is_loopback())
+        }
+    }
+}
+
+/// A listening socket's `(address, port, inode)` from one table row.
+fn parse_listening(line: &str) -> Option<(IpAddr, u16, u64)> {
+    let fields: Vec<&str> = line.split_whitespace().collect();
+    if fields.len() < 10 || fields[3] != TCP_LISTEN {
+        return None;
+    }
+    let (addr, port) = fields[1].split_once(':')?;
+    Some((
+        parse_addr(addr)?,
+        u16::from_str_radix(port, 16).ok()?,
+        fields[9].parse().ok()?,
+    ))
+}
+
+/// Listening sockets as `inode -> port`.
+fn listening_sockets() -> HashMap<u64, u16> {
+    let mut sockets = HashMap::new();
+    for table in ["/proc/net/tcp", "/proc/net/tcp6"] {
+        let Ok(content) = std::fs::read_to_string(table) else {
+            continue;
+        };
+        for (addr, port, inode) in content.lines().skip(1).filter_map(parse_listening) {
+            if reachable_from_loopback(addr) {
+                sockets.insert(inode, port);
+            }
+        }
+    }
+    sockets
+}
+
+/// Whether the tunnel policy lets the hub reach `port` on loopback under
+/// any of its names.
+fn offered(policy: &TunnelPolicy, port: u16) -> bool {
+    LOOPBACK_HOSTS.iter().any(|host| policy.check(host, port).is_ok())
+}
+
+/// Map socket inodes to `(pid, comm)` by walking `/proc/*/fd`. Processes of
+/// other users are unreadable unless we run as root, and stay unattributed.
+fn socket_owners(inodes: &HashMap<u64, u16>) -> HashMap<u64, (u32, String)> {
+    let mut owners = HashMap::new();
+    let Ok(procs) = std::fs::read_dir("/proc") else {
+        return owners;
+    };
+    for entry in procs.flatten() {
+        let Some(pid) = entry.file_name().to_str().and_then(|s| s.parse::<u32>().ok()) else {
+            continue;
+        };
+        let Ok(fds) = std::fs::read_dir(entry.path().join("fd")) else {
+            continue;
+        };
+        for fd in fds.flatten() {
+            let Ok(target) = std::fs::read_link(fd.path()) else {
+                continue;
+            };
+            let Some(inode) = target
+                .to_str()
+                .and_then(|t| t.strip_prefix("socket:["))
+                .and_then(|t| t.strip_suffix(']'))
+                .and_then(|t| t.parse::<u64>().ok())
+            else {
+                continue;
+            };
+            if inodes.contains_key(&inode) && !owners.contains_key(&inode) {
+                let comm = std::fs::read_to_string(entry.path().join("comm"))
+                    .map(|c| c.trim().to_string())
+                    .unwrap_or_default();
+                owners.insert(inode, (pid, comm));
+            }
+        }
+        if owners.len() == inodes.len() {
+            break;
+        }
+    }
+    owners
+}
+
+/// Cheap scan of the socket tables; compare results to decide whether the
+/// expensive process lookup in `resolve` is needed.
+pub fn scan(policy: &TunnelPolicy) -> BTreeMap<u16, Vec<u64>> {
+    let mut ports: BTreeMap<u16, Vec<u64>> = BTreeMap::new();
+    for (inode, port) in listening_sockets() {
+        // Don't offer ports the tunnel policy would refuse
+        if offered(policy, port) {
+            ports.entry(port).or_default().push(inode);
+        }
+    }
+    for inodes in ports.values_mut() {
+        inodes.sort_unstable();
+    }
+    ports
+}
+
+/// Attach process names to a `scan` result, one entry per port.
+pub fn resolve(scanned: &BTreeMap<u16, Vec<u64>>) -> Vec<ListeningPort> {
+    let inodes: HashMap<u64, u16> = scanned
+        .iter()
+        .flat_map(|(port, inodes)| inodes.iter().map(move |inode| (*inode, *port)))
+        .collect();
+    let owners = socket_owners(&inodes);
+    scanned
+        .iter()
+        .map(|(port, inodes)| {
+            let owner = inodes.iter().find_map(|inode| owners.get(inode));
+            ListeningPort {
+                port: *port,
+                process: owner.map(|(_, comm)| comm.clone()).filter(|c| !c.is_empty()),
+                pid: owner.map(|(pid, _)| *pid),
+            }
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Rows as a little-endian kernel prints them
+    const ROWS: [&str; 4] = [
+        "   0: 0100007F:0BB8 00000000:0000 0A 00000000:00000000 00:00000000 00000000  1000        0 4242 1 0000000000000000 100 0 0 10 0",
+        "   1: 00000000:1F90 00000000:0000 0A 00000000:00000000 00:00000000 00000000  1000        0 4243 1 0000000000000000 100 0 0 10 0",
+        "   0: 00000000000000000000000001000000:1538 00000000000000000000000000000000:0000 0A 00000000:00000000 00:00000000 00000000  1000        0 4244 1 0000000000000000 100 0 0 10 0",
+        "   1: 0000000000000000FFFF00000100007F:0050 00000000000000000000000000000000:0000 0A 00000000:00000000 00:00000000 00000000  1000        0 4245 1 0000000000000000 100 0 0 10 0",
+    ];
+
+    #[test]
+    #[cfg(target_endian = "little")]
+    fn parses_listening_rows() {
+        let parsed: Vec<(IpAddr, u16, u64)> = ROWS.iter().filter_map(|r| parse_listening(r)).collect();
+        assert_eq!(
+            parsed,
+            vec![
+                ("127.0.0.1".parse().unwrap(), 3000, 4242),
+                ("0.0.0.0".parse().unwrap(), 8080, 4243),
+                ("::1".parse().unwrap(), 5432, 4244),
+                ("::ffff:127.0.0.1".parse().unwrap(), 80, 4245),
+            ]
+        );
+        assert!(parsed.iter().all(|(addr, _, _)| reachable_from_loopback(*addr)));
+    }
+
+    #[test]
+    #[cfg(target_endian = "little")]
+    fn skips_other_rows() {
+        // Established, and bound to a LAN address
+        let established = ROWS[0].replace(" 0A ", " 01 ");
+        assert_eq!(parse_listening(&established), None);
+        assert_eq!(parse_listening("  sl  local_address rem_address   st"), None);
+        let (addr, _, _) = parse_listening(&ROWS[0].replace("0100007F", "0101A8C0")).unwrap();
+        assert_eq!(addr, "192.168.1.1".parse::<IpAddr>().unwrap());
+        assert!(!reachable_from_loopback(addr));
+        assert_eq!(parse_addr("0100007"), None);
+    }
+
+    #[test]
+    fn ports_are_offered_under_any_loopback_name() {
+        let policy = TunnelPolicy {
+            allowed_hosts: vec!["localhost".to_string()],
+            allowed_ports: vec![3000..=3999],
+            ..TunnelPolicy::default()
+        };
+        assert!(offered(&policy, 3000));
+        assert!(!offered(&policy, 8080));
+        let policy = TunnelPolicy {
+            allowed_hosts: vec!["example.com".to_string()],
+            ..TunnelPolicy::default()
+        };
+        assert!(!offered(&policy, 3000));
+        assert!(offered(&TunnelPolicy::default(), 3000));
+    }
+}
@@ -1,45 +1,68 @@
 use crate::config::Config;
+use crate::connection::{self, AuthError, Failure};
 use crate::metadata::MachineMetadata;
+use crate::metrics::Metrics;
+use std::sync::atomic::Ordering;
 use std::time::Duration;
 
+/// Versions of the hub's copies of our machine fields, for later
+/// compare-and-swap updates.
+pub struct Registration {
+    pub metadata_version: u64,
+    pub runner_state_version: u64,
+}
+
+/// Register (or look up) the machine.
 pub async fn register_machine(
     config: &Config,
     metadata: &MachineMetadata,
-) -> Result<(), Box<dyn std::error::Error>> {
+    metrics: &Metrics,
+) -> Result<Registration, Box<dyn std::error::Error>> {
     let client = reqwest::Client::builder()
         .timeout(Duration::from_secs(60))
         .build()?;
 
-    let url = format!("{}/cli/machines", config.api_url);
-    let body = serde_json::json!({
-        "id": config.machine_id,
-        "metadata": metadata,
-        "runnerState": null,
-    });
-
     let mut delay = Duration::from_secs(1);
     let max_delay = Duration::from_secs(30);
     let max_attempts = 60;
+    // Set by a 429's Retry-After
+    let mut wait = None;
 
     for attempt in 1..=max_attempts {
-        match client
-            .post(&url)
-            .header("Authorization", format!("Bearer {}", config.token))
-            .json(&body)
-            .send()
-            .await
-        {
+        match post_machine(&client, config, metadata).await {
             Ok(resp) if resp.status().is_success() => {
-                log::info!("Machine registered successfully");
-                return Ok(());
+                metrics.registrations_ok.fetch_add(1, Ordering::Relaxed);
+                log::info!("Machine {} registered with {}", config.machine_id, config.api_url);
+                let body: serde_json::Value = resp.json().await.unwrap_or_default();
+                let machine = &body["machine"];
+                return Ok(Registration {
+                    metadata_version: machine["metadataVersion"].as_u64().unwrap_or(0),
+                    runner_state_version: machine["runnerStateVersion"].as_u64().unwrap_or(0),
+                });
             }
             Ok(resp) => {
-                log::warn!(
-                    "Machine registration failed (attempt {}/{}): HTTP {}",
-                    attempt, max_attempts, resp.status()
-                );
+                metrics.registrations_failed.fetch_add(1, Ordering::Relaxed);
+                let status = resp.status();
+                let retry_after = resp
+                    .headers()
+                    .get(reqwest::header::RETRY_AFTER)
+                    .and_then(|v| v.to_str().ok());
+                match connection::classify_status(status.as_u16(), retry_after) {
+                    Failure::Fatal => {
+                        let message = format!("hub rejected the CLI token (HTTP {})", status);
+                        return Err(AuthError(message).into());
+                    }
+                    Failure::Transient { retry_after } => {
+                        log::warn!(
+                            "Machine registration failed (attempt {}/{}): HTTP {}",
+                            attempt, max_attempts, status
+                        );
+                        wait = retry_after;
+                    }
+                }
             }
             Err(e) => {
+                metrics.registrations_failed.fetch_add(1, Ordering::Relaxed);
                 log::warn!(
                     "Machine registration failed (attempt {}/{}): {}",
                     attempt, max_attempts, e
@@ -48,10 +71,30 @@ pub async fn register_machine(
         }
 
         if attempt < max_attempts {
-            tokio::time::sleep(delay).await;
+            tokio::time::sleep(wait.take().unwrap_or_else(|| connection::jitter(delay))).await;
             delay = (delay * 2).min(max_delay);
         }
     }
 
     Err("Machine registration failed after max attempts".into())
 }
+
+/// A single `POST /cli/machines` attempt.
+pub async fn post_machine(
+    client: &reqwest::Client,
+    config: &Config,
+    metadata: &MachineMetadata,
+) -> Result<reqwest::Response, reqwest::Error> {
+    let url = format!("{}/cli/machines", config.api_url);
+    let body = serde_json::json!({
+        "id": config.machine_id,
+        "metadata": metadata,
+        "runnerState": null,
+    });
+    client
+        .post(&url)
+        .header("Authorization", format!("Bearer {}", config.token))
+        .json(&body)
+        .send()
+        .await
+}
@@ -0,0 +1,157 @@
+//! Config reload on SIGHUP. happier.toml, settings.json and the token file
+//! are read again; the log level, the tunnel, session, sandbox and shutdown
+//! policies and the machine name apply live, and fresh metadata goes to the
+//! hub. Other changes are reported as needing a restart. Flags and
+//! environment variables stay as the process started with them.
+//!
+//! `watch` swaps the daemon's config; each hub then `follow`s it.
+
+use std::sync::Arc;
+
+use tokio::signal::unix::Signal;
+use tokio::sync::{watch, Mutex, Notify};
+
+use crate::config::{self, Cli, Config, ConfigError, LogPolicy};
+use crate::logging;
+use crate::metadata::{self, MetadataSync};
+use crate::sandbox;
+use crate::sessions::Sessions;
+
+/// What a reload changes, and what it compares against.
+pub struct Live {
+    /// The running config; only the live settings ever change.
+    pub config: watch::Sender<Config>,
+    pub log_filter: String,
+    pub logging: LogPolicy,
+}
+
+/// Reload on every signal `hangup` delivers.
+pub async fn watch(cli: Cli, mut hangup: Signal, mut live: Live) {
+    while hangup.recv().await.is_some() {
+        log::info!(event = "reload"; "Received SIGHUP, reloading config");
+        reload(&cli, &mut live).await;
+    }
+}
+
+async fn reload(cli: &Cli, live: &mut Live) {
+    let (resolved, errors) = config::resolve(cli);
+    if !errors.is_empty() {
+        log::error!("Keeping the current config: {}", ConfigError(errors));
+        return;
+    }
+    let old = live.config.borrow().clone();
+
+    let mut restart = Vec::new();
+    if resolved.api_url != old.api_url {
+        restart.push("api_url");
+    }
+    if resolved.token.as_ref().map(|(token, _)| token) != Some(&old.token) {
+        restart.push("token");
+    }
+    if resolved.machine_id.as_ref().is_some_and(|id| *id != old.machine_id) {
+        restart.push("machine id");
+    }
+    if resolved.hapi_home != old.hapi_home {
+        restart.push("hapi_home");
+    }
+    if resolved.logging != live.logging {
+        restart.push("log format, file and rotation");
+    }
+    if resolved.telemetry_interval != old.telemetry_interval {
+        restart.push("telemetry.interval_secs");
+    }
+    if resolved.metrics_listen != old.metrics_listen {
+        restart.push("metrics.listen");
+    }
+    if resolved.persist_outbox != old.persist_outbox {
+        restart.push("outbox.persist");
+    }
+    // Machine names apply live; anything else about profiles needs a restart
+    let profiles_kept = resolved.profiles.len() == old.profiles.len()
+        && resolved.profiles.iter().zip(&old.profiles).all(|(new, old)| {
+            new.name == old.name
+                && new.api_url == old.api_url
+                && new.token.as_ref().map(|(token, _)| token) == Some(&old.token)
+                && new.machine_id.as_ref().is_none_or(|id| *id == old.machine_id)
+        });
+    if !profiles_kept {
+        restart.push("profiles");
+    }
+
+    let mut applied = Vec::new();
+    if resolved.log_filter != live.log_filter {
+        logging::set_filter(&resolved.log_filter);
+        live.log_filter = resolved.log_filter;
+        applied.push("log level");
+    }
+    if resolved.machine_name != old.machine_name {
+        applied.push("machine name");
+    }
+    if resolved.tunnel != old.tunnel {
+        applied.push("tunnel policy");
+    }
+    if resolved.sessions != old.sessions {
+        applied.push("session policy");
+    }
+    if resolved.sandbox != old.sandbox {
+        sandbox::configure(resolved.sandbox.clone());
+        applied.push("sandbox policies");
+    }
+    if resolved.shutdown != old.shutdown {
+        applied.push("shutdown policy");
+    }
+    let mut profiles = old.profiles.clone();
+    if profiles_kept {
+        for (profile, new) in profiles.iter_mut().zip(resolved.profiles) {
+            if profile.machine_name != new.machine_name {
+                profile.machine_name = new.machine_name;
+                applied.push("profile machine name");
+            }
+        }
+        applied.dedup();
+    }
+
+    let config = Config {
+        machine_name: resolved.machine_name,
+        tunnel: resolved.tunnel,
+        sessions: resolved.sessions,
+        sandbox: resolved.sandbox,
+        shutdown: resolved.shutdown,
+        profiles,
+        ..old
+    };
+    // Every hub's `follow` wakes up, even if nothing it uses changed
+    live.config.send_replace(config);
+
+    if applied.is_empty() {
+        log::info!("Config reloaded; no live settings changed");
+    } else {
+        log::info!("Config reloaded; applied {}", applied.join(", "));
+    }
+    if !restart.is_empty() {
+        log::warn!(
+            "Changes to {} take effect only after a restart",
+            restart.join(", ")
+        );
+    }
+}
+
+/// Apply each reloaded config to one hub's sessions and metadata.
+pub async fn follow(
+    profile: Option<String>,
+    mut live: watch::Receiver<Config>,
+    metadata: Arc<Mutex<MetadataSync>>,
+    metadata_changed: Arc<Notify>,
+    sessions: Arc<Sessions>,
+) {
+    while live.changed().await.is_ok() {
+        let Some(config) = live.borrow_and_update().hub(profile.as_deref()) else {
+            return;
+        };
+        sessions.reconfigure(config.sessions.clone()).await;
+        // Agents may have changed too; pushed only if anything differs
+        let detected = metadata::detect(&config).await;
+        metadata.lock().await.reconfigure(config, detected);
+        metadata_changed.notify_one();
+    }
+}
@@ -0,0 +1,60 @@
+//! Machine-scoped RPC, as in the Node runner's `RpcHandlerManager`: methods
+//! are registered with the hub as `<machineId>:<method>` and called through
+//! `rpc-request`, with JSON-encoded params and response strings.
+
+use std::collections::HashMap;
+use std::future::Future;
+use std::pin::Pin;
+
+use serde_json::{json, Value};
+
+use crate::events::RpcRegister;
+use crate::socket::SocketClient;
+
+type HandlerFuture = Pin<Box<dyn Future<Output = Value> + Send>>;
+type Handler = Box<dyn Fn(Value) -> HandlerFuture + Send + Sync>;
+
+pub struct RpcHandlers {
+    machine_id: String,
+    handlers: HashMap<String, Handler>,
+}
+
+impl RpcHandlers {
+    pub fn new(machine_id: &str) -> Self {
+        RpcHandlers {
+            machine_id: machine_id.to_string(),
+            handlers: HashMap::new(),
+        }
+    }
+
+    pub fn register<F, Fut>(&mut self, method: &str, handler: F)
+    where
+        F: Fn(Value) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = Value> + Send + 'static,
+    {
+        self.handlers.insert(
+            format!("{}:{}", self.machine_id, method),
+            Box::new(move |params| Box::pin(handler(params))),
+        );
+    }
+
+    /// Tell the hub which methods we serve. Call after every connect.
+    pub async fn announce(&self, client: &SocketClient) -> Result<(), Box<dyn std::error::Error>> {
+        for method in self.handlers.keys() {
+            client.emit_event(&RpcRegister { method }).await?;
+        }
+        log::debug!("Registered {} RPC methods", self.handlers.len());
+        Ok(())
+    }
+
+    /// Run one `rpc-request` and return the response string for the ack.
+    pub async fn handle(&self, method: &str, params: &str) -> String {
+        let Some(handler) = self.handlers.get(method) else {
+            log::warn!("RPC method not found: {}", method);
+            return json!({ "error": "Method not found" }).to_string();
+        };
+        let params = serde_json::from_str(params).unwrap_or(Value::Null);
+        log::debug!("RPC {}", method);
+        handler(params).await.to_string()
+    }
+}
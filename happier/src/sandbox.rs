@@ -0,0 +1,316 @@
+//! Optional sandbox for the commands happier runs on the hub's behalf:
+//! agent sessions and worktree git commands. Policies from `[[sandbox]]` in
+//! happier.toml apply to a directory and everything below it; the most
+//! specific one wins.
+//!
+//! A sandboxed command runs with `no_new_privs`, may write only beneath its
+//! working directory and the policy's `writable` paths, and read and execute
+//! only the system paths and the policy's `read_only` paths (Landlock).
+//! Without `network`, it runs in a fresh network namespace with no
+//! interfaces up.
+
+use std::ffi::CString;
+use std::fs::OpenOptions;
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd};
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::{Path, PathBuf};
+use std::sync::{LazyLock, RwLock};
+
+use crate::config::SandboxPolicy;
+
+/// Readable and executable in every sandbox.
+const SYSTEM_PATHS: [&str; 12] = [
+    "/usr", "/bin", "/sbin", "/lib", "/lib32", "/lib64", "/etc", "/opt", "/proc", "/sys", "/dev",
+    "/run",
+];
+/// Writable in every sandbox.
+const SCRATCH_PATHS: [&str; 2] = ["/tmp", "/dev/null"];
+
+static POLICIES: LazyLock<RwLock<Vec<SandboxPolicy>>> = LazyLock::new(Default::default);
+
+/// Held by tests that configure policies, which are process-wide.
+#[cfg(test)]
+pub(crate) static TEST_POLICIES: std::sync::Mutex<()> = std::sync::Mutex::new(());
+
+/// Replace the configured policies. Their directories are resolved here,
+/// once, so symlinked ones match and rank by where they really are.
+pub fn configure(mut policies: Vec<SandboxPolicy>) {
+    for policy in &mut policies {
+        if let Ok(directory) = std::fs::canonicalize(&policy.directory) {
+            policy.directory = directory;
+        }
+    }
+    *POLICIES.write().unwrap() = policies;
+}
+
+/// The policy for commands run in `dir`, if any.
+pub fn policy_for(dir: &Path) -> Option<SandboxPolicy> {
+    let dir = std::fs::canonicalize(dir).unwrap_or_else(|_| dir.to_path_buf());
+    let policies = POLICIES.read().unwrap();
+    policies
+        .iter()
+        .filter(|p| dir.starts_with(&p.directory))
+        .max_by_key(|p| p.directory.components().count())
+        .cloned()
+}
+
+// Landlock ABI, from linux/landlock.h
+const CREATE_RULESET_VERSION: u32 = 1 << 0;
+const RULE_PATH_BENEATH: libc::c_int = 1;
+const ACCESS_EXECUTE: u64 = 1 << 0;
+const ACCESS_WRITE_FILE: u64 = 1 << 1;
+const ACCESS_READ_FILE: u64 = 1 << 2;
+const ACCESS_READ_DIR: u64 = 1 << 3;
+const ACCESS_REFER: u64 = 1 << 13;
+const ACCESS_TRUNCATE: u64 = 1 << 14;
+const ACCESS_IOCTL_DEV: u64 = 1 << 15;
+/// Rights that make sense on a file rather than a directory
+const ACCESS_FILE: u64 =
+    ACCESS_EXECUTE | ACCESS_WRITE_FILE | ACCESS_READ_FILE | ACCESS_TRUNCATE | ACCESS_IOCTL_DEV;
+
+#[repr(C)]
+struct RulesetAttr {
+    handled_access_fs: u64,
+}
+
+#[repr(C, packed)]
+struct PathBeneathAttr {
+    allowed_access: u64,
+    parent_fd: i32,
+}
+
+/// Filesystem rights this kernel's Landlock can restrict, or `None` without Landlock.
+fn handled_access() -> Option<u64> {
+    let abi = unsafe {
+        libc::syscall(
+            libc::SYS_landlock_create_ruleset,
+            std::ptr::null::<RulesetAttr>(),
+            0usize,
+            CREATE_RULESET_VERSION,
+        )
+    };
+    if abi < 1 {
+        return None;
+    }
+    // ABI 1 covers EXECUTE through MAKE_SYM
+    let mut access = (1 << 13) - 1;
+    if abi >= 2 {
+        access |= ACCESS_REFER;
+    }
+    if abi >= 3 {
+        access |= ACCESS_TRUNCATE;
+    }
+    if abi >= 5 {
+        access |= ACCESS_IOCTL_DEV;
+    }
+    Some(access)
+}
+
+pub fn landlock_supported() -> bool {
+    handled_access().is_some()
+}
+
+/// A Landlock ruleset built in the parent, so the child only has to enforce it.
+struct Ruleset {
+    fd: OwnedFd,
+    handled: u64,
+}
+
+impl Ruleset {
+    fn new() -> std::io::Result<Self> {
+        let handled = handled_access().ok_or_else(|| {
+            std::io::Error::other("Landlock is not supported or not enabled in this kernel")
+        })?;
+        let attr = RulesetAttr {
+            handled_access_fs: handled,
+        };
+        let fd = unsafe {
+            libc::syscall(
+                libc::SYS_landlock_create_ruleset,
+                &attr as *const RulesetAttr,
+                std::mem::size_of::<RulesetAttr>(),
+                0u32,
+            )
+        };
+        if fd < 0 {
+            return Err(std::io::Error::last_os_error());
+        }
+        Ok(Ruleset {
+            fd: unsafe { OwnedFd::from_raw_fd(fd as i32) },
+            handled,
+        })
+    }
+
+    /// Allow `access` beneath `path`. Missing paths are skipped.
+    fn allow(&self, path: &Path, access: u64) -> std::io::Result<()> {
+        let file = match OpenOptions::new()
+            .read(true)
+            .custom_flags(libc::O_PATH | libc::O_CLOEXEC)
+            .open(path)
+        {
+            Ok(file) => file,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(()),
+            Err(e) => return Err(std::io::Error::new(e.kind(), format!("{}: {}", path.display(), e))),
+        };
+        let mut access = access & self.handled;
+        if !file.metadata()?.is_dir() {
+            access &= ACCESS_FILE;
+        }
+        let attr = PathBeneathAttr {
+            allowed_access: access,
+            parent_fd: file.as_raw_fd(),
+        };
+        let result = unsafe {
+            libc::syscall(
+                libc::SYS_landlock_add_rule,
+                self.fd.as_raw_fd(),
+                RULE_PATH_BENEATH,
+                &attr as *const PathBeneathAttr,
+                0u32,
+            )
+        };
+        if result != 0 {
+            let e = std::io::Error::last_os_error();
+            return Err(std::io::Error::new(e.kind(), format!("{}: {}", path.display(), e)));
+        }
+        Ok(())
+    }
+}
+
+/// Sandbox prepared for one command.
+pub struct Sandbox {
+    ruleset: Option<Ruleset>,
+    unshare_network: bool,
+}
+
+impl Sandbox {
+    /// Build the sandbox `policy` describes for a command that may write
+    /// beneath `writable` (its working directory and whatever else it needs).
+    pub fn new(policy: &SandboxPolicy, writable: &[PathBuf]) -> Result<Self, String> {
+        let ruleset = if policy.filesystem {
+            let ruleset = Ruleset::new().map_err(|e| format!("sandbox: {}", e))?;
+            let read = ACCESS_EXECUTE | ACCESS_READ_FILE | ACCESS_READ_DIR | ACCESS_IOCTL_DEV;
+            let system = SYSTEM_PATHS.iter().map(PathBuf::from);
+            for path in system.chain(policy.read_only.iter().cloned()) {
+                ruleset.allow(&path, read).map_err(|e| format!("sandbox: {}", e))?;
+            }
+            let scratch = SCRATCH_PATHS.iter().map(PathBuf::from);
+            for path in scratch.chain(writable.iter().cloned()).chain(policy.writable.iter().cloned()) {
+                ruleset.allow(&path, u64::MAX).map_err(|e| format!("sandbox: {}", e))?;
+            }
+            Some(ruleset)
+        } else {
+            None
+        };
+        Ok(Sandbox {
+            ruleset,
+            unshare_network: !policy.network,
+        })
+    }
+
+    /// Enter the sandbox in the child before it execs. Register after any
+    /// cgroup move: that must happen before we leave the user namespace.
+    pub fn apply(&self, command: &mut tokio::process::Command) {
+        let ruleset = self.ruleset.as_ref().map(|r| r.fd.as_raw_fd());
+        let unshare = self.unshare_network.then(NetworkUnshare::new);
+        // Only async-signal-safe calls from here on: this runs after fork
+        unsafe {
+            command.pre_exec(move || {
+                if let Some(unshare) = &unshare {
+                    unshare.enter()?;
+                }
+                if libc::prctl(libc::PR_SET_NO_NEW_PRIVS, 1, 0, 0, 0) != 0 {
+                    return Err(std::io::Error::last_os_error());
+                }
+                if let Some(fd) = ruleset {
+                    if libc::syscall(libc::SYS_landlock_restrict_self, fd, 0u32) != 0 {
+                        return Err(std::io::Error::last_os_error());
+                    }
+                }
+                Ok(())
+            });
+        }
+    }
+}
+
+/// What the child needs to enter an empty network namespace. Without
+/// CAP_SYS_ADMIN that takes a user namespace too, mapping our own ids so
+/// file ownership looks unchanged.
+struct NetworkUnshare {
+    /// `(path, content)` for setgroups, uid_map and gid_map
+    id_maps: Option<[(CString, CString); 3]>,
+}
+
+impl NetworkUnshare {
+    fn new() -> Self {
+        let (uid, gid) = unsafe { (libc::geteuid(), libc::getegid()) };
+        let id_maps = (uid != 0).then(|| {
+            let c = |s: String| CString::new(s).unwrap();
+            [
+                (c("/proc/self/setgroups".into()), c("deny".into())),
+                (c("/proc/self/uid_map".into()), c(format!("{} {} 1", uid, uid))),
+                (c("/proc/self/gid_map".into()), c(format!("{} {} 1", gid, gid))),
+            ]
+        });
+        NetworkUnshare { id_maps }
+    }
+
+    /// Runs in the child.
+    fn enter(&self) -> std::io::Result<()> {
+        let flags = match self.id_maps {
+            Some(_) => libc::CLONE_NEWUSER | libc::CLONE_NEWNET,
+            None => libc::CLONE_NEWNET,
+        };
+        if unsafe { libc::unshare(flags) } != 0 {
+            return Err(std::io::Error::last_os_error());
+        }
+        for (path, content) in self.id_maps.iter().flatten() {
+            let bytes = content.as_bytes();
+            unsafe {
+                let fd = libc::open(path.as_ptr(), libc::O_WRONLY | libc::O_CLOEXEC);
+                if fd < 0 {
+                    return Err(std::io::Error::last_os_error());
+                }
+                let written = libc::write(fd, bytes.as_ptr().cast(), bytes.len());
+                let error = (written < 0).then(std::io::Error::last_os_error);
+                libc::close(fd);
+                if let Some(e) = error {
+                    return Err(e);
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn the_most_specific_resolved_policy_wins() {
+        let _policies = TEST_POLICIES.lock().unwrap();
+        let base = std::env::temp_dir().join(format!("happier-sandbox-{}", std::process::id()));
+        let real = base.join("work").join("repo");
+        std::fs::create_dir_all(real.join("src")).unwrap();
+        // A short symlinked path to the deeper directory
+        std::os::unix::fs::symlink(&real, base.join("r")).unwrap();
+        let policy = |directory: PathBuf, network: bool| SandboxPolicy {
+            directory,
+            filesystem: true,
+            network,
+            read_only: Vec::new(),
+            writable: Vec::new(),
+        };
+        configure(vec![policy(base.join("r"), false), policy(base.join("work"), true)]);
+
+        let found = policy_for(&real.join("src")).unwrap();
+        assert_eq!(found.directory, std::fs::canonicalize(&real).unwrap());
+        assert!(!found.network);
+        assert!(policy_for(&base.join("work")).unwrap().network);
+        assert!(policy_for(&std::env::temp_dir()).is_none());
+
+        configure(Vec::new());
+        std::fs::remove_dir_all(&base).unwrap();
+    }
+}
@@ -0,0 +1,781 @@
+//! Agent sessions the hub asks for through `spawn-happy-session`, started as
+//! the hapi CLI with the Node runner's arguments and under the configured
+//! resource limits.
+//!
+//! Sessions in a directory with a `[[sandbox]]` policy run sandboxed, with
+//! write access to the session directory, its repository and their own
+//! directory under `hapi_home/sessions`, but not to happier's config,
+//! settings or control socket.
+//!
+//! A session reports its hub session id with `POST $HAPI_RUNNER_URL/session-started`.
+//! We serve that on loopback, with a fresh secret path per spawn so the
+//! report is matched to its spawn even when the CLI runs behind a wrapper
+//! process.
+
+use std::collections::{HashMap, VecDeque};
+use std::os::unix::fs::{DirBuilderExt, OpenOptionsExt};
+use std::os::unix::process::ExitStatusExt;
+use std::path::{Path, PathBuf};
+use std::process::{ExitStatus, Stdio};
+use std::sync::Arc;
+use std::time::Duration;
+
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::process::{Child, ChildStderr};
+use tokio::sync::{oneshot, Mutex};
+
+use crate::config::{Config, SessionPolicy};
+use crate::connection::RunnerState;
+use crate::limits::{Limiter, Prepared};
+use crate::logging;
+use crate::rpc::RpcHandlers;
+use crate::sandbox::{self, Sandbox};
+use crate::socket::{Outbox, SocketClient};
+use crate::state::SessionSnapshot;
+use crate::worktree::{self, SessionWorktree};
+
+/// Same as the Node runner: sessions have been seen to take over 10s to report.
+const WEBHOOK_TIMEOUT: Duration = Duration::from_secs(15);
+const WEBHOOK_MAX_BODY: usize = 1 << 20;
+/// Between SIGTERM and SIGKILL on `stop-session`
+const STOP_GRACE: Duration = Duration::from_secs(10);
+/// Exits kept in `runnerState.sessions.recentExits`
+const RECENT_EXITS: usize = 20;
+const STDERR_TAIL: usize = 4000;
+
+const AGENTS: [&str; 4] = ["claude", "codex", "gemini", "opencode"];
+
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum ExitReason {
+    Exited,
+    Signaled,
+    /// Ended by `stop-session`
+    Stopped,
+    /// Killed for exceeding a resource limit
+    Limit,
+}
+
+/// An entry of `runnerState.sessions.recentExits`.
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct SessionExit {
+    pub pid: u32,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub session_id: Option<String>,
+    pub directory: PathBuf,
+    pub exited_at: u64,
+    pub reason: ExitReason,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub code: Option<i32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub signal: Option<i32>,
+    /// The limit behind `reason: "limit"`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub limit: Option<String>,
+    /// Limits the session ran into, whether or not they ended it
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub limits_hit: Vec<String>,
+}
+
+impl SessionExit {
+    pub fn describe(&self) -> String {
+        match (self.reason, self.limit.as_deref(), self.code, self.signal) {
+            (ExitReason::Limit, Some(limit), _, _) => format!("killed by its {} limit", limit),
+            (ExitReason::Stopped, ..) => "stopped".to_string(),
+            (_, _, _, Some(signal)) => format!("killed by signal {}", signal),
+            (_, _, Some(code), _) => format!("exited with code {}", code),
+            _ => "exited".to_string(),
+        }
+    }
+}
+
+struct Tracked {
+    pid: u32,
+    directory: PathBuf,
+    started_at: u64,
+    session_id: Option<String>,
+    /// Resolved by the session's webhook; dropped if it exits first.
+    started: Option<oneshot::Sender<String>>,
+    stopping: bool,
+}
+
+#[derive(Default)]
+struct Inner {
+    /// Keyed by the spawn's secret webhook path
+    sessions: HashMap<String, Tracked>,
+    exits: VecDeque<SessionExit>,
+}
+
+pub struct Sessions {
+    /// Replaced on config reload; running sessions keep what they started with.
+    policy: std::sync::Mutex<SessionPolicy>,
+    /// The hub sessions report to, as `HAPI_HOME`, `HAPI_API_URL` and
+    /// `CLI_API_TOKEN`: a profile's sessions must not use the main hub's.
+    hapi_home: PathBuf,
+    api_url: String,
+    token: String,
+    limiter: std::sync::Mutex<Limiter>,
+    webhook_port: u16,
+    runner: Arc<Mutex<RunnerState>>,
+    /// The live connection, for publishing exits as they happen.
+    client: std::sync::Mutex<Option<SocketClient>>,
+    /// Where a state that failed to publish waits for the next connect.
+    outbox: Arc<Outbox>,
+    inner: std::sync::Mutex<Inner>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct SpawnParams {
+    directory: PathBuf,
+    agent: Option<String>,
+    model: Option<String>,
+    yolo: Option<bool>,
+    /// Agent credential: a Claude OAuth token, or Codex's auth.json
+    token: Option<String>,
+    resume_session_id: Option<String>,
+    approved_new_directory_creation: Option<bool>,
+    session_type: Option<String>,
+    worktree_name: Option<String>,
+    fork_source_session_id: Option<String>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct StopParams {
+    session_id: String,
+}
+
+impl Sessions {
+    /// Apply the limits policy and start the webhook listener.
+    pub async fn start(
+        config: &Config,
+        runner: Arc<Mutex<RunnerState>>,
+        outbox: Arc<Outbox>,
+    ) -> Result<Arc<Self>, Box<dyn std::error::Error>> {
+        let policy = config.sessions.clone();
+        let limiter = Limiter::new(&policy.limits);
+        runner.lock().await.set_limits(limiter.report());
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let sessions = Arc::new(Sessions {
+            policy: std::sync::Mutex::new(policy),
+            hapi_home: config.hapi_home.clone(),
+            api_url: config.api_url.clone(),
+            token: config.token.clone(),
+            limiter: std::sync::Mutex::new(limiter),
+            webhook_port: listener.local_addr()?.port(),
+            runner,
+            client: std::sync::Mutex::new(None),
+            outbox,
+            inner: std::sync::Mutex::new(Inner::default()),
+        });
+        logging::spawn(sessions.clone().serve_webhook(listener));
+        Ok(sessions)
+    }
+
+    pub fn register(self: &Arc<Self>, rpc: &mut RpcHandlers) {
+        let sessions = self.clone();
+        rpc.register("spawn-happy-session", move |params| {
+            let sessions = sessions.clone();
+            async move {
+                match sessions.spawn(params).await {
+                    Ok(value) => value,
+                    Err(e) => {
+                        log::warn!("Session spawn failed: {}", e);
+                        json!({ "error": e })
+                    }
+                }
+            }
+        });
+        let sessions = self.clone();
+        rpc.register("stop-session", move |params| {
+            let sessions = sessions.clone();
+            async move {
+                match serde_json::from_value::<StopParams>(params) {
+                    Ok(params) if sessions.stop(&params.session_id) => {
+                        json!({ "message": "Session stopped" })
+                    }
+                    Ok(_) => json!({ "error": "Session not found or failed to stop" }),
+                    Err(_) => json!({ "error": "Session ID is required" }),
+                }
+            }
+        });
+    }
+
+    /// Apply a reloaded policy to sessions spawned from now on.
+    pub async fn reconfigure(&self, policy: SessionPolicy) {
+        let limits_changed = self.policy.lock().unwrap().limits != policy.limits;
+        *self.policy.lock().unwrap() = policy.clone();
+        if limits_changed {
+            let limiter = Limiter::new(&policy.limits);
+            self.runner.lock().await.set_limits(limiter.report());
+            *self.limiter.lock().unwrap() = limiter;
+            self.publish().await;
+        }
+    }
+
+    /// Use `client` to publish session changes until the next connect.
+    pub fn attach(&self, client: SocketClient) {
+        *self.client.lock().unwrap() = Some(client);
+    }
+
+    async fn spawn(self: &Arc<Self>, params: Value) -> Result<Value, String> {
+        let params: SpawnParams =
+            serde_json::from_value(params).map_err(|e| format!("invalid params: {}", e))?;
+        if params.fork_source_session_id.is_some() {
+            return Err("forking sessions is not supported by happier".to_string());
+        }
+        let agent = params.agent.as_deref().unwrap_or("claude");
+        if !AGENTS.contains(&agent) {
+            return Err(format!("unknown agent {}", agent));
+        }
+
+        if sandbox::policy_for(&params.directory).is_some_and(|p| !p.network) {
+            return Err(format!(
+                "the sandbox policy for {} disables network access, which sessions need to reach the hub",
+                params.directory.display()
+            ));
+        }
+
+        let worktree = match params.session_type.as_deref() {
+            None | Some("simple") => {
+                if !params.directory.exists() {
+                    if !params.approved_new_directory_creation.unwrap_or(true) {
+                        return Ok(json!({
+                            "type": "requestToApproveDirectoryCreation",
+                            "directory": params.directory,
+                        }));
+                    }
+                    std::fs::create_dir_all(&params.directory).map_err(|e| {
+                        format!("Unable to create directory at '{}': {}", params.directory.display(), e)
+                    })?;
+                    log::info!("Created {} for a new session", params.directory.display());
+                }
+                None
+            }
+            Some("worktree") => {
+                if !params.directory.is_dir() {
+                    return Err(format!(
+                        "Worktree sessions require an existing Git repository. Directory not found: {}",
+                        params.directory.display()
+                    ));
+                }
+                Some(worktree::create_for_session(&params.directory, params.worktree_name.clone()).await?)
+            }
+            Some(other) => return Err(format!("unknown sessionType {}", other)),
+        };
+
+        self.launch(&params, agent, worktree).await
+    }
+
+    async fn launch(
+        self: &Arc<Self>,
+        params: &SpawnParams,
+        agent: &str,
+        worktree: Option<SessionWorktree>,
+    ) -> Result<Value, String> {
+        let key = uuid::Uuid::new_v4().simple().to_string();
+        let private = self.private_dir(&key);
+        if let Err(e) = std::fs::DirBuilder::new().recursive(true).mode(0o700).create(&private) {
+            self.abandon(&key, worktree.as_ref()).await;
+            return Err(format!("Failed to create {}: {}", private.display(), e));
+        }
+        let directory = worktree
+            .as_ref()
+            .map_or(params.directory.clone(), |w| w.worktree_path.clone());
+
+        let mut args = vec![agent.to_string()];
+        if let Some(id) = &params.resume_session_id {
+            match agent {
+                "codex" => args.extend(["resume".to_string(), id.clone()]),
+                _ => args.extend(["--resume".to_string(), id.clone()]),
+            }
+        }
+        args.extend(["--hapi-starting-mode", "remote", "--started-by", "runner"].map(String::from));
+        if let Some(model) = params.model.as_ref().filter(|_| agent != "opencode") {
+            args.extend(["--model".to_string(), model.clone()]);
+        }
+        if params.yolo == Some(true) {
+            args.push("--yolo".to_string());
+        }
+
+        let program = self.policy.lock().unwrap().command.clone();
+        let mut command = tokio::process::Command::new(&program);
+        command
+            .args(&args)
+            .current_dir(&directory)
+            .env("HAPI_HOME", &self.hapi_home)
+            .env("HAPI_API_URL", &self.api_url)
+            .env("CLI_API_TOKEN", &self.token)
+            .env(
+                "HAPI_RUNNER_URL",
+                format!("http://127.0.0.1:{}/{}", self.webhook_port, key),
+            )
+            .stdin(Stdio::null())
+            .stdout(Stdio::null())
+            .stderr(Stdio::piped());
+        if let Some(token) = &params.token {
+            match agent {
+                "codex" => match codex_home(&private, token) {
+                    Ok(home) => {
+                        command.env("CODEX_HOME", home);
+                    }
+                    Err(e) => {
+                        self.abandon(&key, worktree.as_ref()).await;
+                        return Err(format!("Failed to write Codex credentials: {}", e));
+                    }
+                },
+                "claude" => {
+                    command.env("CLAUDE_CODE_OAUTH_TOKEN", token);
+                }
+                _ => {}
+            }
+        }
+        if let Some(w) = &worktree {
+            command
+                .env("HAPI_WORKTREE_BASE_PATH", &w.base_path)
+                .env("HAPI_WORKTREE_BRANCH", &w.branch)
+                .env("HAPI_WORKTREE_NAME", &w.name)
+                .env("HAPI_WORKTREE_PATH", &w.worktree_path)
+                .env("HAPI_WORKTREE_CREATED_AT", w.created_at.to_string());
+        }
+
+        let sandbox = match sandbox::policy_for(&directory) {
+            Some(policy) => {
+                let mut writable = vec![directory.clone(), private.clone()];
+                writable.extend(worktree::git_common_dir(&directory).await);
+                match Sandbox::new(&policy, &writable) {
+                    Ok(sandbox) => Some(sandbox),
+                    Err(e) => {
+                        self.abandon(&key, worktree.as_ref()).await;
+                        return Err(e);
+                    }
+                }
+            }
+            None => None,
+        };
+
+        let prepared = self.limiter.lock().unwrap().prepare(&key);
+        let prepared = match prepared {
+            Ok(prepared) => prepared,
+            Err(e) => {
+                self.abandon(&key, worktree.as_ref()).await;
+                return Err(format!("Failed to set up session limits: {}", e));
+            }
+        };
+        prepared.apply(&mut command);
+        if let Some(sandbox) = &sandbox {
+            sandbox.apply(&mut command);
+        }
+        let mut child = match command.spawn() {
+            Ok(child) => child,
+            Err(e) => {
+                prepared.cleanup();
+                self.abandon(&key, worktree.as_ref()).await;
+                return Err(format!("Failed to run {}: {}", program, e));
+            }
+        };
+        let pid = child.id().unwrap_or_default();
+        log::info!("Spawned {} session in {} (pid {})", agent, directory.display(), pid);
+
+        let (started_tx, started_rx) = oneshot::channel();
+        self.inner.lock().unwrap().sessions.insert(
+            key.clone(),
+            Tracked {
+                pid,
+                directory,
+                started_at: crate::state::now_millis(),
+                session_id: None,
+                started: Some(started_tx),
+                stopping: false,
+            },
+        );
+        let stderr = child.stderr.take();
+        logging::spawn(self.clone().watch(key, child, stderr, prepared));
+
+        match tokio::time::timeout(WEBHOOK_TIMEOUT, started_rx).await {
+            Ok(Ok(session_id)) => Ok(json!({ "type": "success", "sessionId": session_id })),
+            Ok(Err(_)) => {
+                if let Some(w) = &worktree {
+                    worktree::discard(w).await;
+                }
+                let inner = self.inner.lock().unwrap();
+                let exit = inner.exits.iter().rev().find(|e| e.pid == pid);
+                Err(format!(
+                    "Session process {} {} before reporting its session",
+                    pid,
+                    exit.map_or("exited".to_string(), SessionExit::describe)
+                ))
+            }
+            // Still running, so the worktree stays
+            Err(_) => Err(format!("Session webhook timeout for PID {}", pid)),
+        }
+    }
+
+    /// The spawn's own directory under `hapi_home`, for what only that
+    /// session may see, e.g. its Codex credentials. Removed when it exits.
+    fn private_dir(&self, key: &str) -> PathBuf {
+        self.hapi_home.join("sessions").join(key)
+    }
+
+    /// Undo a spawn that failed before its process started.
+    async fn abandon(&self, key: &str, worktree: Option<&SessionWorktree>) {
+        remove_private_dir(&self.private_dir(key));
+        if let Some(w) = worktree {
+            worktree::discard(w).await;
+        }
+    }
+
+    /// Wait for a session to exit, then record and publish why it did.
+    async fn watch(
+        self: Arc<Self>,
+        key: String,
+        mut child: Child,
+        stderr: Option<ChildStderr>,
+        prepared: Prepared,
+    ) {
+        let tail = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let reader = logging::spawn(read_tail(stderr, tail.clone()));
+        let status = child.wait().await;
+        // Descendants may still hold the pipe open
+        let _ = tokio::time::timeout(Duration::from_secs(1), reader).await;
+
+        let Some(mut tracked) = self.inner.lock().unwrap().sessions.remove(&key) else {
+            return;
+        };
+        let exit = classify(&tracked, status, &prepared);
+        prepared.cleanup();
+        remove_private_dir(&self.private_dir(&key));
+
+        let label = tracked.session_id.as_deref().unwrap_or("(unreported)");
+        let expected = match exit.reason {
+            ExitReason::Exited => exit.code == Some(0),
+            ExitReason::Stopped => true,
+            _ => false,
+        };
+        if expected {
+            log::info!("Session {} (pid {}) {}", label, exit.pid, exit.describe());
+        } else {
+            log::warn!("Session {} (pid {}) {}", label, exit.pid, exit.describe());
+            let tail = tail.lock().unwrap();
+            let tail = String::from_utf8_lossy(&tail);
+            if !tail.trim().is_empty() {
+                log::debug!("Session stderr tail:\n{}", tail.trim_end());
+            }
+        }
+
+        {
+            let mut inner = self.inner.lock().unwrap();
+            inner.exits.push_back(exit);
+            if inner.exits.len() > RECENT_EXITS {
+                inner.exits.pop_front();
+            }
+        }
+        // After recording, so a spawn still waiting can report the exit
+        drop(tracked.started.take());
+        self.publish().await;
+    }
+
+    /// SIGTERM the session's process group, then SIGKILL it after `STOP_GRACE`.
+    fn stop(self: &Arc<Self>, session_id: &str) -> bool {
+        let pid_hint = session_id
+            .strip_prefix("PID-")
+            .and_then(|p| p.parse::<u32>().ok());
+        let found = {
+            let mut inner = self.inner.lock().unwrap();
+            inner
+                .sessions
+                .iter_mut()
+                .find(|(_, t)| t.session_id.as_deref() == Some(session_id) || Some(t.pid) == pid_hint)
+                .map(|(key, t)| {
+                    t.stopping = true;
+                    (key.clone(), t.pid)
+                })
+        };
+        let Some((key, pid)) = found else {
+            return false;
+        };
+        log::info!("Stopping session {} (pid {})", session_id, pid);
+        signal_group(pid, libc::SIGTERM);
+
+        let sessions = self.clone();
+        logging::spawn(async move {
+            tokio::time::sleep(STOP_GRACE).await;
+            if sessions.inner.lock().unwrap().sessions.contains_key(&key) {
+                log::warn!("Session pid {} ignored SIGTERM, killing it", pid);
+                signal_group(pid, libc::SIGKILL);
+            }
+        });
+        true
+    }
+
+    /// Stop every running session as `stop` would, waiting until they have
+    /// all exited or been killed.
+    pub async fn stop_all(&self) {
+        let pids: Vec<u32> = {
+            let mut inner = self.inner.lock().unwrap();
+            inner
+                .sessions
+                .values_mut()
+                .map(|t| {
+                    t.stopping = true;
+                    t.pid
+                })
+                .collect()
+        };
+        if pids.is_empty() {
+            return;
+        }
+        log::info!("Stopping {} sessions", pids.len());
+        for pid in &pids {
+            signal_group(*pid, libc::SIGTERM);
+        }
+        let mut deadline = tokio::time::Instant::now() + STOP_GRACE;
+        let mut killed = false;
+        while !self.inner.lock().unwrap().sessions.is_empty() {
+            if tokio::time::Instant::now() >= deadline {
+                if killed {
+                    break;
+                }
+                for tracked in self.inner.lock().unwrap().sessions.values() {
+                    log::warn!("Session pid {} ignored SIGTERM, killing it", tracked.pid);
+                    signal_group(tracked.pid, libc::SIGKILL);
+                }
+                // Long enough for `watch` to record the exits
+                killed = true;
+                deadline = tokio::time::Instant::now() + Duration::from_secs(1);
+            }
+            tokio::time::sleep(Duration::from_millis(100)).await;
+        }
+    }
+
+    fn session_started(&self, key: &str, session_id: String) -> bool {
+        let mut inner = self.inner.lock().unwrap();
+        // Only the first report counts: the URL leaks into the agent's own shells
+        let Some(tracked) = inner.sessions.get_mut(key).filter(|t| t.session_id.is_none()) else {
+            return false;
+        };
+        log::info!("Session {} started (pid {})", session_id, tracked.pid);
+        tracked.session_id = Some(session_id.clone());
+        if let Some(started) = tracked.started.take() {
+            let _ = started.send(session_id);
+        }
+        true
+    }
+
+    /// Take over the recent exits from the runner state an earlier run left
+    /// unsent, ahead of any of ours.
+    pub async fn restore(&self, saved: &Value) {
+        let exits: Vec<SessionExit> =
+            serde_json::from_value(saved["sessions"]["recentExits"].clone()).unwrap_or_default();
+        if exits.is_empty() {
+            return;
+        }
+        {
+            let mut inner = self.inner.lock().unwrap();
+            let ours = std::mem::take(&mut inner.exits);
+            inner.exits = exits.into_iter().chain(ours).collect();
+            while inner.exits.len() > RECENT_EXITS {
+                inner.exits.pop_front();
+            }
+        }
+        self.publish().await;
+    }
+
+    /// Running sessions, oldest first, and the recent exits, for `happier status`.
+    pub fn snapshot(&self) -> (Vec<SessionSnapshot>, Vec<SessionExit>) {
+        let inner = self.inner.lock().unwrap();
+        let mut running: Vec<SessionSnapshot> = inner
+            .sessions
+            .values()
+            .map(|t| SessionSnapshot {
+                pid: t.pid,
+                session_id: t.session_id.clone(),
+                directory: t.directory.clone(),
+                started_at: t.started_at,
+            })
+            .collect();
+        running.sort_by_key(|s| s.started_at);
+        (running, inner.exits.iter().cloned().collect())
+    }
+
+    async fn publish(&self) {
+        let sessions = {
+            let inner = self.inner.lock().unwrap();
+            json!({
+                "running": inner.sessions.len(),
+                "recentExits": inner.exits,
+            })
+        };
+        let client = self.client.lock().unwrap().clone();
+        let mut runner = self.runner.lock().await;
+        runner.set_sessions(sessions);
+        // Otherwise the next connect publishes it
+        if let Some(client) = client {
+            if let Err(e) = runner.publish(&client).await {
+                log::warn!("Failed to publish session state: {}", e);
+                runner.queue(&self.outbox);
+            }
+        }
+    }
+
+    async fn serve_webhook(self: Arc<Self>, listener: TcpListener) {
+        loop {
+            match listener.accept().await {
+                Ok((stream, _)) => {
+                    let sessions = self.clone();
+                    logging::spawn(async move {
+                        let handled =
+                            tokio::time::timeout(Duration::from_secs(10), sessions.handle_webhook(stream));
+                        if let Ok(Err(e)) = handled.await {
+                            log::debug!("Session webhook request failed: {}", e);
+                        }
+                    });
+                }
+                Err(e) => {
+                    log::warn!("Session webhook accept failed: {}", e);
+                    tokio::time::sleep(Duration::from_secs(1)).await;
+                }
+            }
+        }
+    }
+
+    /// One `POST /<key>/session-started` with `{ sessionId, metadata }`.
+    async fn handle_webhook(self: &Arc<Self>, stream: TcpStream) -> std::io::Result<()> {
+        let mut reader = BufReader::new(stream);
+        let mut request_line = String::new();
+        reader.read_line(&mut request_line).await?;
+        let mut content_length = 0;
+        loop {
+            let mut header = String::new();
+            if reader.read_line(&mut header).await? == 0 || header.trim().is_empty() {
+                break;
+            }
+            if let Some((name, value)) = header.split_once(':') {
+                if name.trim().eq_ignore_ascii_case("content-length") {
+                    content_length = value.trim().parse().unwrap_or(0);
+                }
+            }
+        }
+
+        let mut parts = request_line.split_whitespace();
+        let (method, path) = (parts.next().unwrap_or(""), parts.next().unwrap_or(""));
+        let key = path
+            .trim_start_matches('/')
+            .strip_suffix("/session-started")
+            .filter(|_| method == "POST" && content_length <= WEBHOOK_MAX_BODY);
+        let (status, body) = match key {
+            Some(key) => {
+                let mut body = vec![0; content_length];
+                reader.read_exact(&mut body).await?;
+                let session_id = serde_json::from_slice::<Value>(&body)
+                    .ok()
+                    .and_then(|v| v["sessionId"].as_str().map(String::from));
+                match session_id.map(|id| self.session_started(key, id)) {
+                    Some(true) => {
+                        self.publish().await;
+                        ("200 OK", json!({ "status": "ok" }))
+                    }
+                    Some(false) => ("404 Not Found", json!({ "error": "unknown session" })),
+                    None => ("400 Bad Request", json!({ "error": "sessionId is required" })),
+                }
+            }
+            None => ("404 Not Found", json!({ "error": "not found" })),
+        };
+
+        let body = body.to_string();
+        let response = format!(
+            "HTTP/1.1 {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+            status,
+            body.len(),
+            body
+        );
+        reader.get_mut().write_all(response.as_bytes()).await
+    }
+}
+
+fn classify(tracked: &Tracked, status: std::io::Result<ExitStatus>, prepared: &Prepared) -> SessionExit {
+    let (code, signal) = match &status {
+        Ok(status) => (status.code(), status.signal()),
+        Err(e) => {
+            log::warn!("Failed to wait for session pid {}: {}", tracked.pid, e);
+            (None, None)
+        }
+    };
+    // The cgroup also counts OOM kills of tools the agent ran; only blame the
+    // limit when the session itself went down abnormally
+    let abnormal = signal.is_some() || code.is_some_and(|c| c != 0);
+    let limit = prepared.killed_by().filter(|_| abnormal);
+    let reason = if tracked.stopping {
+        ExitReason::Stopped
+    } else if limit.is_some() {
+        ExitReason::Limit
+    } else if signal.is_some() {
+        ExitReason::Signaled
+    } else {
+        ExitReason::Exited
+    };
+    SessionExit {
+        pid: tracked.pid,
+        session_id: tracked.session_id.clone(),
+        directory: tracked.directory.clone(),
+        exited_at: crate::state::now_millis(),
+        reason,
+        code,
+        signal,
+        limit: limit.filter(|_| reason == ExitReason::Limit).map(String::from),
+        limits_hit: prepared.hit().into_iter().map(String::from).collect(),
+    }
+}
+
+fn signal_group(pid: u32, signal: libc::c_int) {
+    // Sessions lead their own process group (see `Prepared::apply`)
+    if pid > 0 && unsafe { libc::kill(-(pid as libc::pid_t), signal) } != 0 {
+        log::debug!("kill(-{}, {}): {}", pid, signal, std::io::Error::last_os_error());
+    }
+}
+
+/// Keep the last `STDERR_TAIL` bytes of the session's stderr.
+async fn read_tail(stderr: Option<ChildStderr>, tail: Arc<std::sync::Mutex<Vec<u8>>>) {
+    let Some(mut stderr) = stderr else {
+        return;
+    };
+    let mut buf = [0u8; 4096];
+    while let Ok(n) = stderr.read(&mut buf).await {
+        if n == 0 {
+            break;
+        }
+        let mut tail = tail.lock().unwrap();
+        tail.extend_from_slice(&buf[..n]);
+        let excess = tail.len().saturating_sub(STDERR_TAIL);
+        tail.drain(..excess);
+    }
+}
+
+/// A private `CODEX_HOME` in `private`, holding the auth.json passed with
+/// the spawn.
+fn codex_home(private: &Path, auth: &str) -> std::io::Result<PathBuf> {
+    use std::io::Write;
+    let home = private.join("codex");
+    std::fs::DirBuilder::new().mode(0o700).create(&home)?;
+    let mut file = std::fs::OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .mode(0o600)
+        .open(home.join("auth.json"))?;
+    file.write_all(auth.as_bytes())?;
+    Ok(home)
+}
+
+fn remove_private_dir(dir: &Path) {
+    match std::fs::remove_dir_all(dir) {
+        Err(e) if e.kind() != std::io::ErrorKind::NotFound => {
+            log::warn!("Failed to remove {}: {}", dir.display(), e);
+        }
+        _ => {}
+    }
+}
@@ -0,0 +1,298 @@
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use tokio::sync::Notify;
+
+use crate::sessions::{SessionExit, Sessions};
+
+/// Where the daemon is in its connect/reconnect cycle.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum ConnectionState {
+    Registering,
+    Connecting,
+    Connected,
+    Disconnected,
+}
+
+impl ConnectionState {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            ConnectionState::Registering => "registering",
+            ConnectionState::Connecting => "connecting",
+            ConnectionState::Connected => "connected",
+            ConnectionState::Disconnected => "disconnected",
+        }
+    }
+}
+
+/// Byte counters for one tunnel, updated by its TCP read/write tasks.
+#[derive(Default)]
+pub struct TunnelCounters {
+    /// Bytes received from the hub and written to the local target.
+    pub bytes_in: AtomicU64,
+    /// Bytes read from the local target and sent to the hub.
+    pub bytes_out: AtomicU64,
+}
+
+struct TunnelEntry {
+    target: String,
+    opened_at: u64,
+    counters: Arc<TunnelCounters>,
+}
+
+struct Inner {
+    connection: ConnectionState,
+    /// Last inbound frame time of the current socket, while connected.
+    last_inbound: Option<Arc<AtomicU64>>,
+    connected_since: Option<u64>,
+    ever_connected: bool,
+    reconnects: u64,
+    tunnels: HashMap<String, TunnelEntry>,
+    /// Set once the hub's sessions are up.
+    sessions: Option<Arc<Sessions>>,
+}
+
+/// Runtime state of one hub connection, shared by its connection loop,
+/// its tunnels and the control server.
+#[derive(Clone)]
+pub struct RuntimeState {
+    inner: Arc<Mutex<Inner>>,
+    /// `None` for the main hub, else the `[[profile]]` name.
+    profile: Option<String>,
+    machine_id: String,
+    api_url: String,
+}
+
+/// The whole daemon: every hub's state, and shutdown requests.
+#[derive(Clone)]
+pub struct DaemonState {
+    /// The main hub first, then the profiles in config order.
+    hubs: Arc<Vec<RuntimeState>>,
+    started_at: u64,
+    shutdown: Arc<Notify>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct TunnelSnapshot {
+    pub id: String,
+    pub target: String,
+    #[serde(rename = "openedAt")]
+    pub opened_at: u64,
+    #[serde(rename = "bytesIn")]
+    pub bytes_in: u64,
+    #[serde(rename = "bytesOut")]
+    pub bytes_out: u64,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SessionSnapshot {
+    pub pid: u32,
+    /// Once the session has reported it
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub session_id: Option<String>,
+    pub directory: PathBuf,
+    pub started_at: u64,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct HubSnapshot {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub profile: Option<String>,
+    #[serde(rename = "machineId")]
+    pub machine_id: String,
+    #[serde(rename = "apiUrl")]
+    pub api_url: String,
+    pub connection: ConnectionState,
+    #[serde(rename = "connectedSince")]
+    pub connected_since: Option<u64>,
+    pub reconnects: u64,
+    pub tunnels: Vec<TunnelSnapshot>,
+    #[serde(default)]
+    pub sessions: Vec<SessionSnapshot>,
+    #[serde(default, rename = "recentExits")]
+    pub recent_exits: Vec<SessionExit>,
+}
+
+/// The main hub's fields at the top level, as before profiles existed.
+#[derive(Serialize, Deserialize)]
+pub struct StatusSnapshot {
+    pub pid: u32,
+    pub version: String,
+    #[serde(rename = "startedAt")]
+    pub started_at: u64,
+    #[serde(flatten)]
+    pub hub: HubSnapshot,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub profiles: Vec<HubSnapshot>,
+}
+
+impl DaemonState {
+    pub fn new(hubs: Vec<RuntimeState>) -> Self {
+        DaemonState {
+            hubs: Arc::new(hubs),
+            started_at: now_millis(),
+            shutdown: Arc::new(Notify::new()),
+        }
+    }
+
+    pub fn hubs(&self) -> &[RuntimeState] {
+        &self.hubs
+    }
+
+    /// Ask the daemon to shut down (e.g. from `happier stop`).
+    pub fn request_shutdown(&self) {
+        self.shutdown.notify_one();
+    }
+
+    pub async fn shutdown_requested(&self) {
+        self.shutdown.notified().await;
+    }
+
+    pub fn snapshot(&self) -> StatusSnapshot {
+        let mut hubs = self.hubs.iter().map(RuntimeState::snapshot);
+        StatusSnapshot {
+            pid: std::process::id(),
+            version: env!("CARGO_PKG_VERSION").to_string(),
+            started_at: self.started_at,
+            hub: hubs.next().expect("the main hub is always configured"),
+            profiles: hubs.collect(),
+        }
+    }
+}
+
+impl RuntimeState {
+    pub fn new(profile: Option<&str>, machine_id: &str, api_url: &str) -> Self {
+        RuntimeState {
+            inner: Arc::new(Mutex::new(Inner {
+                connection: ConnectionState::Registering,
+                last_inbound: None,
+                connected_since: None,
+                ever_connected: false,
+                reconnects: 0,
+                tunnels: HashMap::new(),
+                sessions: None,
+            })),
+            profile: profile.map(String::from),
+            machine_id: machine_id.to_string(),
+            api_url: api_url.to_string(),
+        }
+    }
+
+    pub fn set_connection(&self, state: ConnectionState) {
+        let mut inner = self.inner.lock().unwrap();
+        if state == ConnectionState::Connected {
+            if inner.ever_connected {
+                inner.reconnects += 1;
+            }
+            inner.ever_connected = true;
+            inner.connected_since = Some(now_millis());
+        } else {
+            inner.connected_since = None;
+            inner.last_inbound = None;
+        }
+        inner.connection = state;
+    }
+
+    /// Attach the live socket's inbound timestamp so health reflects real traffic.
+    pub fn set_last_inbound(&self, last_inbound: Arc<AtomicU64>) {
+        self.inner.lock().unwrap().last_inbound = Some(last_inbound);
+    }
+
+    pub fn connection(&self) -> ConnectionState {
+        self.inner.lock().unwrap().connection
+    }
+
+    pub fn profile(&self) -> Option<&str> {
+        self.profile.as_deref()
+    }
+
+    pub fn api_url(&self) -> &str {
+        &self.api_url
+    }
+
+    pub fn tunnel_count(&self) -> usize {
+        self.inner.lock().unwrap().tunnels.len()
+    }
+
+    /// False only when we believe we're connected but the hub has sent
+    /// nothing for `max_age_ms`, i.e. the socket is wedged. While
+    /// disconnected the reconnect loop is doing its job, and restarting
+    /// the process would not bring the hub back.
+    pub fn is_healthy(&self, max_age_ms: u64) -> bool {
+        let inner = self.inner.lock().unwrap();
+        match (inner.connection, &inner.last_inbound) {
+            (ConnectionState::Connected, Some(t)) => {
+                now_millis().saturating_sub(t.load(Ordering::Relaxed)) < max_age_ms
+            }
+            _ => true,
+        }
+    }
+
+    pub fn add_tunnel(&self, tunnel_id: &str, target: String) -> Arc<TunnelCounters> {
+        let counters = Arc::new(TunnelCounters::default());
+        self.inner.lock().unwrap().tunnels.insert(
+            tunnel_id.to_string(),
+            TunnelEntry {
+                target,
+                opened_at: now_millis(),
+                counters: counters.clone(),
+            },
+        );
+        counters
+    }
+
+    pub fn remove_tunnel(&self, tunnel_id: &str) {
+        self.inner.lock().unwrap().tunnels.remove(tunnel_id);
+    }
+
+    pub fn set_sessions(&self, sessions: Arc<Sessions>) {
+        self.inner.lock().unwrap().sessions = Some(sessions);
+    }
+
+    pub fn clear_tunnels(&self) {
+        self.inner.lock().unwrap().tunnels.clear();
+    }
+
+    pub fn snapshot(&self) -> HubSnapshot {
+        let inner = self.inner.lock().unwrap();
+        let mut tunnels: Vec<TunnelSnapshot> = inner
+            .tunnels
+            .iter()
+            .map(|(id, t)| TunnelSnapshot {
+                id: id.clone(),
+                target: t.target.clone(),
+                opened_at: t.opened_at,
+                bytes_in: t.counters.bytes_in.load(Ordering::Relaxed),
+                bytes_out: t.counters.bytes_out.load(Ordering::Relaxed),
+            })
+            .collect();
+        tunnels.sort_by_key(|t| t.opened_at);
+        let (sessions, recent_exits) = inner
+            .sessions
+            .as_ref()
+            .map(|s| s.snapshot())
+            .unwrap_or_default();
+        HubSnapshot {
+            profile: self.profile.clone(),
+            machine_id: self.machine_id.clone(),
+            api_url: self.api_url.clone(),
+            connection: inner.connection,
+            connected_since: inner.connected_since,
+            reconnects: inner.reconnects,
+            tunnels,
+            sessions,
+            recent_exits,
+        }
+    }
+}
+
+pub fn now_millis() -> u64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap()
+        .as_millis() as u64
+}
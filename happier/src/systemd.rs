@@ -0,0 +1,396 @@
+//! systemd integration: `sd_notify` readiness, watchdog and status, plus
+//! `happier install-service` to write a unit file.
+
+use std::ffi::{CStr, CString, OsStr};
+use std::io::Write;
+use std::os::linux::net::SocketAddrExt;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::MetadataExt;
+use std::os::unix::net::{SocketAddr, UnixDatagram};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use crate::config::{self, Cli, TokenSource};
+use crate::state::{ConnectionState, DaemonState, RuntimeState};
+
+/// No inbound frame for this long while connected counts as unhealthy.
+/// The hub pings every 25s and allows a 20s pong timeout.
+const SOCKET_STALE_MS: u64 = 60_000;
+
+/// Send a state string to `$NOTIFY_SOCKET`. No-op when not run by systemd.
+pub fn notify(state: &str) {
+    let Ok(path) = std::env::var("NOTIFY_SOCKET") else {
+        return;
+    };
+    let addr = match path.strip_prefix('@') {
+        // Abstract namespace socket
+        Some(name) => SocketAddr::from_abstract_name(name.as_bytes()),
+        None => SocketAddr::from_pathname(&path),
+    };
+    let result = addr.and_then(|addr| {
+        let sock = UnixDatagram::unbound()?;
+        sock.send_to_addr(state.as_bytes(), &addr)
+    });
+    if let Err(e) = result {
+        log::debug!("sd_notify({}) failed: {}", state, e);
+    }
+}
+
+/// Watchdog interval requested by the unit, if it is meant for this process.
+fn watchdog_interval() -> Option<Duration> {
+    let usec: u64 = std::env::var("WATCHDOG_USEC").ok()?.parse().ok()?;
+    if let Ok(pid) = std::env::var("WATCHDOG_PID") {
+        if pid.parse::<u32>().ok()? != std::process::id() {
+            return None;
+        }
+    }
+    Some(Duration::from_micros(usec))
+}
+
+/// One clause per hub, the profiles' prefixed with their name.
+fn status_line(daemon: &DaemonState) -> String {
+    let clauses: Vec<String> = daemon
+        .hubs()
+        .iter()
+        .map(|hub| match hub.profile() {
+            Some(name) => format!("{}: {}", name, hub_status(hub)),
+            None => hub_status(hub),
+        })
+        .collect();
+    clauses.join("; ")
+}
+
+fn hub_status(state: &RuntimeState) -> String {
+    let connection = state.connection();
+    match connection {
+        ConnectionState::Connected => format!(
+            "Connected to {} ({} tunnels)",
+            state.api_url(),
+            state.tunnel_count()
+        ),
+        ConnectionState::Registering => format!("Registering machine with {}", state.api_url()),
+        ConnectionState::Connecting => format!("Connecting to {}", state.api_url()),
+        ConnectionState::Disconnected => format!("Disconnected from {}, retrying", state.api_url()),
+    }
+}
+
+/// Report readiness, status and watchdog pings for as long as the daemon runs.
+/// `READY=1` goes out on the first successful connect to any hub;
+/// `WATCHDOG=1` only while `RuntimeState::is_healthy` holds for every hub,
+/// so a wedged socket gets restarted.
+pub async fn supervise(daemon: DaemonState) {
+    if std::env::var_os("NOTIFY_SOCKET").is_none() {
+        return;
+    }
+    let watchdog = watchdog_interval();
+    if let Some(interval) = watchdog {
+        log::info!("systemd watchdog enabled ({:?})", interval);
+    }
+
+    let mut ready = false;
+    let mut last_status = String::new();
+    let mut last_ping = tokio::time::Instant::now();
+    let mut tick = tokio::time::interval(Duration::from_secs(1));
+    loop {
+        tick.tick().await;
+
+        let status = status_line(&daemon);
+        let connected = daemon.hubs().iter().any(|hub| hub.connection() == ConnectionState::Connected);
+        if !ready && connected {
+            ready = true;
+            notify(&format!("READY=1\nSTATUS={}", status));
+            last_status = status;
+        } else if status != last_status {
+            notify(&format!("STATUS={}", status));
+            last_status = status;
+        }
+
+        if let Some(interval) = watchdog {
+            if last_ping.elapsed() >= interval / 2 {
+                if daemon.hubs().iter().all(|hub| hub.is_healthy(SOCKET_STALE_MS)) {
+                    notify("WATCHDOG=1");
+                    last_ping = tokio::time::Instant::now();
+                } else {
+                    log::warn!("Socket looks stale, withholding watchdog ping");
+                }
+            }
+        }
+    }
+}
+
+/// Where `install-service` writes the unit.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum UnitScope {
+    User,
+    System,
+}
+
+pub struct InstallOptions {
+    pub scope: UnitScope,
+    /// Print the unit instead of writing it.
+    pub print: bool,
+    pub force: bool,
+}
+
+fn unit_path(scope: UnitScope) -> Result<PathBuf, Box<dyn std::error::Error>> {
+    match scope {
+        UnitScope::System => Ok(PathBuf::from("/etc/systemd/system/happier.service")),
+        UnitScope::User => {
+            let base = match std::env::var("XDG_CONFIG_HOME") {
+                Ok(dir) if !dir.is_empty() => PathBuf::from(dir),
+                _ => PathBuf::from(std::env::var("HOME").map_err(|_| "HOME is not set")?)
+                    .join(".config"),
+            };
+            Ok(base.join("systemd/user/happier.service"))
+        }
+    }
+}
+
+/// Quote a value for an `Environment=` line.
+fn env_assignment(key: &str, value: &str) -> String {
+    format!("Environment=\"{}={}\"", key, escape(value))
+}
+
+/// Escape `value` for a double-quoted unit file word: backslashes and quotes,
+/// and `%`, which systemd would otherwise expand as a specifier.
+fn escape(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('"', "\\\"").replace('%', "%%")
+}
+
+/// `ExecStart=` for `command`, each word quoted. `$` is doubled too, since
+/// ExecStart= expands `$VAR` and `${VAR}`.
+fn exec_start(command: &[&str]) -> String {
+    let words: Vec<String> = command
+        .iter()
+        .map(|word| format!("\"{}\"", escape(word).replace('$', "$$")))
+        .collect();
+    format!("ExecStart={}", words.join(" "))
+}
+
+/// The account a system unit runs as.
+struct Account {
+    name: String,
+    uid: u32,
+    home: PathBuf,
+}
+
+/// Look `name` up in the passwd database.
+fn lookup_user(name: &str) -> Option<Account> {
+    let c_name = CString::new(name).ok()?;
+    let mut buf = vec![0 as libc::c_char; 1024];
+    loop {
+        let mut pwd: libc::passwd = unsafe { std::mem::zeroed() };
+        let mut found = std::ptr::null_mut();
+        let ret = unsafe {
+            libc::getpwnam_r(c_name.as_ptr(), &mut pwd, buf.as_mut_ptr(), buf.len(), &mut found)
+        };
+        if ret == libc::ERANGE && buf.len() < 1 << 20 {
+            buf.resize(buf.len() * 2, 0);
+            continue;
+        }
+        if ret != 0 || found.is_null() {
+            return None;
+        }
+        let home = unsafe { CStr::from_ptr(pwd.pw_dir) };
+        return Some(Account {
+            name: name.to_string(),
+            uid: pwd.pw_uid,
+            home: PathBuf::from(OsStr::from_bytes(home.to_bytes())),
+        });
+    }
+}
+
+/// Who a system unit should run as: whoever ran sudo, else the current
+/// user, but never root.
+fn service_account() -> Result<Option<Account>, Box<dyn std::error::Error>> {
+    let Some(name) = std::env::var("SUDO_USER")
+        .ok()
+        .or_else(|| std::env::var("USER").ok())
+        .filter(|u| !u.is_empty() && u != "root")
+    else {
+        return Ok(None);
+    };
+    let account = lookup_user(&name).ok_or_else(|| format!("no passwd entry for user {}", name))?;
+    Ok(Some(account))
+}
+
+/// The `hapi_home` a system unit running as `account` uses: `--hapi-home`,
+/// else `$HAPI_HOME`, else the account's own `~/.hapi`. Under sudo `$HOME`
+/// is root's, so it is never used here.
+fn unit_hapi_home(cli: &Cli, account: &Account) -> Result<PathBuf, Box<dyn std::error::Error>> {
+    if let Some(home) = &cli.hapi_home {
+        return Ok(home.clone());
+    }
+    let home = match std::env::var_os("HAPI_HOME") {
+        Some(home) if !home.is_empty() => PathBuf::from(home),
+        _ => account.home.join(".hapi"),
+    };
+    // The service could not read another user's settings.json or token
+    if let Ok(meta) = std::fs::metadata(&home) {
+        if meta.uid() != account.uid {
+            return Err(format!(
+                "{} belongs to uid {}, not to {} who the unit runs as; pass --hapi-home to use it anyway",
+                home.display(),
+                meta.uid(),
+                account.name
+            )
+            .into());
+        }
+    }
+    Ok(home)
+}
+
+fn render_unit(scope: UnitScope, command: &[&str], hapi_home: &Path, user: Option<&str>) -> String {
+    let mut unit = String::new();
+    unit.push_str("# Generated by `happier install-service`\n");
+    unit.push_str("[Unit]\n");
+    unit.push_str("Description=happier - hapi machine runner\n");
+    unit.push_str("After=network-online.target\n");
+    unit.push_str("Wants=network-online.target\n\n");
+
+    unit.push_str("[Service]\n");
+    unit.push_str("Type=notify\n");
+    unit.push_str("NotifyAccess=main\n");
+    unit.push_str(&exec_start(command));
+    unit.push('\n');
+    unit.push_str("ExecReload=/bin/kill -HUP $MAINPID\n");
+    unit.push_str("Restart=on-failure\n");
+    unit.push_str("RestartSec=5\n");
+    // A rejected token stays rejected; restarting would only hammer the hub
+    unit.push_str(&format!("RestartPreventExitStatus={}\n", crate::EXIT_AUTH));
+    unit.push_str("WatchdogSec=120\n");
+    // READY=1 waits for the first hub connection
+    unit.push_str("TimeoutStartSec=300\n");
+    // Lets happier give each session its own cgroup for sessions.limits
+    unit.push_str("Delegate=yes\n");
+    if let Some(user) = user.filter(|_| scope == UnitScope::System) {
+        unit.push_str(&format!("User={}\n", user));
+    }
+    unit.push_str(&env_assignment("HAPI_HOME", &hapi_home.to_string_lossy()));
+    unit.push('\n');
+    // Secrets stay in settings.json; `systemctl show` exposes Environment= to all users
+    for key in ["HAPI_API_URL", "HAPI_MACHINE_NAME", "HAPI_HOSTNAME", "RUST_LOG"] {
+        if let Ok(value) = std::env::var(key) {
+            unit.push_str(&env_assignment(key, &value));
+            unit.push('\n');
+        }
+    }
+    unit.push('\n');
+
+    unit.push_str("[Install]\n");
+    match scope {
+        UnitScope::User => unit.push_str("WantedBy=default.target\n"),
+        UnitScope::System => unit.push_str("WantedBy=multi-user.target\n"),
+    }
+    unit
+}
+
+/// `happier install-service`: write a unit file for this binary and config.
+pub fn install(cli: &Cli, opts: &InstallOptions) -> Result<(), Box<dyn std::error::Error>> {
+    let exe = std::fs::canonicalize(std::env::current_exe()?)?;
+    let account = match opts.scope {
+        UnitScope::System => service_account()?,
+        UnitScope::User => None,
+    };
+    // Resolve as the service will, finding that user's happier.toml
+    let mut cli = cli.clone();
+    if let Some(account) = &account {
+        cli.hapi_home = Some(unit_hapi_home(&cli, account)?);
+    }
+    let (resolved, _) = config::resolve(&cli);
+    let hapi_home = std::fs::canonicalize(&resolved.hapi_home).unwrap_or(resolved.hapi_home.clone());
+    let exe = exe.to_string_lossy();
+    let config = cli.config.as_ref().map(std::fs::canonicalize).transpose()?;
+    let config = config.as_ref().map(|path| path.to_string_lossy());
+    let mut command = vec![exe.as_ref()];
+    if let Some(config) = &config {
+        command.extend(["--config", config.as_ref()]);
+    }
+    let user = account.as_ref().map(|a| a.name.as_str());
+    let unit = render_unit(opts.scope, &command, &hapi_home, user);
+
+    if opts.print {
+        print!("{}", unit);
+        return Ok(());
+    }
+
+    let path = unit_path(opts.scope)?;
+    if path.exists() && !opts.force {
+        return Err(format!("{} already exists (use --force to overwrite)", path.display()).into());
+    }
+    if let Some(dir) = path.parent() {
+        std::fs::create_dir_all(dir)?;
+    }
+    std::fs::File::create(&path)?.write_all(unit.as_bytes())?;
+    println!("Wrote {}", path.display());
+
+    if resolved.token.is_some_and(|(_, source)| source == TokenSource::Env) {
+        println!(
+            "Note: CLI_API_TOKEN is only in your environment and is not copied into the unit. \
+             Store it as cliApiToken in {}, or in a file referenced by \
+             `LoadCredential=cli-api-token:/path/to/token` in a drop-in.",
+            config::settings_path(&hapi_home).display()
+        );
+    }
+
+    let systemctl = match opts.scope {
+        UnitScope::User => "systemctl --user",
+        UnitScope::System => "sudo systemctl",
+    };
+    println!("Enable it with:");
+    println!("  {} daemon-reload", systemctl);
+    println!("  {} enable --now happier", systemctl);
+    if opts.scope == UnitScope::User {
+        println!("To keep it running after logout:");
+        println!("  loginctl enable-linger");
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn system_units_under_sudo_use_the_invoking_users_home() {
+        let unit = render_unit(
+            UnitScope::System,
+            &["/usr/local/bin/happier"],
+            Path::new("/home/alice/.hapi"),
+            Some("alice"),
+        );
+        assert!(unit.contains("\nUser=alice\n"));
+        assert!(unit.contains("\nEnvironment=\"HAPI_HOME=/home/alice/.hapi\"\n"));
+        assert!(!unit.contains("/root"));
+        assert!(unit.contains("WantedBy=multi-user.target"));
+    }
+
+    #[test]
+    fn user_units_have_no_user_line() {
+        let unit = render_unit(UnitScope::User, &["/usr/bin/happier"], Path::new("/home/bob/.hapi"), Some("bob"));
+        assert!(!unit.contains("User="));
+        assert!(unit.contains("WantedBy=default.target"));
+    }
+
+    #[test]
+    fn paths_are_quoted_and_escaped() {
+        let unit = render_unit(
+            UnitScope::User,
+            &["/opt/my apps/happier", "--config", "/srv/\"100%\" $HOME/happier.toml"],
+            Path::new("/home/bob/50% off"),
+            None,
+        );
+        assert!(unit.contains(
+            "\nExecStart=\"/opt/my apps/happier\" \"--config\" \"/srv/\\\"100%%\\\" $$HOME/happier.toml\"\n"
+        ));
+        assert!(unit.contains("\nEnvironment=\"HAPI_HOME=/home/bob/50%% off\"\n"));
+    }
+
+    #[test]
+    fn accounts_come_from_passwd() {
+        let root = lookup_user("root").unwrap();
+        assert_eq!(root.uid, 0);
+        assert!(root.home.is_absolute());
+        assert!(lookup_user("no-such-user-happier").is_none());
+    }
+}
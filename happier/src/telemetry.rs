@@ -0,0 +1,186 @@
+//! Resource telemetry from /proc and statvfs, published in `runnerState.telemetry`.
+//!
+//! Samples are taken at the configured cadence but only sent when something
+//! moved noticeably, or every `HEARTBEAT_SAMPLES` samples, so an idle box
+//! costs the hub one write every few minutes.
+
+use std::ffi::CString;
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+
+use serde::Serialize;
+use tokio::sync::Mutex;
+
+use crate::connection::RunnerState;
+use crate::socket::SocketClient;
+
+/// Send at least every this many samples even when nothing changed.
+const HEARTBEAT_SAMPLES: u32 = 10;
+/// Changes smaller than these are not worth a write.
+const LOAD_DELTA: f64 = 0.5;
+const MEMORY_DELTA_PERCENT: f64 = 5.0;
+const DISK_DELTA_PERCENT: f64 = 1.0;
+
+#[derive(Serialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct Sample {
+    pub sampled_at: u64,
+    /// 1, 5 and 15 minute load averages
+    pub load_avg: [f64; 3],
+    /// Bytes
+    pub mem_total: u64,
+    pub mem_available: u64,
+    pub swap_total: u64,
+    pub swap_free: u64,
+    pub uptime_secs: u64,
+    pub disks: Vec<DiskUsage>,
+}
+
+#[derive(Serialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct DiskUsage {
+    pub path: String,
+    /// Bytes
+    pub total: u64,
+    /// Bytes available to unprivileged users
+    pub available: u64,
+}
+
+fn percent(part: u64, total: u64) -> f64 {
+    if total == 0 {
+        0.0
+    } else {
+        part as f64 * 100.0 / total as f64
+    }
+}
+
+impl Sample {
+    /// Whether `self` differs enough from `prev` to be worth sending.
+    fn differs(&self, prev: &Sample) -> bool {
+        if (self.load_avg[0] - prev.load_avg[0]).abs() >= LOAD_DELTA {
+            return true;
+        }
+        let mem = |s: &Sample| percent(s.mem_available, s.mem_total);
+        let swap = |s: &Sample| percent(s.swap_free, s.swap_total);
+        if (mem(self) - mem(prev)).abs() >= MEMORY_DELTA_PERCENT
+            || (swap(self) - swap(prev)).abs() >= MEMORY_DELTA_PERCENT
+        {
+            return true;
+        }
+        if self.disks.len() != prev.disks.len() {
+            return true;
+        }
+        self.disks.iter().zip(&prev.disks).any(|(a, b)| {
+            (percent(a.available, a.total) - percent(b.available, b.total)).abs()
+                >= DISK_DELTA_PERCENT
+        })
+    }
+}
+
+fn load_avg() -> Option<[f64; 3]> {
+    let content = std::fs::read_to_string("/proc/loadavg").ok()?;
+    let mut fields = content.split_whitespace().map(|f| f.parse::<f64>().ok());
+    Some([fields.next()??, fields.next()??, fields.next()??])
+}
+
+/// `/proc/meminfo` value in bytes.
+fn meminfo_field(meminfo: &str, key: &str) -> u64 {
+    meminfo
+        .lines()
+        .find_map(|line| line.strip_prefix(key)?.strip_prefix(':'))
+        .and_then(|v| v.trim().trim_end_matches("kB").trim().parse::<u64>().ok())
+        .map_or(0, |kb| kb * 1024)
+}
+
+fn uptime_secs() -> u64 {
+    std::fs::read_to_string("/proc/uptime")
+        .ok()
+        .and_then(|s| s.split_whitespace().next()?.parse::<f64>().ok())
+        .map_or(0, |secs| secs as u64)
+}
+
+/// Filesystem id and usage of the filesystem holding `path`.
+fn statvfs(path: &Path) -> Option<(u64, DiskUsage)> {
+    let c_path = CString::new(path.as_os_str().as_bytes()).ok()?;
+    let mut stat: libc::statvfs = unsafe { std::mem::zeroed() };
+    if unsafe { libc::statvfs(c_path.as_ptr(), &mut stat) } != 0 {
+        return None;
+    }
+    let frsize = stat.f_frsize as u64;
+    Some((
+        stat.f_fsid as u64,
+        DiskUsage {
+            path: path.to_string_lossy().to_string(),
+            total: stat.f_blocks as u64 * frsize,
+            available: stat.f_bavail as u64 * frsize,
+        },
+    ))
+}
+
+/// Take a sample. Paths on the same filesystem are reported once.
+pub fn sample(paths: &[PathBuf]) -> Sample {
+    let meminfo = std::fs::read_to_string("/proc/meminfo").unwrap_or_default();
+    let mut seen = Vec::new();
+    let mut disks = Vec::new();
+    for path in paths {
+        if let Some((fsid, usage)) = statvfs(path) {
+            if !seen.contains(&fsid) {
+                seen.push(fsid);
+                disks.push(usage);
+            }
+        }
+    }
+    Sample {
+        sampled_at: crate::state::now_millis(),
+        load_avg: load_avg().unwrap_or_default(),
+        mem_total: meminfo_field(&meminfo, "MemTotal"),
+        mem_available: meminfo_field(&meminfo, "MemAvailable"),
+        swap_total: meminfo_field(&meminfo, "SwapTotal"),
+        swap_free: meminfo_field(&meminfo, "SwapFree"),
+        uptime_secs: uptime_secs(),
+        disks,
+    }
+}
+
+/// Sample every `interval` and publish changes for the lifetime of one connection.
+pub async fn run(
+    client: SocketClient,
+    runner: Arc<Mutex<RunnerState>>,
+    paths: Vec<PathBuf>,
+    interval: Duration,
+) {
+    // The connect already published the last sample; start one interval later
+    let mut tick = tokio::time::interval_at(tokio::time::Instant::now() + interval, interval);
+    let mut last_sent: Option<Sample> = None;
+    let mut skipped = 0;
+    loop {
+        tick.tick().await;
+        let sample = sample(&paths);
+        let send = match &last_sent {
+            Some(prev) => skipped + 1 >= HEARTBEAT_SAMPLES || sample.differs(prev),
+            None => true,
+        };
+        if !send {
+            skipped += 1;
+            continue;
+        }
+
+        let mut runner = runner.lock().await;
+        match serde_json::to_value(&sample) {
+            Ok(value) => runner.set_telemetry(value),
+            Err(e) => {
+                log::warn!("Failed to encode telemetry: {}", e);
+                continue;
+            }
+        }
+        match runner.publish(&client).await {
+            Ok(()) => {
+                last_sent = Some(sample);
+                skipped = 0;
+            }
+            Err(e) => log::warn!("Failed to publish telemetry: {}", e),
+        }
+    }
+}
@@ -1,173 +1,685 @@
 use base64::engine::general_purpose::STANDARD as B64;
 use base64::Engine;
-use serde_json::json;
-use std::collections::HashMap;
+use futures_util::stream::{FuturesUnordered, StreamExt};
+use std::collections::{BTreeMap, HashMap};
+use std::io;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
 use tokio::io::{AsyncReadExt, AsyncWriteExt};
 use tokio::net::TcpStream;
-use tokio::sync::mpsc;
+use tokio::sync::{mpsc, watch, Notify};
 use tokio::task::JoinHandle;
+use tokio::time::{sleep, timeout, Instant};
 
+use crate::config::Config;
 use crate::connection::SocketEvent;
+use crate::events;
+use crate::logging;
 use crate::socket::SocketClient;
+use crate::state::{RuntimeState, TunnelCounters};
+
+/// Delay before racing the next resolved address (RFC 8305 "Connection Attempt Delay").
+const ATTEMPT_DELAY: Duration = Duration::from_millis(250);
+/// How often a drain checks for tunnels that have finished.
+const DRAIN_CHECK: Duration = Duration::from_millis(100);
+/// Bytes from the hub a tunnel may have waiting for its target before it
+/// is closed with `ENOBUFS`.
+const TUNNEL_BUFFER: usize = 8 * 1024 * 1024;
+/// How long a tunnel the hub closed may keep writing what it already got.
+const CLOSE_FLUSH: Duration = Duration::from_secs(5);
+
+/// Machine-readable reason sent as `code` in `tunnel:error`.
+/// Values follow Node's errno names so the web UI can treat both runners alike.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum ErrorCode {
+    ConnectionRefused,
+    NotFound,
+    /// The resolver failed for now; the name may well exist
+    TryAgain,
+    TimedOut,
+    HostUnreachable,
+    NetworkUnreachable,
+    ConnectionReset,
+    ConnectionAborted,
+    AddrNotAvailable,
+    PermissionDenied,
+    BrokenPipe,
+    PolicyDenied,
+    /// The target fell too far behind the hub
+    NoBufferSpace,
+    /// Refused while draining for shutdown
+    ShuttingDown,
+    Other,
+}
+
+impl ErrorCode {
+    fn as_str(self) -> &'static str {
+        match self {
+            ErrorCode::ConnectionRefused => "ECONNREFUSED",
+            ErrorCode::NotFound => "ENOTFOUND",
+            ErrorCode::TryAgain => "EAI_AGAIN",
+            ErrorCode::TimedOut => "ETIMEDOUT",
+            ErrorCode::HostUnreachable => "EHOSTUNREACH",
+            ErrorCode::NetworkUnreachable => "ENETUNREACH",
+            ErrorCode::ConnectionReset => "ECONNRESET",
+            ErrorCode::ConnectionAborted => "ECONNABORTED",
+            ErrorCode::AddrNotAvailable => "EADDRNOTAVAIL",
+            ErrorCode::PermissionDenied => "EACCES",
+            ErrorCode::BrokenPipe => "EPIPE",
+            ErrorCode::PolicyDenied => "EPOLICY",
+            ErrorCode::NoBufferSpace => "ENOBUFS",
+            ErrorCode::ShuttingDown => "ESHUTDOWN",
+            ErrorCode::Other => "EIO",
+        }
+    }
+
+    fn from_io(e: &io::Error) -> Self {
+        match e.kind() {
+            io::ErrorKind::ConnectionRefused => ErrorCode::ConnectionRefused,
+            io::ErrorKind::TimedOut => ErrorCode::TimedOut,
+            io::ErrorKind::HostUnreachable => ErrorCode::HostUnreachable,
+            io::ErrorKind::NetworkUnreachable => ErrorCode::NetworkUnreachable,
+            io::ErrorKind::ConnectionReset => ErrorCode::ConnectionReset,
+            io::ErrorKind::ConnectionAborted => ErrorCode::ConnectionAborted,
+            io::ErrorKind::AddrNotAvailable => ErrorCode::AddrNotAvailable,
+            io::ErrorKind::PermissionDenied => ErrorCode::PermissionDenied,
+            io::ErrorKind::BrokenPipe => ErrorCode::BrokenPipe,
+            _ => ErrorCode::Other,
+        }
+    }
+
+    /// Like Node, a temporary resolver failure is `EAI_AGAIN` and anything
+    /// else `ENOTFOUND`. std keeps only `gai_strerror`'s text, so compare that.
+    fn from_lookup(e: &io::Error) -> Self {
+        // SAFETY: gai_strerror returns a static NUL-terminated string
+        let again = unsafe { std::ffi::CStr::from_ptr(libc::gai_strerror(libc::EAI_AGAIN)) };
+        let temporary = e.kind() == io::ErrorKind::TimedOut
+            || e.raw_os_error() == Some(libc::EAGAIN)
+            || again.to_str().is_ok_and(|again| e.to_string().ends_with(again));
+        if temporary {
+            ErrorCode::TryAgain
+        } else {
+            ErrorCode::NotFound
+        }
+    }
+
+    /// Rank used to pick the most telling error when every address failed.
+    /// A refusal from a reachable host says more than an unreachable route.
+    fn specificity(self) -> u8 {
+        match self {
+            ErrorCode::ConnectionRefused => 5,
+            ErrorCode::ConnectionReset | ErrorCode::ConnectionAborted => 4,
+            ErrorCode::PermissionDenied => 3,
+            ErrorCode::HostUnreachable | ErrorCode::NetworkUnreachable => 2,
+            ErrorCode::AddrNotAvailable | ErrorCode::TimedOut => 1,
+            _ => 0,
+        }
+    }
+}
+
+#[derive(Debug)]
+struct TunnelError {
+    code: ErrorCode,
+    message: String,
+}
+
+impl TunnelError {
+    /// `syscall` mirrors Node's message prefix, e.g. `connect ECONNREFUSED 127.0.0.1:80`.
+    fn from_io(syscall: &str, target: &str, e: &io::Error) -> Self {
+        let code = ErrorCode::from_io(e);
+        TunnelError {
+            code,
+            message: format!("{} {} {} ({})", syscall, code.as_str(), target, e),
+        }
+    }
+}
+
+/// Totals across all tunnels, for the metrics endpoint.
+#[derive(Default)]
+pub struct TunnelMetrics {
+    pub bytes_in: AtomicU64,
+    pub bytes_out: AtomicU64,
+    /// Tunnels closed because their target fell behind.
+    pub overflows: AtomicU64,
+    /// Refused or failed `tunnel:open`s by `tunnel:error` code.
+    open_failures: Mutex<BTreeMap<&'static str, u64>>,
+}
+
+impl TunnelMetrics {
+    fn open_failed(&self, code: ErrorCode) {
+        *self.open_failures.lock().unwrap().entry(code.as_str()).or_default() += 1;
+    }
+
+    pub fn open_failures(&self) -> Vec<(&'static str, u64)> {
+        let failures = self.open_failures.lock().unwrap();
+        failures.iter().map(|(code, n)| (*code, *n)).collect()
+    }
+}
+
+/// Where `tunnel:data` goes: each tunnel's queue to its TCP write task,
+/// filled straight from the socket's read loop so chunks keep their order
+/// and a slow target holds up only its own tunnel. A route exists from
+/// `tunnel:open` on, so data sent before the target is connected waits in it.
+#[derive(Clone, Default)]
+pub struct Routes {
+    inner: Arc<Mutex<RouteTable>>,
+}
+
+#[derive(Default)]
+struct RouteTable {
+    next: u64,
+    routes: HashMap<String, Route>,
+}
+
+struct Route {
+    /// Tells a reopened tunnel from an earlier one with the same id.
+    generation: u64,
+    tx: mpsc::UnboundedSender<Vec<u8>>,
+    /// Bytes sent but not yet written to the target.
+    queued: Arc<AtomicUsize>,
+}
+
+/// The receiving end of a new route, for `tunnel::run` to hand to the
+/// tunnel's TCP write task.
+#[derive(Debug)]
+pub struct Inbox {
+    generation: u64,
+    rx: mpsc::UnboundedReceiver<Vec<u8>>,
+    queued: Arc<AtomicUsize>,
+}
+
+/// What became of a `tunnel:data` chunk.
+pub enum Delivery {
+    Queued,
+    /// No open tunnel by that id, e.g. it was closed or refused.
+    NoTunnel,
+    /// Too much was already queued. The route is gone; the tunnel must be closed.
+    Overflow { generation: u64 },
+}
+
+impl Routes {
+    /// Start queuing data for `tunnel_id`, replacing any earlier route.
+    pub fn open(&self, tunnel_id: &str) -> Inbox {
+        let (tx, rx) = mpsc::unbounded_channel();
+        let queued = Arc::new(AtomicUsize::new(0));
+        let mut table = self.inner.lock().unwrap();
+        table.next += 1;
+        let generation = table.next;
+        let route = Route {
+            generation,
+            tx,
+            queued: queued.clone(),
+        };
+        table.routes.insert(tunnel_id.to_string(), route);
+        Inbox {
+            generation,
+            rx,
+            queued,
+        }
+    }
+
+    /// Queue `bytes` for the tunnel's target without waiting.
+    pub fn deliver(&self, tunnel_id: &str, bytes: Vec<u8>) -> Delivery {
+        let mut table = self.inner.lock().unwrap();
+        let Some(route) = table.routes.get(tunnel_id) else {
+            return Delivery::NoTunnel;
+        };
+        let len = bytes.len();
+        if route.queued.load(Ordering::Relaxed) + len > TUNNEL_BUFFER {
+            let generation = route.generation;
+            table.routes.remove(tunnel_id);
+            return Delivery::Overflow { generation };
+        }
+        // Counted before the write task can see it, so it never goes negative
+        route.queued.fetch_add(len, Ordering::Relaxed);
+        if route.tx.send(bytes).is_err() {
+            // The write task ended on a TCP error
+            table.routes.remove(tunnel_id);
+            return Delivery::NoTunnel;
+        }
+        Delivery::Queued
+    }
+
+    /// Stop queuing for `tunnel_id`. What is queued is still written.
+    pub fn close(&self, tunnel_id: &str) {
+        self.inner.lock().unwrap().routes.remove(tunnel_id);
+    }
+
+    /// Drop the route of one particular opening of `tunnel_id`.
+    fn remove(&self, tunnel_id: &str, generation: u64) {
+        let mut table = self.inner.lock().unwrap();
+        if table
+            .routes
+            .get(tunnel_id)
+            .is_some_and(|route| route.generation == generation)
+        {
+            table.routes.remove(tunnel_id);
+        }
+    }
+}
 
 struct TunnelHandle {
-    /// Send decoded bytes to the TCP write task.
-    write_tx: mpsc::Sender<Vec<u8>>,
+    id: String,
+    /// Which opening of `id` this is, to match `TunnelOverflow`.
+    generation: u64,
+    state: RuntimeState,
     /// Handle to abort the TCP read task.
     read_task: JoinHandle<()>,
-    /// Handle to abort the TCP write task.
-    write_task: JoinHandle<()>,
+    /// Handle to abort the TCP write task; taken by `close`.
+    write_task: Option<JoinHandle<()>>,
+}
+
+impl TunnelHandle {
+    /// Stop reading from the target, but let the write task finish what
+    /// the hub sent before closing, for up to `CLOSE_FLUSH`.
+    fn close(mut self) {
+        self.read_task.abort();
+        if let Some(mut write_task) = self.write_task.take() {
+            logging::spawn(async move {
+                if timeout(CLOSE_FLUSH, &mut write_task).await.is_err() {
+                    write_task.abort();
+                }
+            });
+        }
+    }
 }
 
 impl Drop for TunnelHandle {
     fn drop(&mut self) {
         self.read_task.abort();
-        self.write_task.abort();
+        if let Some(write_task) = &self.write_task {
+            write_task.abort();
+        }
+        self.state.remove_tunnel(&self.id);
     }
 }
 
+/// Why `run` returned.
+pub enum Ended {
+    Disconnected,
+    UnknownMachine,
+    /// Every tunnel finished or was closed after `drain` was notified.
+    Drained,
+}
+
+/// Serve tunnels for one connection. Once `drain` is notified, new tunnels
+/// are refused and open ones get up to `shutdown.drain` to finish before
+/// they are closed.
 pub async fn run(
-    mut event_rx: mpsc::Receiver<SocketEvent>,
+    mut event_rx: mpsc::UnboundedReceiver<SocketEvent>,
     client: SocketClient,
-    _machine_id: String,
-) {
+    routes: Routes,
+    state: RuntimeState,
+    config: watch::Receiver<Config>,
+    metrics: Arc<TunnelMetrics>,
+    drain: Arc<Notify>,
+) -> Ended {
     let mut tunnels: HashMap<String, TunnelHandle> = HashMap::new();
+    let mut deadline: Option<Instant> = None;
+    let mut check = tokio::time::interval(DRAIN_CHECK);
 
-    while let Some(event) = event_rx.recv().await {
+    loop {
+        let event = tokio::select! {
+            event = event_rx.recv() => match event {
+                Some(event) => event,
+                None => break,
+            },
+            _ = drain.notified(), if deadline.is_none() => {
+                let period = config.borrow().shutdown.drain;
+                log::info!(
+                    event = "drain";
+                    "Draining {} tunnels for up to {:?}", tunnels.len(), period
+                );
+                deadline = Some(Instant::now() + period);
+                continue;
+            }
+            _ = check.tick(), if deadline.is_some() => {
+                // A finished read task has already sent its tunnel:close
+                tunnels.retain(|_, handle| !handle.read_task.is_finished());
+                if !tunnels.is_empty() && deadline.is_some_and(|d| Instant::now() < d) {
+                    continue;
+                }
+                for (tunnel_id, handle) in tunnels.drain() {
+                    // Stop the read task before telling the hub
+                    drop(handle);
+                    log::info!(
+                        tunnel_id = tunnel_id.as_str(), event = "drain_close";
+                        "Tunnel {} still open after the drain, closing it", tunnel_id
+                    );
+                    let _ = client.emit_event(&events::TunnelClose { tunnel_id }).await;
+                }
+                state.clear_tunnels();
+                return Ended::Drained;
+            }
+        };
         match event {
-            SocketEvent::TunnelOpen { tunnel_id, host, port } => {
-                let target_host = host.as_deref().unwrap_or("127.0.0.1");
-                log::info!("Tunnel open: {} -> {}:{}", tunnel_id, target_host, port);
-                handle_tunnel_open(&mut tunnels, &client, tunnel_id, target_host, port).await;
+            SocketEvent::TunnelOpen { tunnel_id, inbox, .. } if deadline.is_some() => {
+                routes.remove(&tunnel_id, inbox.generation);
+                let err = TunnelError {
+                    code: ErrorCode::ShuttingDown,
+                    message: format!("{} happier is shutting down", ErrorCode::ShuttingDown.as_str()),
+                };
+                metrics.open_failed(err.code);
+                emit_error(&client, &tunnel_id, &err).await;
             }
-            SocketEvent::TunnelData { tunnel_id, data } => {
-                if let Some(handle) = tunnels.get(&tunnel_id) {
-                    match B64.decode(&data) {
-                        Ok(bytes) => {
-                            if handle.write_tx.send(bytes).await.is_err() {
-                                log::debug!("Tunnel {} TCP write channel closed", tunnel_id);
-                                tunnels.remove(&tunnel_id);
-                            }
-                        }
-                        Err(e) => {
-                            log::warn!("Tunnel {} base64 decode error: {}", tunnel_id, e);
-                        }
+            SocketEvent::TunnelOpen { tunnel_id, host, port, inbox } => {
+                let target_host = host.as_deref().unwrap_or("127.0.0.1");
+                // Reloads apply to tunnels opened from now on
+                let policy = config.borrow().tunnel.clone();
+                log::info!(
+                    tunnel_id = tunnel_id.as_str(), event = "open";
+                    "Tunnel open: {} -> {}:{}", tunnel_id, target_host, port
+                );
+                if let Err(reason) = policy.check(target_host, port) {
+                    log::warn!(
+                        tunnel_id = tunnel_id.as_str(), event = "denied";
+                        "Tunnel {} denied: {}", tunnel_id, reason
+                    );
+                    let err = TunnelError {
+                        code: ErrorCode::PolicyDenied,
+                        message: format!("policy {} {}", ErrorCode::PolicyDenied.as_str(), reason),
+                    };
+                    metrics.open_failed(err.code);
+                    routes.remove(&tunnel_id, inbox.generation);
+                    emit_error(&client, &tunnel_id, &err).await;
+                    continue;
+                }
+                let generation = inbox.generation;
+                let handle = open_tunnel(
+                    &client,
+                    &state,
+                    &metrics,
+                    tunnel_id.clone(),
+                    inbox,
+                    (target_host, port),
+                    policy.connect_timeout,
+                )
+                .await;
+                match handle {
+                    Some(handle) => {
+                        tunnels.insert(tunnel_id, handle);
                     }
+                    None => routes.remove(&tunnel_id, generation),
                 }
             }
+            SocketEvent::TunnelOverflow { tunnel_id, generation } => {
+                if tunnels.get(&tunnel_id).is_none_or(|h| h.generation != generation) {
+                    continue;
+                }
+                drop(tunnels.remove(&tunnel_id));
+                log::warn!(
+                    tunnel_id = tunnel_id.as_str(), event = "overflow";
+                    "Tunnel {} target fell {} bytes behind, closing it", tunnel_id, TUNNEL_BUFFER
+                );
+                metrics.overflows.fetch_add(1, Ordering::Relaxed);
+                let err = TunnelError {
+                    code: ErrorCode::NoBufferSpace,
+                    message: format!(
+                        "write {} more than {} bytes queued for the target",
+                        ErrorCode::NoBufferSpace.as_str(),
+                        TUNNEL_BUFFER
+                    ),
+                };
+                emit_error(&client, &tunnel_id, &err).await;
+            }
             SocketEvent::TunnelClose { tunnel_id } => {
-                log::info!("Tunnel close from hub: {}", tunnel_id);
-                tunnels.remove(&tunnel_id); // Drop triggers abort
+                log::info!(
+                    tunnel_id = tunnel_id.as_str(), event = "close";
+                    "Tunnel close from hub: {}", tunnel_id
+                );
+                if let Some(handle) = tunnels.remove(&tunnel_id) {
+                    handle.close();
+                }
             }
             SocketEvent::Disconnected => {
-                log::warn!("Socket.IO disconnected, closing {} tunnels", tunnels.len());
+                log::warn!(
+                    event = "disconnected";
+                    "Socket.IO disconnected, closing {} tunnels", tunnels.len()
+                );
                 tunnels.clear();
-                return; // Let main loop handle reconnect
+                state.clear_tunnels();
+                return Ended::Disconnected; // Let main loop handle reconnect
+            }
+            SocketEvent::UnknownMachine => {
+                log::warn!(
+                    event = "unknown_machine";
+                    "Hub does not know this machine, closing {} tunnels", tunnels.len()
+                );
+                tunnels.clear();
+                state.clear_tunnels();
+                return Ended::UnknownMachine;
             }
         }
     }
+    Ended::Disconnected
 }
 
-async fn handle_tunnel_open(
-    tunnels: &mut HashMap<String, TunnelHandle>,
+async fn open_tunnel(
     client: &SocketClient,
+    state: &RuntimeState,
+    metrics: &Arc<TunnelMetrics>,
     tunnel_id: String,
-    host: &str,
-    port: u16,
-) {
-    match TcpStream::connect((host, port)).await {
+    inbox: Inbox,
+    (host, port): (&str, u16),
+    connect_timeout: Duration,
+) -> Option<TunnelHandle> {
+    match connect_target(host, port, connect_timeout).await {
         Ok(stream) => {
             // Notify hub that TCP connection is ready
-            if let Err(e) = client
-                .emit("tunnel:ready", json!({ "tunnelId": &tunnel_id }))
-                .await
-            {
-                log::error!("Failed to emit tunnel:ready: {}", e);
-                return;
+            let ready = events::TunnelReady {
+                tunnel_id: &tunnel_id,
+            };
+            if let Err(e) = client.emit_event(&ready).await {
+                log::error!(
+                    tunnel_id = tunnel_id.as_str(), event = "emit_failed";
+                    "Failed to emit tunnel:ready: {}", e
+                );
+                return None;
             }
 
             let (tcp_read, tcp_write) = stream.into_split();
-            let (write_tx, write_rx) = mpsc::channel::<Vec<u8>>(256);
+            let counters = state.add_tunnel(&tunnel_id, format!("{}:{}", host, port));
 
             // Spawn TCP read task: reads from TCP, base64-encodes, emits tunnel:data
             let read_client = client.clone();
             let read_tid = tunnel_id.clone();
-            let read_task = tokio::spawn(async move {
-                tcp_read_loop(tcp_read, &read_client, &read_tid).await;
+            let read_counters = counters.clone();
+            let read_state = state.clone();
+            let read_metrics = metrics.clone();
+            let read_task = logging::spawn(async move {
+                tcp_read_loop(tcp_read, &read_client, &read_tid, &read_counters, &read_metrics)
+                    .await;
+                read_state.remove_tunnel(&read_tid);
             });
 
             // Spawn TCP write task: receives bytes from channel, writes to TCP
-            let write_task = tokio::spawn(async move {
-                tcp_write_loop(tcp_write, write_rx).await;
+            let write_metrics = metrics.clone();
+            let write_task = logging::spawn(async move {
+                tcp_write_loop(tcp_write, inbox.rx, &inbox.queued, &counters, &write_metrics)
+                    .await;
             });
 
-            tunnels.insert(
-                tunnel_id,
-                TunnelHandle {
-                    write_tx,
-                    read_task,
-                    write_task,
-                },
-            );
+            Some(TunnelHandle {
+                id: tunnel_id,
+                generation: inbox.generation,
+                state: state.clone(),
+                read_task,
+                write_task: Some(write_task),
+            })
         }
         Err(e) => {
-            log::error!("Tunnel {} TCP connect failed: {}", tunnel_id, e);
-            let _ = client
-                .emit(
-                    "tunnel:error",
-                    json!({
-                        "tunnelId": &tunnel_id,
-                        "message": format!("connect ECONNREFUSED {}:{}", host, port),
-                    }),
-                )
-                .await;
+            log::error!(
+                tunnel_id = tunnel_id.as_str(), event = "connect_failed";
+                "Tunnel {} TCP connect failed: {}", tunnel_id, e.message
+            );
+            metrics.open_failed(e.code);
+            emit_error(client, &tunnel_id, &e).await;
+            None
         }
     }
 }
 
+async fn emit_error(client: &SocketClient, tunnel_id: &str, e: &TunnelError) {
+    let error = events::TunnelError {
+        tunnel_id,
+        code: e.code.as_str(),
+        message: &e.message,
+    };
+    let _ = client.emit_event(&error).await;
+}
+
+/// Resolve `host` and connect to it within `connect_timeout`.
+async fn connect_target(
+    host: &str,
+    port: u16,
+    connect_timeout: Duration,
+) -> Result<TcpStream, TunnelError> {
+    let deadline = Instant::now() + connect_timeout;
+    let target = format!("{}:{}", host, port);
+    let timed_out = || TunnelError {
+        code: ErrorCode::TimedOut,
+        message: format!(
+            "connect {} {} (no response within {:?})",
+            ErrorCode::TimedOut.as_str(),
+            target,
+            connect_timeout
+        ),
+    };
+
+    let addrs = match host.parse::<IpAddr>() {
+        Ok(ip) => vec![SocketAddr::new(ip, port)],
+        Err(_) => match timeout(connect_timeout, tokio::net::lookup_host((host, port))).await {
+            Ok(Ok(resolved)) => interleave_families(resolved.collect()),
+            Ok(Err(e)) => {
+                let code = ErrorCode::from_lookup(&e);
+                return Err(TunnelError {
+                    code,
+                    message: format!("getaddrinfo {} {} ({})", code.as_str(), host, e),
+                });
+            }
+            Err(_) => return Err(timed_out()),
+        },
+    };
+    if addrs.is_empty() {
+        return Err(TunnelError {
+            code: ErrorCode::NotFound,
+            message: format!("getaddrinfo {} {} (no addresses)", ErrorCode::NotFound.as_str(), host),
+        });
+    }
+
+    match tokio::time::timeout_at(deadline, race_connect(&addrs)).await {
+        Ok(result) => result,
+        Err(_) => Err(timed_out()),
+    }
+}
+
+/// Happy-eyeballs connect: start with the first address and race the next one
+/// every `ATTEMPT_DELAY` (or as soon as an attempt fails). The first success wins.
+async fn race_connect(addrs: &[SocketAddr]) -> Result<TcpStream, TunnelError> {
+    let mut pending = addrs.iter().copied();
+    let mut attempts = FuturesUnordered::new();
+    let mut best: Option<TunnelError> = None;
+
+    let start = |addr: SocketAddr| async move { (addr, TcpStream::connect(addr).await) };
+    if let Some(addr) = pending.next() {
+        attempts.push(start(addr));
+    }
+
+    while !attempts.is_empty() {
+        let next_attempt = sleep(ATTEMPT_DELAY);
+        tokio::select! {
+            Some((addr, result)) = attempts.next() => match result {
+                Ok(stream) => return Ok(stream),
+                Err(e) => {
+                    log::debug!("Connect to {} failed: {}", addr, e);
+                    let err = TunnelError::from_io("connect", &addr.to_string(), &e);
+                    if best.as_ref().is_none_or(|b| err.code.specificity() >= b.code.specificity()) {
+                        best = Some(err);
+                    }
+                    if let Some(addr) = pending.next() {
+                        attempts.push(start(addr));
+                    }
+                }
+            },
+            _ = next_attempt, if pending.len() > 0 => {
+                if let Some(addr) = pending.next() {
+                    attempts.push(start(addr));
+                }
+            }
+        }
+    }
+
+    Err(best.unwrap_or(TunnelError {
+        code: ErrorCode::Other,
+        message: "connect: no addresses attempted".to_string(),
+    }))
+}
+
+/// Order addresses by alternating families, starting with whichever family
+/// the resolver listed first (RFC 8305 section 4).
+fn interleave_families(addrs: Vec<SocketAddr>) -> Vec<SocketAddr> {
+    let Some(first) = addrs.first() else {
+        return addrs;
+    };
+    let prefer_v6 = first.is_ipv6();
+    let (preferred, other): (Vec<_>, Vec<_>) =
+        addrs.into_iter().partition(|a| a.is_ipv6() == prefer_v6);
+    let total = preferred.len() + other.len();
+    let mut out = Vec::with_capacity(total);
+    let mut p = preferred.into_iter();
+    let mut o = other.into_iter();
+    while out.len() < total {
+        out.extend(p.next().into_iter().chain(o.next()));
+    }
+    out
+}
+
 async fn tcp_read_loop(
     mut tcp_read: tokio::net::tcp::OwnedReadHalf,
     client: &SocketClient,
     tunnel_id: &str,
+    counters: &TunnelCounters,
+    metrics: &TunnelMetrics,
 ) {
     let mut buf = [0u8; 16384];
     loop {
         match tcp_read.read(&mut buf).await {
             Ok(0) => {
                 // EOF — TCP connection closed
-                log::debug!("Tunnel {} TCP EOF", tunnel_id);
-                let _ = client
-                    .emit("tunnel:close", json!({ "tunnelId": tunnel_id }))
-                    .await;
+                log::debug!(tunnel_id, event = "eof"; "Tunnel {} TCP EOF", tunnel_id);
+                let close = events::TunnelClose {
+                    tunnel_id: tunnel_id.to_string(),
+                };
+                let _ = client.emit_event(&close).await;
                 break;
             }
             Ok(n) => {
-                let b64 = B64.encode(&buf[..n]);
-                if let Err(e) = client
-                    .emit(
-                        "tunnel:data",
-                        json!({
-                            "tunnelId": tunnel_id,
-                            "data": b64,
-                        }),
-                    )
-                    .await
-                {
-                    log::warn!("Tunnel {} failed to emit data: {}", tunnel_id, e);
+                let data = events::TunnelData {
+                    tunnel_id: tunnel_id.to_string(),
+                    data: B64.encode(&buf[..n]),
+                };
+                if let Err(e) = client.emit_event(&data).await {
+                    log::warn!(
+                        tunnel_id, event = "emit_failed";
+                        "Tunnel {} failed to emit data: {}", tunnel_id, e
+                    );
                     break;
                 }
+                counters.bytes_out.fetch_add(n as u64, Ordering::Relaxed);
+                metrics.bytes_out.fetch_add(n as u64, Ordering::Relaxed);
             }
             Err(e) => {
-                log::debug!("Tunnel {} TCP read error: {}", tunnel_id, e);
-                let _ = client
-                    .emit(
-                        "tunnel:error",
-                        json!({
-                            "tunnelId": tunnel_id,
-                            "message": e.to_string(),
-                        }),
-                    )
-                    .await;
+                log::debug!(
+                    tunnel_id, event = "read_error";
+                    "Tunnel {} TCP read error: {}", tunnel_id, e
+                );
+                let peer = tcp_read
+                    .peer_addr()
+                    .map(|a| a.to_string())
+                    .unwrap_or_default();
+                emit_error(client, tunnel_id, &TunnelError::from_io("read", &peer, &e)).await;
                 break;
             }
         }
@@ -176,12 +688,40 @@ async fn tcp_read_loop(
 
 async fn tcp_write_loop(
     mut tcp_write: tokio::net::tcp::OwnedWriteHalf,
-    mut write_rx: mpsc::Receiver<Vec<u8>>,
+    mut write_rx: mpsc::UnboundedReceiver<Vec<u8>>,
+    queued: &AtomicUsize,
+    counters: &TunnelCounters,
+    metrics: &TunnelMetrics,
 ) {
     while let Some(bytes) = write_rx.recv().await {
         if let Err(e) = tcp_write.write_all(&bytes).await {
             log::debug!("TCP write error: {}", e);
             break;
         }
+        queued.fetch_sub(bytes.len(), Ordering::Relaxed);
+        counters.bytes_in.fetch_add(bytes.len() as u64, Ordering::Relaxed);
+        metrics.bytes_in.fetch_add(bytes.len() as u64, Ordering::Relaxed);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn gai_error(code: libc::c_int) -> io::Error {
+        let text = unsafe { std::ffi::CStr::from_ptr(libc::gai_strerror(code)) };
+        // As std reports resolver failures
+        io::Error::other(format!("failed to lookup address information: {}", text.to_str().unwrap()))
+    }
+
+    #[test]
+    fn temporary_resolver_failures_are_eai_again() {
+        assert_eq!(ErrorCode::from_lookup(&gai_error(libc::EAI_AGAIN)), ErrorCode::TryAgain);
+        assert_eq!(ErrorCode::from_lookup(&gai_error(libc::EAI_NONAME)), ErrorCode::NotFound);
+        assert_eq!(ErrorCode::from_lookup(&gai_error(libc::EAI_FAIL)), ErrorCode::NotFound);
+        assert_eq!(
+            ErrorCode::from_lookup(&io::Error::from_raw_os_error(libc::EAGAIN)),
+            ErrorCode::TryAgain
+        );
     }
 }
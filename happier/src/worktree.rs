@@ -0,0 +1,661 @@
+//! Git worktree RPCs, so parallel sessions on one repository each get their
+//! own checkout. Same layout as the Node runner's `worktree.ts`: worktrees
+//! live in `<repo>-worktrees/<name>` next to the main checkout, on branch
+//! `hapi-<name>` unless the caller names one.
+//!
+//! Every handler replies `{ "ok": true, ... }` or
+//! `{ "ok": false, "code": "...", "error": "..." }`, where `code` is stable
+//! (`dirty`, `branch-exists`, ...) and `error` is for people.
+
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, LazyLock};
+use std::time::Duration;
+
+use serde::Deserialize;
+use serde_json::{json, Value};
+
+use crate::config::SandboxPolicy;
+use crate::rpc::RpcHandlers;
+use crate::sandbox::{self, Sandbox};
+
+const GIT_TIMEOUT: Duration = Duration::from_secs(60);
+const MAX_ATTEMPTS: usize = 5;
+
+/// One lock per repository: `git worktree add` checks and creates branches
+/// non-atomically, so two sessions racing on one repo could collide.
+static REPO_LOCKS: LazyLock<std::sync::Mutex<HashMap<PathBuf, Arc<tokio::sync::Mutex<()>>>>> =
+    LazyLock::new(Default::default);
+
+struct Failure {
+    code: &'static str,
+    message: String,
+}
+
+fn fail(code: &'static str, message: impl Into<String>) -> Failure {
+    Failure {
+        code,
+        message: message.into(),
+    }
+}
+
+fn reply(result: Result<Value, Failure>) -> Value {
+    match result {
+        Ok(mut value) => {
+            value["ok"] = json!(true);
+            value
+        }
+        Err(failure) => json!({
+            "ok": false,
+            "code": failure.code,
+            "error": failure.message,
+        }),
+    }
+}
+
+fn parse<T: serde::de::DeserializeOwned>(params: Value) -> Result<T, Failure> {
+    serde_json::from_value(params).map_err(|e| fail("invalid-params", e.to_string()))
+}
+
+pub fn register(rpc: &mut RpcHandlers) {
+    rpc.register("worktree-create", |params| async move {
+        reply(async { create(parse(params)?).await }.await)
+    });
+    rpc.register("worktree-list", |params| async move {
+        reply(async { list(parse(params)?).await }.await)
+    });
+    rpc.register("worktree-remove", |params| async move {
+        reply(async { remove(parse(params)?).await }.await)
+    });
+    rpc.register("worktree-prune", |params| async move {
+        reply(async { prune(parse(params)?).await }.await)
+    });
+}
+
+/// Worktree created for a session started with `sessionType: "worktree"`.
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SessionWorktree {
+    pub base_path: PathBuf,
+    pub worktree_path: PathBuf,
+    pub branch: String,
+    pub name: String,
+    pub created_at: u64,
+}
+
+pub async fn create_for_session(
+    base_path: &Path,
+    name: Option<String>,
+) -> Result<SessionWorktree, String> {
+    let mut value = create(CreateParams {
+        base_path: base_path.to_path_buf(),
+        name,
+        branch: None,
+        new_branch: None,
+        start_point: None,
+    })
+    .await
+    .map_err(|f| f.message)?;
+    serde_json::from_value(value["info"].take()).map_err(|e| e.to_string())
+}
+
+/// Remove a session worktree, with its branch, when the session never started.
+pub async fn discard(worktree: &SessionWorktree) {
+    let result = remove(RemoveParams {
+        base_path: worktree.base_path.clone(),
+        worktree_path: worktree.worktree_path.clone(),
+        force: true,
+        delete_branch: true,
+    })
+    .await;
+    if let Err(f) = result {
+        log::warn!("Failed to remove worktree {}: {}", worktree.worktree_path.display(), f.message);
+    }
+}
+
+/// The repository's shared git directory, when `dir` is inside one.
+pub async fn git_common_dir(dir: &Path) -> Option<PathBuf> {
+    let out = git(&["rev-parse", "--path-format=absolute", "--git-common-dir"], dir)
+        .await
+        .ok()?;
+    Some(PathBuf::from(out.trim()))
+}
+
+/// Run git and return stdout, or git's own error message.
+async fn git(args: &[&str], cwd: &Path) -> Result<String, String> {
+    git_output(args, cwd).await.map(|(stdout, _)| stdout)
+}
+
+/// Run git and return stdout and stderr, or git's own error message, in
+/// the sandbox of `cwd`'s policy if it has one.
+async fn git_output(args: &[&str], cwd: &Path) -> Result<(String, String), String> {
+    let sandbox = match sandbox::policy_for(cwd) {
+        Some(policy) => Some(git_sandbox(policy, cwd).await?),
+        None => None,
+    };
+    run_git(args, cwd, sandbox.as_ref()).await
+}
+
+/// The sandbox for git under `policy`. Besides the policy's directory, git
+/// may write the repository's git directory and the `<repo>-worktrees`
+/// next to its checkout, both of which can lie outside that directory.
+async fn git_sandbox(mut policy: SandboxPolicy, cwd: &Path) -> Result<Sandbox, String> {
+    // git refuses to run when it can't read the user's config
+    let home = PathBuf::from(std::env::var("HOME").unwrap_or_default());
+    policy.read_only.extend([home.join(".gitconfig"), home.join(".config/git")]);
+    let mut writable = vec![policy.directory.clone()];
+    let probe = Sandbox::new(&policy, &writable)?;
+    let args = ["rev-parse", "--path-format=absolute", "--git-common-dir"];
+    if let Ok((out, _)) = run_git(&args, cwd, Some(&probe)).await {
+        let common_dir = PathBuf::from(out.trim());
+        // A bare repository is its own git directory
+        let checkout = match common_dir.file_name() {
+            Some(name) if name == ".git" => common_dir.parent().map(Path::to_path_buf),
+            _ => None,
+        };
+        writable.extend(checkout.map(|c| worktrees_root(&c)));
+        writable.push(common_dir);
+    }
+    // Landlock can only grant what exists; create makes the worktrees root first
+    writable.retain(|path| path.exists());
+    Sandbox::new(&policy, &writable)
+}
+
+async fn run_git(
+    args: &[&str],
+    cwd: &Path,
+    sandbox: Option<&Sandbox>,
+) -> Result<(String, String), String> {
+    let mut command = tokio::process::Command::new("git");
+    command
+        .args(args)
+        .current_dir(cwd)
+        .env("GIT_TERMINAL_PROMPT", "0")
+        // Stable, parseable messages
+        .env("LC_ALL", "C")
+        .stdin(std::process::Stdio::null())
+        .kill_on_drop(true);
+    if let Some(sandbox) = sandbox {
+        sandbox.apply(&mut command);
+    }
+    let output = command.output();
+    let output = match tokio::time::timeout(GIT_TIMEOUT, output).await {
+        Ok(Ok(output)) => output,
+        Ok(Err(e)) => return Err(format!("failed to run git: {}", e)),
+        Err(_) => return Err(format!("git {} timed out", args.join(" "))),
+    };
+    let stdout = String::from_utf8_lossy(&output.stdout).into_owned();
+    let stderr = String::from_utf8_lossy(&output.stderr).into_owned();
+    if output.status.success() {
+        return Ok((stdout, stderr));
+    }
+    let message = [stderr.trim(), stdout.trim()]
+        .into_iter()
+        .find(|m| !m.is_empty())
+        .unwrap_or("git command failed");
+    Err(message.to_string())
+}
+
+#[derive(Debug, Clone)]
+struct Worktree {
+    path: PathBuf,
+    head: Option<String>,
+    branch: Option<String>,
+    bare: bool,
+    detached: bool,
+    locked: bool,
+    prunable: bool,
+}
+
+impl Worktree {
+    fn to_json(&self, main: bool) -> Value {
+        json!({
+            "path": self.path,
+            "head": self.head,
+            "branch": self.branch,
+            "bare": self.bare,
+            "detached": self.detached,
+            "locked": self.locked,
+            "prunable": self.prunable,
+            "main": main,
+        })
+    }
+}
+
+/// Parse `git worktree list --porcelain`. The main worktree comes first.
+fn parse_worktrees(porcelain: &str) -> Vec<Worktree> {
+    let mut worktrees = Vec::new();
+    for block in porcelain.split("\n\n") {
+        let mut worktree: Option<Worktree> = None;
+        for line in block.lines() {
+            let (key, value) = line.split_once(' ').unwrap_or((line, ""));
+            if key == "worktree" {
+                worktree = Some(Worktree {
+                    path: PathBuf::from(value),
+                    head: None,
+                    branch: None,
+                    bare: false,
+                    detached: false,
+                    locked: false,
+                    prunable: false,
+                });
+                continue;
+            }
+            let Some(w) = worktree.as_mut() else {
+                continue;
+            };
+            match key {
+                "HEAD" => w.head = Some(value.to_string()),
+                "branch" => {
+                    w.branch = Some(value.strip_prefix("refs/heads/").unwrap_or(value).to_string())
+                }
+                "bare" => w.bare = true,
+                "detached" => w.detached = true,
+                "locked" => w.locked = true,
+                "prunable" => w.prunable = true,
+                _ => {}
+            }
+        }
+        worktrees.extend(worktree);
+    }
+    worktrees
+}
+
+/// All worktrees of the repository containing `base_path`, main first.
+async fn worktrees(base_path: &Path) -> Result<Vec<Worktree>, Failure> {
+    if !base_path.is_dir() {
+        return Err(fail(
+            "not-found",
+            format!("{} is not a directory", base_path.display()),
+        ));
+    }
+    let out = git(&["worktree", "list", "--porcelain"], base_path)
+        .await
+        .map_err(|e| fail("not-a-repo", format!("Path is not a Git repository: {}", e)))?;
+    let worktrees = parse_worktrees(&out);
+    if worktrees.is_empty() {
+        return Err(fail("not-a-repo", "git listed no worktrees"));
+    }
+    Ok(worktrees)
+}
+
+async fn lock_repo(repo_root: &Path) -> tokio::sync::OwnedMutexGuard<()> {
+    let lock = REPO_LOCKS
+        .lock()
+        .unwrap_or_else(|e| e.into_inner())
+        .entry(repo_root.to_path_buf())
+        .or_default()
+        .clone();
+    lock.lock_owned().await
+}
+
+async fn branch_exists(repo_root: &Path, branch: &str) -> bool {
+    git(
+        &["show-ref", "--verify", "--quiet", &format!("refs/heads/{}", branch)],
+        repo_root,
+    )
+    .await
+    .is_ok()
+}
+
+fn slug(value: &str) -> String {
+    let mut slug = String::new();
+    for c in value.trim().to_lowercase().chars() {
+        if c.is_ascii_alphanumeric() {
+            slug.push(c);
+        } else if !slug.ends_with('-') {
+            slug.push('-');
+        }
+    }
+    slug.trim_matches('-').to_string()
+}
+
+fn random_suffix() -> String {
+    uuid::Uuid::new_v4().simple().to_string()[..4].to_string()
+}
+
+/// `MMDD-xxxx` in local time, like the Node runner.
+fn default_name() -> String {
+    let now = unsafe { libc::time(std::ptr::null_mut()) };
+    let mut tm: libc::tm = unsafe { std::mem::zeroed() };
+    unsafe { libc::localtime_r(&now, &mut tm) };
+    format!("{:02}{:02}-{}", tm.tm_mon + 1, tm.tm_mday, random_suffix())
+}
+
+fn same_path(a: &Path, b: &Path) -> bool {
+    match (std::fs::canonicalize(a), std::fs::canonicalize(b)) {
+        (Ok(a), Ok(b)) => a == b,
+        _ => a == b,
+    }
+}
+
+/// Where the worktrees of the checkout at `repo_root` are created.
+fn worktrees_root(repo_root: &Path) -> PathBuf {
+    let repo_name = repo_root
+        .file_name()
+        .map(|n| n.to_string_lossy().into_owned())
+        .unwrap_or_else(|| "repo".to_string());
+    repo_root.with_file_name(format!("{}-worktrees", repo_name))
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct CreateParams {
+    base_path: PathBuf,
+    /// Directory name hint; slugified
+    name: Option<String>,
+    /// Check out this existing branch
+    branch: Option<String>,
+    /// Create this branch
+    new_branch: Option<String>,
+    /// Where `new_branch` (or the default branch) starts; defaults to HEAD
+    start_point: Option<String>,
+}
+
+async fn create(params: CreateParams) -> Result<Value, Failure> {
+    if params.branch.is_some() && params.new_branch.is_some() {
+        return Err(fail("invalid-params", "pass either branch or newBranch, not both"));
+    }
+    // git would take these for options
+    let refs = [&params.branch, &params.new_branch, &params.start_point];
+    if let Some(value) = refs.into_iter().flatten().find(|v| v.starts_with('-')) {
+        return Err(fail("invalid-params", format!("{} is not a branch or commit", value)));
+    }
+    let existing = worktrees(&params.base_path).await?;
+    let repo_root = existing[0].path.clone();
+    let _guard = lock_repo(&repo_root).await;
+    // Re-read under the lock: another request may have just added one
+    let existing = worktrees(&repo_root).await?;
+
+    if let Some(branch) = &params.branch {
+        if !branch_exists(&repo_root, branch).await {
+            return Err(fail("branch-not-found", format!("branch {} does not exist", branch)));
+        }
+        if let Some(w) = existing.iter().find(|w| w.branch.as_deref() == Some(branch)) {
+            return Err(fail(
+                "branch-in-use",
+                format!("branch {} is already checked out at {}", branch, w.path.display()),
+            ));
+        }
+    }
+    if let Some(branch) = &params.new_branch {
+        if git(&["check-ref-format", "--branch", branch], &repo_root).await.is_err() {
+            return Err(fail("invalid-branch", format!("{} is not a valid branch name", branch)));
+        }
+        if branch_exists(&repo_root, branch).await {
+            return Err(fail("branch-exists", format!("branch {} already exists", branch)));
+        }
+    }
+
+    let worktrees_root = worktrees_root(&repo_root);
+    std::fs::create_dir_all(&worktrees_root)
+        .map_err(|e| fail("io", format!("{}: {}", worktrees_root.display(), e)))?;
+
+    let hint = params
+        .name
+        .as_deref()
+        .or(params.branch.as_deref())
+        .or(params.new_branch.as_deref())
+        .map(slug)
+        .filter(|s| !s.is_empty());
+    let base_name = hint.unwrap_or_else(default_name);
+
+    for attempt in 0..MAX_ATTEMPTS {
+        let name = match attempt {
+            0 => base_name.clone(),
+            _ => format!("{}-{}", base_name, random_suffix()),
+        };
+        let path = worktrees_root.join(&name);
+        if path.exists() {
+            continue;
+        }
+
+        // `worktree add [-b <new>] -- <path> [<branch>|<start-point>]`
+        let (branch, create) = match (&params.branch, &params.new_branch) {
+            (Some(branch), _) => (branch.clone(), false),
+            (None, Some(branch)) => (branch.clone(), true),
+            (None, None) => {
+                let branch = format!("hapi-{}", name);
+                if branch_exists(&repo_root, &branch).await {
+                    continue;
+                }
+                (branch, true)
+            }
+        };
+        let path_str = path.to_string_lossy().into_owned();
+        let mut args = vec!["worktree", "add"];
+        if create {
+            args.extend(["-b", &branch, "--", &path_str]);
+            args.extend(params.start_point.as_deref());
+        } else {
+            args.extend(["--", path_str.as_str(), branch.as_str()]);
+        }
+
+        git(&args, &repo_root)
+            .await
+            .map_err(|e| fail("git", format!("Failed to create worktree: {}", e)))?;
+        log::info!("Created worktree {} on {}", path.display(), branch);
+        return Ok(json!({
+            "info": {
+                "basePath": repo_root,
+                "worktreePath": path,
+                "branch": branch,
+                "name": name,
+                "createdAt": crate::state::now_millis(),
+            }
+        }));
+    }
+    Err(fail(
+        "exhausted",
+        "Failed to create worktree after multiple attempts. Try again.",
+    ))
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct ListParams {
+    base_path: PathBuf,
+}
+
+async fn list(params: ListParams) -> Result<Value, Failure> {
+    let worktrees = worktrees(&params.base_path).await?;
+    let list: Vec<Value> = worktrees
+        .iter()
+        .enumerate()
+        .map(|(i, w)| w.to_json(i == 0))
+        .collect();
+    Ok(json!({ "basePath": worktrees[0].path, "worktrees": list }))
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct RemoveParams {
+    base_path: PathBuf,
+    worktree_path: PathBuf,
+    /// Discard uncommitted changes and ignore locks
+    #[serde(default)]
+    force: bool,
+    /// Also delete the worktree's branch
+    #[serde(default)]
+    delete_branch: bool,
+}
+
+async fn remove(params: RemoveParams) -> Result<Value, Failure> {
+    let existing = worktrees(&params.base_path).await?;
+    let repo_root = existing[0].path.clone();
+    let _guard = lock_repo(&repo_root).await;
+
+    let Some(index) = existing
+        .iter()
+        .position(|w| same_path(&w.path, &params.worktree_path))
+    else {
+        return Err(fail(
+            "not-a-worktree",
+            format!("{} is not a worktree of {}", params.worktree_path.display(), repo_root.display()),
+        ));
+    };
+    if index == 0 {
+        return Err(fail("main-worktree", "refusing to remove the main worktree"));
+    }
+    let target = &existing[index];
+    if target.locked && !params.force {
+        return Err(fail(
+            "locked",
+            format!("{} is locked; pass force to remove it anyway", target.path.display()),
+        ));
+    }
+    if !params.force && target.path.is_dir() {
+        let status = git(&["status", "--porcelain"], &target.path)
+            .await
+            .map_err(|e| fail("git", e))?;
+        let changes = status.lines().count();
+        if changes > 0 {
+            return Err(fail(
+                "dirty",
+                format!(
+                    "{} has {} uncommitted change(s); commit them or pass force to discard",
+                    target.path.display(),
+                    changes
+                ),
+            ));
+        }
+    }
+
+    let path = target.path.to_string_lossy().into_owned();
+    let mut args = vec!["worktree", "remove"];
+    if params.force {
+        // Twice also overrides a lock
+        args.extend(["--force", "--force"]);
+    }
+    args.push(&path);
+    git(&args, &repo_root).await.map_err(|e| fail("git", e))?;
+    log::info!("Removed worktree {}", path);
+
+    let mut result = json!({ "worktreePath": target.path });
+    if params.delete_branch {
+        match &target.branch {
+            Some(branch) => {
+                let flag = if params.force { "-D" } else { "-d" };
+                match git(&["branch", flag, branch], &repo_root).await {
+                    Ok(_) => result["deletedBranch"] = json!(branch),
+                    // The worktree is gone either way; report why the branch stayed
+                    Err(e) => result["branchError"] = json!(e),
+                }
+            }
+            None => result["branchError"] = json!("worktree had a detached HEAD"),
+        }
+    }
+    Ok(result)
+}
+
+async fn prune(params: ListParams) -> Result<Value, Failure> {
+    let existing = worktrees(&params.base_path).await?;
+    let repo_root = existing[0].path.clone();
+    let _guard = lock_repo(&repo_root).await;
+
+    // --verbose reports on stderr
+    let (_, report) = git_output(&["worktree", "prune", "--verbose"], &repo_root)
+        .await
+        .map_err(|e| fail("git", e))?;
+    let pruned: Vec<&str> = report.lines().filter(|l| !l.trim().is_empty()).collect();
+    Ok(json!({ "basePath": repo_root, "pruned": pruned }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_porcelain_worktree_lists() {
+        let porcelain = "worktree /repo\nHEAD 1111111111111111111111111111111111111111\nbranch refs/heads/main\n\n\
+                         worktree /repo/.hapi-worktrees/fix\nHEAD 2222222222222222222222222222222222222222\ndetached\nlocked reason here\n\n\
+                         worktree /tmp/gone\nHEAD 3333333333333333333333333333333333333333\nbranch refs/heads/feature/x\nprunable gitdir file points to non-existent location\n";
+        let worktrees = parse_worktrees(porcelain);
+        assert_eq!(worktrees.len(), 3);
+
+        assert_eq!(worktrees[0].path, PathBuf::from("/repo"));
+        assert_eq!(worktrees[0].branch.as_deref(), Some("main"));
+        assert!(!worktrees[0].detached && !worktrees[0].locked);
+
+        assert_eq!(worktrees[1].branch, None);
+        assert!(worktrees[1].detached && worktrees[1].locked && !worktrees[1].prunable);
+        assert_eq!(
+            worktrees[1].head.as_deref(),
+            Some("2222222222222222222222222222222222222222")
+        );
+
+        assert_eq!(worktrees[2].branch.as_deref(), Some("feature/x"));
+        assert!(worktrees[2].prunable);
+    }
+
+    #[test]
+    fn worktrees_of_a_sandboxed_repository_are_created_and_removed() {
+        if !sandbox::landlock_supported() {
+            return;
+        }
+        let _policies = sandbox::TEST_POLICIES.lock().unwrap_or_else(|e| e.into_inner());
+        // Not under /tmp, which sandboxed commands may always write
+        let exe = std::env::current_exe().unwrap();
+        let base = exe.with_file_name(format!("happier-worktree-{}", std::process::id()));
+        let repo = base.join("repo");
+        std::fs::create_dir_all(&repo).unwrap();
+        let setup = std::process::Command::new("git")
+            .args(["-c", "user.name=t", "-c", "user.email=t@t"])
+            .args(["init", "-q", "-b", "main", "."])
+            .current_dir(&repo)
+            .status()
+            .unwrap();
+        assert!(setup.success());
+        let commit = std::process::Command::new("git")
+            .args(["-c", "user.name=t", "-c", "user.email=t@t"])
+            .args(["commit", "-q", "--allow-empty", "-m", "init"])
+            .current_dir(&repo)
+            .status()
+            .unwrap();
+        assert!(commit.success());
+        sandbox::configure(vec![SandboxPolicy {
+            directory: repo.clone(),
+            filesystem: true,
+            network: true,
+            read_only: Vec::new(),
+            writable: Vec::new(),
+        }]);
+
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .unwrap();
+        let created = runtime.block_on(create(CreateParams {
+            base_path: repo.clone(),
+            name: Some("fix".to_string()),
+            branch: None,
+            new_branch: None,
+            start_point: None,
+        }));
+        let removed = created.as_ref().ok().map(|created| {
+            runtime.block_on(remove(RemoveParams {
+                base_path: repo.clone(),
+                worktree_path: PathBuf::from(created["info"]["worktreePath"].as_str().unwrap()),
+                force: true,
+                delete_branch: true,
+            }))
+        });
+        sandbox::configure(Vec::new());
+        std::fs::remove_dir_all(&base).unwrap();
+
+        let created = created.map_err(|f| f.message).unwrap();
+        assert!(created["info"]["worktreePath"]
+            .as_str()
+            .unwrap()
+            .contains("repo-worktrees"));
+        removed.unwrap().map_err(|f| f.message).unwrap();
+    }
+
+    #[test]
+    fn parses_bare_repositories_and_empty_output() {
+        let worktrees = parse_worktrees("worktree /srv/repo.git\nbare\n");
+        assert_eq!(worktrees.len(), 1);
+        assert!(worktrees[0].bare);
+        assert!(parse_worktrees("").is_empty());
+    }
+}
@@ -0,0 +1,367 @@
+//! happier end to end, against the stand-in hub in `harness`.
+
+mod harness;
+
+use base64::engine::general_purpose::STANDARD as B64;
+use base64::Engine;
+use harness::{Daemon, Echo, Hub, TOKEN};
+use serde_json::json;
+
+#[tokio::test]
+async fn registers_connects_and_reports_running() {
+    let mut hub = Hub::start().await;
+    let _daemon = Daemon::start(&hub, "");
+    let mut socket = hub.accept().await;
+
+    let registrations = hub.registrations();
+    assert_eq!(registrations.len(), 1);
+    let registration = &registrations[0];
+    assert_eq!(registration.authorization.as_deref(), Some("Bearer test-token"));
+    let machine_id = registration.body["id"].as_str().unwrap().to_string();
+    assert!(registration.body["metadata"]["host"].is_string());
+    assert_eq!(
+        socket.auth,
+        json!({ "token": TOKEN, "clientType": "machine-scoped", "machineId": machine_id })
+    );
+
+    let methods = socket.expect("rpc-register").await;
+    assert!(methods["method"].as_str().unwrap().starts_with(&machine_id));
+    let state = socket.runner_state().await;
+    assert_eq!(state["status"], "running");
+    let alive = socket.expect("machine-alive").await;
+    assert_eq!(alive["machineId"], machine_id);
+}
+
+#[tokio::test]
+async fn tunnel_carries_data_both_ways() {
+    let mut hub = Hub::start().await;
+    let mut echo = Echo::start().await;
+    let _daemon = Daemon::start(&hub, "");
+    let mut socket = hub.accept().await;
+    socket.runner_state().await;
+
+    socket
+        .emit("tunnel:open", json!({ "tunnelId": "t1", "port": echo.port }))
+        .await;
+    assert_eq!(socket.expect("tunnel:ready").await, json!({ "tunnelId": "t1" }));
+
+    let data = B64.encode("hello through the tunnel");
+    socket
+        .emit("tunnel:data", json!({ "tunnelId": "t1", "data": data }))
+        .await;
+    let echoed = socket.expect("tunnel:data").await;
+    assert_eq!(echoed["tunnelId"], "t1");
+    assert_eq!(B64.decode(echoed["data"].as_str().unwrap()).unwrap(), b"hello through the tunnel");
+
+    // Closing from the hub ends the TCP connection
+    socket.emit("tunnel:close", json!({ "tunnelId": "t1" })).await;
+    echo.closed().await;
+}
+
+#[tokio::test]
+async fn tunnel_keeps_a_burst_whole_and_in_order() {
+    let mut hub = Hub::start().await;
+    let echo = Echo::start().await;
+    let _daemon = Daemon::start(&hub, "");
+    let mut socket = hub.accept().await;
+    socket.runner_state().await;
+
+    // Data sent right behind the open waits for the target to connect
+    socket
+        .emit("tunnel:open", json!({ "tunnelId": "t5", "port": echo.port }))
+        .await;
+    let mut sent = Vec::new();
+    for i in 0..500 {
+        if i == 100 {
+            socket.expect("tunnel:ready").await;
+        }
+        let chunk = format!("chunk {:04};", i);
+        sent.extend_from_slice(chunk.as_bytes());
+        socket
+            .emit("tunnel:data", json!({ "tunnelId": "t5", "data": B64.encode(chunk) }))
+            .await;
+    }
+    let mut echoed = Vec::new();
+    while echoed.len() < sent.len() {
+        let data = socket.expect("tunnel:data").await;
+        echoed.extend(B64.decode(data["data"].as_str().unwrap()).unwrap());
+    }
+    assert_eq!(String::from_utf8(echoed).unwrap(), String::from_utf8(sent).unwrap());
+}
+
+#[tokio::test]
+async fn tunnel_whose_target_falls_behind_is_closed() {
+    let mut hub = Hub::start().await;
+    let port = harness::stalled_target().await;
+    let daemon = Daemon::start(&hub, "");
+    let mut socket = hub.accept().await;
+    socket.runner_state().await;
+
+    socket
+        .emit("tunnel:open", json!({ "tunnelId": "t6", "port": port }))
+        .await;
+    socket.expect("tunnel:ready").await;
+    // Far more than the socket buffers and the tunnel's 8 MiB queue hold
+    let chunk = B64.encode(vec![b'x'; 128 * 1024]);
+    for _ in 0..200 {
+        socket
+            .emit("tunnel:data", json!({ "tunnelId": "t6", "data": chunk }))
+            .await;
+    }
+    let error = socket.expect("tunnel:error").await;
+    assert_eq!(error["tunnelId"], "t6");
+    assert_eq!(error["code"], "ENOBUFS", "log:\n{}", daemon.log());
+}
+
+#[tokio::test]
+async fn tunnel_closes_when_the_target_does() {
+    let mut hub = Hub::start().await;
+    let port = harness::closing_target().await;
+    let _daemon = Daemon::start(&hub, "");
+    let mut socket = hub.accept().await;
+    socket.runner_state().await;
+
+    socket
+        .emit("tunnel:open", json!({ "tunnelId": "t2", "port": port }))
+        .await;
+    socket.expect("tunnel:ready").await;
+    assert_eq!(socket.expect("tunnel:close").await, json!({ "tunnelId": "t2" }));
+}
+
+#[tokio::test]
+async fn tunnel_to_a_closed_port_is_refused() {
+    let mut hub = Hub::start().await;
+    let port = harness::closed_port().await;
+    let _daemon = Daemon::start(&hub, "");
+    let mut socket = hub.accept().await;
+    socket.runner_state().await;
+
+    socket
+        .emit("tunnel:open", json!({ "tunnelId": "t3", "port": port }))
+        .await;
+    let error = socket.expect("tunnel:error").await;
+    assert_eq!(error["tunnelId"], "t3");
+    assert_eq!(error["code"], "ECONNREFUSED");
+}
+
+#[tokio::test]
+async fn tunnel_outside_the_policy_is_denied() {
+    let mut hub = Hub::start().await;
+    let echo = Echo::start().await;
+    let _daemon = Daemon::start(&hub, "[tunnel]\nallowed_ports = [1]\n");
+    let mut socket = hub.accept().await;
+    socket.runner_state().await;
+
+    socket
+        .emit("tunnel:open", json!({ "tunnelId": "t4", "port": echo.port }))
+        .await;
+    let error = socket.expect("tunnel:error").await;
+    assert_eq!(error["code"], "EPOLICY");
+}
+
+#[tokio::test]
+async fn reconnects_after_the_socket_drops() {
+    let mut hub = Hub::start().await;
+    let _daemon = Daemon::start(&hub, "");
+    let mut socket = hub.accept().await;
+    socket.runner_state().await;
+    socket.drop_connection().await;
+
+    let mut socket = hub.accept().await;
+    assert_eq!(socket.runner_state().await["status"], "running");
+    // The machine is still registered; only the socket came back
+    assert_eq!(hub.registrations().len(), 1);
+}
+
+#[tokio::test]
+async fn rejected_token_at_registration_is_fatal() {
+    let hub = Hub::start().await;
+    hub.fail_registration(401);
+    let mut daemon = Daemon::start(&hub, "");
+    let status = daemon.wait().await;
+    assert_eq!(status.code(), Some(77), "log:\n{}", daemon.log());
+    assert_eq!(hub.registrations().len(), 1);
+}
+
+#[tokio::test]
+async fn rejected_token_at_connect_is_fatal() {
+    let mut hub = Hub::start().await;
+    let mut daemon = Daemon::start(&hub, "");
+    hub.refuse("Invalid token").await;
+    let status = daemon.wait().await;
+    assert_eq!(status.code(), Some(77), "log:\n{}", daemon.log());
+}
+
+#[tokio::test]
+async fn sigterm_reports_shutdown_and_leaves() {
+    let mut hub = Hub::start().await;
+    let mut daemon = Daemon::start(&hub, "[shutdown]\ndrain_secs = 1\n");
+    let mut socket = hub.accept().await;
+    socket.runner_state().await;
+
+    daemon.signal(libc::SIGTERM);
+    let state = socket.runner_state().await;
+    assert_eq!(state["status"], "shutting-down");
+    assert_eq!(state["shutdownSource"], "os-signal");
+    assert_eq!(socket.runner_state().await["status"], "stopped");
+    socket.expect_disconnect().await;
+    assert_eq!(daemon.wait().await.code(), Some(0), "log:\n{}", daemon.log());
+}
+
+#[tokio::test]
+async fn json_records_carry_the_machine_id_of_their_hub() {
+    let mut hub = Hub::start().await;
+    let mut other = Hub::start().await;
+    let token_file = std::env::temp_dir().join(format!("happier-e2e-token-{}", std::process::id()));
+    std::fs::write(&token_file, TOKEN).unwrap();
+    let config = format!(
+        "[logging]\nformat = \"json\"\n\n[[profile]]\nname = \"other\"\napi_url = \"{}\"\ntoken_file = \"{}\"\n",
+        other.url(),
+        token_file.display()
+    );
+    let daemon = Daemon::start(&hub, &config);
+    hub.accept().await.runner_state().await;
+    other.accept().await.runner_state().await;
+    let _ = std::fs::remove_file(&token_file);
+
+    let records: Vec<serde_json::Value> = daemon
+        .log()
+        .lines()
+        .filter_map(|line| serde_json::from_str(line).ok())
+        .collect();
+    for hub in [&hub, &other] {
+        let machine_id = &hub.registrations()[0].body["id"];
+        let connected = format!("Socket.IO connected to {}/cli", hub.url());
+        let record = records
+            .iter()
+            .find(|r| r["msg"] == connected.as_str())
+            .unwrap_or_else(|| panic!("no {:?} in log:\n{}", connected, daemon.log()));
+        assert_eq!(&record["machine_id"], machine_id);
+    }
+    assert_ne!(hub.registrations()[0].body["id"], other.registrations()[0].body["id"]);
+}
+
+#[tokio::test]
+async fn exits_from_a_run_stopped_offline_survive_into_the_next() {
+    let mut hub = Hub::start().await;
+    let mut daemon = Daemon::start(&hub, "[sessions]\ncommand = \"false\"\n");
+    let mut socket = hub.accept().await;
+    let first_pid = socket.runner_state().await["pid"].clone();
+
+    // A session that exits right away, with code 1
+    let machine_id = hub.registrations()[0].body["id"].as_str().unwrap().to_string();
+    let directory = daemon.home.display().to_string();
+    socket
+        .request(&machine_id, "spawn-happy-session", json!({ "directory": directory }))
+        .await;
+    loop {
+        let state = socket.runner_state().await;
+        if state["sessions"]["recentExits"].as_array().is_some_and(|e| !e.is_empty()) {
+            break;
+        }
+    }
+    socket.drop_connection().await;
+
+    // Stop while the hub turns us away, so "stopped" can't be reported
+    hub.refuse("Hub restarting").await;
+    daemon.signal(libc::SIGTERM);
+    assert_eq!(daemon.wait().await.code(), Some(0), "log:\n{}", daemon.log());
+    assert!(daemon.home.join("outbox.json").exists(), "log:\n{}", daemon.log());
+
+    // The old state is never published as current; its exits and shutdown are
+    let daemon = daemon.restart();
+    let mut socket = hub.accept().await;
+    let state = socket.runner_state().await;
+    assert_eq!(state["status"], "running", "log:\n{}", daemon.log());
+    assert_ne!(state["pid"], first_pid);
+    let exits = state["sessions"]["recentExits"].as_array().unwrap();
+    assert_eq!(exits.len(), 1);
+    assert_eq!(exits[0]["code"], 1);
+    assert_eq!(state["previousRun"]["pid"], first_pid);
+    assert_eq!(state["previousRun"]["status"], "stopped");
+    assert_eq!(state["previousRun"]["shutdownSource"], "os-signal");
+    assert!(!daemon.home.join("outbox.json").exists());
+}
+
+#[tokio::test]
+async fn codex_credentials_are_removed_when_the_session_exits() {
+    let mut hub = Hub::start().await;
+    let daemon = Daemon::start(&hub, "[sessions]\ncommand = \"sh\"\n");
+    let mut socket = hub.accept().await;
+    socket.runner_state().await;
+
+    // `sh codex ...` runs this script, which keeps a copy of what it was given
+    let directory = daemon.home.join("work");
+    std::fs::create_dir(&directory).unwrap();
+    std::fs::write(directory.join("codex"), "cp \"$CODEX_HOME/auth.json\" seen\n").unwrap();
+    let machine_id = hub.registrations()[0].body["id"].as_str().unwrap().to_string();
+    let params = json!({ "directory": directory, "agent": "codex", "token": "{\"k\":1}" });
+    socket.request(&machine_id, "spawn-happy-session", params).await;
+    loop {
+        let state = socket.runner_state().await;
+        if state["sessions"]["recentExits"].as_array().is_some_and(|e| !e.is_empty()) {
+            break;
+        }
+    }
+
+    let seen = std::fs::read_to_string(directory.join("seen"));
+    assert_eq!(seen.ok().as_deref(), Some("{\"k\":1}"), "log:\n{}", daemon.log());
+    let left = std::fs::read_dir(daemon.home.join("sessions")).unwrap().count();
+    assert_eq!(left, 0);
+}
+
+#[tokio::test]
+async fn sessions_are_refused_when_their_limits_cannot_be_enforced() {
+    let mut hub = Hub::start().await;
+    let config = "[sessions]\ncommand = \"sh\"\n\n[sessions.limits]\nmemory_max = \"1G\"\n";
+    let daemon = Daemon::start(&hub, config);
+    let mut socket = hub.accept().await;
+    let state = socket.runner_state().await;
+    if state["limits"]["memoryMax"]["enforcedBy"] == "cgroup" {
+        eprintln!("skipped: cgroup v2 is delegated here");
+        return;
+    }
+    assert_eq!(state["limits"]["memoryMax"]["enforcedBy"], "none");
+
+    // `sh claude ...` would leave this file behind
+    let directory = daemon.home.join("work");
+    std::fs::create_dir(&directory).unwrap();
+    std::fs::write(directory.join("claude"), "touch ran\n").unwrap();
+    let machine_id = hub.registrations()[0].body["id"].as_str().unwrap().to_string();
+    let answer = socket
+        .call(&machine_id, "spawn-happy-session", json!({ "directory": directory }))
+        .await;
+    let error = answer["error"].as_str().unwrap_or_default();
+    assert!(error.contains("memory_max need cgroup v2"), "{}\nlog:\n{}", answer, daemon.log());
+    assert!(!directory.join("ran").exists());
+}
+
+#[tokio::test]
+async fn doctor_looks_the_machine_up_without_registering_it() {
+    let hub = Hub::start().await;
+    let home = harness::new_home(&hub, "");
+    let settings = json!({ "machineId": "doctor-test-machine" });
+    std::fs::write(home.join("settings.json"), settings.to_string()).unwrap();
+
+    let output = harness::happier(&home).arg("doctor").output().await.unwrap();
+    let locked = home.join("happier.lock").exists();
+    let _ = std::fs::remove_dir_all(&home);
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(stdout.contains("doesn't know this machine yet"), "{}", stdout);
+    assert!(hub.registrations().is_empty());
+    assert!(!locked, "doctor created the instance lock");
+}
+
+#[tokio::test]
+async fn stop_without_a_daemon_leaves_the_home_alone() {
+    let hub = Hub::start().await;
+    let home = harness::new_home(&hub, "");
+
+    let output = harness::happier(&home).arg("stop").output().await.unwrap();
+    let locked = home.join("happier.lock").exists();
+    let _ = std::fs::remove_dir_all(&home);
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(!output.status.success());
+    assert!(stderr.contains("not running"), "{}", stderr);
+    assert!(!locked, "stop created the instance lock");
+}
@@ -0,0 +1,410 @@
+//! A stand-in hub for end-to-end tests: `POST /cli/machines` and the `/cli`
+//! Socket.IO namespace on one loopback port, with happier run against it as
+//! a child process. Each test gets its own hub, `HAPI_HOME` and daemon.
+
+#![allow(dead_code)]
+
+use happier_sio::mock::{HttpResponse, MockConnection, MockServer};
+use happier_sio::{Packet, PacketType};
+use serde_json::{json, Value};
+use std::path::{Path, PathBuf};
+use std::process::{ExitStatus, Stdio};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpListener;
+use tokio::sync::mpsc;
+use tokio::time::timeout;
+
+/// How long any one step may take before the test fails.
+pub const WAIT: Duration = Duration::from_secs(10);
+pub const TOKEN: &str = "test-token";
+
+/// A `POST /cli/machines` the hub received.
+#[derive(Debug, Clone)]
+pub struct Registration {
+    pub authorization: Option<String>,
+    pub body: Value,
+}
+
+#[derive(Default)]
+struct HubState {
+    registrations: Vec<Registration>,
+    /// Answer registrations with this status instead of 200.
+    register_status: Option<u16>,
+}
+
+pub struct Hub {
+    server: MockServer,
+    state: Arc<Mutex<HubState>>,
+}
+
+impl Hub {
+    pub async fn start() -> Hub {
+        let state = Arc::new(Mutex::new(HubState::default()));
+        let http_state = state.clone();
+        let server = MockServer::start_with_http(move |request| {
+            let mut state = http_state.lock().unwrap();
+            if let Some(id) = request.path.strip_prefix("/cli/machines/") {
+                let known = state.registrations.iter().any(|r| r.body["id"] == id);
+                if request.method != "GET" || !known {
+                    return HttpResponse::not_found();
+                }
+                return HttpResponse::json(200, &json!({ "machine": { "id": id } }));
+            }
+            if request.method != "POST" || request.path != "/cli/machines" {
+                return HttpResponse::not_found();
+            }
+            state.registrations.push(Registration {
+                authorization: request.header("authorization").map(String::from),
+                body: request.json(),
+            });
+            match state.register_status {
+                Some(status) => HttpResponse::json(status, &json!({ "error": "rejected" })),
+                None => HttpResponse::json(
+                    200,
+                    &json!({ "machine": { "metadataVersion": 1, "runnerStateVersion": 1 } }),
+                ),
+            }
+        })
+        .await
+        .unwrap();
+        Hub { server, state }
+    }
+
+    pub fn url(&self) -> String {
+        self.server.url()
+    }
+
+    pub fn registrations(&self) -> Vec<Registration> {
+        self.state.lock().unwrap().registrations.clone()
+    }
+
+    /// Answer registrations with `status` from now on.
+    pub fn fail_registration(&self, status: u16) {
+        self.state.lock().unwrap().register_status = Some(status);
+    }
+
+    async fn next_connection(&mut self) -> MockConnection {
+        timeout(WAIT, self.server.accept())
+            .await
+            .expect("happier did not open a socket")
+            .expect("mock server stopped")
+    }
+
+    /// The next socket happier opens, let into `/cli`.
+    pub async fn accept(&mut self) -> HubSocket {
+        let mut connection = self.next_connection().await;
+        let auth = connection.accept_connect().await.expect("no namespace connect");
+        HubSocket {
+            connection,
+            auth,
+            version: 1,
+            forget_machine: false,
+        }
+    }
+
+    /// Refuse the next socket's namespace connect with `message`.
+    pub async fn refuse(&mut self, message: &str) {
+        let mut connection = self.next_connection().await;
+        connection.refuse_connect(message).await.expect("no namespace connect");
+    }
+}
+
+/// happier's socket, answering its acks as the hub would.
+pub struct HubSocket {
+    connection: MockConnection,
+    /// What happier sent with its namespace connect.
+    pub auth: Value,
+    version: u64,
+    forget_machine: bool,
+}
+
+/// An event, an ack or a namespace disconnect from happier.
+enum Inbound {
+    Event(String, Value),
+    Ack(u64, Value),
+    Disconnect,
+}
+
+impl HubSocket {
+    /// Answer versioned updates with `not-found` from now on, as a hub
+    /// whose database lost the machine.
+    pub fn forget_machine(&mut self) {
+        self.forget_machine = true;
+    }
+
+    async fn next(&mut self) -> Option<Inbound> {
+        loop {
+            let packet = timeout(WAIT, self.connection.recv())
+                .await
+                .expect("happier sent nothing")?;
+            match packet.kind {
+                PacketType::Disconnect => return Some(Inbound::Disconnect),
+                PacketType::Ack => {
+                    let data = packet.data.as_ref().and_then(|d| d.get(0)).cloned();
+                    return Some(Inbound::Ack(packet.id?, data.unwrap_or(Value::Null)));
+                }
+                PacketType::Event => {}
+                _ => continue,
+            }
+            let Some((name, args)) = packet.event_args() else {
+                continue;
+            };
+            let (name, data) = (name.to_string(), args.first().cloned().unwrap_or(Value::Null));
+            if let Some(id) = packet.id {
+                let answer = self.answer(&name);
+                self.connection
+                    .send(&Packet::ack("/cli", id, vec![answer]))
+                    .await
+                    .ok()?;
+            }
+            return Some(Inbound::Event(name, data));
+        }
+    }
+
+    fn answer(&mut self, event: &str) -> Value {
+        match event {
+            "machine-update-state" | "machine-update-metadata" if self.forget_machine => {
+                json!({ "result": "error", "reason": "not-found" })
+            }
+            "machine-update-state" | "machine-update-metadata" => {
+                self.version += 1;
+                json!({ "result": "success", "version": self.version })
+            }
+            _ => json!({}),
+        }
+    }
+
+    /// The next event called `name`, skipping others; panics if the socket
+    /// closes first.
+    pub async fn expect(&mut self, name: &str) -> Value {
+        loop {
+            match self.next().await {
+                Some(Inbound::Event(event, data)) if event == name => return data,
+                Some(_) => {}
+                None => panic!("socket closed while waiting for {}", name),
+            }
+        }
+    }
+
+    /// The next runner state happier publishes.
+    pub async fn runner_state(&mut self) -> Value {
+        self.expect("machine-update-state").await["runnerState"].take()
+    }
+
+    /// Wait for happier to leave `/cli` and close the socket.
+    pub async fn expect_disconnect(&mut self) {
+        loop {
+            match self.next().await {
+                Some(Inbound::Disconnect) => break,
+                Some(_) => {}
+                None => panic!("socket closed without a disconnect"),
+            }
+        }
+        while self.next().await.is_some() {}
+    }
+
+    pub async fn emit(&mut self, event: &str, data: Value) {
+        self.connection
+            .emit("/cli", event, data)
+            .await
+            .expect("socket closed");
+    }
+
+    /// Call `method` on the machine with `rpc-request`, without waiting for
+    /// the answer.
+    pub async fn request(&mut self, machine_id: &str, method: &str, params: Value) {
+        let request = json!({
+            "method": format!("{}:{}", machine_id, method),
+            "params": params.to_string(),
+        });
+        self.connection
+            .send(&Packet::event("/cli", "rpc-request", vec![request], Some(1)))
+            .await
+            .expect("socket closed");
+    }
+
+    /// Call `method` on the machine and wait for its answer.
+    pub async fn call(&mut self, machine_id: &str, method: &str, params: Value) -> Value {
+        self.request(machine_id, method, params).await;
+        loop {
+            match self.next().await {
+                Some(Inbound::Ack(1, answer)) => {
+                    return serde_json::from_str(answer.as_str().expect("answer is a string"))
+                        .expect("answer is JSON")
+                }
+                Some(_) => {}
+                None => panic!("socket closed while waiting for {}", method),
+            }
+        }
+    }
+
+    /// Drop the connection without a Socket.IO disconnect.
+    pub async fn drop_connection(self) {
+        self.connection.close().await;
+    }
+}
+
+/// happier, run against a hub with its own `HAPI_HOME`.
+pub struct Daemon {
+    child: tokio::process::Child,
+    pub home: PathBuf,
+}
+
+impl Daemon {
+    /// Start happier with `config` added to its happier.toml.
+    pub fn start(hub: &Hub, config: &str) -> Daemon {
+        Daemon::spawn(new_home(hub, config))
+    }
+
+    /// Start happier again in the same `HAPI_HOME`, once this one has exited.
+    pub fn restart(mut self) -> Daemon {
+        let home = std::mem::take(&mut self.home);
+        Daemon::spawn(home)
+    }
+
+    fn spawn(home: PathBuf) -> Daemon {
+        let log = std::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(home.join("test.log"))
+            .unwrap();
+
+        let child = happier(&home)
+            .stdin(Stdio::null())
+            .stdout(Stdio::null())
+            .stderr(log)
+            .kill_on_drop(true)
+            .spawn()
+            .unwrap();
+        Daemon { child, home }
+    }
+
+    pub fn signal(&self, signal: i32) {
+        let pid = self.child.id().expect("happier already exited");
+        unsafe {
+            libc::kill(pid as i32, signal);
+        }
+    }
+
+    /// Wait for happier to exit.
+    pub async fn wait(&mut self) -> ExitStatus {
+        match timeout(WAIT, self.child.wait()).await {
+            Ok(status) => status.unwrap(),
+            Err(_) => panic!("happier did not exit; log:\n{}", self.log()),
+        }
+    }
+
+    /// What happier logged so far.
+    pub fn log(&self) -> String {
+        std::fs::read_to_string(self.home.join("test.log")).unwrap_or_default()
+    }
+}
+
+/// A fresh `HAPI_HOME` whose happier.toml points at `hub`, plus `config`.
+pub fn new_home(hub: &Hub, config: &str) -> PathBuf {
+    static NEXT: AtomicUsize = AtomicUsize::new(0);
+    let home = std::env::temp_dir().join(format!(
+        "happier-e2e-{}-{}",
+        std::process::id(),
+        NEXT.fetch_add(1, Ordering::Relaxed)
+    ));
+    let _ = std::fs::remove_dir_all(&home);
+    std::fs::create_dir_all(&home).unwrap();
+    let toml = format!("[hub]\napi_url = \"{}\"\n\n{}", hub.url(), config);
+    std::fs::write(home.join("happier.toml"), toml).unwrap();
+    home
+}
+
+/// happier in `home`, with nothing from the environment running the tests,
+/// e.g. NOTIFY_SOCKET.
+pub fn happier(home: &Path) -> tokio::process::Command {
+    let mut command = tokio::process::Command::new(env!("CARGO_BIN_EXE_happier"));
+    command
+        .env_clear()
+        .env("PATH", std::env::var_os("PATH").unwrap_or_default())
+        .env("HOME", home)
+        .env("HAPI_HOME", home)
+        .env("CLI_API_TOKEN", TOKEN)
+        .env("RUST_LOG", "debug");
+    command
+}
+
+impl Drop for Daemon {
+    fn drop(&mut self) {
+        let _ = self.child.start_kill();
+        // Empty once `restart` handed the home on
+        if !self.home.as_os_str().is_empty() {
+            let _ = std::fs::remove_dir_all(&self.home);
+        }
+    }
+}
+
+/// A TCP echo server on loopback, reporting each connection that ends.
+pub struct Echo {
+    pub port: u16,
+    closed: mpsc::UnboundedReceiver<()>,
+}
+
+impl Echo {
+    pub async fn start() -> Echo {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let (tx, closed) = mpsc::unbounded_channel();
+        tokio::spawn(async move {
+            while let Ok((mut stream, _)) = listener.accept().await {
+                let tx = tx.clone();
+                tokio::spawn(async move {
+                    let mut buf = [0; 4096];
+                    while let Ok(n) = stream.read(&mut buf).await {
+                        if n == 0 || stream.write_all(&buf[..n]).await.is_err() {
+                            break;
+                        }
+                    }
+                    let _ = tx.send(());
+                });
+            }
+        });
+        Echo { port, closed }
+    }
+
+    /// Wait for a connection to end.
+    pub async fn closed(&mut self) {
+        timeout(WAIT, self.closed.recv())
+            .await
+            .expect("tunnel target still connected");
+    }
+}
+
+/// A port that accepts connections and closes them at once.
+pub async fn closing_target() -> u16 {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let port = listener.local_addr().unwrap().port();
+    tokio::spawn(async move {
+        while let Ok((stream, _)) = listener.accept().await {
+            drop(stream);
+        }
+    });
+    port
+}
+
+/// A loopback port nothing listens on.
+pub async fn closed_port() -> u16 {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    listener.local_addr().unwrap().port()
+}
+
+/// A port that accepts connections and never reads from them.
+pub async fn stalled_target() -> u16 {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let port = listener.local_addr().unwrap().port();
+    tokio::spawn(async move {
+        let mut held = Vec::new();
+        while let Ok((stream, _)) = listener.accept().await {
+            held.push(stream);
+        }
+    });
+    port
+}
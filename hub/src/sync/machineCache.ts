@@ -10,9 +10,27 @@ const machineMetadataSchema = z.object({
     displayName: z.string().optional(),
     homeDir: z.string().optional(),
     happyHomeDir: z.string().optional(),
-    happyLibDir: z.string().optional()
+    happyLibDir: z.string().optional(),
+    arch: z.string().optional(),
+    osRelease: z.string().optional(),
+    kernelVersion: z.string().optional(),
+    cpuCount: z.number().optional(),
+    totalMemory: z.number().optional(),
+    agents: z.array(z.object({
+        flavor: z.string(),
+        version: z.string().optional(),
+        path: z.string().optional()
+    })).optional(),
+    listeningPorts: z.array(z.object({
+        port: z.number(),
+        process: z.string().optional(),
+        pid: z.number().optional()
+    })).optional()
 })
 
+export type MachineAgent = { flavor: string; version?: string; path?: string }
+export type MachineListeningPort = { port: number; process?: string; pid?: number }
+
 export interface Machine {
     id: string
     namespace: string
@@ -29,6 +47,15 @@ export interface Machine {
         homeDir?: string
         happyHomeDir?: string
         happyLibDir?: string
+        arch?: string
+        osRelease?: string
+        kernelVersion?: string
+        cpuCount?: number
+        totalMemory?: number
+        // Installed agent CLIs; absent when the runner does not report them
+        agents?: MachineAgent[]
+        // Loopback TCP ports the runner would open a tunnel to
+        listeningPorts?: MachineListeningPort[]
     } | null
     metadataVersion: number
     runnerState: unknown | null
@@ -101,7 +128,11 @@ export class MachineCache {
             const homeDir = typeof data.homeDir === 'string' ? data.homeDir : undefined
             const happyHomeDir = typeof data.happyHomeDir === 'string' ? data.happyHomeDir : undefined
             const happyLibDir = typeof data.happyLibDir === 'string' ? data.happyLibDir : undefined
-            return { host, platform, happyCliVersion, displayName, homeDir, happyHomeDir, happyLibDir }
+            const { arch, osRelease, kernelVersion, cpuCount, totalMemory, agents, listeningPorts } = data
+            return {
+                host, platform, happyCliVersion, displayName, homeDir, happyHomeDir, happyLibDir,
+                arch, osRelease, kernelVersion, cpuCount, totalMemory, agents, listeningPorts
+            }
         })()
 
         const storedActiveAt = stored.activeAt ?? stored.createdAt
@@ -108,7 +108,8 @@ export type TunnelClosePayload = z.infer<typeof TunnelClosePayloadSchema>
 
 export const TunnelErrorPayloadSchema = z.object({
     tunnelId: z.string().min(1),
-    message: z.string()
+    message: z.string(),
+    code: z.string().optional()
 })
 
 export type TunnelErrorPayload = z.infer<typeof TunnelErrorPayloadSchema>
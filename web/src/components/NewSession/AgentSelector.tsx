@@ -3,6 +3,7 @@ import { useTranslation } from '@/lib/use-translation'
 
 export function AgentSelector(props: {
     agent: AgentType
+    agents: AgentType[]
     isDisabled: boolean
     onAgentChange: (value: AgentType) => void
 }) {
@@ -14,7 +15,7 @@ export function AgentSelector(props: {
                 {t('newSession.agent')}
             </label>
             <div className="flex gap-3">
-                {(['claude', 'codex', 'gemini', 'opencode'] as const).map((agentType) => (
+                {props.agents.map((agentType) => (
                     <label
                         key={agentType}
                         className="flex items-center gap-1.5 cursor-pointer"
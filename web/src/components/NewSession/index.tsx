@@ -7,7 +7,7 @@ import { useSessions } from '@/hooks/queries/useSessions'
 import { useActiveSuggestions, type Suggestion } from '@/hooks/useActiveSuggestions'
 import { useDirectorySuggestions } from '@/hooks/useDirectorySuggestions'
 import { useRecentPaths } from '@/hooks/useRecentPaths'
-import type { AgentType, SessionType } from './types'
+import { AGENT_TYPES, type AgentType, type SessionType } from './types'
 import { ActionButtons } from './ActionButtons'
 import { AgentSelector } from './AgentSelector'
 import { DirectorySection } from './DirectorySection'
@@ -50,6 +50,21 @@ export function NewSession(props: {
     const [error, setError] = useState<string | null>(null)
     const worktreeInputRef = useRef<HTMLInputElement>(null)
 
+    // Machines that report installed agents only offer those
+    const availableAgents = useMemo(() => {
+        const machine = props.machines.find((m) => m.id === machineId)
+        const installed = machine?.metadata?.agents?.map((a) => a.flavor)
+        if (!installed) return AGENT_TYPES
+        const filtered = AGENT_TYPES.filter((type) => installed.includes(type))
+        return filtered.length > 0 ? filtered : AGENT_TYPES
+    }, [props.machines, machineId])
+
+    useEffect(() => {
+        if (!availableAgents.includes(agent)) {
+            setAgent(availableAgents[0])
+        }
+    }, [availableAgents, agent])
+
     useEffect(() => {
         if (sessionType === 'worktree') {
             worktreeInputRef.current?.focus()
@@ -296,6 +311,7 @@ export function NewSession(props: {
             />
             <AgentSelector
                 agent={agent}
+                agents={availableAgents}
                 isDisabled={isFormDisabled}
                 onAgentChange={setAgent}
             />
@@ -1,4 +1,5 @@
 export type AgentType = 'claude' | 'codex' | 'gemini' | 'opencode'
+export const AGENT_TYPES: AgentType[] = ['claude', 'codex', 'gemini', 'opencode']
 export type SessionType = 'simple' | 'worktree'
 
 export const MODEL_OPTIONS: Record<AgentType, { value: string; label: string }[]> = {
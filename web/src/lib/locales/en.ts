@@ -50,6 +50,7 @@ export default {
   'qr.error.authFailed': 'Authentication failed',
   'qr.confirm.title': 'Authorize new device',
   'qr.confirm.description': 'A new device is requesting to sign in to your account.',
+  'qr.confirm.code': 'Check that the device shows code {code}',
   'qr.confirm.allow': 'Allow',
   'qr.confirm.deny': 'Deny',
   'qr.confirm.confirming': 'Authorizing…',
@@ -50,6 +50,7 @@ export default {
   'qr.error.authFailed': '认证失败',
   'qr.confirm.title': '授权新设备',
   'qr.confirm.description': '一个新设备请求登录您的账户。',
+  'qr.confirm.code': '请确认设备显示的代码为 {code}',
   'qr.confirm.allow': '允许',
   'qr.confirm.deny': '拒绝',
   'qr.confirm.confirming': '授权中…',
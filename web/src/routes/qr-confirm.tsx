@@ -10,6 +10,8 @@ export default function QrConfirmPage() {
     const { qrId } = useParams({ from: '/qr/$qrId' })
     const search = useSearch({ from: '/qr/$qrId' })
     const secret = (search as { s?: string }).s
+    // Matches the code printed by the requesting terminal (e.g. `happier login`)
+    const shortCode = qrId.replace(/-/g, '').slice(0, 8).toUpperCase().replace(/^(.{4})/, '$1-')
 
     const [status, setStatus] = useState<'idle' | 'confirming' | 'confirmed' | 'denying' | 'denied' | 'error'>('idle')
     const [error, setError] = useState<string | null>(null)
@@ -101,6 +103,9 @@ export default function QrConfirmPage() {
                             <div className="text-sm text-[var(--app-hint)]">
                                 {t('qr.confirm.description')}
                             </div>
+                            <div className="text-sm text-[var(--app-hint)]">
+                                {t('qr.confirm.code', { code: shortCode })}
+                            </div>
                         </div>
 
                         {!secret && (
@@ -46,6 +46,15 @@ export type Machine = {
         platform: string
         happyCliVersion: string
         displayName?: string
+        arch?: string
+        osRelease?: string
+        kernelVersion?: string
+        cpuCount?: number
+        totalMemory?: number
+        // Installed agent CLIs; absent when the runner does not report them
+        agents?: { flavor: string; version?: string }[]
+        // Loopback TCP ports the machine would open a tunnel to
+        listeningPorts?: { port: number; process?: string }[]
     } | null
 }
 